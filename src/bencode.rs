@@ -2,10 +2,12 @@ mod de;
 mod error;
 mod mappers;
 mod prelude;
+mod raw;
 mod ser;
 mod value;
 
 pub use de::*;
 pub use mappers::*;
+pub use raw::*;
 pub use ser::*;
 pub use value::*;
@@ -5,6 +5,7 @@ use serde::{
 
 use super::prelude::*;
 use crate::prelude::*;
+#[allow(dead_code)]
 pub fn from_str<'de, T, V>(data: T) -> Result<V>
 where
     T: AsRef<str>,
@@ -14,12 +15,70 @@ where
 }
 
 pub fn from_bytes<'de, 'a, V>(data: &'a [u8]) -> Result<V>
+where
+    V: serde::de::Deserialize<'de>,
+{
+    from_bytes_with_config(data, ParseConfig::default())
+}
+
+pub fn from_bytes_with_config<'de, 'a, V>(data: &'a [u8], config: ParseConfig) -> Result<V>
 where
     V: serde::de::Deserialize<'de>,
 {
     let mut iter = data.iter().copied();
-    let mut deserialize = Deserializer::new(&mut iter);
-    V::deserialize(&mut deserialize).context("from_bytes deserialize")
+    let mut deserialize = Deserializer::new(&mut iter, config);
+    let value = V::deserialize(&mut deserialize).context("from_bytes deserialize")?;
+    if config.strict && iter.next().is_some() {
+        bail!("trailing data after bencode value");
+    }
+    Ok(value)
+}
+
+/// Lenient by default for interop with buggy encoders
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseConfig {
+    /// Rejects '+' sign and leading zeros in integers,
+    /// unsorted dictionary keys and trailing data
+    pub strict: bool,
+    /// Bounds memory of decoded value, none for trusted input
+    pub budget: Option<DecodeBudget>,
+}
+
+/// Limits of decoded content, checked while parsing, before anything is allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeBudget {
+    /// Total bytes of all strings
+    pub max_string_bytes: usize,
+    /// Total count of integers, strings, lists and dictionaries
+    pub max_elements: usize,
+}
+
+/// Whether decoding failed on [`DecodeBudget`], anywhere in error chain
+pub fn is_budget_exceeded(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| matches!(cause.downcast_ref(), Some(Error::BudgetExceeded(_))))
+}
+
+/// Longest encoding of a single element: integer of 20 digits with sign,
+/// or length prefix of string
+const MAX_ELEMENT_OVERHEAD: usize = 22;
+
+impl DecodeBudget {
+    /// Longest input that could fit budget, anything bigger is rejected before it's read
+    pub fn max_encoded_len(&self) -> usize {
+        self.max_string_bytes
+            .saturating_add(self.max_elements.saturating_mul(MAX_ELEMENT_OVERHEAD))
+    }
+}
+
+impl ParseConfig {
+    pub fn with_budget(self, budget: DecodeBudget) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
 }
 
 enum ElemenentParse {
@@ -33,8 +92,17 @@ enum ElemenentParse {
 struct Deserializer<'a, T: Iterator> {
     data: &'a mut T,
     seq_parse: Option<ElemenentParse>,
+    config: ParseConfig,
+    /// Last key of each dictionary being parsed
+    map_keys: Vec<Option<Vec<u8>>>,
+    depth: usize,
+    string_bytes: usize,
+    elements: usize,
 }
 
+/// Nesting limit, so hostile input can't overflow the stack
+pub const MAX_NESTING_DEPTH: usize = 64;
+
 impl<'a, 'de, T: Iterator<Item = u8>> SeqAccess<'de> for Deserializer<'a, T> {
     type Error = Error;
 
@@ -70,8 +138,14 @@ impl<'a, 'de, T: Iterator<Item = u8>> MapAccess<'de> for Deserializer<'a, T> {
         // println!("Type of T: {}", std::any::type_name::<T>());
         // println!("Type of K: {}", std::any::type_name::<K>());
         match self.get_next_element()? {
-            ElemenentParse::End => Ok(None),
+            ElemenentParse::End => {
+                self.map_keys.pop();
+                Ok(None)
+            }
             m => {
+                if let ElemenentParse::String(key) = &m {
+                    self.check_key_order(key)?;
+                }
                 self.seq_parse = Some(m);
                 let ele = seed
                     .deserialize(self)
@@ -101,13 +175,24 @@ impl<'a, 'de, T: Iterator<Item = u8>> serde::Deserializer<'de> for &mut Deserial
         match self.get_next_element()? {
             ElemenentParse::Integer(v) => visitor.visit_i64(v),
             ElemenentParse::String(v) => visitor.visit_bytes(&v),
-            ElemenentParse::List => self.deserialize_seq(visitor),
-            ElemenentParse::Map => self.deserialize_map(visitor),
+            ElemenentParse::List => visitor.visit_seq(self),
+            ElemenentParse::Map => {
+                self.enter_map();
+                visitor.visit_map(self)
+            }
             ElemenentParse::End => Err(Error::UnexpectedEnd),
         }
     }
 
-    forward_to_deserialize_any! { enum i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 bytes struct char unit unit_struct option str string ignored_any }
+    forward_to_deserialize_any! { enum i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 bytes struct char unit unit_struct str string ignored_any }
+
+    // NOTE: bencode has no null, present key is always some
+    fn deserialize_option<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
 
     fn deserialize_bool<V>(self, _: V) -> std::result::Result<V::Value, Self::Error>
     where
@@ -139,7 +224,13 @@ impl<'a, 'de, T: Iterator<Item = u8>> serde::Deserializer<'de> for &mut Deserial
         V: serde::de::Visitor<'de>,
     {
         //println!("Type of V: {}", std::any::type_name::<V>());
-        visitor.visit_seq(self)
+        match self.get_next_element()? {
+            ElemenentParse::List => visitor.visit_seq(self),
+            other => {
+                self.seq_parse = Some(other);
+                self.deserialize_any(visitor)
+            }
+        }
     }
 
     fn deserialize_tuple<V>(self, _: usize, _: V) -> std::result::Result<V::Value, Self::Error>
@@ -172,16 +263,72 @@ impl<'a, 'de, T: Iterator<Item = u8>> serde::Deserializer<'de> for &mut Deserial
     where
         V: serde::de::Visitor<'de>,
     {
-        visitor.visit_map(self)
+        match self.get_next_element()? {
+            ElemenentParse::Map => {
+                self.enter_map();
+                visitor.visit_map(self)
+            }
+            other => {
+                self.seq_parse = Some(other);
+                self.deserialize_any(visitor)
+            }
+        }
     }
 }
 
 impl<'a, T: Iterator<Item = u8>> Deserializer<'a, T> {
-    fn new(data: &'a mut T) -> Self {
+    fn new(data: &'a mut T, config: ParseConfig) -> Self {
         Self {
             data,
             seq_parse: None,
+            config,
+            map_keys: Vec::new(),
+            depth: 0,
+            string_bytes: 0,
+            elements: 0,
+        }
+    }
+
+    /// Accounts element about to be read, string length is known from its prefix
+    fn spend_budget(&mut self, string_len: usize) -> Result<()> {
+        self.elements += 1;
+        self.string_bytes = self.string_bytes.saturating_add(string_len);
+        let Some(budget) = self.config.budget else {
+            return Ok(());
+        };
+        if self.elements > budget.max_elements {
+            return Err(Error::BudgetExceeded(format!(
+                "more than {} elements",
+                budget.max_elements
+            ))
+            .into());
+        }
+        if self.string_bytes > budget.max_string_bytes {
+            return Err(Error::BudgetExceeded(format!(
+                "more than {} bytes of strings",
+                budget.max_string_bytes
+            ))
+            .into());
         }
+        Ok(())
+    }
+
+    fn enter_map(&mut self) {
+        self.map_keys.push(None);
+    }
+
+    fn check_key_order(&mut self, key: &[u8]) -> Result<()> {
+        let Some(last_key) = self.map_keys.last_mut() else {
+            return Ok(());
+        };
+        if self.config.strict && last_key.as_deref().is_some_and(|last| last >= key) {
+            bail!(
+                "dictionary key {} is out of order",
+                String::from_utf8_lossy(key)
+            );
+        }
+        *last_key = Some(key.to_vec());
+        Ok(())
     }
 
     fn get_int(&mut self) -> Result<i64> {
@@ -189,6 +336,9 @@ impl<'a, T: Iterator<Item = u8>> Deserializer<'a, T> {
 
         for byte in &mut self.data {
             if byte == b'e' {
+                if self.config.strict {
+                    validate_strict_int(&int_vec)?;
+                }
                 let integer = String::from_utf8(int_vec)
                     .context("utf8 expected as char for int")?
                     .parse::<i64>()
@@ -204,6 +354,7 @@ impl<'a, T: Iterator<Item = u8>> Deserializer<'a, T> {
 
     fn get_string_bytes(&mut self, first_number: u8) -> Result<Vec<u8>> {
         let string_len = self.get_length_of_bytes(first_number)?;
+        self.spend_budget(string_len)?;
         let byte_string = self.data.take(string_len).collect::<Vec<u8>>();
         let byte_string_len = byte_string.len();
         if byte_string_len != string_len {
@@ -240,11 +391,146 @@ impl<'a, T: Iterator<Item = u8>> Deserializer<'a, T> {
 
         match next {
             x if x.is_ascii_digit() => Ok(ElemenentParse::String(self.get_string_bytes(x)?)),
-            b'i' => Ok(ElemenentParse::Integer(self.get_int()?)),
-            b'l' => Ok(ElemenentParse::List),
-            b'd' => Ok(ElemenentParse::Map),
-            b'e' => Ok(ElemenentParse::End),
+            b'i' => {
+                self.spend_budget(0)?;
+                Ok(ElemenentParse::Integer(self.get_int()?))
+            }
+            b'l' | b'd' => {
+                self.spend_budget(0)?;
+                self.depth += 1;
+                anyhow::ensure!(
+                    self.depth <= MAX_NESTING_DEPTH,
+                    "nesting deeper than {MAX_NESTING_DEPTH}"
+                );
+                Ok(if next == b'l' {
+                    ElemenentParse::List
+                } else {
+                    ElemenentParse::Map
+                })
+            }
+            b'e' => {
+                self.depth = self.depth.saturating_sub(1);
+                Ok(ElemenentParse::End)
+            }
             s => bail!("invalid character {}", s),
         }
     }
 }
+
+/// No '+' sign, no leading zeros and no negative zero
+fn validate_strict_int(int_bytes: &[u8]) -> Result<()> {
+    let digits = int_bytes.strip_prefix(b"-").unwrap_or(int_bytes);
+    if int_bytes.first() == Some(&b'+') {
+        bail!("leading '+' in integer is not allowed");
+    }
+    if digits.len() > 1 && digits[0] == b'0' {
+        bail!("leading zero in integer is not allowed");
+    }
+    if int_bytes == b"-0" {
+        bail!("negative zero is not allowed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::Value;
+    use serde::Deserialize;
+
+    const INPUT_LEN: usize = 50 * 1024 * 1024;
+
+    fn tracker_budget() -> ParseConfig {
+        ParseConfig::default().with_budget(DecodeBudget {
+            max_string_bytes: 1024 * 1024,
+            max_elements: 100_000,
+        })
+    }
+
+    /// Decodes lazily generated input, returns error and count of bytes read
+    fn decode_counting(input: impl Iterator<Item = u8>) -> (anyhow::Error, usize) {
+        let mut consumed = 0;
+        let mut iter = input.inspect(|_| consumed += 1);
+        let error = Value::deserialize(&mut Deserializer::new(&mut iter, tracker_budget()))
+            .expect_err("over budget");
+        drop(iter);
+        (error.into(), consumed)
+    }
+
+    #[test]
+    fn huge_string_is_rejected_from_its_length_prefix() {
+        let prefix = format!("d5:peers{INPUT_LEN}:");
+        let input = prefix
+            .clone()
+            .into_bytes()
+            .into_iter()
+            .chain(std::iter::repeat_n(b'x', INPUT_LEN))
+            .chain(*b"e");
+
+        let (error, consumed) = decode_counting(input);
+
+        assert!(is_budget_exceeded(&error), "{error:?}");
+        assert_eq!(consumed, prefix.len());
+    }
+
+    #[test]
+    fn huge_list_is_rejected_once_elements_run_out() {
+        let input = b"l"
+            .iter()
+            .copied()
+            .chain(b"i1e".iter().copied().cycle().take(INPUT_LEN))
+            .chain(*b"e");
+
+        let (error, consumed) = decode_counting(input);
+
+        assert!(is_budget_exceeded(&error), "{error:?}");
+        assert!(consumed < 1024 * 1024, "read {consumed} bytes");
+    }
+
+    #[test]
+    fn input_within_budget_decodes() {
+        let value: Value =
+            from_bytes_with_config(b"d5:peers6:abcdefe", tracker_budget()).expect("within budget");
+        assert!(matches!(value, Value::Dict(_)));
+    }
+
+    fn strict() -> ParseConfig {
+        ParseConfig {
+            strict: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plus_sign_is_accepted_only_when_lenient() {
+        let lenient: i64 = from_bytes_with_config(b"i+5e", ParseConfig::default()).unwrap();
+        assert_eq!(lenient, 5);
+
+        let err = from_bytes_with_config::<i64>(b"i+5e", strict()).unwrap_err();
+        assert!(format!("{err:#}").contains("leading '+'"), "{err:#}");
+    }
+
+    #[test]
+    fn strict_mode_still_takes_canonical_integers() {
+        for (input, expected) in [(&b"i5e"[..], 5), (b"i-5e", -5), (b"i0e", 0)] {
+            assert_eq!(
+                from_bytes_with_config::<i64>(input, strict()).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn corpus_never_panics_decoder() {
+        crate::torrent::testing::assert_no_panics(|input| {
+            let _ = from_bytes::<Value>(input);
+            let _ = from_bytes_with_config::<Value>(
+                input,
+                strict().with_budget(DecodeBudget {
+                    max_string_bytes: 1024,
+                    max_elements: 64,
+                }),
+            );
+        });
+    }
+}
@@ -4,6 +4,8 @@ use thiserror::Error;
 pub enum Error {
     #[error("unexpected end")]
     UnexpectedEnd,
+    #[error("decode budget exceeded: {0}")]
+    BudgetExceeded(String),
     #[error("generic error")]
     Other(#[from] anyhow::Error),
 }
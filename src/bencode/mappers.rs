@@ -1,12 +1,14 @@
 use std::{
     fmt,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
 
 use super::prelude::*;
+use crate::prelude::Bytes20;
 use reqwest::Url;
 
-pub fn deserialize_hashes<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+pub fn deserialize_hashes<'de, D>(deserializer: D) -> Result<Arc<[Bytes20]>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -20,29 +22,64 @@ where
     deserializer.deserialize_str(UrlVisitor)
 }
 
-pub fn deserialize_ips<'de, D>(deserializer: D) -> Result<Vec<SocketAddrV4>, D::Error>
+/// Peer as tracker lists it, dictionary peers could name a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerPeer {
+    Addr(SocketAddr),
+    /// Resolved later, lookups don't belong into parsing
+    Host(String, u16),
+}
+
+pub fn deserialize_ips<'de, D>(deserializer: D) -> Result<Vec<TrackerPeer>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     deserializer.deserialize_bytes(IpsVisitor)
 }
 
-pub fn bytes_serialize<S>(x: &[Vec<u8>], s: S) -> Result<S::Ok, S::Error>
+/// Compact ipv6 peers (BEP 7), 16 bytes address and 2 bytes port each
+pub fn deserialize_ips6<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
 where
-    S: serde::Serializer,
+    D: serde::Deserializer<'de>,
 {
-    let bytes: Vec<u8> = x.iter().flatten().copied().collect();
-    s.serialize_bytes(&bytes)
+    deserializer.deserialize_bytes(Ips6Visitor)
+}
+
+/// Invalid tracker urls are skipped, a single dead entry shouldn't reject torrent
+pub fn deserialize_announce_list<'de, D>(deserializer: D) -> Result<Vec<Vec<Url>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let tiers: Vec<Vec<serde_bytes::ByteBuf>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(tiers
+        .into_iter()
+        .map(|tier| {
+            tier.into_iter()
+                .filter_map(|url| {
+                    UrlVisitor
+                        .visit_bytes::<serde::de::value::Error>(&url)
+                        .map_err(|e| tracing::debug!("skipping tracker: {e}"))
+                        .ok()
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|tier| !tier.is_empty())
+        .collect())
+}
+
+pub fn deserialize_external_ip<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserializer.deserialize_bytes(ExternalIpVisitor).map(Some)
 }
 
-pub fn bytes_lossy_string_serialize<S>(x: &[u8], s: S) -> Result<S::Ok, S::Error>
+pub fn bytes_serialize<S>(x: &[Bytes20], s: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    // NOTE: Just convert it to string, regardless if it is correct or not,
-    // reqwest will serde encode chars it will need
-    let encoded = unsafe { String::from_utf8_unchecked(x.to_vec()) };
-    s.serialize_str(&encoded)
+    let bytes: Vec<u8> = x.iter().flatten().copied().collect();
+    s.serialize_bytes(&bytes)
 }
 
 struct UrlVisitor;
@@ -58,7 +95,12 @@ impl<'de> Visitor<'de> for UrlVisitor {
     where
         E: serde::de::Error,
     {
-        Url::parse(value).map_err(E::custom)
+        let url = Url::parse(value).map_err(E::custom)?;
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(E::custom("url with empty host"));
+        }
+
+        Ok(url)
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
@@ -66,39 +108,83 @@ impl<'de> Visitor<'de> for UrlVisitor {
         E: serde::de::Error,
     {
         let value = String::from_utf8_lossy(v);
-        Url::parse(&value).map_err(E::custom)
+        self.visit_str(&value)
     }
 }
 
 struct HashesVisitor;
 
 impl<'de> Visitor<'de> for HashesVisitor {
-    type Value = Vec<Vec<u8>>;
+    type Value = Arc<[Bytes20]>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a valid Vec string")
+        formatter.write_str("a byte string of concatenated 20 byte hashes")
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        let mut hashes = Vec::new();
-        for hash in v.chunks(20) {
-            hashes.push(hash.into())
+        let hashes = v.chunks_exact(20);
+        if !hashes.remainder().is_empty() {
+            return Err(E::custom(format!(
+                "pieces length {} is not a multiple of 20",
+                v.len()
+            )));
         }
 
-        Ok(hashes)
+        Ok(hashes
+            .map(|hash| {
+                let mut bytes = [0u8; 20];
+                bytes.copy_from_slice(hash);
+                bytes
+            })
+            .collect())
     }
 }
 
 struct IpsVisitor;
 
+/// Peer of non compact tracker response
+#[derive(serde::Deserialize)]
+struct DictPeer {
+    ip: serde_bytes::ByteBuf,
+    port: u16,
+}
+
+impl DictPeer {
+    /// Ip could be a hostname
+    fn tracker_peer(&self) -> Option<TrackerPeer> {
+        let host = std::str::from_utf8(&self.ip).ok()?;
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Some(TrackerPeer::Addr(SocketAddr::new(ip, self.port))),
+            Err(_) => Some(TrackerPeer::Host(host.to_string(), self.port)),
+        }
+    }
+}
+
 impl<'de> Visitor<'de> for IpsVisitor {
-    type Value = Vec<SocketAddrV4>;
+    type Value = Vec<TrackerPeer>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a valid URL string")
+        formatter.write_str("a compact peer string or a list of peer dictionaries")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut peers = Vec::new();
+        while let Some(peer) = seq.next_element::<DictPeer>()? {
+            match peer.tracker_peer() {
+                Some(peer) => peers.push(peer),
+                None => tracing::debug!(
+                    "skipping peer with invalid ip {}",
+                    String::from_utf8_lossy(&peer.ip)
+                ),
+            }
+        }
+        Ok(peers)
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
@@ -110,9 +196,130 @@ impl<'de> Visitor<'de> for IpsVisitor {
             .map(|f| {
                 let ip = Ipv4Addr::new(f[0], f[1], f[2], f[3]);
                 let port = u16::from_be_bytes([f[4], f[5]]);
-                SocketAddrV4::new(ip, port)
+                TrackerPeer::Addr(SocketAddr::new(ip.into(), port))
             })
             .collect();
         Ok(ips)
     }
 }
+
+struct Ips6Visitor;
+
+impl<'de> Visitor<'de> for Ips6Visitor {
+    type Value = Vec<SocketAddr>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a compact ipv6 peer string")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(compact_peers6(v))
+    }
+}
+
+/// Splits 18 bytes chunks into ipv6 addresses, trailing partial chunk is ignored
+fn compact_peers6(v: &[u8]) -> Vec<SocketAddr> {
+    v.chunks_exact(18)
+        .map(|f| {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&f[..16]);
+            let port = u16::from_be_bytes([f[16], f[17]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        })
+        .collect()
+}
+
+/// Trackers send either compact 4 or 16 bytes address or a dotted string
+struct ExternalIpVisitor;
+
+impl<'de> Visitor<'de> for ExternalIpVisitor {
+    type Value = IpAddr;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a compact or string ip address")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        if let Ok(octets) = <[u8; 4]>::try_from(v) {
+            return Ok(Ipv4Addr::from(octets).into());
+        }
+        if let Ok(octets) = <[u8; 16]>::try_from(v) {
+            return Ok(Ipv6Addr::from(octets).into());
+        }
+
+        let value = std::str::from_utf8(v).map_err(E::custom)?;
+        value.trim().parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::from_bytes;
+
+    #[derive(serde::Deserialize)]
+    struct Peers {
+        #[serde(deserialize_with = "deserialize_ips")]
+        peers: Vec<TrackerPeer>,
+    }
+
+    #[test]
+    fn compact_peers() {
+        let body = b"d5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e";
+
+        let Peers { peers } = from_bytes(body).unwrap();
+        assert_eq!(
+            peers,
+            [
+                TrackerPeer::Addr("127.0.0.1:6881".parse().unwrap()),
+                TrackerPeer::Addr("10.0.0.2:80".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_peers_keep_hostnames_unresolved() {
+        let body = b"d5:peersl\
+            d2:ip8:10.0.0.27:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+            d2:ip3:::14:porti51413ee\
+            d2:ip16:peer.example.org4:porti80ee\
+            d2:ip2:\xff\xfe4:porti1ee\
+            ee";
+
+        let Peers { peers } = from_bytes(body).unwrap();
+        assert_eq!(
+            peers,
+            [
+                TrackerPeer::Addr("10.0.0.2:6881".parse().unwrap()),
+                TrackerPeer::Addr("[::1]:51413".parse().unwrap()),
+                TrackerPeer::Host("peer.example.org".to_string(), 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_peer_port_must_be_integer() {
+        let body = b"d5:peersld2:ip8:10.0.0.24:port4:6881eee";
+
+        assert!(from_bytes::<Peers>(body).is_err());
+    }
+
+    #[test]
+    fn compact_peers6_drop_trailing_partial_entry() {
+        let mut compact = Vec::new();
+        compact.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        compact.extend_from_slice(&6881u16.to_be_bytes());
+        compact.extend_from_slice(&[0xff; 17]);
+
+        assert_eq!(
+            compact_peers6(&compact),
+            ["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
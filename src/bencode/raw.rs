@@ -0,0 +1,89 @@
+use crate::prelude::*;
+
+use super::MAX_NESTING_DEPTH;
+
+/// Exact bytes of the value stored under `key` of top level dictionary,
+/// so hashes are computed over original encoding, keys we don't model included
+pub fn raw_dict_value<'a>(data: &'a [u8], key: &[u8]) -> Result<&'a [u8]> {
+    anyhow::ensure!(data.first() == Some(&b'd'), "dictionary expected");
+    let mut position = 1;
+    while data.get(position) != Some(&b'e') {
+        let key_end = skip_value(data, position, 1)?;
+        let dict_key = string_bytes(&data[position..key_end])?;
+        let value_end = skip_value(data, key_end, 1)?;
+        if dict_key == key {
+            return Ok(&data[key_end..value_end]);
+        }
+        position = value_end;
+    }
+
+    bail!("key {} not found", String::from_utf8_lossy(key))
+}
+
+/// Length of the first value in `data`, for payloads with raw bytes after bencode
+pub fn raw_value_length(data: &[u8]) -> Result<usize> {
+    skip_value(data, 0, 0)
+}
+
+fn string_bytes(data: &[u8]) -> Result<&[u8]> {
+    let colon = data
+        .iter()
+        .position(|f| *f == b':')
+        .context("string expected as dictionary key")?;
+    Ok(&data[colon + 1..])
+}
+
+/// Position right after the value starting at `position`
+fn skip_value(data: &[u8], position: usize, depth: usize) -> Result<usize> {
+    match data.get(position).context("unexpected end")? {
+        b'i' => {
+            let end = data[position..]
+                .iter()
+                .position(|f| *f == b'e')
+                .context("'e' character was expected")?;
+            Ok(position + end + 1)
+        }
+        b'l' | b'd' => {
+            anyhow::ensure!(
+                depth < MAX_NESTING_DEPTH,
+                "nesting deeper than {MAX_NESTING_DEPTH}"
+            );
+            let mut position = position + 1;
+            while data.get(position).context("unexpected end")? != &b'e' {
+                position = skip_value(data, position, depth + 1)?;
+            }
+            Ok(position + 1)
+        }
+        x if x.is_ascii_digit() => {
+            let colon = data[position..]
+                .iter()
+                .position(|f| *f == b':')
+                .context("':' character was expected")?;
+            let length = std::str::from_utf8(&data[position..position + colon])
+                .context("utf8 expected as char for int")?
+                .parse::<usize>()
+                .context("failed to parse")?;
+            let end = (position + colon + 1)
+                .checked_add(length)
+                .filter(|end| *end <= data.len())
+                .context("Unexpected len of string")?;
+            Ok(end)
+        }
+        s => bail!("invalid character {}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dict_value_is_exact_span_of_original_bytes() {
+        let data = b"d8:announce3:url4:infod1:zi1e1:al1:xee7:privatei1ee";
+
+        assert_eq!(raw_dict_value(data, b"info").unwrap(), b"d1:zi1e1:al1:xee");
+        assert_eq!(raw_dict_value(data, b"private").unwrap(), b"i1e");
+        assert!(raw_dict_value(data, b"missing").is_err());
+        assert_eq!(raw_value_length(b"4:spamtrailing").unwrap(), 6);
+    }
+}
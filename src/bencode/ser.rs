@@ -55,14 +55,14 @@ impl<'a> SerializerMap<'a> {
     }
 }
 
-impl<'a> serde::ser::SerializeSeq for &'a mut Serializer {
+impl serde::ser::SerializeSeq for &mut Serializer {
     type Ok = ();
 
     type Error = super::error::Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -73,13 +73,13 @@ impl<'a> serde::ser::SerializeSeq for &'a mut Serializer {
     }
 }
 
-impl<'a> serde::ser::SerializeTuple for &'a mut Serializer {
+impl serde::ser::SerializeTuple for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -90,13 +90,13 @@ impl<'a> serde::ser::SerializeTuple for &'a mut Serializer {
     }
 }
 
-impl<'a> serde::ser::SerializeTupleStruct for &'a mut Serializer {
+impl serde::ser::SerializeTupleStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -107,13 +107,13 @@ impl<'a> serde::ser::SerializeTupleStruct for &'a mut Serializer {
     }
 }
 
-impl<'a> serde::ser::SerializeTupleVariant for &'a mut Serializer {
+impl serde::ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -129,9 +129,9 @@ impl<'a> serde::ser::SerializeMap for SerializerMap<'a> {
 
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_key<T>(&mut self, key: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         if let Some(ref key) = self.current_key {
             let key = String::from_utf8_lossy(key);
@@ -149,9 +149,9 @@ impl<'a> serde::ser::SerializeMap for SerializerMap<'a> {
         Ok(())
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         if let Some(key) = self.current_key.take() {
             let mut serializer = Serializer::new();
@@ -185,13 +185,13 @@ impl<'a> serde::ser::SerializeStruct for SerializerMap<'a> {
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
+    fn serialize_field<T>(
         &mut self,
         key: &'static str,
         value: &T,
     ) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         self.serialize_entry(key, value)
     }
@@ -201,18 +201,14 @@ impl<'a> serde::ser::SerializeStruct for SerializerMap<'a> {
     }
 }
 
-impl<'a> serde::ser::SerializeStructVariant for &'a mut Serializer {
+impl serde::ser::SerializeStructVariant for &mut Serializer {
     type Ok = ();
 
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        _: &'static str,
-        _: &T,
-    ) -> std::result::Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, _: &'static str, _: &T) -> std::result::Result<(), Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         todo!()
     }
@@ -311,11 +307,11 @@ impl<'a> serde::ser::Serializer for &'a mut Serializer {
         todo!()
     }
 
-    fn serialize_some<T: ?Sized>(self, _: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
-        todo!()
+        value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
@@ -335,18 +331,14 @@ impl<'a> serde::ser::Serializer for &'a mut Serializer {
         todo!()
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
-        self,
-        _: &'static str,
-        _: &T,
-    ) -> Result<Self::Ok, Self::Error>
+    fn serialize_newtype_struct<T>(self, _: &'static str, _: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         todo!()
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
         _: &'static str,
         _: u32,
@@ -354,7 +346,7 @@ impl<'a> serde::ser::Serializer for &'a mut Serializer {
         _: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: serde::Serialize,
+        T: ?Sized + serde::Serialize,
     {
         todo!()
     }
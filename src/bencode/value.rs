@@ -111,3 +111,53 @@ impl<'de> serde::Deserialize<'de> for Value {
         deserializer.deserialize_any(ValueVisitor)
     }
 }
+
+impl serde::Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Value::String(bytes) => serializer.serialize_bytes(bytes),
+            Value::Integer(number) => serializer.serialize_i64(*number),
+            Value::List(values) => serializer.collect_seq(values),
+            Value::Dict(entries) => {
+                serializer.collect_map(entries.iter().map(|(k, v)| (serde_bytes::Bytes::new(k), v)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{from_bytes, to_bytes};
+
+    #[test]
+    fn nested_torrent_like_value_round_trips_byte_identical() {
+        let mut input = b"d8:announce17:http://x/announce4:infod5:filesld6:lengthi5e4:pathl1:a1:beed6:lengthi-3e4:pathl1:ceee4:name4:test12:piece lengthi16384e6:pieces20:".to_vec();
+        input.extend((0..20u8).map(|byte| byte.wrapping_mul(13)));
+        input.extend_from_slice(b"e1:xl0:deee");
+
+        let value: Value = from_bytes(&input).unwrap();
+
+        assert_eq!(to_bytes(&value).unwrap(), input);
+    }
+
+    #[test]
+    fn digit_strings_stay_strings() {
+        let value = Value::List(vec![Value::String(b"42".to_vec()), Value::Integer(42)]);
+
+        assert_eq!(to_bytes(&value).unwrap(), b"l2:42i42ee");
+    }
+
+    #[test]
+    fn dictionary_keys_are_sorted() {
+        let value = Value::Dict(BTreeMap::from([
+            (b"b".to_vec(), Value::Integer(1)),
+            (b"a".to_vec(), Value::Integer(2)),
+        ]));
+
+        assert_eq!(to_bytes(&value).unwrap(), b"d1:ai2e1:bi1ee");
+    }
+}
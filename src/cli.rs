@@ -1,10 +1,12 @@
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-};
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf, time::Duration};
 
-use crate::prelude::*;
-use clap::{arg, command, Parser, Subcommand};
+use crate::{
+    bencode::{DecodeBudget, ParseConfig},
+    prelude::*,
+    torrent::*,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::Url;
 
 const DEFAULT_PORT: u16 = 6881;
 const DEFAULT_MAX_PEERS: u8 = 10;
@@ -20,6 +22,130 @@ pub struct Cli {
     pub max_peers: u8,
     #[arg(short, long)]
     pub tokio_console: bool,
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS_PER_PEER_IP)]
+    pub max_connections_per_peer_ip: u8,
+    #[arg(long, default_value_t = DEFAULT_CONNECT_TIMEOUT_SECONDS, help = "peer connect timeout in seconds")]
+    pub connect_timeout: u64,
+    #[arg(long, default_value_t = DEFAULT_MAX_BUFFER_MEMORY, help = "memory budget in bytes for in-flight pieces")]
+    pub max_buffer_memory: usize,
+    #[arg(long, default_value_t = DEFAULT_STALL_TIMEOUT_SECONDS, help = "abort download after seconds without progress")]
+    pub stall_timeout: u64,
+    #[arg(long, default_value_t = DEFAULT_MAX_PIECE_RETRIES, help = "give up on a piece after this many failures")]
+    pub max_piece_retries: u32,
+    #[arg(long, default_value_t = DEFAULT_MAX_PEER_HASH_FAILURES, help = "ban a peer after this many corrupt pieces")]
+    pub max_peer_hash_failures: u32,
+    #[arg(long, default_value_t = DEFAULT_MIN_ANNOUNCE_INTERVAL_SECONDS, help = "minimum seconds between tracker re-announces")]
+    pub min_announce_interval: u64,
+    #[arg(long, default_value_t = DEFAULT_ENDGAME_BLOCKS, help = "request last blocks from every peer once this few are left, 0 disables")]
+    pub endgame_blocks: usize,
+    #[arg(long, default_value_t = DEFAULT_BLOCK_REQUEST_TIMEOUT_SECONDS, help = "seconds before unanswered block request goes to another peer")]
+    pub block_request_timeout: u64,
+    #[arg(long, default_value_t = DEFAULT_UPLOAD_SLOTS, help = "peers unchoked at once while seeding")]
+    pub upload_slots: usize,
+    #[arg(long, default_value_t = DEFAULT_MAX_OPEN_FILES, help = "files of multi-file torrent kept open at once")]
+    pub max_open_files: usize,
+    #[arg(long, default_value_t = DEFAULT_PIECES_IN_FLIGHT, help = "pieces downloaded at once")]
+    pub pieces_in_flight: usize,
+    #[arg(
+        long,
+        help = "download every piece again, ignoring valid ones at output path"
+    )]
+    pub no_resume: bool,
+    #[arg(long, value_parser = parse_size, help = "download rate limit in bytes per second across all peers, e.g. 500K")]
+    pub max_rate: Option<u32>,
+    #[arg(long, default_value_t = DEFAULT_TRACKER_CONNECT_TIMEOUT_SECONDS, help = "tracker connect timeout in seconds")]
+    pub tracker_connect_timeout: u64,
+    #[arg(long, default_value_t = DEFAULT_TRACKER_REQUEST_TIMEOUT_SECONDS, help = "deadline of a tracker request in seconds")]
+    pub tracker_timeout: u64,
+    #[arg(long, default_value_t = DEFAULT_TRACKER_MAX_RESPONSE_SIZE, help = "largest tracker response in bytes")]
+    pub tracker_max_response_size: usize,
+    #[arg(long, default_value_t = DEFAULT_TRACKER_RETRIES, help = "announce retries on connect errors and timeouts")]
+    pub tracker_retries: u32,
+    #[arg(long, default_value = "16K", value_parser = parse_size, help = "requested block size, e.g. 16384 or 16K")]
+    pub block_size: u32,
+    #[arg(
+        long,
+        help = "file of ip ranges to never connect to, cidr or emule format"
+    )]
+    pub blocklist: Option<PathBuf>,
+    #[arg(long, help = "extra tracker url, can be repeated")]
+    pub tracker: Vec<Url>,
+    #[arg(long, help = "allow block sizes above 16 KiB")]
+    pub i_know_what_im_doing: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+    #[arg(long, help = "path to write json summary of download to")]
+    pub summary: Option<PathBuf>,
+    #[arg(long, help = "print torrent status to stderr twice a second")]
+    pub progress: bool,
+    #[arg(
+        long,
+        help = "path to write tracker, peer, piece and disk events to, one json per line"
+    )]
+    pub session_log: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "reject any bencode spec violation, for untrusted torrents"
+    )]
+    pub strict: bool,
+    #[arg(long, help = "wall-clock budget in seconds for the whole command")]
+    pub timeout: Option<u64>,
+    #[arg(long, default_value_t = DEFAULT_MAX_TORRENT_STRING_BYTES, value_parser = parse_size, help = "largest total of strings in a torrent file, e.g. 64M")]
+    pub max_torrent_string_bytes: u32,
+    #[arg(long, default_value_t = DEFAULT_MAX_TORRENT_ELEMENTS, help = "most values a torrent file may hold")]
+    pub max_torrent_elements: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Cli {
+    /// Torrent files and urls are untrusted, they're decoded within budget
+    pub fn parse_config(&self) -> ParseConfig {
+        ParseConfig {
+            strict: self.strict,
+            budget: Some(DecodeBudget {
+                max_string_bytes: self.max_torrent_string_bytes as usize,
+                max_elements: self.max_torrent_elements,
+            }),
+        }
+    }
+
+    /// Explicit `--timeout` wins, download is only bounded when it's set
+    pub fn command_timeout(&self) -> Option<Duration> {
+        self.timeout
+            .or(self.command.default_timeout_seconds())
+            .map(Duration::from_secs)
+    }
+
+    pub fn download_config(&self) -> Result<DownloadConfig> {
+        Ok(DownloadConfig {
+            max_connections_per_peer_ip: self.max_connections_per_peer_ip,
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            max_buffer_memory: self.max_buffer_memory,
+            stall_timeout: Duration::from_secs(self.stall_timeout),
+            block_size: checked_block_size(self.block_size, self.i_know_what_im_doing)?,
+            max_piece_retries: self.max_piece_retries,
+            max_peer_hash_failures: self.max_peer_hash_failures,
+            min_announce_interval: Duration::from_secs(self.min_announce_interval),
+            endgame_blocks: self.endgame_blocks,
+            block_request_timeout: Duration::from_secs(self.block_request_timeout),
+            upload_slots: self.upload_slots,
+            max_open_files: self.max_open_files,
+            pieces_in_flight: self.pieces_in_flight,
+            resume: !self.no_resume,
+            max_download_bytes_per_sec: self.max_rate,
+            tracker: TrackerConfig {
+                connect_timeout: Duration::from_secs(self.tracker_connect_timeout),
+                request_timeout: Duration::from_secs(self.tracker_timeout),
+                max_response_size: self.tracker_max_response_size,
+                retries: self.tracker_retries,
+            },
+        })
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -34,18 +160,51 @@ pub enum Command {
     },
     #[command(long_about = "Encode Bencode Value")]
     Encode {
-        #[arg(name = "value", help = "value to encode")]
-        value: String,
+        #[arg(name = "value", help = "value to encode, could be non utf8 bytes")]
+        value: OsString,
+        #[arg(
+            long,
+            short,
+            name = "output path",
+            help = "write encoded bytes to file instead of stdout"
+        )]
+        output: Option<PathBuf>,
     },
     #[command(long_about = "Print metadata info of a torrent")]
     Info {
         #[arg(name = "torrent path", help = "torrent path")]
         torrent_path: PathBuf,
+        #[arg(long, help = "list padding files too")]
+        show_padding: bool,
+    },
+    #[command(
+        long_about = "Print what a magnet link holds and the torrent info fetched from peers, \
+            or why fetching it failed",
+        alias = "magnet"
+    )]
+    MagnetInfo {
+        #[arg(
+            name = "magnet link",
+            help = "magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<tracker>"
+        )]
+        link: String,
+        #[arg(
+            long,
+            help = "print only what the link holds, without fetching metadata"
+        )]
+        offline: bool,
     },
     #[command(long_about = "Print ips of peers")]
     Peers {
         #[arg(name = "torrent path", help = "torrent path")]
         torrent_path: PathBuf,
+        #[arg(long, help = "handshake each peer and report reachability")]
+        connect_test: bool,
+    },
+    #[command(long_about = "Print seeders, leechers and completed count from tracker")]
+    Scrape {
+        #[arg(name = "torrent path", help = "torrent path")]
+        torrent_path: PathBuf,
     },
     #[command(long_about = "Handshake with peer")]
     Handshake {
@@ -64,8 +223,26 @@ pub enum Command {
             long,
             short,
             name = "output path",
-            help = "output path for piece to download"
+            help = "output path for piece to download",
+            required_unless_present = "into path"
         )]
+        output: Option<PathBuf>,
+        #[arg(
+            long,
+            name = "into path",
+            help = "repair piece in place inside existing download (file or directory)",
+            conflicts_with = "output path"
+        )]
+        into: Option<PathBuf>,
+    },
+    #[command(long_about = "Download torrent of magnet link, metadata is fetched from peers")]
+    MagnetDownload {
+        #[arg(
+            name = "magnet link",
+            help = "magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<tracker>"
+        )]
+        link: String,
+        #[arg(long, short, name = "output path", help = "output path for download")]
         output: PathBuf,
     },
     #[command(long_about = "Download torrent")]
@@ -79,16 +256,96 @@ pub enum Command {
             help = "output path for piece to download"
         )]
         output: PathBuf,
+        #[arg(
+            long,
+            value_parser = parse_piece_deadline,
+            help = "piece needed within milliseconds, e.g. 3=2000, could be repeated"
+        )]
+        piece_deadline: Vec<(usize, Duration)>,
+        #[arg(
+            long,
+            help = "existing copy of the content, matching pieces are linked or copied instead of downloaded"
+        )]
+        link_existing: Option<PathBuf>,
+    },
+    #[command(long_about = "Seed completed download to peers connecting to --port")]
+    Seed {
+        #[arg(name = "torrent path", help = "torrent path")]
+        torrent_path: PathBuf,
+        #[arg(name = "download path", help = "completed download, file or directory")]
+        path: PathBuf,
+    },
+    #[command(long_about = "Check every piece of a download against torrent hashes")]
+    Verify {
+        #[arg(name = "torrent path", help = "torrent path")]
+        torrent_path: PathBuf,
+        #[arg(name = "download path", help = "downloaded file or directory")]
+        path: PathBuf,
+    },
+    #[command(long_about = "Build single file torrent of a file")]
+    Create {
+        #[arg(name = "file path", help = "file to share")]
+        input: PathBuf,
+        #[arg(long, short, name = "output path", help = "path of .torrent to write")]
+        output: PathBuf,
+        #[arg(long, help = "tracker url")]
+        announce: Url,
+        #[arg(long, default_value = "256K", value_parser = parse_size, help = "piece length, e.g. 262144 or 256K")]
+        piece_length: u32,
+    },
+    #[command(
+        long_about = "Rebuild piece and peer timelines from a --session-log file and flag anomalies"
+    )]
+    SessionReport {
+        #[arg(name = "session log path", help = "session log path")]
+        path: PathBuf,
     },
 }
 
-pub fn pares_peer_arg(arg: &str) -> Result<SocketAddrV4> {
-    let parts: Vec<&str> = arg.split(':').collect();
-    if parts.len() != 2 {
-        bail!("please set ip correctly");
+impl Command {
+    fn default_timeout_seconds(&self) -> Option<u64> {
+        match self {
+            Command::Decode { .. } | Command::Encode { .. } => None,
+            Command::MagnetInfo { offline: true, .. } => None,
+            Command::Info { .. } | Command::Peers { .. } | Command::Scrape { .. } => Some(30),
+            Command::MagnetInfo { offline: false, .. } => Some(30),
+            Command::Handshake { .. } => Some(15),
+            Command::Verify { .. } | Command::Create { .. } | Command::SessionReport { .. } => None,
+            Command::DownloadPiece { .. } => Some(120),
+            Command::Download { .. } | Command::MagnetDownload { .. } | Command::Seed { .. } => {
+                None
+            }
+        }
     }
-    let ip = parts[0].parse::<Ipv4Addr>().context("failed to parse ip")?;
-    let port = parts[1].parse::<u16>().context("failed to parse port")?;
+}
+
+/// `ip:port`, ipv6 in brackets `[ip]:port`
+pub fn pares_peer_arg(arg: &str) -> Result<SocketAddr> {
+    arg.parse::<SocketAddr>()
+        .context("please set ip correctly, e.g. 127.0.0.1:6881 or [::1]:6881")
+}
+
+/// `<piece index>=<milliseconds>`
+fn parse_piece_deadline(arg: &str) -> Result<(usize, Duration)> {
+    let (index, millis) = arg
+        .split_once('=')
+        .context("expected <piece index>=<milliseconds>")?;
+    let index = index.trim().parse().context("invalid piece index")?;
+    let millis = millis.trim().parse().context("invalid milliseconds")?;
+    Ok((index, Duration::from_millis(millis)))
+}
 
-    Ok(SocketAddrV4::new(ip, port))
+/// Bytes, with optional `K`/`KiB` or `M`/`MiB` suffix
+fn parse_size(arg: &str) -> Result<u32> {
+    let arg = arg.trim();
+    let (number, multiplier) =
+        if let Some(number) = arg.strip_suffix("KiB").or(arg.strip_suffix(['K', 'k'])) {
+            (number, 1024)
+        } else if let Some(number) = arg.strip_suffix("MiB").or(arg.strip_suffix(['M', 'm'])) {
+            (number, 1024 * 1024)
+        } else {
+            (arg, 1)
+        };
+    let number = number.parse::<u32>().context("failed to parse size")?;
+    number.checked_mul(multiplier).context("size is too large")
 }
@@ -5,6 +5,7 @@ use crate::prelude::*;
 
 pub const BLOCK_SIZE: u32 = 16 * 1024;
 
+#[allow(dead_code)]
 pub trait WithInfoHash {
     fn info_hash(&self) -> Bytes20;
 }
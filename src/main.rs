@@ -1,8 +1,15 @@
-use std::collections::HashSet;
+#![forbid(unsafe_code)]
 
 use bencode::*;
 use clap::Parser;
-use cli::{pares_peer_arg, Cli, Command};
+use cli::{pares_peer_arg, Cli, Command, OutputFormat};
+use phase::Phase;
+use std::{
+    io::{IsTerminal, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use tokio::sync::watch;
 
 use tracing_subscriber::{prelude::*, EnvFilter};
 
@@ -10,6 +17,7 @@ use crate::{prelude::*, torrent::*};
 mod bencode;
 mod cli;
 mod common;
+mod phase;
 
 mod prelude;
 mod torrent;
@@ -33,35 +41,194 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     init_tracing(cli.tokio_console);
 
+    // NOTE: magnet info bounds its metadata fetch itself, so it could still report
+    let command_timeout = match cli.command {
+        Command::MagnetInfo { .. } => None,
+        _ => cli.command_timeout(),
+    };
+    let Some(timeout) = command_timeout else {
+        return exit_on_malformed_torrent(run(cli, &watch::Sender::new(Phase::Starting)).await);
+    };
+
+    match run_within(cli, timeout).await {
+        Ok(result) => exit_on_malformed_torrent(result),
+        Err(timed_out) => {
+            eprintln!("{timed_out}");
+            std::process::exit(TIMEOUT_EXIT_CODE);
+        }
+    }
+}
+
+const TIMEOUT_EXIT_CODE: i32 = 8;
+const MALFORMED_TORRENT_EXIT_CODE: i32 = 3;
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Command ran out of its `--timeout` budget
+#[derive(Debug, thiserror::Error)]
+#[error("timed out after {}s during {phase}", .timeout.as_secs())]
+struct CommandTimedOut {
+    timeout: std::time::Duration,
+    phase: Phase,
+}
+
+async fn run_within(
+    cli: Cli,
+    timeout: std::time::Duration,
+) -> std::result::Result<Result<()>, CommandTimedOut> {
+    let phase = watch::Sender::new(Phase::Starting);
+    tokio::time::timeout(timeout, run(cli, &phase))
+        .await
+        .map_err(|_| CommandTimedOut {
+            timeout,
+            phase: *phase.borrow(),
+        })
+}
+
+/// Broken torrent gets its own exit code, scripts can tell it from a failed download
+fn exit_on_malformed_torrent(result: Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.is::<MalformedTorrent>() => {
+            eprintln!("Error: {e:?}");
+            std::process::exit(MALFORMED_TORRENT_EXIT_CODE);
+        }
+        result => result,
+    }
+}
+
+#[allow(unused)]
+async fn run(cli: Cli, phase: &watch::Sender<Phase>) -> Result<()> {
+    let download_config = cli.download_config()?;
+    let parse_config = cli.parse_config();
+    let fetch_timeout = cli.command_timeout();
+    let peer_filter = cli
+        .blocklist
+        .as_deref()
+        .map(Blocklist::load)
+        .transpose()?
+        .map(PeerFilter::from);
+    let session_log = cli
+        .session_log
+        .as_deref()
+        .map(SessionLog::create)
+        .transpose()?
+        .map(std::sync::Arc::new);
+    // NOTE: every torrent is configured from cli the same way
+    let configure = |builder: TorrentBuilder| {
+        let mut builder = builder
+            .port(cli.port)
+            .max_peers(cli.max_peers)
+            .config(download_config.clone());
+        if let Some(peer_filter) = &peer_filter {
+            builder = builder.peer_filter(peer_filter.clone());
+        }
+        if let Some(session_log) = &session_log {
+            builder = builder.session_log(session_log.clone());
+        }
+        cli.tracker
+            .iter()
+            .cloned()
+            .fold(builder, TorrentBuilder::add_tracker)
+    };
+
     match cli.command {
         Command::Decode { bencoded_value } => {
-            let decoded: Value = from_str(bencoded_value)?;
+            let decoded: Value = from_bytes_with_config(bencoded_value.as_bytes(), parse_config)?;
             println!("{}", decoded);
         }
-        Command::Info { torrent_path } => {
-            let metadata = TorrentMetadataInfo::from_file(torrent_path)?;
+        Command::Info {
+            torrent_path,
+            show_padding,
+        } => {
+            phase.send_replace(Phase::LoadingTorrent);
+            let metadata = TorrentMetadataInfo::load(torrent_path, parse_config).await?;
             println!("{}", metadata);
+            if metadata.info.files.is_some() {
+                let listing = FilesListing {
+                    info: &metadata.info,
+                    show_padding,
+                };
+                println!("{}", listing);
+            }
         }
-        Command::Encode { value } => {
-            let value = to_bytes(value).context("encoding to bencode")?;
-            let value = String::from_utf8_lossy(&value);
-            println!("{}", value);
+        Command::Encode { value, output } => {
+            let value = to_bytes(serde_bytes::Bytes::new(value.as_bytes()))
+                .context("encoding to bencode")?;
+            write_raw_output(&value, output.as_deref())?;
         }
-        Command::Peers { torrent_path } => {
-            let torrent = Torrent::from_file(torrent_path, cli.port, cli.max_peers)
-                .context("loading torrent")?;
-            let peers = torrent.get_peers_tracker_response().await?;
+        Command::MagnetInfo { link, offline } => {
+            let magnet = MagnetLink::parse(&link)?;
+            let magnet_info = if offline {
+                MagnetInfo::offline(&magnet)
+            } else {
+                let timeout = fetch_timeout.expect("magnet info fetch has default timeout");
+                let torrent = configure(TorrentBuilder::from_magnet(&link)?).build()?;
+                phase.send_replace(Phase::Announcing);
+                MagnetInfo::fetched(&magnet, torrent.fetch_metadata_report(timeout).await)
+            };
+            match cli.output {
+                OutputFormat::Text => print!("{magnet_info}"),
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&magnet_info)
+                        .context("serializing magnet info")?
+                ),
+            }
+        }
+        Command::Peers {
+            torrent_path,
+            connect_test,
+        } => {
+            phase.send_replace(Phase::LoadingTorrent);
+            let torrent = configure(
+                TorrentBuilder::from_file(torrent_path, parse_config)
+                    .await
+                    .context("loading torrent")?,
+            )
+            .build()?;
+            phase.send_replace(Phase::Announcing);
+            if connect_test {
+                phase.send_replace(Phase::Handshaking);
+                for probe in torrent.probe_peers().await? {
+                    println!("{}", probe);
+                }
+                return Ok(());
+            }
+            let peers = torrent.get_peers_tracker_response(None).await?;
+            // NOTE: stdout is kept to one peer per line
+            if let Some(swarm) = peers.swarm() {
+                eprintln!("{swarm}");
+            }
             println!("{}", peers);
         }
+        Command::Scrape { torrent_path } => {
+            phase.send_replace(Phase::LoadingTorrent);
+            let torrent = configure(
+                TorrentBuilder::from_file(torrent_path, parse_config)
+                    .await
+                    .context("loading torrent")?,
+            )
+            .build()?;
+            phase.send_replace(Phase::Announcing);
+            let stats = torrent.scrape().await?;
+            print!("{}", stats);
+        }
         Command::Handshake { torrent_path, peer } => {
             let peer = pares_peer_arg(&peer).context("parsing peer param")?;
-            let metadata = TorrentMetadataInfo::from_file(torrent_path)?;
+            phase.send_replace(Phase::LoadingTorrent);
+            let metadata = TorrentMetadataInfo::load(torrent_path, parse_config).await?;
             let peer_id = generate_peer_id();
-            let peer_id = Peer::handshake(peer, peer_id, metadata.info_hash, &metadata.info)
-                .await
-                .context("connecting to peer")?;
+            phase.send_replace(Phase::Handshaking);
+            let handshake = Peer::handshake(
+                peer,
+                peer_id,
+                metadata.info_hash,
+                &metadata.info,
+                download_config.connect_timeout,
+            )
+            .await
+            .context("connecting to peer")?;
 
-            let remote_peer_id: Bytes20 = peer_id.into();
+            let remote_peer_id: Bytes20 = handshake.peer_id.into();
             let remote_peer_id = hex::encode(remote_peer_id);
             println!("Peer ID: {}", remote_peer_id);
         }
@@ -69,36 +236,69 @@ async fn main() -> Result<()> {
             torrent_path,
             piece_number,
             output,
+            into,
         } => {
-            let dir_path = std::path::Path::new(&output);
+            phase.send_replace(Phase::LoadingTorrent);
+            let torrent = configure(
+                TorrentBuilder::from_file(torrent_path, parse_config)
+                    .await
+                    .context("loading torrent")?,
+            )
+            .build()?;
+            let mut storage = into
+                .map(|into| {
+                    let storage = torrent.storage(into.clone())?;
+                    storage.validate_existing()?;
+                    anyhow::Ok((storage, into))
+                })
+                .transpose()
+                .context("checking existing download")?;
 
-            let torrent = Torrent::from_file(torrent_path, cli.port, cli.max_peers)
-                .context("loading torrent")?;
-            let mut peers = torrent.get_peers_addresses().await?;
-            // nvm, hacking this in post download refactoring
-            let peer_hash_sets: HashSet<_> = peers.iter().copied().collect();
+            phase.send_replace(Phase::Announcing);
+            let mut peers = torrent.get_peers_addresses(None).await?;
             if let Some(random_peer) = remove_random_element(&mut peers) {
                 let peer_id = generate_peer_id();
+                phase.send_replace(Phase::Handshaking);
                 let mut peer = Peer::connect(
                     random_peer,
                     peer_id,
                     torrent.metadata.info_hash,
                     &torrent.metadata.info,
+                    download_config.connect_timeout,
                 )
                 .await
                 .context("connecting to peer")?;
 
-                let piece = Piece::new(piece_number, &torrent.metadata.info, peer_hash_sets)
-                    .context("piece construction")?;
+                let piece_blocks = torrent
+                    .metadata
+                    .info
+                    .blocks_for_piece(piece_number, download_config.block_size)
+                    .context("piece blocks")?;
 
-                let piece_data = peer
-                    .receive_file_piece(
-                        piece_number,
-                        piece.piece_blocks(BLOCK_SIZE, &torrent.metadata.info),
-                    )
-                    .await?;
+                phase.send_replace(Phase::Downloading);
+                let piece_data = peer.receive_file_piece(piece_number, piece_blocks).await?;
 
-                std::fs::write(dir_path, piece_data).context("failed to save piece")?;
+                match (&mut storage, output) {
+                    (Some((storage, into)), _) => {
+                        let offset =
+                            piece_number as u64 * torrent.metadata.info.piece_length as u64;
+                        storage
+                            .write(offset, &piece_data)
+                            .context("failed to repair piece")?;
+                        piece_repaired(
+                            storage,
+                            into,
+                            torrent.metadata.info_hash,
+                            torrent.metadata.info.pieces.len(),
+                            piece_number,
+                            piece_data.len(),
+                        );
+                    }
+                    (None, Some(output)) => {
+                        std::fs::write(output, piece_data).context("failed to save piece")?;
+                    }
+                    (None, None) => unreachable!("clap requires output or into"),
+                }
             } else {
                 bail!("No peers")
             }
@@ -106,13 +306,380 @@ async fn main() -> Result<()> {
         Command::Download {
             torrent_path,
             output,
+            piece_deadline,
+            link_existing,
         } => {
             let dir_path = std::path::Path::new(&output);
 
-            let mut torrent = Torrent::from_file(torrent_path, cli.port, cli.max_peers)
-                .context("loading torrent")?;
-            torrent.download(output).await?;
+            phase.send_replace(Phase::LoadingTorrent);
+            let builder = configure(
+                TorrentBuilder::from_file(torrent_path, parse_config)
+                    .await
+                    .context("loading torrent")?,
+            );
+            let torrent = piece_deadline
+                .into_iter()
+                .fold(builder, |builder, (index, deadline)| {
+                    builder.piece_deadline(index, deadline)
+                })
+                .build()?;
+            if let Some(existing) = link_existing {
+                let reused = torrent
+                    .link_existing(&existing, &output)
+                    .with_context(|| format!("reusing {}", existing.display()))?;
+                if cli.output == OutputFormat::Text {
+                    println!(
+                        "reused {reused} of {} pieces from {}",
+                        torrent.metadata.info.pieces.len(),
+                        existing.display()
+                    );
+                }
+            }
+            phase.send_replace(Phase::Downloading);
+            let report =
+                with_progress(&torrent, cli.progress, cli.output, torrent.download(output)).await?;
+            finish_download(&report, cli.output, cli.summary)?;
+        }
+        Command::MagnetDownload { link, output } => {
+            let mut torrent = configure(TorrentBuilder::from_magnet(&link)?).build()?;
+            phase.send_replace(Phase::Announcing);
+            torrent
+                .resolve_metadata()
+                .await
+                .context("fetching metadata")?;
+            phase.send_replace(Phase::Downloading);
+            let report =
+                with_progress(&torrent, cli.progress, cli.output, torrent.download(output)).await?;
+            finish_download(&report, cli.output, cli.summary)?;
+        }
+        Command::Seed { torrent_path, path } => {
+            phase.send_replace(Phase::LoadingTorrent);
+            let torrent = configure(
+                TorrentBuilder::from_file(torrent_path, parse_config)
+                    .await
+                    .context("loading torrent")?,
+            )
+            .build()?;
+            phase.send_replace(Phase::Seeding);
+            with_progress(&torrent, cli.progress, cli.output, torrent.seed(path)).await?;
+        }
+        Command::Verify { torrent_path, path } => {
+            phase.send_replace(Phase::LoadingTorrent);
+            let metadata = TorrentMetadataInfo::load(torrent_path, parse_config).await?;
+            phase.send_replace(Phase::Verifying);
+            let mut storage = Storage::new(
+                path,
+                &metadata.info,
+                download_config.max_open_files,
+                Default::default(),
+            )?;
+            storage.validate_existing()?;
+            let have = storage.verified_pieces(&metadata.info)?;
+            let failed_pieces: Vec<usize> = have.iter_zeros().collect();
+            match cli.output {
+                OutputFormat::Text => {
+                    for piece_index in &failed_pieces {
+                        println!("piece {piece_index} failed");
+                    }
+                    let result = if failed_pieces.is_empty() {
+                        "PASS"
+                    } else {
+                        "FAIL"
+                    };
+                    println!(
+                        "{result}: {} of {} pieces verified",
+                        have.count_ones(),
+                        have.len()
+                    );
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "pieces": have.len(),
+                        "failed_pieces": failed_pieces,
+                    })
+                ),
+            }
+            if !failed_pieces.is_empty() {
+                bail!("{} pieces failed verification", failed_pieces.len())
+            }
+        }
+        Command::Create {
+            input,
+            output,
+            announce,
+            piece_length,
+        } => {
+            phase.send_replace(Phase::Creating);
+            let metadata = TorrentMetadataInfo::create(&input, announce, piece_length as usize)?;
+            let torrent = metadata.to_torrent_bytes()?;
+            // NOTE: hash of what's written, as any client loading the file would see it
+            let written = TorrentMetadataInfo::from_bytes(&torrent, parse_config)
+                .context("reading back created torrent")?;
+            anyhow::ensure!(
+                written.info_hash == metadata.info_hash,
+                "info hash of written torrent {} differs from {}",
+                hex::encode(written.info_hash),
+                hex::encode(metadata.info_hash)
+            );
+            std::fs::write(&output, torrent)
+                .with_context(|| format!("writing {}", output.display()))?;
+            match cli.output {
+                OutputFormat::Text => {
+                    println!("Info Hash: {}", hex::encode(written.info_hash));
+                    println!("Pieces: {}", written.info.pieces.len());
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "info_hash": hex::encode(written.info_hash),
+                        "pieces": written.info.pieces.len(),
+                    })
+                ),
+            }
+        }
+        Command::SessionReport { path } => {
+            let report = SessionReport::load(&path)?;
+            match cli.output {
+                OutputFormat::Text => print!("{report}"),
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).context("serializing report")?
+                ),
+            }
+            if !report.anomalies.is_empty() {
+                bail!("{} anomalies found", report.anomalies.len())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drives `future`, printing [`Torrent::status`] to stderr on every tick when `enabled`
+async fn with_progress<T>(
+    torrent: &Torrent,
+    enabled: bool,
+    output: OutputFormat,
+    future: impl std::future::Future<Output = T>,
+) -> T {
+    if !enabled {
+        return future.await;
+    }
+    tokio::pin!(future);
+    let mut ticks = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut future => return result,
+            _ = ticks.tick() => {
+                let status = torrent.status();
+                match output {
+                    OutputFormat::Text => eprintln!("{status}"),
+                    OutputFormat::Json => match serde_json::to_string(&status) {
+                        Ok(status) => eprintln!("{status}"),
+                        Err(e) => warn!("serializing status: {e:#}"),
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Prints or saves report as requested, failing on incomplete download
+fn finish_download(
+    report: &DownloadReport,
+    output: OutputFormat,
+    summary: Option<PathBuf>,
+) -> Result<()> {
+    if output == OutputFormat::Text && report.resumed_pieces > 0 {
+        println!(
+            "{} pieces already present, not downloaded again",
+            report.resumed_pieces
+        );
+    }
+    if output == OutputFormat::Json || summary.is_some() {
+        let report_json = serde_json::to_string_pretty(report).context("serializing report")?;
+        if output == OutputFormat::Json {
+            println!("{}", report_json);
+        }
+        if let Some(summary) = summary {
+            std::fs::write(summary, report_json).context("writing summary")?;
         }
     }
+
+    if !report.is_completed() {
+        bail!("Download incomplete: {:?}", report.outcome)
+    }
+
     Ok(())
 }
+
+/// Raw bytes to file or piped stdout, terminal gets lossy rendering
+fn write_raw_output(bytes: &[u8], output: Option<&Path>) -> Result<()> {
+    if let Some(output) = output {
+        return std::fs::write(output, bytes).context("writing output file");
+    }
+
+    let mut stdout = std::io::stdout();
+    if stdout.is_terminal() && std::str::from_utf8(bytes).is_err() {
+        eprintln!(
+            "WARNING: binary content is rendered lossily, pipe or use --output for raw bytes"
+        );
+        println!("{}", String::from_utf8_lossy(bytes));
+        return Ok(());
+    }
+
+    stdout.write_all(bytes).context("writing stdout")?;
+    if stdout.is_terminal() {
+        stdout.write_all(b"\n").context("writing stdout")?;
+    }
+    stdout.flush().context("flushing stdout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::testing::{
+        announce_response, metadata, seeder, torrent_bytes, MockHttp, MockPeer, Reply, TempDir,
+    };
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn peers_against_silent_tracker_times_out_during_announce() {
+        // NOTE: accepts connections and never answers
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = tracker.accept().await {
+                held.push(stream);
+            }
+        });
+        let dir = TempDir::new("timeout-peers");
+        let torrent_path = dir.join("test.torrent");
+        std::fs::write(&torrent_path, torrent_bytes(&announce, 16, &[1; 32])).unwrap();
+        let cli = Cli::try_parse_from([
+            "bittorrent".as_ref(),
+            "--timeout".as_ref(),
+            "1".as_ref(),
+            "peers".as_ref(),
+            torrent_path.as_os_str(),
+        ])
+        .unwrap();
+        let timeout = cli.command_timeout().unwrap();
+
+        let started = Instant::now();
+        let timed_out = run_within(cli, timeout).await.unwrap_err();
+
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(timed_out.phase, Phase::Announcing);
+        assert_eq!(
+            timed_out.to_string(),
+            "timed out after 1s during tracker announce"
+        );
+        assert_eq!(TIMEOUT_EXIT_CODE, 8);
+    }
+
+    #[tokio::test]
+    async fn encode_writes_binary_bytes_exactly() {
+        let dir = TempDir::new("encode-binary");
+        let output = dir.join("encoded");
+        let value = [b'a', 0x00, 0xff, b'z'];
+        let cli = Cli::try_parse_from([
+            "bittorrent".as_ref(),
+            "encode".as_ref(),
+            std::ffi::OsStr::from_bytes(&value),
+            "--output".as_ref(),
+            output.as_os_str(),
+        ])
+        .unwrap();
+
+        run(cli, &watch::Sender::new(Phase::Starting))
+            .await
+            .unwrap();
+
+        let encoded = std::fs::read(&output).unwrap();
+        assert_eq!(encoded, b"4:a\x00\xffz");
+        let decoded: serde_bytes::ByteBuf = from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.as_slice(), value);
+    }
+
+    /// Runs verify of `download` against torrent of `data` in pieces of 32 bytes
+    async fn verify(data: &[u8], download: &[u8]) -> Result<()> {
+        let dir = TempDir::new("verify");
+        let torrent_path = dir.join("test.torrent");
+        let torrent = torrent_bytes("http://127.0.0.1:1/announce", 32, data);
+        std::fs::write(&torrent_path, torrent).unwrap();
+        let path = dir.join("test.bin");
+        std::fs::write(&path, download).unwrap();
+        let cli = Cli::try_parse_from([
+            "bittorrent".as_ref(),
+            "verify".as_ref(),
+            torrent_path.as_os_str(),
+            path.as_os_str(),
+        ])
+        .unwrap();
+
+        run(cli, &watch::Sender::new(Phase::Starting)).await
+    }
+
+    #[tokio::test]
+    async fn verify_passes_good_file_with_short_last_piece() {
+        let data: Vec<u8> = (0..90).collect();
+
+        verify(&data, &data).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_fails_file_with_flipped_byte() {
+        let data: Vec<u8> = (0..90).collect();
+        let mut flipped = data.clone();
+        flipped[70] ^= 0x01;
+
+        let err = verify(&data, &flipped).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "1 pieces failed verification");
+    }
+
+    #[tokio::test]
+    async fn download_piece_into_repairs_corrupt_piece_in_place() {
+        let data: Vec<u8> = (0..96).collect();
+        let info_hash = metadata("http://127.0.0.1:1/announce", 32, &data).info_hash;
+        let peer = MockPeer::start(
+            info_hash,
+            vec![Reply::bitfield(3)],
+            seeder(data.clone(), 32),
+        )
+        .await;
+        let response = announce_response(&[peer.addr()]);
+        let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+        let dir = TempDir::new("repair-piece");
+        let torrent_path = dir.join("test.torrent");
+        let torrent = torrent_bytes(tracker.url("/announce").as_str(), 32, &data);
+        std::fs::write(&torrent_path, torrent).unwrap();
+        let download = dir.join("test.bin");
+        let mut corrupt = data.clone();
+        corrupt[40] ^= 0xff;
+        std::fs::write(&download, &corrupt).unwrap();
+        let cli = Cli::try_parse_from([
+            "bittorrent".as_ref(),
+            "download_piece".as_ref(),
+            "--into".as_ref(),
+            download.as_os_str(),
+            torrent_path.as_os_str(),
+            "1".as_ref(),
+        ])
+        .unwrap();
+
+        run(cli, &watch::Sender::new(Phase::Starting))
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&download).unwrap(), data);
+        assert_eq!(peer.received().iter().filter(|m| m.id == 6).count(), 1);
+    }
+}
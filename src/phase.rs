@@ -0,0 +1,30 @@
+use std::fmt;
+
+/// Coarse progress of a command, reported when it runs out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Starting,
+    LoadingTorrent,
+    Announcing,
+    Handshaking,
+    Downloading,
+    Seeding,
+    Verifying,
+    Creating,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            Phase::Starting => "startup",
+            Phase::LoadingTorrent => "loading torrent",
+            Phase::Announcing => "tracker announce",
+            Phase::Handshaking => "peer handshake",
+            Phase::Downloading => "download",
+            Phase::Seeding => "seeding",
+            Phase::Verifying => "verification",
+            Phase::Creating => "torrent creation",
+        };
+        write!(f, "{phase}")
+    }
+}
@@ -1,5 +1,5 @@
 pub use crate::common::*;
 pub use anyhow::{anyhow, bail, Context, Result};
 pub use std::todo;
-pub use tracing::{debug, error, info, instrument, span, trace, warn, Level};
+pub use tracing::{debug, instrument, trace, warn};
 pub type Bytes20 = [u8; 20];
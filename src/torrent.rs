@@ -1,152 +1,482 @@
+mod budget;
+mod builder;
+mod config;
+mod create;
+mod endgame;
+mod extension;
 mod file;
+mod magnet;
+mod magnet_info;
+mod message_ring;
+mod metadata_fetch;
 mod peer;
+mod peer_filter;
+mod rate_limit;
+mod report;
+mod reserved;
+mod resume;
+mod reuse;
+mod seed;
+mod session_log;
+pub mod sidecar;
+mod status;
+mod storage;
+#[cfg(test)]
+pub(crate) mod testing;
 mod tracker;
+mod udp_tracker;
 
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap, HashSet},
-    fs::OpenOptions,
-    io::{Seek, SeekFrom, Write},
-    net::SocketAddrV4,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, MutexGuard,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::prelude::*;
+use crate::{bencode::ParseConfig, prelude::*};
+pub use budget::*;
+pub use builder::*;
+pub use config::*;
+pub use endgame::*;
+pub use extension::*;
 pub use file::*;
+pub use magnet::*;
+pub use magnet_info::*;
+pub use message_ring::*;
+pub use metadata_fetch::*;
 
+use bitvec::{order::Msb0, vec::BitVec};
 use futures_util::stream::FuturesUnordered;
 pub use peer::*;
+pub use peer_filter::*;
+pub use rate_limit::*;
 mod piece;
+mod protocol;
 use futures::{Future, StreamExt};
 pub use piece::*;
+pub use protocol::*;
 use rand::{distributions::Alphanumeric, Rng};
+pub use report::*;
+pub use reserved::*;
+pub use resume::*;
+pub use session_log::*;
+pub use status::*;
+pub use storage::*;
 use tokio::sync::RwLock;
 pub use tracker::*;
+pub use udp_tracker::*;
 
 #[allow(unused)]
 #[derive(Debug)]
 pub struct Torrent {
     pub metadata: TorrentMetadataInfo,
-    download_queue: RwLock<BinaryHeap<Reverse<Piece>>>,
+    download_queue: RwLock<BinaryHeap<Reverse<QueueKey>>>,
     peer_id: PeerId,
     tracker: Tracker,
     port: u16,
     max_peers: u8,
+    /// Peers given up front, connected alongside tracker ones
+    known_peers: Vec<SocketAddr>,
+    peer_filter: Option<PeerFilter>,
+    /// Count of filtered out peer addresses by reason
+    denied_peers: std::sync::Mutex<BTreeMap<String, u32>>,
+    /// Addresses that answered handshake in another protocol
+    wrong_protocol_peers: std::sync::Mutex<HashSet<SocketAddr>>,
+    /// Last reason each address couldn't be connected
+    dial_failures: std::sync::Mutex<BTreeMap<SocketAddr, String>>,
+    /// Addresses banned for sending pieces failing their hash
+    corrupt_peers: std::sync::Mutex<HashSet<SocketAddr>>,
+    config: DownloadConfig,
+    /// Pieces needed by a point in time, picked before everything else
+    piece_deadlines: std::sync::Mutex<BTreeMap<usize, Instant>>,
+    /// Counters of current download, or seeding
+    stats: std::sync::Mutex<TransferStats>,
+    status_board: std::sync::Mutex<StatusBoard>,
+    /// Verified pieces sent to writer and not written yet
+    disk_queue: Arc<AtomicUsize>,
+    /// File handles held by storage
+    open_files: Arc<AtomicUsize>,
+    session_log: Option<Arc<SessionLog>>,
+    /// Shared by every peer, see [`DownloadConfig::max_download_bytes_per_sec`]
+    download_limiter: Option<RateLimiter>,
 }
 
 impl Torrent {
-    pub fn from_file(file_path: PathBuf, port: u16, max_peers: u8) -> Result<Self> {
-        let metadata = TorrentMetadataInfo::from_file(file_path)?;
-        tracing::trace!("File {:?}", metadata.info);
-        Ok(Torrent::new(metadata, port, max_peers))
+    pub fn builder(metadata: TorrentMetadataInfo) -> TorrentBuilder {
+        TorrentBuilder::new(metadata)
     }
 
-    pub fn new(metadata: TorrentMetadataInfo, port: u16, max_peers: u8) -> Self {
-        let peer_id = generate_peer_id();
-        Self {
-            max_peers,
-            peer_id,
-            tracker: Tracker::new(&metadata.announce, port, peer_id),
-            metadata,
-            port,
-            download_queue: RwLock::new(BinaryHeap::new()),
+    #[allow(dead_code)]
+    #[deprecated(note = "use TorrentBuilder::from_file")]
+    pub async fn from_file(
+        file_path: PathBuf,
+        port: u16,
+        max_peers: u8,
+        parse_config: ParseConfig,
+    ) -> Result<Self> {
+        TorrentBuilder::from_file(file_path, parse_config)
+            .await?
+            .port(port)
+            .max_peers(max_peers)
+            .build()
+    }
+
+    #[allow(dead_code)]
+    #[deprecated(note = "use TorrentBuilder::from_magnet")]
+    pub fn from_magnet(uri: &str, port: u16, max_peers: u8) -> Result<Self> {
+        TorrentBuilder::from_magnet(uri)?
+            .port(port)
+            .max_peers(max_peers)
+            .build()
+    }
+
+    #[allow(dead_code)]
+    #[deprecated(note = "use Torrent::builder")]
+    pub fn new(metadata: TorrentMetadataInfo, port: u16, max_peers: u8) -> Result<Self> {
+        Torrent::builder(metadata)
+            .port(port)
+            .max_peers(max_peers)
+            .build()
+    }
+
+    /// Requires piece within `deadline` from now, urgent pieces go first, earliest deadline first
+    pub fn set_piece_deadline(&self, index: usize, deadline: Duration) {
+        self.piece_deadlines
+            .lock()
+            .expect("deadlines lock")
+            .insert(index, Instant::now() + deadline);
+    }
+
+    pub fn reset_piece_deadline(&self, index: usize) {
+        self.piece_deadlines
+            .lock()
+            .expect("deadlines lock")
+            .remove(&index);
+    }
+
+    /// Storage of download at `path`, open files bounded by config
+    pub fn storage(&self, path: PathBuf) -> Result<Storage> {
+        Storage::new(
+            path,
+            &self.metadata.info,
+            self.config.max_open_files,
+            self.open_files.clone(),
+        )
+    }
+
+    fn stats(&self) -> MutexGuard<'_, TransferStats> {
+        self.stats.lock().expect("stats lock")
+    }
+
+    fn log_session(&self, event: impl FnOnce() -> SessionEvent) {
+        record_to(self.session_log.as_deref(), event);
+    }
+
+    /// Most urgent queued piece if any has a deadline, otherwise the regular pick
+    async fn next_piece(&self) -> Option<Piece> {
+        let mut urgent: Vec<(Instant, usize)> = {
+            let deadlines = self.piece_deadlines.lock().expect("deadlines lock");
+            deadlines.iter().map(|(index, at)| (*at, *index)).collect()
+        };
+        urgent.sort_unstable();
+
+        let now = Instant::now();
+        for (_, index) in urgent.iter().filter(|(at, _)| *at <= now) {
+            if self.stats().missed_deadlines.insert(*index) {
+                warn!("piece {index} missed its deadline");
+            }
         }
+
+        let mut queue = self.download_queue.write().await;
+        let position = urgent.iter().find_map(|(_, index)| {
+            queue
+                .iter()
+                .position(|Reverse(QueueKey(piece))| piece.piece_index() == *index)
+        });
+        let Some(position) = position else {
+            return queue.pop().map(|Reverse(QueueKey(piece))| piece);
+        };
+
+        // NOTE: heap can't remove arbitrary element, rebuilding is fine for a few urgent pieces
+        let mut pieces = std::mem::take(&mut *queue).into_vec();
+        let Reverse(QueueKey(piece)) = pieces.swap_remove(position);
+        *queue = BinaryHeap::from(pieces);
+        trace!("picked urgent piece {}", piece.piece_index());
+        Some(piece)
     }
 
-    async fn get_peers(&self, limit: u8) -> Result<Vec<Peer>> {
-        let peers = self.get_peers_addresses().await?;
+    async fn connect_peers(&self, addresses: Vec<SocketAddr>, limit: u8) -> Vec<Peer<'_>> {
+        let peers = cap_connections_per_ip(addresses, self.config.max_connections_per_peer_ip);
         let limit = limit as usize;
         let mut peers = futures::stream::iter(peers)
             .map(|f| async move {
-                Peer::connect(
+                let _dialing = Dialing::start(&self.status_board);
+                let connection = Peer::connect(
                     f,
                     self.peer_id,
                     self.metadata.info_hash,
                     &self.metadata.info,
+                    self.config.connect_timeout,
                 )
-                .await
+                .await;
+                (f, connection)
             })
             .buffer_unordered(limit);
         let mut peers_connected = Vec::new();
-        while let Some(connection) = peers.next().await {
+        while let Some((socket_addr, connection)) = peers.next().await {
             match connection {
-                Ok(peer) => {
+                Ok(mut peer) => {
+                    peer.attach_session_log(self.session_log.as_deref());
+                    peer.attach_download_limiter(self.download_limiter.as_ref());
                     peers_connected.push(peer);
                     if peers_connected.len() >= limit {
                         break;
                     }
                 }
-                Err(e) => eprint!("Error {e}"),
+                Err(e) => {
+                    if e.is::<HandshakeError>() {
+                        debug!("peer {socket_addr} is not a BitTorrent peer: {e:#}");
+                        self.wrong_protocol_peers
+                            .lock()
+                            .expect("wrong protocol peers lock")
+                            .insert(socket_addr);
+                    } else {
+                        match e.downcast_ref::<DialError>() {
+                            Some(DialError::Unreachable(..)) => debug!("peer unreachable: {e:#}"),
+                            _ => debug!("peer unresponsive: {e:#}"),
+                        }
+                    }
+                    self.dial_failures
+                        .lock()
+                        .expect("dial failures lock")
+                        .insert(socket_addr, format!("{e:#}"));
+                }
             }
         }
 
-        Ok(peers_connected)
+        peers_connected
     }
 
-    // NOTE: well, just passing peers to piece
-    // to filter peers with pieces would have been easier
-    fn get_pieces(&self, peers: &[Peer]) -> Vec<Piece> {
-        peers
+    /// Connects peers found by re-announce into free slots, skipping connected ones.
+    /// `busy` are addresses of peers taken out of `peers` by running pieces
+    async fn connect_new_peers<'a>(
+        &'a self,
+        peers: &mut Vec<Peer<'a>>,
+        busy: &BTreeSet<SocketAddr>,
+        addresses: Vec<SocketAddr>,
+    ) {
+        let connected: BTreeSet<_> = peers
             .iter()
-            .flat_map(|f| {
-                f.available_pieces()
-                    .into_iter()
-                    .map(|piece_number| (piece_number, f.socket_addr()))
-            })
-            .fold(
-                HashMap::new(),
-                |mut acc: HashMap<usize, HashSet<SocketAddrV4>>, (piece_number, socket_addr)| {
-                    acc.entry(piece_number).or_default().insert(socket_addr);
-                    acc
-                },
-            )
+            .map(|peer| peer.socket_addr())
+            .chain(busy.iter().copied())
+            .collect();
+        let free_slots = (self.max_peers as usize).saturating_sub(connected.len());
+        if free_slots == 0 {
+            trace!("no free peer slots");
+            return;
+        }
+
+        // NOTE: connected peers go first so they count towards per ip cap
+        let candidates = connected
+            .iter()
+            .copied()
+            .chain(addresses.into_iter().filter(|f| !connected.contains(f)))
+            .collect();
+        let addresses = cap_connections_per_ip(candidates, self.config.max_connections_per_peer_ip)
+            .into_iter()
+            .filter(|f| !connected.contains(f))
+            .collect();
+        let new_peers = self.connect_peers(addresses, free_slots as u8).await;
+        debug!("connected {} new peers", new_peers.len());
+        peers.extend(new_peers);
+    }
+
+    fn announce_interval(&self, response: &PeersResponse) -> Duration {
+        let interval = response
+            .interval
+            .max(response.min_interval.unwrap_or_default());
+        Duration::from_secs(interval).max(self.config.min_announce_interval)
+    }
+
+    /// Re-announces every interval tracker asks for, feeding peers to the download
+    async fn reannounce(
+        &self,
+        mut interval: Duration,
+        new_peers: tokio::sync::mpsc::UnboundedSender<Vec<SocketAddr>>,
+    ) {
+        loop {
+            self.board().next_announce = Some(Instant::now() + interval);
+            tokio::time::sleep(interval).await;
+            match self.get_peers_tracker_response(None).await {
+                Ok(response) => {
+                    interval = self.announce_interval(&response);
+                    trace!("re-announce got {} peers", response.peers.len());
+                    if new_peers.send(response.peers).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("re-announce failed: {e:#}"),
+            }
+        }
+    }
+
+    /// Connected peers having each piece, `pieces` has a piece number for every peer having it
+    fn piece_availability(&self, pieces: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut availability = vec![0usize; self.metadata.info.pieces.len()];
+        for piece_number in pieces {
+            availability[piece_number] += 1;
+        }
+        availability
+    }
+
+    /// Re-sorts queued pieces by their availability among idle `peers` and peers of `active` pieces
+    async fn refresh_availability(
+        &self,
+        peers: &[Peer<'_>],
+        active: &BTreeMap<usize, ActivePiece>,
+    ) {
+        let availability = self.piece_availability(
+            live_peers(peers)
+                .flat_map(|peer| peer.available_pieces())
+                .chain(
+                    active
+                        .values()
+                        .flat_map(|piece| piece.available_pieces.iter().copied()),
+                ),
+        );
+        let mut queue = self.download_queue.write().await;
+        let mut pieces = std::mem::take(&mut *queue).into_vec();
+        for Reverse(QueueKey(piece)) in &mut pieces {
+            piece.set_availability(availability[piece.piece_index()]);
+        }
+        *queue = BinaryHeap::from(pieces);
+    }
+
+    fn get_pieces(&self, peers: &[Peer]) -> Vec<Piece> {
+        self.piece_availability(live_peers(peers).flat_map(|peer| peer.available_pieces()))
             .into_iter()
-            .filter_map(|(k, v)| Piece::new(k, &self.metadata.info, v).ok())
+            .enumerate()
+            .filter_map(|(k, v)| Piece::new(k, &self.metadata, v).ok())
             .collect()
     }
 
-    #[instrument(skip(self, peers, saved_block, save_file_piece))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(
+        self,
+        piece_buffer,
+        peers,
+        peer_addrs,
+        saved_block,
+        save_file_piece,
+        endgame
+    ))]
     async fn cooperative_download_piece<T: Future<Output = Result<PeerId>>>(
         &self,
         piece_index: usize,
-        piece_length: usize,
+        mut piece_buffer: PieceBuffer,
         peers: &mut FuturesUnordered<T>,
+        peer_addrs: Vec<SocketAddr>,
         saved_block: async_channel::Receiver<ReceivedBlock>,
         save_file_piece: tokio::sync::mpsc::Sender<(u64, Vec<u8>)>,
+        endgame: &mut Endgame,
     ) -> Result<()> {
         let average_piece_length = self.metadata.info.piece_length;
+        let started = Instant::now();
+        let peers_involved = peer_addrs.len();
 
-        let mut bytes_written = 0;
-        let mut piece_blocks = vec![0u8; piece_length];
         loop {
             trace!("loop");
+            let stall_deadline = self.stats().progress_deadline(self.config.stall_timeout);
             tokio::select! {
             peer_id = peers.next() => {
                 trace!("peer future");
                 match peer_id {
-                    Some(peer_id) => {
+                    Some(Ok(peer_id)) => {
                         trace!("peer response {:?}", peer_id);
                     },
+                    Some(Err(err)) => {
+                        debug!("peer disconnected: {err:#}");
+                        self.stats().peer_disconnected(&err);
+                    },
                     None => {
+                        // NOTE: no peer left to deliver remaining blocks,
+                        // blocks already sent are drained by other arm first
+                        if saved_block.is_empty() {
+                            return Err(PeersExhausted(piece_index).into());
+                        }
                         trace!("peers exited");
                     },
                 }
             }
+            _ = tokio::time::sleep_until(stall_deadline) => {
+                bail!(
+                    "no progress for {} seconds",
+                    self.config.stall_timeout.as_secs()
+                );
+            }
             block = saved_block.recv() => {
                     trace!("saved_block channel message {:?}", block);
                     match block {
                         Ok(block) => {
-                            let begin = block.begin() as usize;
-                            piece_blocks
-                                .get_mut(begin..begin + block.data().len())
-                                .context("getting slice to copy piece")?
-                                .copy_from_slice(block.data());
-
-                            bytes_written += block.data().len();
-                            if bytes_written == piece_length {
-                                save_file_piece.send(((piece_index * average_piece_length) as u64, piece_blocks)).await.expect("sent");
+                            let block_length = block.data().len() as u64;
+                            self.stats().block_downloaded(block_length);
+                            match piece_buffer.insert(&block) {
+                                Ok(true) => endgame.block_saved(block.begin()),
+                                Ok(false) => {
+                                    let mut stats = self.stats();
+                                    stats.bytes_wasted += block_length;
+                                    if endgame.is_active() {
+                                        stats.endgame_waste += block_length;
+                                    }
+                                    continue;
+                                }
+                                Err(err) => {
+                                    warn!("discarding block: {err:#}");
+                                    self.stats().bytes_wasted += block_length;
+                                    continue;
+                                }
+                            }
+
+                            trace!("piece bytes written {}", piece_buffer.bytes_written());
+                            if piece_buffer.is_complete() {
+                                let piece_length = piece_buffer.bytes_written();
+                                let data = piece_buffer.into_data();
+                                // NOTE: only verified pieces reach the writer
+                                let (data, received) = tokio::task::spawn_blocking(move || {
+                                    let hash = sha1_hash(&data);
+                                    (data, hash)
+                                })
+                                .await
+                                .context("hashing piece")?;
+                                let expected = self.metadata.info.pieces[piece_index];
+                                if received != expected {
+                                    let mut stats = self.stats();
+                                    stats.bytes_wasted += piece_length as u64;
+                                    stats.hash_failures += 1;
+                                    return Err(HashMismatch {
+                                        piece_index,
+                                        piece_length,
+                                        expected,
+                                        received,
+                                        peers: peer_addrs,
+                                    }
+                                    .into());
+                                }
+                                self.log_session(|| SessionEvent::PieceVerified { piece: piece_index });
+                                self.disk_queue.fetch_add(1, Ordering::Relaxed);
+                                save_file_piece
+                                    .send(((piece_index * average_piece_length) as u64, data))
+                                    .await
+                                    .map_err(|_| WriterStopped)?;
+                                self.stats().piece_completed(piece_index, piece_length, started.elapsed(), peers_involved);
                                 break;
                             }
+                            endgame.try_start(&piece_buffer)?;
                         },
                         Err(err) => {
                             tracing::error!("done recv() failed, {:?}", err);
@@ -160,104 +490,901 @@ impl Torrent {
         Ok(())
     }
 
-    #[instrument(skip(self))]
-    pub async fn download(&mut self, output: PathBuf) -> Result<()> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(output)
-            .context("opening file")?;
-
-        file.set_len(self.metadata.info.length as u64)
-            .context("setting file size")?;
+    pub async fn download(&self, output: PathBuf) -> Result<DownloadReport> {
+        self.download_with_progress(output, |_| {}).await
+    }
+
+    /// Same as [`Torrent::download`], `on_progress` is called once a verified piece
+    /// is written. Called from download task, never from file writer
+    #[instrument(skip(self, on_progress))]
+    pub async fn download_with_progress(
+        &self,
+        output: PathBuf,
+        mut on_progress: impl FnMut(DownloadProgress),
+    ) -> Result<DownloadReport> {
+        *self.stats() = TransferStats::default();
+        *self.board() = StatusBoard {
+            state: TorrentState::Downloading,
+            ..Default::default()
+        };
+        let error = self.download_pieces(output, &mut on_progress).await.err();
+        let stats = self.stats();
+        let report = DownloadReport::new(
+            &stats,
+            self.tracker.announce_counts(),
+            self.tracker.peer_modes(),
+            self.denied_peers.lock().expect("denied peers lock").clone(),
+            self.wrong_protocol_peers
+                .lock()
+                .expect("wrong protocol peers lock")
+                .len(),
+            self.metadata.info.pieces.len(),
+            error,
+        );
+        let mut board = self.board();
+        board.state = if report.is_completed() {
+            TorrentState::Completed
+        } else {
+            TorrentState::Failed
+        };
+        board.active_pieces.clear();
+        board.next_announce = None;
+        Ok(report)
+    }
+
+    async fn download_pieces(
+        &self,
+        output: PathBuf,
+        on_progress: &mut dyn FnMut(DownloadProgress),
+    ) -> Result<()> {
+        anyhow::ensure!(
+            self.metadata.info.is_resolved(),
+            "torrent metadata is not fetched yet"
+        );
+        let resume_path = ResumeState::path(&output);
+        let mut storage = self.storage(output)?;
+        let (present, mut resume_state) = if self.config.resume {
+            let hashes = self.metadata.info.pieces.clone();
+            let piece_length = self.metadata.info.piece_length;
+            let info_hash = self.metadata.info_hash;
+            let resume_path = resume_path.clone();
+            // NOTE: hashing gigabytes takes a while, runtime keeps serving meanwhile
+            let (returned, present) = tokio::task::spawn_blocking(move || {
+                let present =
+                    resumable_pieces(&mut storage, &resume_path, info_hash, &hashes, piece_length);
+                (storage, present)
+            })
+            .await
+            .context("checking existing download")?;
+            storage = returned;
+            present.context("checking existing download")?
+        } else {
+            let present = BitVec::repeat(false, self.metadata.info.pieces.len());
+            let resume_state = ResumeState::new(self.metadata.info_hash, &present);
+            (present, resume_state)
+        };
+        storage.allocate()?;
+        let total_pieces = self.metadata.info.pieces.len();
+        if present.any() {
+            debug!(
+                "{} of {total_pieces} pieces already present",
+                present.count_ones()
+            );
+            let mut stats = self.stats();
+            stats.completed_pieces.extend(present.iter_ones());
+            stats.resumed_pieces = present.count_ones();
+        }
+        let mut progress = DownloadProgress {
+            pieces_completed: present.count_ones(),
+            total_pieces,
+            bytes_written: 0,
+            connected_peers: 0,
+        };
+        let num_pieces = present.count_zeros();
+        if num_pieces == 0 {
+            on_progress(progress);
+            return Ok(());
+        }
+
         let (send_file_piece, mut receive_file_piece) =
-            tokio::sync::mpsc::channel::<(u64, Vec<u8>)>(self.metadata.info.pieces.len() / 2);
-        let num_pieces = self.metadata.info.pieces.len();
+            tokio::sync::mpsc::channel::<(u64, Vec<u8>)>((total_pieces / 2).max(1));
+        let disk_queue = self.disk_queue.clone();
+        let session_log = self.session_log.clone();
+        let piece_length = self.metadata.info.piece_length as u64;
+        // NOTE: unbounded so writer never waits on download loop
+        let (send_written, mut written) = tokio::sync::mpsc::unbounded_channel();
         let file_handle = tokio::task::spawn_blocking(move || -> Result<()> {
             let mut num_pieces_saved = 0;
+            let mut resume_saved = Instant::now();
             while let Some((index, data)) = receive_file_piece.blocking_recv() {
                 trace!("saving {}", index);
-                file.seek(SeekFrom::Start(index)).context("seeking file")?;
-                file.write_all(&data).context("writing file")?;
+                storage.write(index, &data)?;
+                let piece_index = (index / piece_length) as usize;
+                record_to(session_log.as_deref(), || SessionEvent::PieceWritten {
+                    piece: piece_index,
+                    offset: index,
+                    length: data.len(),
+                });
+                disk_queue.fetch_sub(1, Ordering::Relaxed);
+                resume_state.piece_written(piece_index, data.len());
+                if resume_saved.elapsed() >= RESUME_SAVE_INTERVAL {
+                    resume_state.save_synced(&mut storage, &resume_path);
+                    resume_saved = Instant::now();
+                }
+                let _ = send_written.send(data.len());
                 trace!("saved");
                 num_pieces_saved += 1;
                 if num_pieces_saved == num_pieces {
                     break;
                 }
             }
+            resume_state.save_synced(&mut storage, &resume_path);
             Ok(())
         });
 
-        let mut peers = self.get_peers(self.max_peers).await?;
+        let response = self
+            .get_peers_tracker_response(Some(TrackerEvent::Started))
+            .await?;
+        if let Some(swarm) = response.swarm() {
+            debug!("{swarm}");
+        }
+        let announce_interval = self.announce_interval(&response);
+        let mut peers = self.connect_peers(response.peers, self.max_peers).await;
         let pieces = self.get_pieces(&peers);
 
         {
             let mut download_queue = self.download_queue.write().await;
 
-            for piece in pieces.into_iter().filter(|f| f.has_peers()) {
-                download_queue.push(Reverse(piece));
+            // NOTE: pieces no connected peer has are queued too, re-announced peers may have them
+            for piece in pieces.into_iter().filter(|f| !present[f.piece_index()]) {
+                download_queue.push(Reverse(QueueKey(piece)));
+            }
+        }
+
+        let (new_peers_sender, mut new_peers) = tokio::sync::mpsc::unbounded_channel();
+        tokio::select! {
+            result = self.download_queued_pieces(
+                &mut peers,
+                &mut new_peers,
+                &send_file_piece,
+                &mut written,
+                &mut progress,
+                on_progress,
+            ) => {
+                if let Err(e) = result {
+                    // NOTE: writer failing stops download, its own error tells why
+                    if e.is::<WriterStopped>() {
+                        drop(send_file_piece);
+                        file_handle.await.context("savig file")??;
+                    }
+                    return Err(e);
+                }
+            }
+            () = self.reannounce(announce_interval, new_peers_sender) => {
+                unreachable!("re-announce runs as long as new peers are received")
+            }
+        }
+
+        // NOTE: writer stops early once all senders are gone, pieces could be abandoned
+        drop(send_file_piece);
+        file_handle.await.context("savig file")??;
+        while let Ok(length) = written.try_recv() {
+            self.piece_written(&mut progress, length, on_progress);
+        }
+
+        shutdown_peers(peers).await;
+
+        let completed = self.stats().abandoned_pieces.is_empty();
+        if completed {
+            if let Err(e) = self
+                .get_peers_tracker_response(Some(TrackerEvent::Completed))
+                .await
+            {
+                warn!("announcing completion failed: {e:#}");
             }
         }
 
-        anyhow::ensure!(self.download_queue.read().await.len() == self.metadata.info.pieces.len());
+        Ok(())
+    }
+
+    /// Keeps up to `pieces_in_flight` pieces downloading, each from its own share of
+    /// idle peers. Peers come back once their piece is done, failed or not
+    async fn download_queued_pieces<'a>(
+        &'a self,
+        peers: &mut Vec<Peer<'a>>,
+        new_peers: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+        send_file_piece: &tokio::sync::mpsc::Sender<(u64, Vec<u8>)>,
+        written: &mut tokio::sync::mpsc::UnboundedReceiver<usize>,
+        progress: &mut DownloadProgress,
+        on_progress: &mut dyn FnMut(DownloadProgress),
+    ) -> Result<()> {
+        let buffer_budget = BufferBudget::new(self.config.max_buffer_memory);
+        let mut jobs = FuturesUnordered::new();
+        let mut active = BTreeMap::new();
+        let mut swarm = (connected_addresses(peers, &active), known_pieces(peers));
+        // NOTE: block lists reused across pieces, one per piece in flight
+        let mut scratch = Vec::new();
 
         // TODO: move queue to a download coordinator
-        while let Some(piece) = self.download_queue.write().await.pop() {
-            let piece = piece.0;
-            trace!("downloading piece {}", piece.piece_index());
-            let blocks = piece.piece_blocks(BLOCK_SIZE, &self.metadata.info);
-            let total_piece_size = blocks.iter().map(|f| f.block_size).sum::<u32>() as usize;
-            let (request_block, requested_block) = async_channel::bounded(blocks.len());
-            let (save_block, saved_block) = async_channel::bounded(blocks.len());
-            for block in blocks {
-                request_block
-                    .send(block)
-                    .await
-                    .context("sending blocks to process")?;
+        loop {
+            // NOTE: idle peers may announce pieces well after connecting
+            for peer in peers.iter_mut() {
+                peer.drain_idle();
+            }
+            // NOTE: rarest first only holds while availability follows peers joining,
+            // leaving and announcing pieces
+            let now_swarm = (connected_addresses(peers, &active), known_pieces(peers));
+            if now_swarm != swarm {
+                trace!("peers changed, re-sorting queue");
+                self.refresh_availability(peers, &active).await;
+                swarm = now_swarm;
+            }
+
+            while jobs.len() < self.config.pieces_in_flight {
+                let Some((piece, mut selected)) =
+                    self.next_startable_piece(peers, !jobs.is_empty()).await
+                else {
+                    break;
+                };
+                // NOTE: nothing runs, so no connected peer has it
+                if !selected.contains(&true) {
+                    if !self.find_piece_peers(peers, new_peers, &piece).await {
+                        return Err(PeersExhausted(piece.piece_index()))
+                            .context("no connected peer has the piece");
+                    }
+                    self.download_queue
+                        .write()
+                        .await
+                        .push(Reverse(QueueKey(piece)));
+                    self.refresh_availability(peers, &active).await;
+                    continue;
+                }
+                let piece_length = self.metadata.info.piece_size(piece.piece_index());
+                let reservation = match buffer_budget.try_reserve(piece_length)? {
+                    Some(reservation) => reservation,
+                    None if !jobs.is_empty() => {
+                        trace!("piece buffers memory in use {}", buffer_budget.in_use());
+                        self.download_queue
+                            .write()
+                            .await
+                            .push(Reverse(QueueKey(piece)));
+                        break;
+                    }
+                    None => buffer_budget.reserve(piece_length).await?,
+                };
+                // NOTE: last queued piece gets every peer having it, the rest share peers out
+                if !self.download_queue.read().await.is_empty() {
+                    let total_peers =
+                        peers.len() + active.values().map(ActivePiece::peers).sum::<usize>();
+                    let share = (total_peers / self.config.pieces_in_flight).max(1);
+                    for selected in selected.iter_mut().filter(|s| **s).skip(share) {
+                        *selected = false;
+                    }
+                }
+                let mut position = 0;
+                let (job_peers, idle) = std::mem::take(peers).into_iter().partition(|_| {
+                    position += 1;
+                    selected[position - 1]
+                });
+                *peers = idle;
+                active.insert(piece.piece_index(), ActivePiece::new(&job_peers));
+                self.log_session(|| SessionEvent::PieceAssigned {
+                    piece: piece.piece_index(),
+                    peers: job_peers.iter().map(Peer::socket_addr).collect(),
+                });
+                trace!(
+                    "downloading piece {} from {} of {} peers having it",
+                    piece.piece_index(),
+                    job_peers.len(),
+                    piece.availability()
+                );
+                jobs.push(self.download_piece(
+                    piece,
+                    job_peers,
+                    reservation,
+                    scratch.pop().unwrap_or_default(),
+                    send_file_piece.clone(),
+                ));
+            }
+            self.publish_peers(peers, &active);
+            if jobs.is_empty() {
+                break;
+            }
+
+            let keep_alive_due = peers
+                .iter()
+                .map(Peer::keep_alive_due)
+                .min()
+                .unwrap_or_else(|| tokio::time::Instant::now() + IDLE_PEERS_CHECK);
+            tokio::select! {
+                Some(job) = jobs.next() => {
+                    scratch.push(job.blocks);
+                    active.remove(&job.piece.piece_index());
+                    peers.extend(job.peers);
+                    let busy = busy_addresses(&active);
+                    self.finish_piece(peers, &busy, new_peers, job.piece, &job.attempt_peers, job.result).await?;
+                }
+                Some(addresses) = new_peers.recv() => {
+                    self.connect_new_peers(peers, &busy_addresses(&active), addresses).await;
+                }
+                Some(length) = written.recv() => self.piece_written(progress, length, on_progress),
+                () = tokio::time::sleep_until(keep_alive_due) => {
+                    // NOTE: peers waiting for a piece still need to hear from us
+                    for peer in peers.iter_mut() {
+                        peer.keep_alive().await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn piece_written(
+        &self,
+        progress: &mut DownloadProgress,
+        length: usize,
+        on_progress: &mut dyn FnMut(DownloadProgress),
+    ) {
+        progress.pieces_completed += 1;
+        progress.bytes_written += length as u64;
+        progress.connected_peers = self.board().peers.len();
+        on_progress(progress.clone());
+    }
+
+    /// Next queued piece some idle peer has. Pieces only busy peers have stay queued,
+    /// with nothing running the next piece is taken regardless
+    async fn next_startable_piece(
+        &self,
+        idle: &[Peer<'_>],
+        running: bool,
+    ) -> Option<(Piece, Vec<bool>)> {
+        if running && idle.is_empty() {
+            return None;
+        }
+        let mut skipped = Vec::new();
+        let startable = loop {
+            if skipped.len() >= MAX_SKIPPED_PIECES {
+                break None;
             }
+            let Some(piece) = self.next_piece().await else {
+                break None;
+            };
+            let mut selected = select_piece_peers(&piece, idle);
+            self.narrow_retry_peers(piece.piece_index(), idle, &mut selected);
+            // NOTE: failed piece waits for a peer that didn't fail it, busy ones may free up
+            let startable = selected.iter().any(|selected| *selected)
+                && !self.only_suspects(piece.piece_index(), idle, &selected);
+            if !running || startable {
+                break Some((piece, selected));
+            }
+            skipped.push(Reverse(QueueKey(piece)));
+        };
+        self.download_queue.write().await.extend(skipped);
+        startable
+    }
 
-            trace!("blocks sent to process");
-            let mut peers_interacting = FuturesUnordered::new();
-            for peer in peers.iter_mut().filter(|peer| piece.peer_has_piece(peer)) {
-                let request_block = request_block.clone();
-                let requested_block = requested_block.clone();
-                let saved_block = save_block.clone();
+    /// Downloads one piece from `peers`, handing them back with the result
+    async fn download_piece<'a>(
+        &'a self,
+        piece: Piece,
+        mut peers: Vec<Peer<'a>>,
+        reservation: BufferReservation,
+        mut blocks: Vec<PieceBlock>,
+        send_file_piece: tokio::sync::mpsc::Sender<(u64, Vec<u8>)>,
+    ) -> PieceJob<'a> {
+        piece.piece_blocks_into(self.config.block_size, &self.metadata.info, &mut blocks);
+        let piece_buffer = PieceBuffer::new(
+            piece.piece_index(),
+            self.config.block_size,
+            &blocks,
+            reservation,
+        );
+        // NOTE: unbounded, endgame queues copies of blocks on top
+        let (request_block, requested_block) = async_channel::unbounded();
+        let (save_block, saved_block) = async_channel::bounded(blocks.len());
+        for block in blocks.drain(..) {
+            request_block
+                .try_send(block)
+                .expect("unbounded channel is open");
+        }
 
-                peers_interacting.push(peer.process(request_block, requested_block, saved_block));
+        trace!("blocks sent to process");
+        let queued_blocks = self.download_queue.read().await.len()
+            * self
+                .metadata
+                .info
+                .piece_length
+                .div_ceil(self.config.block_size as usize);
+        let (mut endgame, saved_blocks) = Endgame::new(
+            self.config.endgame_blocks,
+            queued_blocks,
+            request_block.clone(),
+        );
+        let mut attempt_peers = Vec::new();
+        let mut peers_interacting = FuturesUnordered::new();
+        for peer in peers.iter_mut() {
+            let mut stats = self.stats();
+            stats.peers_used.insert(peer.socket_addr());
+            if peer.is_seed() {
+                stats.seeds_used.insert(peer.socket_addr());
             }
+            drop(stats);
+            attempt_peers.push(peer.socket_addr());
 
-            trace!("futures created");
+            peers_interacting.push(peer.process(
+                request_block.clone(),
+                requested_block.clone(),
+                save_block.clone(),
+                saved_blocks.clone(),
+                self.config.block_request_timeout,
+            ));
+        }
+        endgame.set_peers(attempt_peers.len());
+        // NOTE: peers count receivers to tell whether someone else takes reassigned blocks
+        drop(requested_block);
 
-            let send_file_piece = send_file_piece.clone();
+        trace!("futures created");
 
-            self.cooperative_download_piece(
+        let result = self
+            .cooperative_download_piece(
                 piece.piece_index(),
-                total_piece_size,
+                piece_buffer,
                 &mut peers_interacting,
+                attempt_peers.clone(),
                 saved_block,
                 send_file_piece,
+                &mut endgame,
             )
-            .await
-            .context("saving file")?;
+            .await;
+        drop(peers_interacting);
+        // NOTE: peers still waiting on endgame copies
+        for peer in peers.iter_mut() {
+            if let Err(e) = peer.cancel_in_flight().await {
+                debug!("cancel to {} failed: {e:#}", peer.socket_addr());
+            }
         }
 
-        file_handle.await.context("savig file")??;
+        PieceJob {
+            piece,
+            blocks,
+            peers,
+            attempt_peers,
+            result,
+        }
+    }
+
+    /// Acts on piece outcome: bans peers found corrupting, requeues failed piece.
+    /// Piece no connected peer has fails download, unless `busy` peers
+    /// of running pieces or peers tracker lists now may have it
+    async fn finish_piece<'a>(
+        &'a self,
+        peers: &mut Vec<Peer<'a>>,
+        busy: &BTreeSet<SocketAddr>,
+        new_peers: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+        piece: Piece,
+        attempt_peers: &[SocketAddr],
+        result: Result<()>,
+    ) -> Result<()> {
+        // NOTE: peers banned while they were busy with other piece
+        let banned: Vec<_> = peers
+            .iter()
+            .map(Peer::socket_addr)
+            .filter(|peer| {
+                self.corrupt_peers
+                    .lock()
+                    .expect("corrupt peers lock")
+                    .contains(peer)
+            })
+            .collect();
+        if !banned.is_empty() {
+            self.ban_corrupt_peers(peers, &banned).await;
+        }
 
+        if let Err(err) = &result {
+            self.log_session(|| SessionEvent::PieceFailed {
+                piece: piece.piece_index(),
+                reason: format!("{err:#}"),
+            });
+        }
+        match result {
+            Ok(()) => {
+                self.reset_piece_deadline(piece.piece_index());
+                let culprits = self.stats().piece_verified(
+                    piece.piece_index(),
+                    attempt_peers,
+                    self.config.max_peer_hash_failures,
+                );
+                if !culprits.is_empty() {
+                    self.ban_corrupt_peers(peers, &culprits).await;
+                }
+            }
+            Err(err) if err.is::<PeersExhausted>() || err.is::<HashMismatch>() => {
+                if let Some(mismatch) = err.downcast_ref::<HashMismatch>() {
+                    self.stats()
+                        .piece_corrupted(mismatch.piece_index, &mismatch.peers);
+                }
+                peers.retain(|peer| !peer.is_disconnected());
+                // NOTE: fresh peers from re-announce may have it, failing only without them
+                while let Ok(addresses) = new_peers.try_recv() {
+                    self.connect_new_peers(peers, busy, addresses).await;
+                }
+                if busy.is_empty()
+                    && !peers.iter().any(|peer| piece.peer_has_piece(peer))
+                    && !self.find_piece_peers(peers, new_peers, &piece).await
+                {
+                    return Err(err).context("no connected peer has the piece");
+                }
+                let retry = self
+                    .stats()
+                    .piece_failed(piece.piece_index(), self.config.max_piece_retries);
+                if retry {
+                    debug!("{err}, re-queueing");
+                    self.download_queue
+                        .write()
+                        .await
+                        .push(Reverse(QueueKey(piece)));
+                } else {
+                    warn!("{err}, retries exhausted, abandoning piece");
+                }
+            }
+            Err(err) => return Err(err).context("saving file"),
+        }
         Ok(())
     }
 
-    pub async fn get_peers_tracker_response(&self) -> Result<PeersResponse> {
+    /// Nothing runs and no connected peer has `piece`. Peers that announced nothing get
+    /// a while to do so, peers re-announce brought meanwhile are connected, then tracker
+    /// is asked right away. Peers used before aren't dialed again
+    async fn find_piece_peers<'a>(
+        &'a self,
+        peers: &mut Vec<Peer<'a>>,
+        new_peers: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<SocketAddr>>,
+        piece: &Piece,
+    ) -> bool {
+        let has_piece = |peers: &[Peer]| peers.iter().any(|peer| piece.peer_has_piece(peer));
+        let deadline = tokio::time::Instant::now() + QUIET_PEERS_WAIT;
+        loop {
+            for peer in peers.iter_mut() {
+                peer.drain_idle();
+            }
+            let quiet: Vec<_> = live_peers(peers)
+                .filter(|peer| peer.known_pieces() == 0)
+                .map(|peer| Box::pin(peer.readable()))
+                .collect();
+            if has_piece(peers) || quiet.is_empty() {
+                break;
+            }
+            trace!("waiting for {} peers to announce pieces", quiet.len());
+            tokio::select! {
+                _ = futures::future::select_all(quiet) => {}
+                () = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        while let Ok(addresses) = new_peers.try_recv() {
+            self.connect_new_peers(peers, &BTreeSet::new(), addresses)
+                .await;
+        }
+        if has_piece(peers) {
+            return true;
+        }
+
+        debug!(
+            "no connected peer has piece {}, re-announcing",
+            piece.piece_index()
+        );
+        let addresses = match self.get_peers_tracker_response(None).await {
+            Ok(response) => response.peers,
+            Err(e) => {
+                warn!("re-announce failed: {e:#}");
+                return false;
+            }
+        };
+        let used = self.stats().peers_used.clone();
+        let fresh = addresses
+            .into_iter()
+            .filter(|f| !used.contains(f))
+            .collect();
+        self.connect_new_peers(peers, &BTreeSet::new(), fresh).await;
+        has_piece(peers)
+    }
+
+    /// Piece that failed its hash is retried from a single peer, so the next
+    /// failure points at it. Peers with fewest strikes that sent
+    /// fewest failed attempts go first
+    fn narrow_retry_peers(&self, piece_index: usize, peers: &[Peer], selected: &mut [bool]) {
+        let stats = self.stats();
+        let Some(suspects) = stats.failed_piece_peers.get(&piece_index) else {
+            return;
+        };
+        let chosen = peers
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| selected[*position])
+            .min_by_key(|(_, peer)| {
+                let socket_addr = peer.socket_addr();
+                (
+                    stats.hash_strikes.get(&socket_addr).copied().unwrap_or(0),
+                    suspects.get(&socket_addr).copied().unwrap_or(0),
+                )
+            })
+            .map(|(position, _)| position);
+        let Some(chosen) = chosen else {
+            return;
+        };
+        trace!(
+            "retrying piece {piece_index} from {}",
+            peers[chosen].socket_addr()
+        );
+        for (position, selected) in selected.iter_mut().enumerate() {
+            *selected = position == chosen;
+        }
+    }
+
+    /// Every selected peer already sent a failed attempt of the piece
+    fn only_suspects(&self, piece_index: usize, peers: &[Peer], selected: &[bool]) -> bool {
+        let stats = self.stats();
+        let Some(suspects) = stats.failed_piece_peers.get(&piece_index) else {
+            return false;
+        };
+        peers
+            .iter()
+            .zip(selected)
+            .filter(|(_, selected)| **selected)
+            .all(|(peer, _)| suspects.contains_key(&peer.socket_addr()))
+    }
+
+    /// Bans peers for the rest of session and disconnects them
+    async fn ban_corrupt_peers(&self, peers: &mut Vec<Peer<'_>>, culprits: &[SocketAddr]) {
+        warn!("banning {culprits:?} for sending corrupt pieces");
+        self.stats().banned_peers.extend(culprits);
+        self.corrupt_peers
+            .lock()
+            .expect("corrupt peers lock")
+            .extend(culprits);
+        let (banned, kept) = std::mem::take(peers)
+            .into_iter()
+            .partition(|peer| culprits.contains(&peer.socket_addr()));
+        *peers = kept;
+        shutdown_peers(banned).await;
+    }
+
+    /// Peer summaries for [`Torrent::status`], idle `peers` stand by
+    fn publish_peers(&self, peers: &[Peer], active: &BTreeMap<usize, ActivePiece>) {
+        let summaries = peers
+            .iter()
+            .map(|peer| peer.summary(false))
+            .chain(
+                active
+                    .values()
+                    .flat_map(|piece| piece.peers.iter().cloned()),
+            )
+            .collect();
+        let mut board = self.board();
+        board.peers = summaries;
+        board.active_pieces = active
+            .iter()
+            .map(|(piece_index, piece)| PieceAssignment {
+                piece_index: *piece_index,
+                peers: piece.peers.iter().map(|peer| peer.socket_addr).collect(),
+            })
+            .collect();
+    }
+
+    /// Handshakes every peer tracker knows about, with bounded concurrency
+    pub async fn probe_peers(&self) -> Result<Vec<PeerProbe>> {
+        let peers = self.get_peers_addresses(None).await?;
+        let probes = futures::stream::iter(peers)
+            .map(|socket_addr| async move {
+                let handshake = Peer::handshake(
+                    socket_addr,
+                    self.peer_id,
+                    self.metadata.info_hash,
+                    &self.metadata.info,
+                    self.config.connect_timeout,
+                )
+                .await;
+                // NOTE: peer serving other torrent is still reachable
+                let result = match handshake {
+                    Ok(handshake) => Ok((handshake.peer_id, true)),
+                    Err(e) => match e.downcast_ref::<InfoHashMismatch>() {
+                        Some(mismatch) => Ok((mismatch.peer_id, false)),
+                        None => Err(e),
+                    },
+                };
+                PeerProbe {
+                    socket_addr,
+                    result,
+                }
+            })
+            .buffer_unordered(self.max_peers.max(1) as usize)
+            .collect::<Vec<_>>()
+            .await;
+
+        Ok(probes)
+    }
+
+    pub async fn scrape(&self) -> Result<ScrapeStats> {
         self.tracker
-            .peers(&self.metadata)
+            .scrape(&self.metadata)
+            .await
+            .context("scraping tracker")
+    }
+
+    pub async fn get_peers_tracker_response(
+        &self,
+        event: Option<TrackerEvent>,
+    ) -> Result<PeersResponse> {
+        let response = self
+            .tracker
+            .peers(&self.metadata, event, self.announce_progress())
             .await
-            .context("getting peers")
+            .context("getting peers");
+        self.board().last_announce = Some(match &response {
+            Ok(response) => format!("{} peers", response.peers.len()),
+            Err(err) => format!("{err:#}"),
+        });
+        let mut response = response?;
+        let tracker_peers = std::mem::take(&mut response.peers);
+        let sourced_peers = tracker_peers
+            .into_iter()
+            .map(|peer| (peer, PeerSourceKind::Tracker))
+            .chain(
+                self.known_peers
+                    .iter()
+                    .map(|peer| (*peer, PeerSourceKind::Manual)),
+            );
+        for (peer, source) in sourced_peers {
+            if !response.peers.contains(&peer) && self.is_peer_allowed(peer, source) {
+                response.peers.push(peer);
+            }
+        }
+        Ok(response)
+    }
+
+    /// Totals so far, pieces verified on disk before download count as done
+    fn announce_progress(&self) -> AnnounceProgress {
+        let info = &self.metadata.info;
+        let stats = self.stats();
+        let done = info.payload_size_of(stats.completed_pieces.iter().copied());
+        AnnounceProgress {
+            left: info.payload_length().saturating_sub(done) as u64,
+            downloaded: stats.bytes_downloaded,
+            uploaded: stats.bytes_uploaded,
+        }
+    }
+
+    /// Every address goes through filter before it could be dialed
+    fn is_peer_allowed(&self, peer: SocketAddr, source: PeerSourceKind) -> bool {
+        let wrong_protocol = self
+            .wrong_protocol_peers
+            .lock()
+            .expect("wrong protocol peers lock")
+            .contains(&peer);
+        let corrupt = self
+            .corrupt_peers
+            .lock()
+            .expect("corrupt peers lock")
+            .contains(&peer);
+        // NOTE: address that spoke other protocol won't change its mind, never dialed again
+        let reason = if wrong_protocol {
+            trace!("{source:?} peer {peer} is not a BitTorrent peer");
+            NOT_BITTORRENT_PEER.to_string()
+        } else if corrupt {
+            trace!("{source:?} peer {peer} is banned for corrupt pieces");
+            CORRUPT_PEER.to_string()
+        } else {
+            let Some(filter) = &self.peer_filter else {
+                return true;
+            };
+            match filter.decide(peer, source) {
+                FilterDecision::Allow => return true,
+                FilterDecision::Deny { reason } => {
+                    debug!("{source:?} peer {peer} denied: {reason}");
+                    reason
+                }
+                FilterDecision::DenySilently => "silently".to_string(),
+            }
+        };
+        *self
+            .denied_peers
+            .lock()
+            .expect("denied peers lock")
+            .entry(reason)
+            .or_default() += 1;
+        false
     }
 
-    pub async fn get_peers_addresses(&self) -> Result<Vec<SocketAddrV4>> {
-        let peer_response = self.get_peers_tracker_response().await?;
+    pub async fn get_peers_addresses(
+        &self,
+        event: Option<TrackerEvent>,
+    ) -> Result<Vec<SocketAddr>> {
+        let peer_response = self.get_peers_tracker_response(event).await?;
+        if let Some(swarm) = peer_response.swarm() {
+            debug!("{swarm}");
+        }
         Ok(peer_response.peers)
     }
+
+    /// Fetches info dict of magnet torrent from peers, trying them one by one
+    #[instrument(skip(self))]
+    pub async fn resolve_metadata(&mut self) -> Result<()> {
+        if self.metadata.info.is_resolved() {
+            return Ok(());
+        }
+
+        let mut fetch = MetadataFetch::new(self.metadata.info_hash);
+        let info = self.fetch_metadata(&mut fetch).await?;
+        debug!("fetched metadata of {}", info.name);
+        self.metadata.info = info;
+        Ok(())
+    }
+
+    /// Fetches metadata within `timeout` without keeping it, failures of trackers
+    /// and peers are reported whether fetch succeeds, fails or runs out of time
+    pub async fn fetch_metadata_report(&self, timeout: Duration) -> MetadataFetchReport {
+        let started = Instant::now();
+        let mut fetch = MetadataFetch::new(self.metadata.info_hash);
+        let info = tokio::time::timeout(timeout, self.fetch_metadata(&mut fetch))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", timeout.as_secs())));
+
+        let peer_failures = self
+            .dial_failures
+            .lock()
+            .expect("dial failures lock")
+            .clone()
+            .into_iter()
+            .chain(fetch.failures().clone())
+            .collect::<BTreeMap<_, _>>();
+        let failures = self
+            .tracker
+            .announce_failures()
+            .into_iter()
+            .map(|(tracker, reason)| SourceFailure {
+                source: format!("tracker {tracker}"),
+                reason,
+            })
+            .chain(
+                peer_failures
+                    .into_iter()
+                    .map(|(peer, reason)| SourceFailure {
+                        source: format!("peer {peer}"),
+                        reason,
+                    }),
+            )
+            .collect();
+        MetadataFetchReport {
+            info,
+            elapsed: started.elapsed(),
+            suppliers: fetch.suppliers().iter().copied().collect(),
+            failures,
+        }
+    }
+
+    /// Partial metadata and bans in `fetch` carry over between announce rounds
+    async fn fetch_metadata(&self, fetch: &mut MetadataFetch) -> Result<TorrentInfo> {
+        let mut last_error = None;
+        for round in 0..METADATA_FETCH_ROUNDS {
+            let addresses = self
+                .get_peers_addresses(None)
+                .await?
+                .into_iter()
+                .filter(|f| !fetch.is_banned(f))
+                .collect();
+            let peers = self.connect_peers(addresses, self.max_peers).await;
+            match fetch.fetch(peers).await {
+                Ok(info) => return Ok(info),
+                Err(e) if e.is::<MalformedTorrent>() => return Err(e),
+                Err(e) => {
+                    debug!("metadata fetch round {round} failed: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("no peer provided metadata")))
+    }
 }
 
 #[allow(unused)]
@@ -266,13 +1393,1919 @@ struct TorrentPiece {
     hash: Bytes20,
 }
 
-pub fn generate_peer_id() -> PeerId {
-    let data: Vec<_> = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(20)
-        .collect();
+/// Reachability of a single peer, peer id and whether info hash matched
+#[derive(Debug)]
+pub struct PeerProbe {
+    pub socket_addr: SocketAddr,
+    pub result: Result<(PeerId, bool)>,
+}
 
-    let mut arr = [0u8; 20];
-    arr.copy_from_slice(&data);
-    arr.into()
+impl std::fmt::Display for PeerProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.result {
+            Ok((peer_id, info_hash_matched)) => {
+                let peer_id: Bytes20 = (*peer_id).into();
+                write!(
+                    f,
+                    "{} reachable peer id {} info hash {}",
+                    self.socket_addr,
+                    hex::encode(peer_id),
+                    if *info_hash_matched {
+                        "matched"
+                    } else {
+                        "mismatched"
+                    }
+                )
+            }
+            Err(e) => write!(f, "{} unreachable: {e:#}", self.socket_addr),
+        }
+    }
+}
+
+/// Deny reason of addresses that answered in another protocol
+const NOT_BITTORRENT_PEER: &str = "not a bittorrent peer";
+
+/// Deny reason of addresses banned for sending pieces failing their hash
+const CORRUPT_PEER: &str = "sent corrupt pieces";
+
+/// Announces to find peers serving metadata before giving up
+const METADATA_FETCH_ROUNDS: usize = 3;
+
+#[derive(Debug, thiserror::Error)]
+#[error("all peers exited before piece {0} completed")]
+struct PeersExhausted(usize);
+
+/// File writer exited, its join result has the cause
+#[derive(Debug, thiserror::Error)]
+#[error("file writer stopped")]
+struct WriterStopped;
+
+/// Pieces already at output path, with resume state to keep up to date.
+/// Resume file of the torrent is trusted once a few of its pieces check out,
+/// otherwise every piece is hashed
+fn resumable_pieces(
+    storage: &mut Storage,
+    resume_path: &Path,
+    info_hash: Bytes20,
+    hashes: &[Bytes20],
+    piece_length: usize,
+) -> Result<(BitVec<u8, Msb0>, ResumeState)> {
+    if let Err(e) = storage.validate_existing() {
+        trace!("nothing to resume: {e:#}");
+        let present = BitVec::repeat(false, hashes.len());
+        let resume_state = ResumeState::new(info_hash, &present);
+        return Ok((present, resume_state));
+    }
+
+    let saved = ResumeState::load(resume_path).unwrap_or_else(|e| {
+        warn!("{e:#}");
+        ResumeState::default()
+    });
+    let trusted = match saved.pieces_of(info_hash, hashes.len()) {
+        Some(pieces) if resume::spot_check(storage, &pieces, hashes, piece_length)? => Some(pieces),
+        Some(_) => {
+            debug!("resume file doesn't match download, verifying every piece");
+            None
+        }
+        None => None,
+    };
+    let present = match &trusted {
+        Some(pieces) => pieces.clone(),
+        None => storage.verified_hashes(hashes, piece_length)?,
+    };
+
+    let mut resume_state = ResumeState::new(info_hash, &present);
+    if saved.is_for(info_hash) {
+        resume_state.downloaded = saved.downloaded;
+        resume_state.uploaded = saved.uploaded;
+    }
+    if trusted.is_none() {
+        resume_state.save_synced(storage, resume_path);
+    }
+    Ok((present, resume_state))
+}
+
+/// Common piece is left to non-seeds once this many of them have it
+const COMMON_PIECE_HOLDERS: usize = 2;
+
+/// Peers downloading the piece, by position in `peers`. Seeds can serve
+/// anything, so they sit out common pieces and are kept for rare ones
+fn select_piece_peers(piece: &Piece, peers: &[Peer]) -> Vec<bool> {
+    let non_seed_holders = peers
+        .iter()
+        .filter(|peer| !peer.is_disconnected() && !peer.is_seed() && piece.peer_has_piece(peer))
+        .count();
+    let skip_seeds = non_seed_holders >= COMMON_PIECE_HOLDERS;
+    peers
+        .iter()
+        .map(|peer| {
+            !peer.is_disconnected() && piece.peer_has_piece(peer) && !(skip_seeds && peer.is_seed())
+        })
+        .collect()
+}
+
+fn live_peers<'p>(peers: &'p [Peer]) -> impl Iterator<Item = &'p Peer<'p>> {
+    peers.iter().filter(|peer| !peer.is_disconnected())
+}
+
+fn connected_addresses(
+    peers: &[Peer],
+    active: &BTreeMap<usize, ActivePiece>,
+) -> BTreeSet<SocketAddr> {
+    live_peers(peers)
+        .map(|peer| peer.socket_addr())
+        .chain(busy_addresses(active))
+        .collect()
+}
+
+/// Pieces idle peers announced, changes as they send haves
+fn known_pieces(peers: &[Peer]) -> usize {
+    live_peers(peers).map(Peer::known_pieces).sum()
+}
+
+fn busy_addresses(active: &BTreeMap<usize, ActivePiece>) -> BTreeSet<SocketAddr> {
+    active
+        .values()
+        .flat_map(|piece| piece.peers.iter().map(|peer| peer.socket_addr))
+        .collect()
+}
+
+/// Piece being downloaded, with what is known of peers taken for it
+struct ActivePiece {
+    peers: Vec<PeerSummary>,
+    /// Piece numbers every peer has, for availability while peers are busy
+    available_pieces: Vec<usize>,
+}
+
+impl ActivePiece {
+    fn new(peers: &[Peer]) -> Self {
+        Self {
+            peers: peers.iter().map(|peer| peer.summary(true)).collect(),
+            available_pieces: peers
+                .iter()
+                .flat_map(|peer| peer.available_pieces())
+                .collect(),
+        }
+    }
+
+    fn peers(&self) -> usize {
+        self.peers.len()
+    }
+}
+
+/// Outcome of a piece with peers it took
+struct PieceJob<'a> {
+    piece: Piece,
+    /// Emptied block list, handed to the next piece
+    blocks: Vec<PieceBlock>,
+    peers: Vec<Peer<'a>>,
+    attempt_peers: Vec<SocketAddr>,
+    result: Result<()>,
+}
+
+/// Idle peers are kept alive at least this often while pieces download
+const IDLE_PEERS_CHECK: Duration = Duration::from_secs(5);
+/// Queued pieces looked at for one idle peers could serve
+const MAX_SKIPPED_PIECES: usize = 32;
+/// Once no connected peer has a needed piece, peers that announced nothing
+/// yet get this long to do so
+const QUIET_PEERS_WAIT: Duration = Duration::from_secs(10);
+
+/// Tears down all peers concurrently, each teardown is time bounded
+async fn shutdown_peers(peers: Vec<Peer<'_>>) {
+    futures::future::join_all(peers.into_iter().map(Peer::shutdown)).await;
+}
+
+fn cap_connections_per_ip(peers: Vec<SocketAddr>, cap: u8) -> Vec<SocketAddr> {
+    let mut connections_per_ip = HashMap::new();
+    peers
+        .into_iter()
+        .filter(|peer| {
+            let connections = connections_per_ip.entry(peer.ip()).or_insert(0u8);
+            if *connections >= cap {
+                trace!("skipping {peer}, connections per ip cap reached");
+                return false;
+            }
+            *connections += 1;
+            true
+        })
+        .collect()
+}
+
+pub fn generate_peer_id() -> PeerId {
+    let data: Vec<_> = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .collect();
+
+    let mut arr = [0u8; 20];
+    arr.copy_from_slice(&data);
+    arr.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{to_bytes, Value};
+    use crate::torrent::testing::{
+        announce_response, announce_response_every, fixture, foreign_listener, metadata,
+        metadata_seeder, seeder, torrent_bytes, MockHttp, MockPeer, Reply, TempDir, WireMessage,
+        LTEP_RESERVED,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Behaviour = Box<dyn FnMut(&WireMessage) -> Vec<Reply> + Send>;
+
+    /// Mock peers with every piece behaving as told and a tracker listing them
+    struct Swarm {
+        tracker: MockHttp,
+        peers: Vec<MockPeer>,
+        torrent_file: Vec<u8>,
+    }
+
+    impl Swarm {
+        async fn start(data: &[u8], piece_length: usize, behaviours: Vec<Behaviour>) -> Self {
+            let metadata = metadata("http://127.0.0.1:1/announce", piece_length, data);
+            let pieces = metadata.info.pieces.len();
+            let mut peers = Vec::new();
+            for behaviour in behaviours {
+                peers.push(
+                    MockPeer::start(metadata.info_hash, vec![Reply::bitfield(pieces)], behaviour)
+                        .await,
+                );
+            }
+            let response = announce_response(&peers.iter().map(MockPeer::addr).collect::<Vec<_>>());
+            let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+            let torrent_file = torrent_bytes(tracker.url("/announce").as_str(), piece_length, data);
+            Self {
+                tracker,
+                peers,
+                torrent_file,
+            }
+        }
+
+        fn seeders(data: &[u8], piece_length: usize, count: usize) -> Vec<Behaviour> {
+            (0..count)
+                .map(|_| Box::new(seeder(data.to_vec(), piece_length)) as Behaviour)
+                .collect()
+        }
+
+        fn metadata(&self) -> TorrentMetadataInfo {
+            TorrentMetadataInfo::from_bytes(&self.torrent_file, ParseConfig::default()).unwrap()
+        }
+
+        fn torrent(&self, config: DownloadConfig) -> Torrent {
+            Torrent::builder(self.metadata())
+                .config(config)
+                .build()
+                .unwrap()
+        }
+    }
+
+    /// Small blocks, so pieces take several requests
+    fn small_blocks() -> DownloadConfig {
+        DownloadConfig {
+            block_size: 16,
+            ..Default::default()
+        }
+    }
+
+    fn torrent(data: &[u8], config: DownloadConfig) -> Torrent {
+        Torrent::builder(metadata("http://127.0.0.1:1/announce", 16, data))
+            .config(config)
+            .build()
+            .unwrap()
+    }
+
+    /// Info dict of single file torrent with `pieces` pieces of 16 bytes
+    fn info_of_pieces(pieces: usize) -> Vec<u8> {
+        let file = torrent_bytes("http://127.0.0.1:1/announce", 16, &vec![7; pieces * 16]);
+        crate::bencode::raw_dict_value(&file, b"info")
+            .unwrap()
+            .to_vec()
+    }
+
+    /// Magnet torrent with tracker listing `peers`, tracker has to outlive fetch
+    fn magnet_link(info_hash: Bytes20, tracker: &MockHttp) -> String {
+        format!(
+            "magnet:?xt=urn:btih:{}&dn=linked.bin&tr={}",
+            hex::encode(info_hash),
+            urlencoding::encode(tracker.url("/announce").as_str())
+        )
+    }
+
+    async fn magnet_torrent(
+        info_hash: Bytes20,
+        peers: Vec<SocketAddr>,
+        config: DownloadConfig,
+    ) -> (MockHttp, Torrent) {
+        let tracker = MockHttp::start(move |_| (200, announce_response(&peers))).await;
+        let torrent = TorrentBuilder::from_magnet(&magnet_link(info_hash, &tracker))
+            .unwrap()
+            .config(config)
+            .build()
+            .unwrap();
+        (tracker, torrent)
+    }
+
+    #[tokio::test]
+    async fn many_ports_of_one_ip_are_capped() {
+        let data = vec![1; 32];
+        let torrent = torrent(
+            &data,
+            DownloadConfig {
+                max_connections_per_peer_ip: 2,
+                ..Default::default()
+            },
+        );
+        let mut mocks = Vec::new();
+        for _ in 0..5 {
+            mocks.push(
+                MockPeer::start(torrent.metadata.info_hash, Vec::new(), |_| Vec::new()).await,
+            );
+        }
+
+        let peers = torrent
+            .connect_peers(mocks.iter().map(MockPeer::addr).collect(), 10)
+            .await;
+
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[test]
+    fn cap_counts_each_ip_separately() {
+        let peers = [
+            "10.0.0.1:1",
+            "10.0.0.1:2",
+            "10.0.0.2:1",
+            "10.0.0.1:3",
+            "10.0.0.2:2",
+        ]
+        .map(|addr| addr.parse().unwrap())
+        .to_vec();
+
+        let capped = cap_connections_per_ip(peers.clone(), 1);
+
+        assert_eq!(capped, [peers[0], peers[2]]);
+    }
+
+    #[tokio::test]
+    async fn unreachable_peer_is_given_up_after_connect_timeout() {
+        let torrent = torrent(
+            &[1; 32],
+            DownloadConfig {
+                connect_timeout: Duration::from_millis(300),
+                ..Default::default()
+            },
+        );
+        let mock = MockPeer::start(torrent.metadata.info_hash, vec![Reply::bitfield(2)], |_| {
+            Vec::new()
+        })
+        .await;
+        // NOTE: non-routable, connect either hangs until timeout or fails right away
+        let unreachable: SocketAddr = "10.255.255.1:6881".parse().unwrap();
+
+        let started = Instant::now();
+        let peers = torrent
+            .connect_peers(vec![unreachable, mock.addr()], 10)
+            .await;
+
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(peers.len(), 1);
+        assert!(torrent
+            .dial_failures
+            .lock()
+            .unwrap()
+            .contains_key(&unreachable));
+    }
+
+    #[tokio::test]
+    async fn report_of_healthy_download_matches_swarm() {
+        let data: Vec<u8> = (0..=255).collect();
+        let swarm = Swarm::start(&data, 64, Swarm::seeders(&data, 64, 2)).await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("report");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(
+            report.bytes_downloaded,
+            data.len() as u64 + report.bytes_wasted
+        );
+        assert_eq!(report.hash_failures, 0);
+        assert!(report.corrupt_peers.is_empty());
+        assert_eq!(report.resumed_pieces, 0);
+        assert_eq!(report.seeds_used, report.peers_used);
+        assert!((1..=2).contains(&report.peers_used));
+        let tracker = RedactedUrl(&swarm.tracker.url("/announce")).to_string();
+        // NOTE: started and completed
+        assert_eq!(report.tracker_announces.get(&tracker), Some(&2));
+        assert!(swarm
+            .peers
+            .iter()
+            .any(|peer| peer.received_ids().contains(&6)));
+    }
+
+    #[test]
+    fn queue_of_million_pieces_stays_small() {
+        const PIECES: usize = 1 << 20;
+        const PIECE_LENGTH: usize = 16 * 1024;
+        let info = BTreeMap::from([
+            (
+                b"length".to_vec(),
+                Value::Integer((PIECES * PIECE_LENGTH) as i64),
+            ),
+            (b"name".to_vec(), Value::String(b"huge.bin".to_vec())),
+            (
+                b"piece length".to_vec(),
+                Value::Integer(PIECE_LENGTH as i64),
+            ),
+            (b"pieces".to_vec(), Value::String(vec![0; PIECES * 20])),
+        ]);
+        let torrent_file = to_bytes(Value::Dict(BTreeMap::from([
+            (
+                b"announce".to_vec(),
+                Value::String(b"http://127.0.0.1:1/announce".to_vec()),
+            ),
+            (b"info".to_vec(), Value::Dict(info)),
+        ])))
+        .unwrap();
+        let metadata =
+            TorrentMetadataInfo::from_bytes(&torrent_file, ParseConfig::default()).unwrap();
+        let torrent = Torrent::builder(metadata).build().unwrap();
+
+        let queue: BinaryHeap<_> = torrent
+            .get_pieces(&[])
+            .into_iter()
+            .map(|piece| Reverse(QueueKey(piece)))
+            .collect();
+
+        // NOTE: crate forbids unsafe code, so no counting allocator. Pieces hold
+        // no heap data of their own, the queue allocation is all there is
+        assert_eq!(queue.len(), PIECES);
+        let bytes = queue.capacity() * std::mem::size_of::<Reverse<QueueKey>>();
+        assert!(bytes < 64 * 1024 * 1024, "{bytes} bytes");
+        assert_eq!(
+            Arc::strong_count(&torrent.metadata.info.pieces),
+            PIECES + 1,
+            "hashes are shared, never copied per piece"
+        );
+    }
+
+    fn failure(report: &DownloadReport) -> &str {
+        match &report.outcome {
+            DownloadOutcome::Failed { error, .. } => error,
+            outcome => panic!("download didn't fail: {outcome:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_peers_trip_stall_watchdog() {
+        let data = vec![1; 64];
+        let silent: Vec<Behaviour> = (0..2)
+            .map(|_| Box::new(|_: &WireMessage| Vec::new()) as Behaviour)
+            .collect();
+        let swarm = Swarm::start(&data, 32, silent).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            stall_timeout: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let dir = TempDir::new("stall");
+
+        let started = Instant::now();
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(
+            failure(&report).contains("no progress for 1 seconds"),
+            "{report:?}"
+        );
+        assert!(
+            started.elapsed() < Duration::from_secs(4),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(report.bytes_downloaded, 0);
+    }
+
+    /// Seeder answering requests above standard size with `oversized`
+    fn strict_seeder(
+        data: &[u8],
+        piece_length: usize,
+        oversized: fn(&WireMessage) -> Reply,
+    ) -> Behaviour {
+        let mut serve = seeder(data.to_vec(), piece_length);
+        Box::new(move |message: &WireMessage| {
+            if message.id == 6 && message.block().2 > MAX_STANDARD_BLOCK_SIZE {
+                return vec![oversized(message)];
+            }
+            serve(message)
+        })
+    }
+
+    fn oversized_blocks() -> DownloadConfig {
+        DownloadConfig {
+            block_size: 2 * MAX_STANDARD_BLOCK_SIZE,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_oversized_requests_fall_back_to_standard_blocks() {
+        let piece_length = 2 * MAX_STANDARD_BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..2 * piece_length).map(|i| (i % 251) as u8).collect();
+        let reject = |message: &WireMessage| Reply::Send(16, message.payload.clone());
+        let swarm = Swarm::start(
+            &data,
+            piece_length,
+            vec![strict_seeder(&data, piece_length, reject)],
+        )
+        .await;
+        let torrent = swarm.torrent(oversized_blocks());
+        let dir = TempDir::new("oversized-reject");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(
+            matches!(report.outcome, DownloadOutcome::Completed),
+            "{report:?}"
+        );
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        let requests: Vec<u32> = swarm.peers[0]
+            .received()
+            .iter()
+            .filter(|message| message.id == 6)
+            .map(|message| message.block().2)
+            .collect();
+        assert_eq!(requests.first(), Some(&(2 * MAX_STANDARD_BLOCK_SIZE)));
+        assert!(
+            requests[1..]
+                .iter()
+                .all(|&size| size == MAX_STANDARD_BLOCK_SIZE),
+            "{requests:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn hangup_after_oversized_request_is_named_in_report() {
+        let piece_length = 2 * MAX_STANDARD_BLOCK_SIZE as usize;
+        let data = vec![3; piece_length];
+        let swarm = Swarm::start(
+            &data,
+            piece_length,
+            vec![strict_seeder(&data, piece_length, |_| Reply::Hangup)],
+        )
+        .await;
+        let torrent = swarm.torrent(oversized_blocks());
+        let dir = TempDir::new("oversized-hangup");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert_eq!(
+            report
+                .disconnect_reasons
+                .get("possible oversized-request rejection"),
+            Some(&1),
+            "{report:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn piece_fails_promptly_once_all_peers_exit() {
+        let data = vec![5; 64];
+        let quitters: Vec<Behaviour> = (0..2)
+            .map(|_| {
+                Box::new(|message: &WireMessage| match message.id {
+                    2 => vec![Reply::Send(1, Vec::new())],
+                    _ => vec![Reply::Hangup],
+                }) as Behaviour
+            })
+            .collect();
+        let swarm = Swarm::start(&data, 32, quitters).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("peers-exhausted");
+
+        let started = Instant::now();
+        let report = tokio::time::timeout(
+            Duration::from_secs(10),
+            torrent.download(dir.join("test.bin")),
+        )
+        .await
+        .expect("download hangs once peers are gone")
+        .unwrap();
+
+        assert!(
+            failure(&report).contains("no connected peer has the piece"),
+            "{report:?}"
+        );
+        assert!(
+            started.elapsed() < Duration::from_secs(3),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn only_peer_dying_after_one_block_fails_download_promptly() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let mut served = false;
+        let dying: Behaviour = Box::new(move |message: &WireMessage| match message.id {
+            6 if served => vec![Reply::Hangup],
+            6 => {
+                served = true;
+                serve(message)
+            }
+            _ => serve(message),
+        });
+        let swarm = Swarm::start(&data, 32, vec![dying]).await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("only-peer-dies");
+
+        let started = Instant::now();
+        let report = tokio::time::timeout(
+            Duration::from_secs(10),
+            torrent.download(dir.join("test.bin")),
+        )
+        .await
+        .expect("download hangs once only peer is gone")
+        .unwrap();
+
+        assert!(
+            failure(&report).contains("no connected peer has the piece"),
+            "{report:?}"
+        );
+        assert!(
+            started.elapsed() < Duration::from_secs(3),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(
+            swarm.peers[0]
+                .received_ids()
+                .iter()
+                .filter(|f| **f == 6)
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_index_piece_drops_liar_and_download_goes_on() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut honest = seeder(data.clone(), 32);
+        let liar: Behaviour = Box::new(move |message: &WireMessage| match message.id {
+            6 => {
+                let (index, begin, length) = message.block();
+                vec![Reply::piece(index ^ 1, begin, &vec![0; length as usize])]
+            }
+            _ => honest(message),
+        });
+        let mut behaviours = vec![liar];
+        behaviours.extend(Swarm::seeders(&data, 32, 1));
+        let swarm = Swarm::start(&data, 32, behaviours).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("wrong-index");
+        let output = dir.join("test.bin");
+
+        let download = std::panic::AssertUnwindSafe(torrent.download(output));
+        let report = futures::FutureExt::catch_unwind(download)
+            .await
+            .expect("download panicked")
+            .unwrap();
+        assert!(
+            matches!(report.outcome, DownloadOutcome::Completed),
+            "{report:?}"
+        );
+        assert_eq!(
+            report.disconnect_reasons.get("protocol violation"),
+            Some(&1)
+        );
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+    }
+
+    #[test]
+    fn hash_mismatch_names_piece_hashes_and_peers() {
+        let mismatch = HashMismatch {
+            piece_index: 3,
+            piece_length: 32,
+            expected: [0xab; 20],
+            received: [0x01; 20],
+            peers: vec!["127.0.0.1:6881".parse().unwrap()],
+        };
+
+        assert_eq!(
+            mismatch.to_string(),
+            format!(
+                "piece 3 of 32 bytes hash mismatch, expected {} got {}, from [127.0.0.1:6881]",
+                "ab".repeat(20),
+                "01".repeat(20)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_reports_reachability_of_each_peer() {
+        let data = vec![1; 32];
+        let info_hash = metadata("http://127.0.0.1:1/announce", 16, &data).info_hash;
+        let matching = MockPeer::start(info_hash, vec![Reply::bitfield(2)], |_| Vec::new()).await;
+        let other_torrent = MockPeer::start([9; 20], Vec::new(), |_| Vec::new()).await;
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let blackhole: SocketAddr = "10.255.255.1:6881".parse().unwrap();
+        let listed = [matching.addr(), other_torrent.addr(), refused, blackhole];
+        let response = announce_response(&listed);
+        let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+        let torrent_file = torrent_bytes(tracker.url("/announce").as_str(), 16, &data);
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(&torrent_file, ParseConfig::default()).unwrap(),
+        )
+        .config(DownloadConfig {
+            connect_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+
+        let probes = torrent.probe_peers().await.unwrap();
+
+        let probe = |addr: SocketAddr| {
+            probes
+                .iter()
+                .find(|probe| probe.socket_addr == addr)
+                .expect("every listed peer is probed")
+        };
+        assert_eq!(probes.len(), listed.len());
+        let reached = |addr| {
+            let (peer_id, matched) = probe(addr).result.as_ref().unwrap();
+            (Bytes20::from(*peer_id), *matched)
+        };
+        assert_eq!(reached(matching.addr()), (*b"-MK0001-000000000000", true));
+        assert_eq!(
+            reached(other_torrent.addr()),
+            (*b"-MK0001-000000000000", false)
+        );
+        assert!(probe(refused).result.is_err());
+        assert!(probe(blackhole).to_string().contains("unreachable"));
+    }
+
+    #[tokio::test]
+    async fn always_corrupt_piece_is_abandoned_after_retry_limit() {
+        let data: Vec<u8> = (0..64).collect();
+        let corrupting: Vec<Behaviour> = (0..2)
+            .map(|_| {
+                let mut corrupt = data.clone();
+                corrupt[32..].fill(0);
+                Box::new(seeder(corrupt, 32)) as Behaviour
+            })
+            .collect();
+        let swarm = Swarm::start(&data, 32, corrupting).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            max_piece_retries: 2,
+            ..Default::default()
+        });
+        let dir = TempDir::new("retry-limit");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        let DownloadOutcome::Partial {
+            missing_pieces,
+            abandoned_pieces,
+            ..
+        } = &report.outcome
+        else {
+            panic!("partial download expected: {report:?}");
+        };
+        assert_eq!(missing_pieces, &[1]);
+        assert_eq!(abandoned_pieces, &[1]);
+        assert_eq!(report.hash_failures, 3);
+    }
+
+    #[tokio::test]
+    async fn corrupt_peer_is_banned_and_honest_one_completes() {
+        let data: Vec<u8> = (0..=255).collect();
+        let corrupt: Vec<u8> = data.iter().map(|f| f ^ 0x01).collect();
+        let swarm = Swarm::start(
+            &data,
+            32,
+            vec![
+                Box::new(seeder(data.clone(), 32)),
+                Box::new(seeder(corrupt, 32)),
+            ],
+        )
+        .await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("corrupt-peer-banned");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(report.corrupt_peers, [swarm.peers[1].addr()]);
+        assert!(report.hash_failures >= 2, "{report:?}");
+        assert!(
+            torrent
+                .denied_peers
+                .lock()
+                .unwrap()
+                .contains_key("sent corrupt pieces"),
+            "{report:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn slowly_served_piece_tops_slowest_pieces() {
+        let data: Vec<u8> = (0..128).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let slow_on_piece_2: Behaviour = Box::new(move |message: &WireMessage| {
+            let mut replies = serve(message);
+            if message.id == 6 && message.block().0 == 2 {
+                replies.insert(0, Reply::Delay(Duration::from_millis(300)));
+            }
+            replies
+        });
+        let swarm = Swarm::start(&data, 32, vec![slow_on_piece_2]).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("slow-piece");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        let slowest = &report.slowest_pieces;
+        assert_eq!(slowest.len(), 4, "{slowest:?}");
+        assert_eq!(slowest[0].piece_index, 2, "{slowest:?}");
+        assert!(slowest[0].latency_ms >= 300, "{slowest:?}");
+        assert!(slowest[1].latency_ms < 300, "{slowest:?}");
+        assert_eq!((slowest[0].peers, slowest[0].retries), (1, 0));
+        assert_eq!(report.piece_latency.max_ms, slowest[0].latency_ms);
+    }
+
+    #[tokio::test]
+    async fn magnet_metadata_is_fetched_across_pieces() {
+        // ~820 piece hashes make info span two 16 KiB metadata pieces
+        let info = info_of_pieces(820);
+        assert!(info.len() > METADATA_PIECE_SIZE);
+        let info_hash = sha1_hash(&info);
+
+        let plain = MockPeer::start(info_hash, vec![Reply::bitfield(820)], |_| Vec::new()).await;
+        let supplier = MockPeer::start_with_reserved(
+            info_hash,
+            LTEP_RESERVED,
+            vec![Reply::extended_handshake(info.len()), Reply::bitfield(820)],
+            metadata_seeder(info.clone()),
+        )
+        .await;
+        let (_tracker, torrent) = magnet_torrent(
+            info_hash,
+            vec![plain.addr(), supplier.addr()],
+            DownloadConfig::default(),
+        )
+        .await;
+        let report = torrent.fetch_metadata_report(Duration::from_secs(10)).await;
+
+        let fetched = report.info.unwrap();
+        assert_eq!(fetched.name, "test.bin");
+        assert_eq!(fetched.pieces.len(), 820);
+        assert_eq!(report.suppliers, vec![supplier.addr()]);
+        let requested: Vec<_> = supplier
+            .received()
+            .iter()
+            .filter_map(WireMessage::metadata_request)
+            .collect();
+        assert_eq!(requested, vec![0, 1]);
+    }
+
+    /// Json of `magnet_info` with its field names, which must not depend on state
+    fn magnet_info_json(magnet_info: &MagnetInfo) -> (serde_json::Value, Vec<String>) {
+        let json = serde_json::to_value(magnet_info).unwrap();
+        let fields = json.as_object().unwrap().keys().cloned().collect();
+        (json, fields)
+    }
+
+    #[tokio::test]
+    async fn magnet_info_reports_offline_failed_and_fetched_states() {
+        let info = info_of_pieces(2);
+        let info_hash = sha1_hash(&info);
+        let plain = MockPeer::start(info_hash, vec![Reply::bitfield(2)], |_| Vec::new()).await;
+        let supplier = MockPeer::start_with_reserved(
+            info_hash,
+            LTEP_RESERVED,
+            vec![Reply::extended_handshake(info.len()), Reply::bitfield(2)],
+            metadata_seeder(info.clone()),
+        )
+        .await;
+
+        let (tracker, _) =
+            magnet_torrent(info_hash, vec![plain.addr()], DownloadConfig::default()).await;
+        let magnet = MagnetLink::parse(&magnet_link(info_hash, &tracker)).unwrap();
+        let (offline, fields) = magnet_info_json(&MagnetInfo::offline(&magnet));
+        assert_eq!(offline["state"], "offline");
+        assert_eq!(offline["info_hash"], hex::encode(info_hash));
+        assert_eq!(offline["name"], "linked.bin");
+        assert_eq!(offline["trackers"].as_array().unwrap().len(), 1);
+        for unknown in ["info", "fetch_seconds", "supplied_by", "error", "failures"] {
+            assert!(offline[unknown].is_null(), "{unknown}: {offline}");
+        }
+        assert!(tracker.requests().is_empty());
+
+        let (_tracker, torrent) =
+            magnet_torrent(info_hash, vec![plain.addr()], DownloadConfig::default()).await;
+        let report = torrent.fetch_metadata_report(Duration::from_secs(1)).await;
+        let failed = MagnetInfo::fetched(&magnet, report);
+        let text = failed.to_string();
+        let (failed, failed_fields) = magnet_info_json(&failed);
+        assert_eq!(failed_fields, fields);
+        assert_eq!(failed["state"], "failed");
+        assert_eq!(failed["name"], "linked.bin");
+        assert!(failed["info"].is_null() && failed["supplied_by"].is_null());
+        assert!(failed["error"].is_string(), "{failed}");
+        let plain_source = format!("peer {}", plain.addr());
+        assert!(
+            failed["failures"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|failure| failure["source"] == plain_source.as_str()),
+            "{failed}"
+        );
+        assert!(text.contains(&plain_source), "{text}");
+
+        let (_tracker, torrent) = magnet_torrent(
+            info_hash,
+            vec![plain.addr(), supplier.addr()],
+            DownloadConfig::default(),
+        )
+        .await;
+        let report = torrent.fetch_metadata_report(Duration::from_secs(10)).await;
+        let (fetched, fetched_fields) = magnet_info_json(&MagnetInfo::fetched(&magnet, report));
+        assert_eq!(fetched_fields, fields);
+        assert_eq!(fetched["state"], "fetched");
+        assert_eq!(fetched["name"], "test.bin");
+        assert_eq!(fetched["info"]["piece_hashes"].as_array().unwrap().len(), 2);
+        assert_eq!(fetched["supplied_by"], serde_json::json!([supplier.addr()]));
+        assert!(fetched["fetch_seconds"].as_f64().unwrap() < 10.0);
+        assert!(fetched["error"].is_null());
+    }
+
+    #[tokio::test]
+    async fn absurd_magnet_metadata_is_rejected_as_malformed() {
+        let piece_hash = [7; 20];
+        let absurd: [Vec<u8>; 2] = [
+            // NOTE: 1000 bytes in pieces of 16 need 63 hashes, there's one
+            [
+                &b"d6:lengthi1000e4:name8:test.bin12:piece lengthi16e6:pieces20:"[..],
+                &piece_hash,
+                b"e",
+            ]
+            .concat(),
+            [
+                &b"d5:filesld6:lengthi16e4:pathl2:..6:escapeee4:name4:evil"[..],
+                b"12:piece lengthi16e6:pieces20:",
+                &piece_hash,
+                b"e",
+            ]
+            .concat(),
+        ];
+
+        for info in absurd {
+            let info_hash = sha1_hash(&info);
+            let supplier = MockPeer::start_with_reserved(
+                info_hash,
+                LTEP_RESERVED,
+                vec![Reply::extended_handshake(info.len()), Reply::bitfield(1)],
+                metadata_seeder(info.clone()),
+            )
+            .await;
+            let (tracker, mut torrent) =
+                magnet_torrent(info_hash, vec![supplier.addr()], DownloadConfig::default()).await;
+
+            let err = torrent.resolve_metadata().await.unwrap_err();
+
+            let malformed = err
+                .downcast_ref::<MalformedTorrent>()
+                .unwrap_or_else(|| panic!("not malformed: {err:#}"));
+            assert_eq!(malformed.0, "metadata of magnet");
+            // NOTE: no other round could bring different metadata for the same hash
+            assert_eq!(tracker.requests().len(), 1, "{err:#}");
+            assert!(!torrent.metadata.info.is_resolved());
+        }
+    }
+
+    #[tokio::test]
+    async fn download_announces_started_then_completed() {
+        let data: Vec<u8> = (0..64).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("events");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        let requests = swarm.tracker.requests();
+        assert_eq!(requests.len(), 2, "{requests:?}");
+        assert!(requests[0].contains("event=started"), "{requests:?}");
+        assert!(requests[0].contains("left=64"), "{requests:?}");
+        assert!(requests[1].contains("event=completed"), "{requests:?}");
+        assert!(requests[1].contains("left=0"), "{requests:?}");
+    }
+
+    #[tokio::test]
+    async fn corrupt_block_is_caught_before_write_and_retried_once() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let mut corrupted = false;
+        let corrupt_once: Behaviour = Box::new(move |message: &WireMessage| {
+            let mut replies = serve(message);
+            if message.id == 6 && message.block() == (1, 16, 16) && !corrupted {
+                corrupted = true;
+                replies = vec![Reply::piece(1, 16, &[0xff; 16])];
+            }
+            replies
+        });
+        let swarm = Swarm::start(&data, 32, vec![corrupt_once]).await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("corrupt-block");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(report.hash_failures, 1);
+        assert_eq!(report.bytes_wasted, 32);
+        let requested_twice = swarm.peers[0]
+            .received()
+            .iter()
+            .filter(|f| f.id == 6 && f.block() == (1, 16, 16))
+            .count();
+        assert_eq!(requested_twice, 2);
+    }
+
+    #[tokio::test]
+    async fn flipped_byte_never_reaches_file() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let flipped = data[48..].to_vec();
+        let flipper: Behaviour = Box::new(move |message: &WireMessage| {
+            if message.id == 6 && message.block() == (1, 16, 16) {
+                let mut block = flipped.clone();
+                block[3] ^= 0x01;
+                return vec![Reply::piece(1, 16, &block)];
+            }
+            serve(message)
+        });
+        let swarm = Swarm::start(&data, 32, vec![flipper]).await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("flipped-byte");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        let DownloadOutcome::Partial {
+            abandoned_pieces,
+            abandoned_piece_peers,
+            ..
+        } = &report.outcome
+        else {
+            panic!("corrupt piece wasn't abandoned: {:?}", report.outcome);
+        };
+        assert_eq!(abandoned_pieces, &[1]);
+        assert_eq!(abandoned_piece_peers[&1], [swarm.peers[0].addr()]);
+        assert!(report.hash_failures >= 1, "{report:?}");
+        let written = std::fs::read(dir.join("test.bin")).unwrap();
+        assert_eq!(written[..32], data[..32]);
+        // NOTE: corrupt piece leaves its part of the file untouched
+        assert!(written[32..].iter().all(|&f| f == 0), "{written:?}");
+    }
+
+    #[tokio::test]
+    async fn half_written_file_requests_only_missing_pieces() {
+        // NOTE: last piece is shorter, found in place only if hashed over its 22 bytes
+        let data: Vec<u8> = (0..246).map(|f| f as u8).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("resume-half");
+        let mut existing = data.clone();
+        for piece in existing.chunks_mut(32).step_by(2) {
+            piece.fill(0);
+        }
+        std::fs::write(dir.join("test.bin"), &existing).unwrap();
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(report.resumed_pieces, 4);
+        assert_eq!(requested_pieces(&swarm), BTreeSet::from([0, 2, 4, 6]));
+    }
+
+    #[tokio::test]
+    async fn rare_pieces_are_downloaded_before_common_ones() {
+        let data: Vec<u8> = (0..192).collect();
+        let metadata = metadata("http://127.0.0.1:1/announce", 32, &data);
+        let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut peers = Vec::new();
+        // NOTE: pieces 0 to 2 are on both peers, 3 to 5 on the first only
+        for bitfield in [0b1111_1100, 0b1110_0000] {
+            let mut serve = seeder(data.clone(), 32);
+            let requested = Arc::clone(&requested);
+            peers.push(
+                MockPeer::start(
+                    metadata.info_hash,
+                    vec![Reply::Send(5, vec![bitfield])],
+                    move |message| {
+                        if message.id == 6 {
+                            requested.lock().unwrap().push(message.block().0);
+                        }
+                        serve(message)
+                    },
+                )
+                .await,
+            );
+        }
+        let response = announce_response(&peers.iter().map(MockPeer::addr).collect::<Vec<_>>());
+        let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(
+                &torrent_bytes(tracker.url("/announce").as_str(), 32, &data),
+                ParseConfig::default(),
+            )
+            .unwrap(),
+        )
+        .config(DownloadConfig {
+            pieces_in_flight: 1,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+        let dir = TempDir::new("rarest-first");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        let mut order = Vec::new();
+        for piece in requested.lock().unwrap().iter() {
+            if !order.contains(piece) {
+                order.push(*piece);
+            }
+        }
+        let (rare, common) = order.split_at(3);
+        assert!(rare.iter().all(|f| *f >= 3), "{order:?}");
+        assert!(common.iter().all(|f| *f < 3), "{order:?}");
+    }
+
+    /// Pieces requested from any of swarm's peers
+    fn requested_pieces(swarm: &Swarm) -> BTreeSet<u32> {
+        swarm
+            .peers
+            .iter()
+            .flat_map(MockPeer::received)
+            .filter(|f| f.id == 6)
+            .map(|f| f.block().0)
+            .collect()
+    }
+
+    /// `left` of first announce to swarm's tracker
+    fn first_announced_left(swarm: &Swarm) -> String {
+        let requests = swarm.tracker.requests();
+        let query = requests[0].split_once('?').unwrap().1;
+        query
+            .split('&')
+            .find_map(|f| f.strip_prefix("left="))
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn download_rate_limit_holds_across_peers() {
+        const RATE: u32 = 512;
+        let data: Vec<u8> = (0..1024).map(|f| f as u8).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 2)).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            max_download_bytes_per_sec: Some(RATE),
+            ..Default::default()
+        });
+        let dir = TempDir::new("rate-limit");
+        let started = Instant::now();
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        // NOTE: bucket starts with a second worth, rest comes at the rate
+        let expected = Duration::from_secs_f64((data.len() as u32 - RATE) as f64 / RATE as f64);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= expected, "{elapsed:?} < {expected:?}");
+    }
+
+    #[tokio::test]
+    async fn untrusted_resume_file_falls_back_to_full_verification() {
+        let data: Vec<u8> = (0..246).map(|f| f as u8).collect();
+        let mut existing = data.clone();
+        existing[32..64].fill(0);
+        for (name, of_other_torrent) in [("corrupt", false), ("other-torrent", true)] {
+            let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+            let torrent = swarm.torrent(DownloadConfig::default());
+            let dir = TempDir::new(&format!("resume-{name}"));
+            let output = dir.join("test.bin");
+            std::fs::write(&output, &existing).unwrap();
+            let resume_path = ResumeState::path(&output);
+            match of_other_torrent {
+                // NOTE: claims every piece, piece 1 included
+                true => ResumeState::new([9; 20], &BitVec::repeat(true, 8))
+                    .save(&resume_path)
+                    .unwrap(),
+                false => std::fs::write(&resume_path, b"not a resume file").unwrap(),
+            }
+
+            let report = torrent.download(output.clone()).await.unwrap();
+
+            assert!(report.is_completed(), "{name}: {:?}", report.outcome);
+            assert_eq!(report.resumed_pieces, 7, "{name}");
+            assert_eq!(requested_pieces(&swarm), BTreeSet::from([1]), "{name}");
+            assert_eq!(std::fs::read(&output).unwrap(), data, "{name}");
+            let saved = ResumeState::load(&resume_path).unwrap();
+            assert_eq!(
+                saved.pieces_of(torrent.metadata.info_hash, 8),
+                Some(BitVec::repeat(true, 8))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn final_progress_event_reports_every_piece_complete() {
+        let data: Vec<u8> = (0..246).map(|f| f as u8).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 2)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("progress");
+        let mut events = Vec::new();
+
+        let report = torrent
+            .download_with_progress(dir.join("test.bin"), |progress| events.push(progress))
+            .await
+            .unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(events.len(), 8);
+        for (n, progress) in events.iter().enumerate() {
+            assert_eq!(progress.pieces_completed, n + 1);
+            assert_eq!(progress.total_pieces, 8);
+            assert!(progress.connected_peers >= 1, "{progress:?}");
+        }
+        let last = events.last().unwrap();
+        assert_eq!(last.pieces_completed, last.total_pieces);
+        assert_eq!(last.bytes_written, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn resumed_download_announces_only_missing_bytes_as_left() {
+        let data: Vec<u8> = (0..246).map(|f| f as u8).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("resume-left");
+        let mut existing = data.clone();
+        existing[..64].fill(0);
+        std::fs::write(dir.join("test.bin"), &existing).unwrap();
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(first_announced_left(&swarm), "64");
+        assert_eq!(requested_pieces(&swarm), BTreeSet::from([0, 1]));
+    }
+
+    #[tokio::test]
+    async fn no_resume_downloads_every_piece_again() {
+        let data: Vec<u8> = (0..246).map(|f| f as u8).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            resume: false,
+            ..Default::default()
+        });
+        let dir = TempDir::new("no-resume");
+        std::fs::write(dir.join("test.bin"), &data).unwrap();
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(report.resumed_pieces, 0);
+        assert_eq!(first_announced_left(&swarm), "246");
+        assert_eq!(requested_pieces(&swarm), (0..8).collect());
+    }
+
+    #[tokio::test]
+    async fn pristine_existing_copy_is_linked_and_nothing_requested() {
+        let data: Vec<u8> = (0..192).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("link-existing");
+        std::fs::write(dir.join("elsewhere.bin"), &data).unwrap();
+
+        let reused = torrent
+            .link_existing(&dir.join("elsewhere.bin"), &dir.join("test.bin"))
+            .unwrap();
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert_eq!(reused, 6);
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(requested_pieces(&swarm), BTreeSet::new());
+    }
+
+    #[tokio::test]
+    async fn corrupted_pieces_of_existing_copy_are_downloaded() {
+        let data: Vec<u8> = (0..192).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let torrent = swarm.torrent(DownloadConfig::default());
+        let dir = TempDir::new("link-existing-corrupt");
+        let mut existing = data.clone();
+        existing[40] ^= 0xff;
+        existing[170] ^= 0xff;
+        std::fs::write(dir.join("elsewhere.bin"), &existing).unwrap();
+
+        let reused = torrent
+            .link_existing(&dir.join("elsewhere.bin"), &dir.join("test.bin"))
+            .unwrap();
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert_eq!(reused, 4);
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert_eq!(requested_pieces(&swarm), BTreeSet::from([1, 5]));
+        assert_eq!(std::fs::read(dir.join("elsewhere.bin")).unwrap(), existing);
+    }
+
+    #[tokio::test]
+    async fn two_pieces_in_flight_double_throughput_of_two_peers() {
+        let data: Vec<u8> = (0..=255).collect();
+        let download = |pieces_in_flight| {
+            let data = data.clone();
+            async move {
+                let slow: Vec<Behaviour> = (0..2)
+                    .map(|_| {
+                        let mut serve = seeder(data.clone(), 32);
+                        Box::new(move |message: &WireMessage| {
+                            let mut replies = serve(message);
+                            if message.id == 6 {
+                                replies.insert(0, Reply::Delay(Duration::from_millis(40)));
+                            }
+                            replies
+                        }) as Behaviour
+                    })
+                    .collect();
+                let swarm = Swarm::start(&data, 32, slow).await;
+                // NOTE: without endgame every block is requested once
+                let torrent = swarm.torrent(DownloadConfig {
+                    pieces_in_flight,
+                    endgame_blocks: 0,
+                    ..Default::default()
+                });
+                let dir = TempDir::new(&format!("in-flight-{pieces_in_flight}"));
+                let started = Instant::now();
+                let report = torrent.download(dir.join("test.bin")).await.unwrap();
+                let elapsed = started.elapsed();
+                assert!(report.is_completed(), "{:?}", report.outcome);
+                assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+                elapsed
+            }
+        };
+
+        let one = download(1).await;
+        let two = download(2).await;
+
+        assert!(two * 4 < one * 3, "one in flight {one:?}, two {two:?}");
+    }
+
+    #[tokio::test]
+    async fn session_report_of_healthy_run_is_clean_and_catches_injected_disorder() {
+        let data: Vec<u8> = (0..128).collect();
+        let swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 2)).await;
+        let dir = TempDir::new("session-log");
+        let log_path = dir.join("session.ndjson");
+        let torrent = Torrent::builder(swarm.metadata())
+            .config(small_blocks())
+            .session_log(Arc::new(SessionLog::create(&log_path).unwrap()))
+            .build()
+            .unwrap();
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+        let session = SessionReport::load(&log_path).unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert!(session.anomalies.is_empty(), "{session}");
+        assert_eq!(session.pieces.len(), 4, "{session}");
+        assert!(session
+            .pieces
+            .values()
+            .all(|f| f.verified_us.is_some() && f.written_us.is_some()));
+        assert!(session.peers.values().any(|f| f.blocks_requested > 0));
+
+        let records: Vec<SessionRecord> = std::fs::read_to_string(&log_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (requested_at, peer) = records
+            .iter()
+            .enumerate()
+            .find_map(|(position, record)| match record.event {
+                SessionEvent::BlockRequested { peer, .. } => Some((position, peer)),
+                _ => None,
+            })
+            .unwrap();
+        let mut disordered = records.clone();
+        disordered.insert(
+            requested_at,
+            SessionRecord {
+                event: SessionEvent::Choked { peer },
+                subsystem: Subsystem::Peer,
+                ..disordered[requested_at].clone()
+            },
+        );
+        disordered.retain(|f| !matches!(f.event, SessionEvent::PieceVerified { piece: 2 }));
+        let anomalies = SessionReport::from_records(&disordered).anomalies;
+
+        assert!(
+            anomalies
+                .iter()
+                .any(|f| matches!(f, Anomaly::RequestBeforeUnchoke { peer: p, .. } if *p == peer)),
+            "{anomalies:?}"
+        );
+        assert!(
+            anomalies.contains(&Anomaly::WriteBeforeVerify {
+                at_us: session.pieces[&2].written_us.unwrap(),
+                piece: 2
+            }),
+            "{anomalies:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reannounce_connects_peers_tracker_lists_later() {
+        let data: Vec<u8> = (0..192).collect();
+        let metadata = metadata("http://127.0.0.1:1/announce", 32, &data);
+        let mut serve = seeder(data.clone(), 32);
+        let slow = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(6)],
+            move |message| {
+                let mut replies = serve(message);
+                if message.id == 6 {
+                    replies.insert(0, Reply::Delay(Duration::from_millis(150)));
+                }
+                replies
+            },
+        )
+        .await;
+        let late = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(6)],
+            seeder(data.clone(), 32),
+        )
+        .await;
+        let (first, later) = ([slow.addr()], [slow.addr(), late.addr()]);
+        let announces = std::sync::atomic::AtomicUsize::new(0);
+        let tracker = MockHttp::start(move |_| {
+            let peers: &[SocketAddr] = match announces.fetch_add(1, Ordering::SeqCst) {
+                0 => &first,
+                _ => &later,
+            };
+            (200, announce_response_every(0, peers))
+        })
+        .await;
+        let torrent_file = torrent_bytes(tracker.url("/announce").as_str(), 32, &data);
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(&torrent_file, ParseConfig::default()).unwrap(),
+        )
+        .config(DownloadConfig {
+            min_announce_interval: Duration::from_millis(200),
+            pieces_in_flight: 1,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+        let dir = TempDir::new("reannounce");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        let requests = tracker.requests();
+        assert!(requests.len() >= 3, "{requests:?}");
+        assert!(
+            requests[1..requests.len() - 1]
+                .iter()
+                .all(|f| !f.contains("event=")),
+            "{requests:?}"
+        );
+        assert!(
+            late.received_ids().contains(&6),
+            "{:?}",
+            late.received_ids()
+        );
+    }
+
+    #[tokio::test]
+    async fn pieces_first_peers_lack_come_from_peers_tracker_lists_later() {
+        let data: Vec<u8> = (0..192).collect();
+        let metadata = metadata("http://127.0.0.1:1/announce", 32, &data);
+        // NOTE: pieces 0 to 2 on the first peer, 3 to 5 only on the later one
+        let mut partial = Vec::new();
+        for bitfield in [0b1110_0000, 0b0001_1100] {
+            partial.push(
+                MockPeer::start(
+                    metadata.info_hash,
+                    vec![Reply::Send(5, vec![bitfield])],
+                    seeder(data.clone(), 32),
+                )
+                .await,
+            );
+        }
+        let (first, later) = ([partial[0].addr()], [partial[0].addr(), partial[1].addr()]);
+        let announces = std::sync::atomic::AtomicUsize::new(0);
+        let tracker = MockHttp::start(move |_| {
+            let peers: &[SocketAddr] = match announces.fetch_add(1, Ordering::SeqCst) {
+                0 => &first,
+                _ => &later,
+            };
+            (200, announce_response(peers))
+        })
+        .await;
+        let torrent_file = torrent_bytes(tracker.url("/announce").as_str(), 32, &data);
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(&torrent_file, ParseConfig::default()).unwrap(),
+        )
+        .build()
+        .unwrap();
+        let dir = TempDir::new("partial-first-peers");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        let requested = |peer: &MockPeer| -> BTreeSet<u32> {
+            peer.received()
+                .iter()
+                .filter(|f| f.id == 6)
+                .map(|f| f.block().0)
+                .collect()
+        };
+        assert_eq!(requested(&partial[0]), BTreeSet::from([0, 1, 2]));
+        assert_eq!(requested(&partial[1]), BTreeSet::from([3, 4, 5]));
+        // NOTE: interval is half an hour, tracker is asked again once pieces run out
+        let requests = tracker.requests();
+        assert_eq!(requests.len(), 3, "{requests:?}");
+        assert!(!requests[1].contains("event="), "{requests:?}");
+    }
+
+    #[tokio::test]
+    async fn peer_announcing_pieces_only_after_bitfield_wait_is_used() {
+        let data: Vec<u8> = (0..64).collect();
+        let metadata = metadata("http://127.0.0.1:1/announce", 32, &data);
+        let late = MockPeer::start(
+            metadata.info_hash,
+            vec![
+                Reply::Delay(Duration::from_millis(2500)),
+                Reply::have(0),
+                Reply::have(1),
+            ],
+            seeder(data.clone(), 32),
+        )
+        .await;
+        let response = announce_response(&[late.addr()]);
+        let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+        let torrent_file = torrent_bytes(tracker.url("/announce").as_str(), 32, &data);
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(&torrent_file, ParseConfig::default()).unwrap(),
+        )
+        .build()
+        .unwrap();
+        let dir = TempDir::new("late-haves");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn metadata_fetch_ignores_size_liar_and_spreads_pieces() {
+        // ~1700 piece hashes make info span three 16 KiB metadata pieces
+        let info = info_of_pieces(1700);
+        assert_eq!(info.len().div_ceil(METADATA_PIECE_SIZE), 3);
+        let info_hash = sha1_hash(&info);
+
+        let liar = MockPeer::start_with_reserved(
+            info_hash,
+            LTEP_RESERVED,
+            vec![
+                Reply::extended_handshake(info.len() + METADATA_PIECE_SIZE),
+                Reply::bitfield(1700),
+            ],
+            metadata_seeder(info.clone()),
+        )
+        .await;
+        let mut serve = metadata_seeder(info.clone());
+        let slow = MockPeer::start_with_reserved(
+            info_hash,
+            LTEP_RESERVED,
+            vec![Reply::extended_handshake(info.len()), Reply::bitfield(1700)],
+            move |message| {
+                let mut replies = serve(message);
+                if !replies.is_empty() {
+                    replies.insert(0, Reply::Delay(Duration::from_millis(200)));
+                }
+                replies
+            },
+        )
+        .await;
+        let correct = MockPeer::start_with_reserved(
+            info_hash,
+            LTEP_RESERVED,
+            vec![Reply::extended_handshake(info.len()), Reply::bitfield(1700)],
+            metadata_seeder(info.clone()),
+        )
+        .await;
+        let (_tracker, torrent) = magnet_torrent(
+            info_hash,
+            vec![liar.addr(), slow.addr(), correct.addr()],
+            DownloadConfig {
+                // NOTE: every mock peer is on loopback
+                max_connections_per_peer_ip: 3,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let report = torrent.fetch_metadata_report(Duration::from_secs(10)).await;
+
+        assert_eq!(report.info.unwrap().pieces.len(), 1700);
+        assert!(
+            !report.suppliers.contains(&liar.addr()),
+            "{:?}",
+            report.suppliers
+        );
+        let requests = |peer: &MockPeer| -> Vec<usize> {
+            peer.received()
+                .iter()
+                .filter_map(WireMessage::metadata_request)
+                .collect()
+        };
+        assert!(requests(&liar).is_empty(), "{:?}", requests(&liar));
+        let (mut slow, mut correct) = (requests(&slow), requests(&correct));
+        assert!(
+            !slow.is_empty() && !correct.is_empty(),
+            "{slow:?} {correct:?}"
+        );
+        slow.append(&mut correct);
+        slow.sort();
+        assert_eq!(slow, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn deadline_pieces_go_first_and_overdue_ones_are_reported() {
+        let data: Vec<u8> = (0..192).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let stalled_on_piece_4: Behaviour = Box::new(move |message: &WireMessage| {
+            let mut replies = serve(message);
+            if message.id == 6 && message.block().0 == 4 {
+                replies.insert(0, Reply::Delay(Duration::from_millis(400)));
+            }
+            replies
+        });
+        let swarm = Swarm::start(&data, 32, vec![stalled_on_piece_4]).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            pieces_in_flight: 1,
+            ..Default::default()
+        });
+        torrent.set_piece_deadline(1, Duration::from_millis(250));
+        torrent.set_piece_deadline(4, Duration::from_millis(200));
+        torrent.set_piece_deadline(5, Duration::from_secs(60));
+        torrent.reset_piece_deadline(5);
+        let dir = TempDir::new("deadlines");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        let order: Vec<_> = swarm.peers[0]
+            .received()
+            .iter()
+            .filter(|f| f.id == 6)
+            .map(|f| f.block().0)
+            .collect();
+        assert_eq!(order[..2], [4, 1], "{order:?}");
+        assert_eq!(report.missed_deadlines, [1]);
+    }
+
+    #[tokio::test]
+    async fn wrong_block_never_lands_in_file() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut corrupt = data.clone();
+        corrupt[32..48].fill(0xff);
+        let swarm = Swarm::start(&data, 32, vec![Box::new(seeder(corrupt, 32))]).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            block_size: 16,
+            max_piece_retries: 1,
+            ..Default::default()
+        });
+        let dir = TempDir::new("wrong-block");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(!report.is_completed(), "{:?}", report.outcome);
+        let written = std::fs::read(dir.join("test.bin")).unwrap();
+        assert_eq!(written[..32], data[..32]);
+        assert!(!written.contains(&0xff), "{written:?}");
+        let attempts = swarm.peers[0]
+            .received()
+            .iter()
+            .filter(|f| f.id == 6 && f.block() == (1, 0, 16))
+            .count();
+        assert_eq!(attempts, 2);
+    }
+
+    fn blocklist() -> PeerFilter {
+        Blocklist::load(&fixture("blocklist.p2p")).unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn http_server_listed_as_peer_is_dialed_once() {
+        let data: Vec<u8> = (0..64).collect();
+        let (http, accepted) = foreign_listener(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
+        let mut swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let peers = [http, swarm.peers[0].addr()];
+        swarm.tracker = MockHttp::start(move |_| (200, announce_response(&peers))).await;
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(
+                &torrent_bytes(swarm.tracker.url("/announce").as_str(), 32, &data),
+                ParseConfig::default(),
+            )
+            .unwrap(),
+        )
+        .build()
+        .unwrap();
+        let dir = TempDir::new("wrong-protocol");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(report.wrong_protocol_peers, 1);
+        // NOTE: completed announce lists it again, it's denied instead of dialed
+        assert_eq!(report.denied_peers.get(NOT_BITTORRENT_PEER), Some(&1));
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn blocklisted_tracker_peer_is_never_dialed() {
+        let data: Vec<u8> = (0..64).collect();
+        let jailed = tokio::net::TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let mut swarm = Swarm::start(&data, 32, Swarm::seeders(&data, 32, 1)).await;
+        let peers = [swarm.peers[0].addr(), jailed.local_addr().unwrap()];
+        swarm.tracker = MockHttp::start(move |_| (200, announce_response(&peers))).await;
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(
+                &torrent_bytes(swarm.tracker.url("/announce").as_str(), 32, &data),
+                ParseConfig::default(),
+            )
+            .unwrap(),
+        )
+        .peer_filter(blocklist())
+        .build()
+        .unwrap();
+        let dir = TempDir::new("blocklist-dial");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        // NOTE: listed by both started and completed announce
+        assert_eq!(report.denied_peers.get("blocklist"), Some(&2));
+        let dialed = tokio::time::timeout(Duration::from_millis(50), jailed.accept()).await;
+        assert!(dialed.is_err(), "blocklisted peer was dialed");
+    }
+
+    #[tokio::test]
+    async fn blocklisted_incoming_peer_is_dropped_before_handshake() {
+        let data: Vec<u8> = (0..64).collect();
+        let dir = TempDir::new("blocklist-accept");
+        std::fs::write(dir.join("test.bin"), &data).unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let metadata = metadata("http://127.0.0.1:1/announce", 32, &data);
+        let info_hash = metadata.info_hash;
+        let torrent = Torrent::builder(metadata)
+            .port(port)
+            .peer_filter(blocklist())
+            .build()
+            .unwrap();
+
+        let handshake_from = |source: &'static str| async move {
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind(format!("{source}:0").parse().unwrap()).unwrap();
+            let mut stream = socket.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+            let mut handshake = vec![19];
+            handshake.extend_from_slice(b"BitTorrent protocol");
+            handshake.extend_from_slice(&[0; 8]);
+            handshake.extend_from_slice(&info_hash);
+            handshake.extend_from_slice(&[3; 20]);
+            let _ = stream.write_all(&handshake).await;
+            let mut reply = [0; 68];
+            stream.read_exact(&mut reply).await.map(|_| reply)
+        };
+        let clients = async {
+            // NOTE: seeding listener needs a moment to bind
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            (
+                handshake_from("127.0.0.2").await,
+                handshake_from("127.0.0.1").await,
+            )
+        };
+
+        let (jailed, allowed) = tokio::select! {
+            result = torrent.seed(dir.join("test.bin")) => panic!("seeding stopped: {result:?}"),
+            replies = clients => replies,
+        };
+
+        assert!(jailed.is_err(), "blocklisted peer got {jailed:?}");
+        assert_eq!(allowed.unwrap()[28..48], info_hash);
+        assert_eq!(
+            torrent.denied_peers.lock().unwrap().get("blocklist"),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_alives_between_pieces_are_skipped() {
+        let data: Vec<u8> = (0..128).collect();
+        let mut serve = seeder(data.clone(), 32);
+        let chatty: Behaviour = Box::new(move |message: &WireMessage| {
+            serve(message)
+                .into_iter()
+                .flat_map(|reply| [Reply::KeepAlive, reply, Reply::KeepAlive])
+                .collect()
+        });
+        let swarm = Swarm::start(&data, 32, vec![chatty]).await;
+        let torrent = swarm.torrent(small_blocks());
+        let dir = TempDir::new("keep-alives");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        assert!(report.disconnect_reasons.is_empty(), "{report:?}");
+    }
+
+    #[tokio::test]
+    async fn endgame_cancels_block_held_by_slow_peer() {
+        let data: Vec<u8> = (0..64).collect();
+        // NOTE: whoever gets last block first, endgame sends it to the other
+        // one too and faster peer's answer cancels the slow one
+        let behaviours: Vec<Behaviour> = [Duration::from_secs(1), Duration::from_millis(200)]
+            .into_iter()
+            .map(|delay| {
+                let mut serve = seeder(data.clone(), 32);
+                Box::new(move |message: &WireMessage| {
+                    let mut replies = serve(message);
+                    if message.id == 6 && message.block() == (1, 16, 16) {
+                        replies.insert(0, Reply::Delay(delay));
+                    }
+                    replies
+                }) as Behaviour
+            })
+            .collect();
+        let swarm = Swarm::start(&data, 32, behaviours).await;
+        let torrent = swarm.torrent(DownloadConfig {
+            pieces_in_flight: 1,
+            ..small_blocks()
+        });
+        let dir = TempDir::new("endgame-cancel");
+
+        let report = torrent.download(dir.join("test.bin")).await.unwrap();
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert_eq!(std::fs::read(dir.join("test.bin")).unwrap(), data);
+        // NOTE: download as a whole still lingers on slow peer at shutdown
+        assert!(
+            report.piece_latency.max_ms < 1000,
+            "{:?}",
+            report.piece_latency
+        );
+        // NOTE: slow peer reads cancel only once its delay is over
+        let cancelled = || {
+            swarm.peers[0]
+                .received()
+                .iter()
+                .any(|f| f.id == 8 && f.block() == (1, 16, 16))
+        };
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while !cancelled() && std::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(cancelled(), "{:?}", swarm.peers[0].received_ids());
+    }
 }
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+use crate::prelude::*;
+
+/// Limits memory held by in-flight piece buffers,
+/// new piece waits until enough bytes are released
+#[derive(Debug, Clone)]
+pub struct BufferBudget {
+    semaphore: Arc<Semaphore>,
+    total: usize,
+}
+
+#[derive(Debug)]
+pub struct BufferReservation {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BufferBudget {
+    pub fn new(total: usize) -> Self {
+        let total = total.clamp(1, Semaphore::MAX_PERMITS);
+        Self {
+            semaphore: Arc::new(Semaphore::new(total)),
+            total,
+        }
+    }
+
+    pub async fn reserve(&self, bytes: usize) -> Result<BufferReservation> {
+        // NOTE: piece bigger than whole budget would never fit, take all of it instead
+        let bytes = bytes.min(self.total);
+        let permits = u32::try_from(bytes).context("piece buffer size")?;
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_many_owned(permits)
+            .await
+            .context("buffer budget closed")?;
+
+        Ok(BufferReservation { _permit: permit })
+    }
+
+    /// Reservation if enough bytes are free right now
+    pub fn try_reserve(&self, bytes: usize) -> Result<Option<BufferReservation>> {
+        let bytes = bytes.min(self.total);
+        let permits = u32::try_from(bytes).context("piece buffer size")?;
+        match self.semaphore.clone().try_acquire_many_owned(permits) {
+            Ok(permit) => Ok(Some(BufferReservation { _permit: permit })),
+            Err(TryAcquireError::NoPermits) => Ok(None),
+            Err(TryAcquireError::Closed) => bail!("buffer budget closed"),
+        }
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.total - self.semaphore.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn caps_concurrent_buffers() {
+        let budget = BufferBudget::new(100);
+        let first = budget.reserve(60).await.unwrap();
+
+        assert!(budget.try_reserve(60).unwrap().is_none());
+        let waiting = tokio::time::timeout(Duration::from_millis(50), budget.reserve(60)).await;
+        assert!(
+            waiting.is_err(),
+            "second buffer fits only once first is released"
+        );
+        assert_eq!(budget.in_use(), 60);
+
+        drop(first);
+        let second = budget.reserve(60).await.unwrap();
+        assert_eq!(budget.in_use(), 60);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn piece_bigger_than_budget_takes_all_of_it() {
+        let budget = BufferBudget::new(100);
+
+        let whole = budget.reserve(1000).await.unwrap();
+
+        assert_eq!(budget.in_use(), 100);
+        assert!(budget.try_reserve(1).unwrap().is_none());
+        drop(whole);
+        assert!(budget.try_reserve(1000).unwrap().is_some());
+    }
+}
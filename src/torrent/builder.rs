@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::Url;
+use tokio::sync::RwLock;
+
+use crate::{bencode::ParseConfig, prelude::*};
+
+use super::{
+    generate_peer_id, DownloadConfig, MagnetLink, PeerFilter, RateLimiter, SessionLog, Torrent,
+    TorrentMetadataInfo, Tracker,
+};
+
+pub const DEFAULT_TORRENT_PORT: u16 = 6881;
+pub const DEFAULT_TORRENT_MAX_PEERS: u8 = 10;
+
+/// Single configuration path of [`Torrent`], options are checked in [`TorrentBuilder::build`]
+///
+/// ```ignore
+/// let torrent = Torrent::builder(metadata)
+///     .port(6881)
+///     .max_peers(20)
+///     .config(DownloadConfig::default())
+///     .add_peer("127.0.0.1:51413".parse()?)
+///     .build()?;
+/// ```
+#[derive(Debug)]
+pub struct TorrentBuilder {
+    metadata: TorrentMetadataInfo,
+    port: u16,
+    max_peers: u8,
+    config: DownloadConfig,
+    trackers: Vec<Url>,
+    peers: Vec<SocketAddr>,
+    piece_deadlines: Vec<(usize, Duration)>,
+    peer_filter: Option<PeerFilter>,
+    session_log: Option<Arc<SessionLog>>,
+}
+
+impl TorrentBuilder {
+    pub fn new(metadata: TorrentMetadataInfo) -> Self {
+        Self {
+            metadata,
+            port: DEFAULT_TORRENT_PORT,
+            max_peers: DEFAULT_TORRENT_MAX_PEERS,
+            config: DownloadConfig::default(),
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            piece_deadlines: Vec::new(),
+            peer_filter: None,
+            session_log: None,
+        }
+    }
+
+    /// Loads torrent file, url or magnet uri, peers of magnet (x.pe) are added too
+    pub async fn from_file(file_path: PathBuf, parse_config: ParseConfig) -> Result<Self> {
+        if let Some(uri) = file_path.to_str().filter(|f| f.starts_with("magnet:")) {
+            return Self::from_magnet(uri);
+        }
+        let metadata = TorrentMetadataInfo::load(file_path, parse_config).await?;
+        trace!("File {:?}", metadata.info);
+        Ok(Self::new(metadata))
+    }
+
+    /// Info is unresolved, only tracker announces work until metadata is fetched
+    pub fn from_magnet(uri: &str) -> Result<Self> {
+        let magnet = MagnetLink::parse(uri)?;
+        let peers = magnet.peers.clone();
+        let builder = Self::new(magnet.into_metadata()?);
+        Ok(peers.into_iter().fold(builder, Self::add_peer))
+    }
+
+    /// Port announced to trackers
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn max_peers(mut self, max_peers: u8) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
+
+    pub fn config(mut self, config: DownloadConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Tracker gets its own tier after the ones from torrent
+    pub fn add_tracker(mut self, url: Url) -> Self {
+        self.trackers.push(url);
+        self
+    }
+
+    /// Peer is connected alongside the ones trackers return
+    pub fn add_peer(mut self, addr: SocketAddr) -> Self {
+        self.peers.push(addr);
+        self
+    }
+
+    /// See [`Torrent::set_piece_deadline`], deadline counts from build
+    pub fn piece_deadline(mut self, index: usize, deadline: Duration) -> Self {
+        self.piece_deadlines.push((index, deadline));
+        self
+    }
+
+    /// Decides on every peer address before it's dialed
+    pub fn peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.peer_filter = Some(filter);
+        self
+    }
+
+    /// Tracker, peer, scheduler and disk activity is recorded to `log`
+    pub fn session_log(mut self, log: Arc<SessionLog>) -> Self {
+        self.session_log = Some(log);
+        self
+    }
+
+    pub fn build(self) -> Result<Torrent> {
+        anyhow::ensure!(self.port != 0, "port must be in 1..=65535");
+        anyhow::ensure!(self.max_peers > 0, "max peers must be above zero");
+        anyhow::ensure!(self.config.block_size > 0, "block size is zero");
+        anyhow::ensure!(
+            !self.config.stall_timeout.is_zero(),
+            "stall timeout is zero"
+        );
+        anyhow::ensure!(
+            !self.config.block_request_timeout.is_zero(),
+            "block request timeout is zero"
+        );
+        anyhow::ensure!(self.config.upload_slots > 0, "upload slots is zero");
+        anyhow::ensure!(self.config.max_open_files > 0, "max open files is zero");
+        anyhow::ensure!(self.config.pieces_in_flight > 0, "pieces in flight is zero");
+        anyhow::ensure!(
+            self.config.max_download_bytes_per_sec != Some(0),
+            "download rate limit is zero"
+        );
+        anyhow::ensure!(
+            self.config.max_peer_hash_failures > 0,
+            "max peer hash failures is zero"
+        );
+        let tracker_config = self.config.tracker;
+        anyhow::ensure!(
+            !tracker_config.connect_timeout.is_zero() && !tracker_config.request_timeout.is_zero(),
+            "tracker timeout is zero"
+        );
+        anyhow::ensure!(
+            tracker_config.max_response_size > 0,
+            "tracker response size is zero"
+        );
+        for tracker in &self.trackers {
+            anyhow::ensure!(
+                matches!(tracker.scheme(), "http" | "https" | "udp"),
+                "unsupported tracker {tracker}"
+            );
+        }
+        if self.metadata.info.is_resolved() {
+            let pieces = self.metadata.info.pieces.len();
+            for (index, _) in &self.piece_deadlines {
+                anyhow::ensure!(
+                    *index < pieces,
+                    "deadline for piece {index}, torrent has {pieces} pieces"
+                );
+            }
+        }
+
+        let mut metadata = self.metadata;
+        if !self.trackers.is_empty() && metadata.announce_list.is_empty() {
+            // NOTE: tracker uses announce only when there's no list, keep it as first tier
+            metadata.announce_list.push(vec![metadata.announce.clone()]);
+        }
+        metadata
+            .announce_list
+            .extend(self.trackers.into_iter().map(|url| vec![url]));
+
+        let peer_id = generate_peer_id();
+        let mut tracker = Tracker::new(&metadata, self.port, peer_id, tracker_config)?;
+        tracker.set_session_log(self.session_log.clone());
+        let torrent = Torrent {
+            max_peers: self.max_peers,
+            peer_id,
+            tracker,
+            metadata,
+            port: self.port,
+            known_peers: self.peers,
+            peer_filter: self.peer_filter,
+            denied_peers: std::sync::Mutex::new(BTreeMap::new()),
+            wrong_protocol_peers: std::sync::Mutex::new(HashSet::new()),
+            dial_failures: std::sync::Mutex::new(BTreeMap::new()),
+            corrupt_peers: std::sync::Mutex::new(HashSet::new()),
+            download_queue: RwLock::new(Default::default()),
+            download_limiter: self.config.max_download_bytes_per_sec.map(RateLimiter::new),
+            config: self.config,
+            piece_deadlines: std::sync::Mutex::new(BTreeMap::new()),
+            stats: Default::default(),
+            status_board: Default::default(),
+            disk_queue: Default::default(),
+            open_files: Default::default(),
+            session_log: self.session_log,
+        };
+        for (index, deadline) in self.piece_deadlines {
+            torrent.set_piece_deadline(index, deadline);
+        }
+        Ok(torrent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::testing::metadata;
+
+    fn builder() -> TorrentBuilder {
+        Torrent::builder(metadata("http://127.0.0.1:1/announce", 16, &[1; 64]))
+    }
+
+    #[test]
+    fn options_end_up_in_torrent() {
+        let peer: SocketAddr = "127.0.0.1:51413".parse().unwrap();
+        let extra = Url::parse("udp://tracker.example.org:6969").unwrap();
+
+        let torrent = builder()
+            .port(51000)
+            .max_peers(3)
+            .config(DownloadConfig {
+                block_size: 1024,
+                ..Default::default()
+            })
+            .add_tracker(extra.clone())
+            .add_peer(peer)
+            .piece_deadline(2, Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        assert_eq!(torrent.port, 51000);
+        assert_eq!(torrent.max_peers, 3);
+        assert_eq!(torrent.config.block_size, 1024);
+        assert_eq!(torrent.known_peers, [peer]);
+        assert_eq!(
+            torrent.metadata.announce_list,
+            [
+                vec![Url::parse("http://127.0.0.1:1/announce").unwrap()],
+                vec![extra]
+            ]
+        );
+        let deadlines = torrent.piece_deadlines.lock().unwrap();
+        assert_eq!(deadlines.keys().collect::<Vec<_>>(), [&2]);
+    }
+
+    #[test]
+    fn invalid_options_fail_build() {
+        let cases: Vec<(TorrentBuilder, &str)> = vec![
+            (builder().port(0), "port"),
+            (builder().max_peers(0), "max peers"),
+            (
+                builder().config(DownloadConfig {
+                    block_size: 0,
+                    ..Default::default()
+                }),
+                "block size",
+            ),
+            (
+                builder().config(DownloadConfig {
+                    pieces_in_flight: 0,
+                    ..Default::default()
+                }),
+                "pieces in flight",
+            ),
+            (
+                builder().config(DownloadConfig {
+                    max_download_bytes_per_sec: Some(0),
+                    ..Default::default()
+                }),
+                "download rate limit",
+            ),
+            (
+                builder().add_tracker(Url::parse("ftp://tracker.example.org").unwrap()),
+                "unsupported tracker",
+            ),
+            (
+                builder().piece_deadline(4, Duration::from_secs(1)),
+                "deadline for piece 4",
+            ),
+        ];
+
+        for (builder, expected) in cases {
+            let Err(e) = builder.build() else {
+                panic!("build should fail with {expected}");
+            };
+            assert!(e.to_string().contains(expected), "{e}");
+        }
+    }
+}
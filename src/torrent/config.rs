@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use crate::prelude::*;
+
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER_IP: u8 = 2;
+pub const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 8;
+pub const DEFAULT_MAX_BUFFER_MEMORY: usize = 64 * 1024 * 1024;
+pub const DEFAULT_STALL_TIMEOUT_SECONDS: u64 = 120;
+pub const DEFAULT_MAX_PIECE_RETRIES: u32 = 5;
+pub const DEFAULT_MAX_PEER_HASH_FAILURES: u32 = 2;
+pub const DEFAULT_MIN_ANNOUNCE_INTERVAL_SECONDS: u64 = 30;
+pub const DEFAULT_ENDGAME_BLOCKS: usize = 16;
+pub const DEFAULT_BLOCK_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+pub const DEFAULT_PIECES_IN_FLIGHT: usize = 4;
+pub const DEFAULT_TRACKER_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+pub const DEFAULT_TRACKER_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+pub const DEFAULT_TRACKER_MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_TRACKER_RETRIES: u32 = 2;
+/// Torrent file is untrusted, its strings are mostly piece hashes
+pub const DEFAULT_MAX_TORRENT_STRING_BYTES: u32 = 64 * 1024 * 1024;
+/// Enough for hundreds of thousands of files
+pub const DEFAULT_MAX_TORRENT_ELEMENTS: usize = 2_000_000;
+/// Largest request most clients serve, bigger ones are commonly dropped
+pub const MAX_STANDARD_BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Caps connections to a single host, so tracker returning
+    /// one ip with many ports can't take all of our slots
+    pub max_connections_per_peer_ip: u8,
+    /// Timeout of establishing tcp connection, separate from protocol timeouts
+    pub connect_timeout: Duration,
+    /// Bytes allowed to be held by in-flight piece buffers
+    pub max_buffer_memory: usize,
+    /// Download is aborted if no piece completes within this window
+    pub stall_timeout: Duration,
+    /// Size of requested blocks, peers falling back to standard size on reject
+    pub block_size: u32,
+    /// Piece is abandoned after failing this many times
+    pub max_piece_retries: u32,
+    /// Peer is banned once this many pieces it sent failed their hash
+    /// and then verified from others
+    pub max_peer_hash_failures: u32,
+    /// Floor of re-announce interval, tracker asking for less is ignored
+    pub min_announce_interval: Duration,
+    /// Endgame starts once this few blocks are left, each of them is then requested from every peer
+    pub endgame_blocks: usize,
+    /// Block not delivered in time goes to other peers, slow peer stays connected
+    pub block_request_timeout: Duration,
+    /// Peers unchoked at once while seeding, others wait for a free slot
+    pub upload_slots: usize,
+    /// Handles kept open by storage, lowered to fit below open files rlimit
+    pub max_open_files: usize,
+    /// Pieces downloaded at once, peers are shared out between them
+    pub pieces_in_flight: usize,
+    /// Pieces already at output path matching their hash aren't downloaded again
+    pub resume: bool,
+    /// Aggregate download rate across all peers, unlimited if none
+    pub max_download_bytes_per_sec: Option<u32>,
+    pub tracker: TrackerConfig,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_peer_ip: DEFAULT_MAX_CONNECTIONS_PER_PEER_IP,
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            max_buffer_memory: DEFAULT_MAX_BUFFER_MEMORY,
+            stall_timeout: Duration::from_secs(DEFAULT_STALL_TIMEOUT_SECONDS),
+            block_size: BLOCK_SIZE,
+            max_piece_retries: DEFAULT_MAX_PIECE_RETRIES,
+            max_peer_hash_failures: DEFAULT_MAX_PEER_HASH_FAILURES,
+            min_announce_interval: Duration::from_secs(DEFAULT_MIN_ANNOUNCE_INTERVAL_SECONDS),
+            endgame_blocks: DEFAULT_ENDGAME_BLOCKS,
+            block_request_timeout: Duration::from_secs(DEFAULT_BLOCK_REQUEST_TIMEOUT_SECONDS),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            pieces_in_flight: DEFAULT_PIECES_IN_FLIGHT,
+            resume: true,
+            max_download_bytes_per_sec: None,
+            tracker: TrackerConfig::default(),
+        }
+    }
+}
+
+/// Limits of a single tracker request, instead of http client defaults
+#[derive(Debug, Clone, Copy)]
+pub struct TrackerConfig {
+    /// Http connect timeout, for udp the wait for the first response, doubled on retransmit
+    pub connect_timeout: Duration,
+    /// Deadline of one request, body included. Http retries get their own,
+    /// udp retransmits share it
+    pub request_timeout: Duration,
+    /// Bigger response is cut off with [`TrackerResponseTooLarge`]
+    pub max_response_size: usize,
+    /// Extra attempts of announce failing to connect or timing out,
+    /// tracker answering with error is never retried
+    pub retries: u32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(DEFAULT_TRACKER_CONNECT_TIMEOUT_SECONDS),
+            request_timeout: Duration::from_secs(DEFAULT_TRACKER_REQUEST_TIMEOUT_SECONDS),
+            max_response_size: DEFAULT_TRACKER_MAX_RESPONSE_SIZE,
+            retries: DEFAULT_TRACKER_RETRIES,
+        }
+    }
+}
+
+/// Guards against our own misconfiguration, blocks above 16 KiB
+/// are dropped or answered with disconnect by many clients
+pub fn checked_block_size(block_size: u32, i_know_what_im_doing: bool) -> Result<u32> {
+    anyhow::ensure!(block_size > 0, "block size is zero");
+    if block_size > MAX_STANDARD_BLOCK_SIZE {
+        anyhow::ensure!(
+            i_know_what_im_doing,
+            "block size {block_size} is above {MAX_STANDARD_BLOCK_SIZE} bytes, \
+             many peers drop such requests or disconnect, \
+             pass --i-know-what-im-doing to use it anyway"
+        );
+        warn!("oversized block size {block_size}, expect stalls and disconnects");
+        eprintln!(
+            "WARNING: block size {block_size} is above {MAX_STANDARD_BLOCK_SIZE} bytes, \
+             peers may silently drop requests or disconnect"
+        );
+    }
+    Ok(block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_block_needs_explicit_consent() {
+        let oversized = MAX_STANDARD_BLOCK_SIZE + 1;
+
+        let err = checked_block_size(oversized, false).unwrap_err();
+
+        assert!(err.to_string().contains("--i-know-what-im-doing"), "{err}");
+        assert_eq!(checked_block_size(oversized, true).unwrap(), oversized);
+        assert_eq!(
+            checked_block_size(MAX_STANDARD_BLOCK_SIZE, false).unwrap(),
+            MAX_STANDARD_BLOCK_SIZE
+        );
+        assert!(checked_block_size(0, true).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{ErrorKind, Read},
+    path::Path,
+};
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{bencode::to_bytes, prelude::*};
+
+use super::{TorrentInfo, TorrentMetadataInfo};
+
+/// Shape of .torrent file on disk, keys are sorted by encoder
+#[derive(Serialize)]
+struct TorrentFileEncoding<'a> {
+    announce: &'a str,
+    info: &'a TorrentInfo,
+}
+
+impl TorrentMetadataInfo {
+    /// Single file torrent of `input`, hashed `piece_length` bytes at a time
+    pub fn create(input: &Path, announce: Url, piece_length: usize) -> Result<Self> {
+        anyhow::ensure!(piece_length > 0, "piece length is zero");
+        let name = input
+            .file_name()
+            .context("input has no file name")?
+            .to_str()
+            .context("input file name is not utf8")?
+            .to_string();
+        let mut file = File::open(input).with_context(|| format!("opening {}", input.display()))?;
+
+        let mut pieces = Vec::new();
+        let mut length = 0;
+        let mut piece = vec![0; piece_length];
+        loop {
+            let read = read_piece(&mut file, &mut piece)
+                .with_context(|| format!("reading {}", input.display()))?;
+            if read == 0 {
+                break;
+            }
+            pieces.push(sha1_hash(&piece[..read]));
+            length += read;
+            if read < piece_length {
+                break;
+            }
+        }
+        anyhow::ensure!(length > 0, "{} is empty", input.display());
+
+        let info = TorrentInfo {
+            length: Some(length),
+            files: None,
+            name,
+            piece_length,
+            pieces: pieces.into(),
+            info_extra: BTreeMap::new(),
+        };
+        info.validate()?;
+        let info_hash = sha1_hash(&to_bytes(&info).context("encoding info")?);
+        Ok(Self {
+            announce,
+            announce_list: Vec::new(),
+            info,
+            info_hash,
+        })
+    }
+
+    /// Bencoded .torrent, info is encoded the same way its hash was taken
+    pub fn to_torrent_bytes(&self) -> Result<Vec<u8>> {
+        to_bytes(TorrentFileEncoding {
+            announce: self.announce.as_str(),
+            info: &self.info,
+        })
+        .context("encoding torrent")
+    }
+}
+
+/// Fills `piece` unless file ends first, returns bytes read
+fn read_piece(file: &mut File, piece: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < piece.len() {
+        match file.read(&mut piece[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bencode::ParseConfig,
+        torrent::testing::{metadata, TempDir},
+    };
+
+    const ANNOUNCE: &str = "http://127.0.0.1:1/announce";
+
+    fn create_and_load(name: &str, data: &[u8], piece_length: usize) -> TorrentMetadataInfo {
+        let dir = TempDir::new(name);
+        std::fs::write(dir.join("test.bin"), data).unwrap();
+        let created = TorrentMetadataInfo::create(
+            &dir.join("test.bin"),
+            Url::parse(ANNOUNCE).unwrap(),
+            piece_length,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("test.torrent"),
+            created.to_torrent_bytes().unwrap(),
+        )
+        .unwrap();
+
+        let loaded =
+            TorrentMetadataInfo::from_file(dir.join("test.torrent"), ParseConfig::default())
+                .unwrap();
+
+        assert_eq!(loaded.info_hash, created.info_hash);
+        assert_eq!(loaded.info.pieces.len(), created.info.pieces.len());
+        assert_eq!(loaded.announce, created.announce);
+        loaded
+    }
+
+    #[test]
+    fn created_torrent_round_trips_with_short_last_piece() {
+        let data: Vec<u8> = (0..100).collect();
+
+        let loaded = create_and_load("create-short-last", &data, 32);
+
+        assert_eq!(loaded.info.pieces.len(), 4);
+        assert_eq!(loaded.info.total_length(), 100);
+        assert_eq!(loaded.info.pieces[3], sha1_hash(&data[96..]));
+        assert_eq!(loaded.info.name, "test.bin");
+    }
+
+    #[test]
+    fn created_torrent_of_whole_pieces_matches_independent_encoding() {
+        let data: Vec<u8> = (0..128).collect();
+
+        let loaded = create_and_load("create-whole", &data, 32);
+
+        assert_eq!(loaded.info.pieces.len(), 4);
+        assert_eq!(loaded.info_hash, metadata(ANNOUNCE, 32, &data).info_hash);
+    }
+
+    #[test]
+    fn empty_file_and_zero_piece_length_are_rejected() {
+        let dir = TempDir::new("create-invalid");
+        std::fs::write(dir.join("empty.bin"), []).unwrap();
+        std::fs::write(dir.join("test.bin"), [1; 8]).unwrap();
+        let announce = Url::parse(ANNOUNCE).unwrap();
+
+        assert!(TorrentMetadataInfo::create(&dir.join("empty.bin"), announce.clone(), 32).is_err());
+        assert!(TorrentMetadataInfo::create(&dir.join("test.bin"), announce, 0).is_err());
+    }
+}
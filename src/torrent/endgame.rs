@@ -0,0 +1,171 @@
+use std::collections::BTreeSet;
+
+use async_channel::Sender;
+use tokio::sync::watch;
+
+use crate::prelude::*;
+
+use super::{PieceBlock, PieceBuffer};
+
+/// Tail of a download, outstanding blocks go to every peer at once
+/// so one slow peer holding the last block doesn't hold up the finish.
+/// Peers still waiting for a block someone else delivered cancel it
+#[derive(Debug)]
+pub struct Endgame {
+    threshold: usize,
+    /// Blocks of pieces queued after the current one
+    queued_blocks: usize,
+    peers: usize,
+    request_block: Sender<PieceBlock>,
+    saved_blocks: watch::Sender<BTreeSet<u32>>,
+    active: bool,
+}
+
+impl Endgame {
+    pub fn new(
+        threshold: usize,
+        queued_blocks: usize,
+        request_block: Sender<PieceBlock>,
+    ) -> (Self, watch::Receiver<BTreeSet<u32>>) {
+        let (saved_blocks, saved_blocks_receiver) = watch::channel(BTreeSet::new());
+        let endgame = Self {
+            threshold,
+            queued_blocks,
+            peers: 0,
+            request_block,
+            saved_blocks,
+            active: false,
+        };
+        (endgame, saved_blocks_receiver)
+    }
+
+    pub fn set_peers(&mut self, peers: usize) {
+        self.peers = peers;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Wakes peers waiting on the same block, they cancel their requests.
+    /// Not only in endgame, blocks of slow peers are handed out twice too
+    pub fn block_saved(&self, block_offset: u32) {
+        self.saved_blocks.send_modify(|saved| {
+            saved.insert(block_offset);
+        });
+    }
+
+    /// Starts endgame once every block is handed out and few are left,
+    /// blocks still missing are queued again once per peer
+    pub fn try_start(&mut self, piece_buffer: &PieceBuffer) -> Result<()> {
+        if self.active || self.peers < 2 || !self.request_block.is_empty() {
+            return Ok(());
+        }
+        let missing = piece_buffer.missing_blocks();
+        if missing.is_empty() || missing.len() + self.queued_blocks > self.threshold {
+            return Ok(());
+        }
+
+        debug!(
+            "endgame, {} blocks outstanding across {} peers",
+            missing.len(),
+            self.peers
+        );
+        self.active = true;
+        for block in missing {
+            for _ in 0..self.peers {
+                self.request_block
+                    .try_send(block)
+                    .context("queueing endgame block")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{BufferBudget, ReceivedBlock};
+
+    const TEST_BLOCK: u32 = 4;
+
+    /// Piece of 4 blocks with the first `received` of them in
+    fn buffer(received: u32) -> PieceBuffer {
+        let blocks: Vec<PieceBlock> = (0..4)
+            .map(|number| PieceBlock {
+                piece_index: 0,
+                block_offset: number * TEST_BLOCK,
+                block_size: TEST_BLOCK,
+            })
+            .collect();
+        let reservation = BufferBudget::new(16).try_reserve(16).unwrap().unwrap();
+        let mut buffer = PieceBuffer::new(0, TEST_BLOCK, &blocks, reservation);
+        for number in 0..received {
+            let mut wire = vec![0; 4];
+            wire.extend_from_slice(&(number * TEST_BLOCK).to_be_bytes());
+            wire.extend_from_slice(&[1; TEST_BLOCK as usize]);
+            buffer
+                .insert(&ReceivedBlock::from(wire.as_slice()))
+                .unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn missing_blocks_are_queued_once_per_peer() {
+        let (request_block, requested_block) = async_channel::unbounded();
+        let (mut endgame, _saved_blocks) = Endgame::new(2, 0, request_block);
+        endgame.set_peers(3);
+
+        endgame.try_start(&buffer(2)).unwrap();
+
+        assert!(endgame.is_active());
+        let offsets: Vec<u32> = std::iter::from_fn(|| requested_block.try_recv().ok())
+            .map(|block| block.block_offset)
+            .collect();
+        assert_eq!(offsets, [8, 8, 8, 12, 12, 12]);
+    }
+
+    #[test]
+    fn waits_for_queue_to_drain_and_few_blocks_left() {
+        let (request_block, requested_block) = async_channel::unbounded();
+        let (mut endgame, _saved_blocks) = Endgame::new(2, 0, request_block.clone());
+        endgame.set_peers(2);
+
+        endgame.try_start(&buffer(1)).unwrap();
+        assert!(!endgame.is_active(), "3 blocks missing, threshold is 2");
+
+        request_block
+            .try_send(buffer(0).missing_blocks()[0])
+            .unwrap();
+        endgame.try_start(&buffer(3)).unwrap();
+        assert!(!endgame.is_active(), "blocks still queued");
+
+        requested_block.try_recv().unwrap();
+        endgame.try_start(&buffer(3)).unwrap();
+        assert!(endgame.is_active());
+    }
+
+    #[test]
+    fn single_peer_never_starts_endgame() {
+        let (request_block, _requested_block) = async_channel::unbounded();
+        let (mut endgame, _saved_blocks) = Endgame::new(4, 0, request_block);
+        endgame.set_peers(1);
+
+        endgame.try_start(&buffer(3)).unwrap();
+
+        assert!(!endgame.is_active());
+    }
+
+    #[test]
+    fn saved_block_wakes_waiting_peers() {
+        let (request_block, _requested_block) = async_channel::unbounded();
+        let (endgame, mut saved_blocks) = Endgame::new(4, 0, request_block);
+
+        endgame.block_saved(8);
+
+        assert!(saved_blocks.has_changed().unwrap());
+        assert!(saved_blocks.borrow_and_update().contains(&8));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bencode::{from_bytes, raw_value_length, to_bytes};
+use crate::prelude::*;
+
+use super::{MetadataMessageType, UT_METADATA};
+
+/// Id we ask peers to use when sending us ut_metadata messages
+pub const UT_METADATA_LOCAL_ID: u8 = 1;
+/// Info dicts are tiny compared to this, bigger sizes are peers lying
+pub const MAX_METADATA_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ExtendedHandshake {
+    /// Extension name to message id, id 0 means extension is disabled
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Extensions we support
+    pub fn local() -> Self {
+        ExtendedHandshake {
+            m: BTreeMap::from([(UT_METADATA.to_string(), UT_METADATA_LOCAL_ID as i64)]),
+            metadata_size: None,
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        from_bytes(payload).context("parse extended handshake")
+    }
+
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        to_bytes(self).context("serialize extended handshake")
+    }
+
+    /// Id remote peer expects for ut_metadata messages
+    pub fn ut_metadata_id(&self) -> Option<u8> {
+        self.m
+            .get(UT_METADATA)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MetadataHeader {
+    msg_type: u8,
+    piece: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// BEP 9 message, data piece follows bencoded header directly
+#[derive(Debug)]
+pub struct MetadataMessage {
+    pub msg_type: MetadataMessageType,
+    pub piece: usize,
+    pub data: Vec<u8>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: usize) -> Self {
+        MetadataMessage {
+            msg_type: MetadataMessageType::Request,
+            piece,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let header_length = raw_value_length(payload).context("ut_metadata header")?;
+        let header: MetadataHeader =
+            from_bytes(&payload[..header_length]).context("parse ut_metadata header")?;
+        let msg_type = MetadataMessageType::try_from(header.msg_type)?;
+
+        Ok(MetadataMessage {
+            msg_type,
+            piece: header.piece,
+            data: payload[header_length..].to_vec(),
+        })
+    }
+
+    pub fn to_payload(&self) -> Result<Vec<u8>> {
+        let header = MetadataHeader {
+            msg_type: self.msg_type as u8,
+            piece: self.piece,
+            total_size: None,
+        };
+        let mut payload = to_bytes(header).context("serialize ut_metadata header")?;
+        payload.extend_from_slice(&self.data);
+        Ok(payload)
+    }
+}
@@ -3,39 +3,210 @@ use core::fmt;
 use reqwest::Url;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::borrow::Borrow;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 use std::writeln;
 
-use crate::bencode::{bytes_serialize, deserialize_hashes, deserialize_url};
-use crate::bencode::{from_bytes, to_bytes};
+use crate::bencode::{
+    bytes_serialize, deserialize_announce_list, deserialize_hashes, deserialize_url,
+};
+use crate::bencode::{from_bytes_with_config, raw_dict_value, ParseConfig, Value};
+
+use super::RedactedUrl;
+
+/// Block offsets are 32 bit, pieces stay well below that
+pub const MAX_PIECE_LENGTH: usize = 512 * 1024 * 1024;
+
+/// Torrent is bigger than anything its decode budget allows, it's never read whole
+#[derive(Debug, thiserror::Error)]
+#[error("torrent file exceeds {cap} bytes")]
+pub struct TorrentTooLarge {
+    pub cap: usize,
+}
+
+impl TorrentTooLarge {
+    fn error(cap: usize) -> anyhow::Error {
+        anyhow::Error::new(TorrentTooLarge { cap }).context(MalformedTorrent("torrent file"))
+    }
+}
+
+/// Unbounded without budget, for trusted input
+fn max_torrent_size(config: ParseConfig) -> usize {
+    config
+        .budget
+        .map_or(usize::MAX, |budget| budget.max_encoded_len())
+}
+
+/// Torrent itself is broken, no peer or retry can fix it
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is malformed")]
+pub struct MalformedTorrent(pub &'static str);
 
 #[derive(Deserialize, Debug)]
 pub struct TorrentMetadataInfo {
     #[serde(deserialize_with = "deserialize_url")]
     pub announce: Url,
+    /// Tiers of trackers (BEP 12), empty if torrent has none
+    #[serde(
+        rename = "announce-list",
+        default,
+        deserialize_with = "deserialize_announce_list"
+    )]
+    pub announce_list: Vec<Vec<Url>>,
     pub info: TorrentInfo,
     #[serde(skip)]
     pub info_hash: Bytes20,
 }
 
 impl TorrentMetadataInfo {
-    pub fn from_file(torrent_path: PathBuf) -> Result<TorrentMetadataInfo> {
-        let torrent = std::fs::read(torrent_path).context("read torrent file")?;
+    /// Loads torrent from local path or downloads it if path is http(s) url
+    pub async fn load(torrent_path: PathBuf, config: ParseConfig) -> Result<TorrentMetadataInfo> {
+        match torrent_url(&torrent_path) {
+            Some(url) => Self::from_url(url, config).await,
+            None => Self::from_file(torrent_path, config),
+        }
+    }
+
+    pub async fn from_url(url: Url, config: ParseConfig) -> Result<TorrentMetadataInfo> {
+        // NOTE: content type is ignored on purpose, servers often
+        // serve torrents as octet-stream or text/plain
+        let mut response = reqwest::get(url.clone())
+            .await
+            .context("download torrent file")?
+            .error_for_status()
+            .context("download torrent file")?;
+        let cap = max_torrent_size(config);
+        if response
+            .content_length()
+            .is_some_and(|length| length > cap as u64)
+        {
+            return Err(TorrentTooLarge::error(cap));
+        }
+        let mut torrent = Vec::new();
+        while let Some(chunk) = response.chunk().await.context("read torrent response")? {
+            if torrent.len() + chunk.len() > cap {
+                return Err(TorrentTooLarge::error(cap));
+            }
+            torrent.extend_from_slice(&chunk);
+        }
+        trace!("downloaded torrent from {url}");
+        Self::from_bytes(&torrent, config)
+    }
+
+    pub fn from_file(torrent_path: PathBuf, config: ParseConfig) -> Result<TorrentMetadataInfo> {
+        let cap = max_torrent_size(config);
+        let mut torrent = Vec::new();
+        std::fs::File::open(torrent_path)
+            .and_then(|file| {
+                file.take((cap as u64).saturating_add(1))
+                    .read_to_end(&mut torrent)
+            })
+            .context("read torrent file")?;
+        if torrent.len() > cap {
+            return Err(TorrentTooLarge::error(cap));
+        }
+        Self::from_bytes(&torrent, config)
+    }
+
+    pub fn from_bytes(torrent: &[u8], config: ParseConfig) -> Result<TorrentMetadataInfo> {
+        Self::parse(torrent, config).context(MalformedTorrent("torrent file"))
+    }
+
+    fn parse(torrent: &[u8], config: ParseConfig) -> Result<TorrentMetadataInfo> {
         let mut metadata: TorrentMetadataInfo =
-            from_bytes(&torrent).context("deserialize torrent file")?;
+            from_bytes_with_config(torrent, config).context("deserialize torrent file")?;
 
-        let info_bytes = to_bytes(&metadata.info).context("serialize info of torrent file")?;
+        // NOTE: hashing original bytes, re-serializing would drop keys we don't model
+        let info_bytes = raw_dict_value(torrent, b"info").context("locate info of torrent file")?;
         let mut hasher = Sha1::new();
-        hasher.update(&info_bytes);
+        hasher.update(info_bytes);
         let info_hash: Bytes20 = hasher.finalize().into();
 
         metadata.info_hash = info_hash;
+        metadata.info.validate()?;
         Ok(metadata)
     }
 }
 
+impl TorrentInfo {
+    /// Bytes covered by pieces, including padding files
+    pub fn total_length(&self) -> usize {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
+
+    /// Bytes of real data, padding files excluded
+    pub fn payload_length(&self) -> usize {
+        self.total_length() - self.padding_length()
+    }
+
+    pub fn padding_length(&self) -> usize {
+        self.files
+            .iter()
+            .flatten()
+            .filter(|f| f.is_padding())
+            .map(|f| f.length)
+            .sum()
+    }
+
+    /// Checks length and pieces agree, so every piece has at least one byte,
+    /// and file paths stay inside output. Lengths and piece math can't overflow after
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.length.is_some() != self.files.is_some(),
+            "exactly one of length and files is expected"
+        );
+        anyhow::ensure!(self.piece_length > 0, "piece length is zero");
+        anyhow::ensure!(
+            self.piece_length <= MAX_PIECE_LENGTH,
+            "piece length {} is above {MAX_PIECE_LENGTH}",
+            self.piece_length
+        );
+        anyhow::ensure!(!self.pieces.is_empty(), "torrent has no pieces");
+        for file in self.files.iter().flatten() {
+            file.relative_path()?;
+        }
+
+        // NOTE: checked here, total_length and piece offsets rely on it
+        let length = match &self.files {
+            Some(files) => files
+                .iter()
+                .try_fold(0usize, |total, file| total.checked_add(file.length)),
+            None => self.length,
+        }
+        .context("total length overflows")?;
+        let full_pieces_length = (self.pieces.len() - 1)
+            .checked_mul(self.piece_length)
+            .filter(|full| full.checked_add(self.piece_length).is_some())
+            .with_context(|| format!("{} pieces overflow total length", self.pieces.len()))?;
+        anyhow::ensure!(
+            length > full_pieces_length && length <= full_pieces_length + self.piece_length,
+            "length {} doesn't match {} pieces of length {}",
+            length,
+            self.pieces.len(),
+            self.piece_length
+        );
+
+        Ok(())
+    }
+}
+
+fn torrent_url(torrent_path: &Path) -> Option<Url> {
+    let path = torrent_path.to_str()?;
+    if !(path.starts_with("http://") || path.starts_with("https://")) {
+        return None;
+    }
+
+    Url::parse(path).ok()
+}
+
 impl<T: Borrow<TorrentMetadataInfo>> WithInfoHash for T {
     fn info_hash(&self) -> Bytes20 {
         self.borrow().info_hash
@@ -44,13 +215,13 @@ impl<T: Borrow<TorrentMetadataInfo>> WithInfoHash for T {
 
 impl std::fmt::Display for TorrentMetadataInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Tracker URL: {}", self.announce)?;
-        writeln!(f, "Length: {}", self.info.length)?;
+        writeln!(f, "Tracker URL: {}", RedactedUrl(&self.announce))?;
+        writeln!(f, "Length: {}", self.info.total_length())?;
         writeln!(f, "Info Hash: {}", hex::encode(self.info_hash))?;
         writeln!(f, "Piece Length: {}", self.info.piece_length)?;
 
         f.write_str("Piece Hashes:\n")?;
-        for hash in &self.info.pieces {
+        for hash in self.info.pieces.iter() {
             writeln!(f, "{}", hex::encode(hash))?;
         }
 
@@ -60,7 +231,12 @@ impl std::fmt::Display for TorrentMetadataInfo {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TorrentInfo {
-    pub length: usize,
+    /// Set for single file torrents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Set for multi file torrents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<TorrentFile>>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: usize,
@@ -68,5 +244,263 @@ pub struct TorrentInfo {
         deserialize_with = "deserialize_hashes",
         serialize_with = "bytes_serialize"
     )]
-    pub pieces: Vec<Vec<u8>>,
+    pub pieces: Arc<[Bytes20]>,
+    /// Keys we don't model (private, source, ...), kept for re-encoding
+    #[serde(flatten)]
+    pub info_extra: BTreeMap<ByteBuf, Value>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TorrentFile {
+    pub length: usize,
+    pub path: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl TorrentFile {
+    /// Padding files (BEP 47) align next file to piece boundary,
+    /// they are hashed as zeros but never written
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+
+    /// Relative path of file, rejecting anything escaping output directory
+    pub fn relative_path(&self) -> Result<PathBuf> {
+        anyhow::ensure!(!self.path.is_empty(), "empty file path");
+        let mut result = PathBuf::new();
+        for segment in &self.path {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(component)), None) => result.push(component),
+                _ => bail!("invalid segment {segment:?} in file path {:?}", self.path),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Lists files of torrent, padding files are hidden unless asked for
+pub struct FilesListing<'a> {
+    pub info: &'a TorrentInfo,
+    pub show_padding: bool,
+}
+
+impl std::fmt::Display for FilesListing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(files) = &self.info.files else {
+            return Ok(());
+        };
+
+        f.write_str("Files:\n")?;
+        for file in files
+            .iter()
+            .filter(|file| self.show_padding || !file.is_padding())
+        {
+            let padding = if file.is_padding() { " (padding)" } else { "" };
+            writeln!(f, "{} {}{}", file.path.join("/"), file.length, padding)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{to_bytes, DecodeBudget};
+    use crate::torrent::testing::{torrent_bytes, MockHttp, TempDir};
+
+    fn small_budget() -> ParseConfig {
+        ParseConfig::default().with_budget(DecodeBudget {
+            max_string_bytes: 1024,
+            max_elements: 16,
+        })
+    }
+
+    fn assert_too_large(error: &anyhow::Error) {
+        assert!(
+            error.chain().any(|cause| cause.is::<TorrentTooLarge>()),
+            "{error:?}"
+        );
+        assert!(error.is::<MalformedTorrent>(), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn download_above_budget_is_rejected() {
+        let cap = max_torrent_size(small_budget());
+        let http = MockHttp::start(move |_| (200, vec![b'x'; cap + 1])).await;
+
+        let error = TorrentMetadataInfo::from_url(http.url("/big.torrent"), small_budget())
+            .await
+            .expect_err("too large");
+
+        assert_too_large(&error);
+    }
+
+    #[tokio::test]
+    async fn download_within_budget_parses() {
+        let torrent = torrent_bytes("http://tracker/announce", 16, b"some data");
+        let http = MockHttp::start(move |_| (200, torrent.clone())).await;
+
+        let metadata = TorrentMetadataInfo::from_url(http.url("/ok.torrent"), small_budget())
+            .await
+            .expect("within budget");
+
+        assert_eq!(metadata.info.name, "test.bin");
+    }
+
+    #[test]
+    fn file_above_budget_is_rejected() {
+        let cap = max_torrent_size(small_budget());
+        let path = std::env::temp_dir().join(format!("big-{}.torrent", std::process::id()));
+        std::fs::write(&path, vec![b'x'; cap * 4]).expect("write torrent");
+
+        let error = TorrentMetadataInfo::from_file(path.clone(), small_budget());
+        std::fs::remove_file(&path).expect("remove torrent");
+
+        assert_too_large(&error.expect_err("too large"));
+    }
+
+    #[tokio::test]
+    async fn url_source_loads_same_torrent_as_path() {
+        let torrent = torrent_bytes("http://tracker/announce", 4, b"0123456789");
+        let served = torrent.clone();
+        let http = MockHttp::start(move |_| (200, served.clone())).await;
+        let dir = TempDir::new("url-source");
+        let path = dir.join("test.torrent");
+        std::fs::write(&path, &torrent).unwrap();
+
+        let from_url = TorrentMetadataInfo::load(
+            PathBuf::from(http.url("/test.torrent").as_str()),
+            ParseConfig::default(),
+        )
+        .await
+        .unwrap();
+        let from_path = TorrentMetadataInfo::load(path, ParseConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(http.requests(), ["/test.torrent"]);
+        assert_eq!(from_url.info_hash, from_path.info_hash);
+        assert_eq!(from_url.announce, from_path.announce);
+        assert_eq!(from_url.info.pieces, from_path.info.pieces);
+        assert_eq!(from_url.info.name, from_path.info.name);
+    }
+
+    #[tokio::test]
+    async fn failing_download_is_an_error() {
+        let http = MockHttp::start(|_| (404, Vec::new())).await;
+
+        let result =
+            TorrentMetadataInfo::from_url(http.url("/gone.torrent"), ParseConfig::default()).await;
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("404"));
+    }
+
+    fn single_file_torrent(length: usize, piece_length: usize, pieces: usize) -> Vec<u8> {
+        let info = BTreeMap::from([
+            (b"length".to_vec(), Value::Integer(length as i64)),
+            (b"name".to_vec(), Value::String(b"test.bin".to_vec())),
+            (
+                b"piece length".to_vec(),
+                Value::Integer(piece_length as i64),
+            ),
+            (b"pieces".to_vec(), Value::String(vec![0; pieces * 20])),
+        ]);
+        to_bytes(Value::Dict(BTreeMap::from([
+            (b"announce".to_vec(), Value::String(b"http://t/a".to_vec())),
+            (b"info".to_vec(), Value::Dict(info)),
+        ])))
+        .unwrap()
+    }
+
+    #[test]
+    fn length_must_match_piece_count() {
+        let parse = |length, pieces| {
+            TorrentMetadataInfo::from_bytes(
+                &single_file_torrent(length, 32, pieces),
+                ParseConfig::default(),
+            )
+        };
+
+        assert!(parse(64, 2).is_ok());
+        assert!(parse(33, 2).is_ok());
+        // NOTE: extra hash would be a piece of zero blocks, never completing
+        let err = parse(64, 3).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("doesn't match 3 pieces"),
+            "{err:#}"
+        );
+        assert!(parse(65, 2).is_err());
+        assert!(parse(0, 1).is_err());
+    }
+
+    #[test]
+    fn minor_violations_fail_only_under_strict() {
+        let strict = ParseConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let trailing = [torrent_bytes("http://t/a", 16, &[1; 32]), b"\n".to_vec()].concat();
+        let mut plus_sign = torrent_bytes("http://t/a", 16, &[1; 32]);
+        let length = plus_sign
+            .windows(12)
+            .position(|window| window == b"6:lengthi32e")
+            .unwrap();
+        plus_sign.insert(length + 9, b'+');
+
+        for torrent in [trailing, plus_sign] {
+            assert!(TorrentMetadataInfo::from_bytes(&torrent, ParseConfig::default()).is_ok());
+            let err = TorrentMetadataInfo::from_bytes(&torrent, strict).unwrap_err();
+            assert!(err.is::<MalformedTorrent>(), "{err:#}");
+        }
+    }
+
+    #[test]
+    fn info_hash_covers_keys_we_dont_model() {
+        // NOTE: source before private, re-encoding would sort them too
+        let info = b"d6:lengthi4e4:name1:x12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+                     6:source3:abc7:privatei1ee";
+        let torrent = [&b"d8:announce10:http://t/a4:info"[..], info, b"e"].concat();
+
+        let metadata = TorrentMetadataInfo::from_bytes(&torrent, ParseConfig::default()).unwrap();
+
+        assert_eq!(metadata.info_hash, sha1_hash(info));
+        let modeled_only = TorrentInfo {
+            info_extra: Default::default(),
+            ..metadata.info
+        };
+        assert_ne!(
+            sha1_hash(&to_bytes(&modeled_only).unwrap()),
+            metadata.info_hash
+        );
+    }
+
+    #[test]
+    fn unknown_info_keys_survive_round_trip() {
+        let info = b"d6:lengthi4e4:name1:x12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+                     7:privatei1e8:x-customl1:ai2eee";
+        let torrent = [&b"d8:announce10:http://t/a4:info"[..], info, b"e"].concat();
+        let mut metadata =
+            TorrentMetadataInfo::from_bytes(&torrent, ParseConfig::default()).unwrap();
+
+        let encoded = to_bytes(&metadata.info).unwrap();
+        assert_eq!(encoded, info);
+        assert_eq!(sha1_hash(&encoded), metadata.info_hash);
+
+        metadata.info.name = "renamed".to_string();
+        let renamed = to_bytes(&metadata.info).unwrap();
+        let decoded: TorrentInfo = crate::bencode::from_bytes(&renamed).unwrap();
+        assert_eq!(decoded.name, "renamed");
+        assert_eq!(
+            decoded
+                .info_extra
+                .keys()
+                .map(|key| key.as_slice())
+                .collect::<Vec<_>>(),
+            [&b"private"[..], b"x-custom"]
+        );
+    }
 }
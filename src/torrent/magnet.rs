@@ -0,0 +1,214 @@
+use core::fmt;
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use reqwest::Url;
+
+use crate::prelude::*;
+
+use super::{RedactedUrl, TorrentInfo, TorrentMetadataInfo};
+
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// Parsed `magnet:?xt=urn:btih:<hash>&dn=<name>&tr=<tracker>&x.pe=<ip:port>` link
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Bytes20,
+    pub display_name: Option<String>,
+    pub trackers: Vec<Url>,
+    /// Peer hints from `x.pe`
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<MagnetLink> {
+        let url = Url::parse(uri).context("parse magnet uri")?;
+        anyhow::ensure!(url.scheme() == "magnet", "not a magnet uri");
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    let Some(hash) = value.strip_prefix(BTIH_PREFIX) else {
+                        trace!("skipping unsupported exact topic {value}");
+                        continue;
+                    };
+                    info_hash = Some(parse_info_hash(hash)?);
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" => match Url::parse(&value) {
+                    Ok(tracker) => trackers.push(tracker),
+                    Err(e) => warn!("skipping invalid tracker {value}: {e}"),
+                },
+                "x.pe" => match value.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(e) => warn!("skipping invalid peer hint {value}: {e}"),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.context("magnet uri has no btih exact topic (xt)")?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// Metadata with info left unresolved until fetched from peers
+    pub fn into_metadata(self) -> Result<TorrentMetadataInfo> {
+        let announce = self
+            .trackers
+            .first()
+            .cloned()
+            .context("magnet uri has no tracker (tr)")?;
+        let name = self
+            .display_name
+            .unwrap_or_else(|| hex::encode(self.info_hash));
+        Ok(TorrentMetadataInfo {
+            announce,
+            // NOTE: every tr gets its own tier, as there's no tier info in magnet
+            announce_list: self.trackers.into_iter().map(|f| vec![f]).collect(),
+            info: TorrentInfo::unresolved(name),
+            info_hash: self.info_hash,
+        })
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Info Hash: {}", hex::encode(self.info_hash))?;
+        if let Some(name) = &self.display_name {
+            writeln!(f, "Name: {}", name)?;
+        }
+        for tracker in &self.trackers {
+            writeln!(f, "Tracker URL: {}", RedactedUrl(tracker))?;
+        }
+        for peer in &self.peers {
+            writeln!(f, "Peer: {}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash is either 40 chars hex or 32 chars base32
+fn parse_info_hash(hash: &str) -> Result<Bytes20> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        len => bail!("info hash of {len} chars, expected 40 hex or 32 base32"),
+    };
+
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&bytes);
+    Ok(info_hash)
+}
+
+/// RFC 4648 base32 without padding
+fn base32_decode(value: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in value.bytes() {
+        let digit = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            c => bail!("invalid base32 character {}", c as char),
+        };
+        buffer = (buffer << 5) | digit as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+impl TorrentInfo {
+    /// Placeholder for torrents known only by info hash
+    pub fn unresolved(name: String) -> Self {
+        TorrentInfo {
+            length: None,
+            files: None,
+            name,
+            piece_length: 0,
+            pieces: Arc::from([]),
+            info_extra: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX_HASH: &str = "0102030405060708090a0b0c0d0e0f1011121314";
+    const BASE32_HASH: &str = "AEBAGBAFAYDQQCIKBMGA2DQPCAIREEYU";
+
+    fn expected_hash() -> Bytes20 {
+        std::array::from_fn(|i| i as u8 + 1)
+    }
+
+    #[test]
+    fn hex_and_base32_hashes_are_the_same_bytes() {
+        for hash in [HEX_HASH, BASE32_HASH, &BASE32_HASH.to_lowercase()] {
+            let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{hash}&dn=name")).unwrap();
+
+            assert_eq!(magnet.info_hash, expected_hash(), "{hash}");
+            assert_eq!(magnet.display_name.as_deref(), Some("name"));
+        }
+    }
+
+    #[test]
+    fn every_tracker_gets_its_own_tier() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{HEX_HASH}&tr=http%3A%2F%2Fone%2Fannounce&tr=udp%3A%2F%2Ftwo%3A80"
+        );
+
+        let metadata = MagnetLink::parse(&uri).unwrap().into_metadata().unwrap();
+
+        assert_eq!(metadata.announce.as_str(), "http://one/announce");
+        let tiers: Vec<Vec<&str>> = metadata
+            .announce_list
+            .iter()
+            .map(|tier| tier.iter().map(Url::as_str).collect())
+            .collect();
+        assert_eq!(tiers, [["http://one/announce"], ["udp://two:80"]]);
+        assert!(!metadata.info.is_resolved());
+    }
+
+    #[test]
+    fn link_without_btih_or_with_bad_hash_is_rejected() {
+        assert!(MagnetLink::parse("magnet:?dn=name&tr=http%3A%2F%2Ft").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:sha1:abc").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:0102").is_err());
+        assert!(MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", "zz".repeat(20))).is_err());
+        assert!(MagnetLink::parse(&format!("http://x/?xt=urn:btih:{HEX_HASH}")).is_err());
+    }
+
+    #[test]
+    fn peer_hints_are_kept_and_displayed_like_info() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{HEX_HASH}&dn=file&tr=http%3A%2F%2Ft%2Fa\
+             &x.pe=10.0.0.1:6881&x.pe=not-a-peer&x.pe=%5B%3A%3A1%5D:80"
+        );
+
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(
+            magnet.to_string(),
+            format!(
+                "Info Hash: {HEX_HASH}\nName: file\nTracker URL: http://t/a\n\
+                 Peer: 10.0.0.1:6881\nPeer: [::1]:80\n"
+            )
+        );
+    }
+}
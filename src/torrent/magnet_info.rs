@@ -0,0 +1,178 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use serde::Serialize;
+
+use crate::prelude::*;
+
+use super::{FilesListing, MagnetLink, RedactedUrl, TorrentInfo};
+
+/// Outcome of [`Torrent::fetch_metadata_report`](super::Torrent::fetch_metadata_report)
+#[derive(Debug)]
+pub struct MetadataFetchReport {
+    pub info: Result<TorrentInfo>,
+    pub elapsed: Duration,
+    /// Peers whose pieces made up fetched metadata
+    pub suppliers: Vec<SocketAddr>,
+    /// Last failure of each tracker and peer
+    pub failures: Vec<SourceFailure>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceFailure {
+    pub source: String,
+    pub reason: String,
+}
+
+/// How much of a magnet is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataState {
+    /// Fetch not attempted, only the link is known
+    Offline,
+    Failed,
+    Fetched,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FetchedFile {
+    pub path: String,
+    pub length: usize,
+    pub padding: bool,
+}
+
+/// Info dict of fetched metadata, as `info` shows it for a torrent file
+#[derive(Debug, Serialize)]
+pub struct FetchedInfo {
+    pub name: String,
+    pub length: usize,
+    pub piece_length: usize,
+    pub piece_hashes: Vec<String>,
+    /// Set for multi file torrents
+    pub files: Option<Vec<FetchedFile>>,
+    #[serde(skip)]
+    info: TorrentInfo,
+}
+
+impl From<TorrentInfo> for FetchedInfo {
+    fn from(info: TorrentInfo) -> Self {
+        Self {
+            name: info.name.clone(),
+            length: info.total_length(),
+            piece_length: info.piece_length,
+            piece_hashes: info.pieces.iter().map(hex::encode).collect(),
+            files: info.files.as_ref().map(|files| {
+                files
+                    .iter()
+                    .map(|file| FetchedFile {
+                        path: file.path.join("/"),
+                        length: file.length,
+                        padding: file.is_padding(),
+                    })
+                    .collect()
+            }),
+            info,
+        }
+    }
+}
+
+/// What is known of a magnet link, best effort. Every field is present in json
+/// whatever the state, unknown ones are null
+#[derive(Debug, Serialize)]
+pub struct MagnetInfo {
+    pub state: MetadataState,
+    pub info_hash: String,
+    /// Fetched name, otherwise display name of link
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+    pub info: Option<FetchedInfo>,
+    pub fetch_seconds: Option<f64>,
+    pub supplied_by: Option<Vec<SocketAddr>>,
+    pub error: Option<String>,
+    pub failures: Option<Vec<SourceFailure>>,
+}
+
+impl MagnetInfo {
+    pub fn offline(magnet: &MagnetLink) -> Self {
+        Self {
+            state: MetadataState::Offline,
+            info_hash: hex::encode(magnet.info_hash),
+            name: magnet.display_name.clone(),
+            trackers: magnet
+                .trackers
+                .iter()
+                .map(|tracker| RedactedUrl(tracker).to_string())
+                .collect(),
+            peers: magnet.peers.clone(),
+            info: None,
+            fetch_seconds: None,
+            supplied_by: None,
+            error: None,
+            failures: None,
+        }
+    }
+
+    pub fn fetched(magnet: &MagnetLink, report: MetadataFetchReport) -> Self {
+        let mut magnet_info = Self::offline(magnet);
+        magnet_info.fetch_seconds = Some(report.elapsed.as_secs_f64());
+        magnet_info.failures = Some(report.failures);
+        match report.info {
+            Ok(info) => {
+                magnet_info.state = MetadataState::Fetched;
+                magnet_info.name = Some(info.name.clone());
+                magnet_info.info = Some(info.into());
+                magnet_info.supplied_by = Some(report.suppliers);
+            }
+            Err(e) => {
+                magnet_info.state = MetadataState::Failed;
+                magnet_info.error = Some(format!("{e:#}"));
+            }
+        }
+        magnet_info
+    }
+}
+
+impl fmt::Display for MagnetInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Info Hash: {}", self.info_hash)?;
+        if let Some(name) = &self.name {
+            writeln!(f, "Name: {}", name)?;
+        }
+        for tracker in &self.trackers {
+            writeln!(f, "Tracker URL: {}", tracker)?;
+        }
+        for peer in &self.peers {
+            writeln!(f, "Peer: {}", peer)?;
+        }
+        if let Some(info) = &self.info {
+            writeln!(f, "Length: {}", info.length)?;
+            writeln!(f, "Piece Length: {}", info.piece_length)?;
+            f.write_str("Piece Hashes:\n")?;
+            for hash in &info.piece_hashes {
+                writeln!(f, "{}", hash)?;
+            }
+            let listing = FilesListing {
+                info: &info.info,
+                show_padding: false,
+            };
+            write!(f, "{}", listing)?;
+        }
+        if let (Some(seconds), Some(suppliers)) = (self.fetch_seconds, &self.supplied_by) {
+            let suppliers: Vec<_> = suppliers.iter().map(ToString::to_string).collect();
+            writeln!(
+                f,
+                "Metadata fetched in {seconds:.1}s from {}",
+                suppliers.join(", ")
+            )?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "Metadata not fetched: {error}")?;
+        }
+        if self.state == MetadataState::Failed {
+            for failure in self.failures.iter().flatten() {
+                writeln!(f, "  {}: {}", failure.source, failure.reason)?;
+            }
+        }
+        Ok(())
+    }
+}
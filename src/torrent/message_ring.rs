@@ -0,0 +1,140 @@
+use std::{collections::VecDeque, fmt, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Messages kept per connection, oldest are dropped first
+pub const MESSAGE_RING_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Handshake,
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    RejectRequest,
+    Extended,
+}
+
+/// Key fields of a message, only the ones its kind has are set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub piece: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    /// Block length, payload length for bitfield and extended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extended_id: Option<u8>,
+}
+
+impl MessageFields {
+    pub fn block(piece: u32, offset: u32, length: u32) -> Self {
+        Self {
+            piece: Some(piece),
+            offset: Some(offset),
+            length: Some(length),
+            extended_id: None,
+        }
+    }
+}
+
+/// Message as seen on the wire, payload left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSummary {
+    /// Milliseconds since connection was opened
+    pub at_ms: u32,
+    pub direction: Direction,
+    pub kind: MessageKind,
+    #[serde(flatten)]
+    pub fields: MessageFields,
+}
+
+impl fmt::Display for MessageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        };
+        write!(f, "+{}ms {direction} {:?}", self.at_ms, self.kind)?;
+        if let Some(piece) = self.fields.piece {
+            write!(f, " piece {piece}")?;
+        }
+        if let Some(offset) = self.fields.offset {
+            write!(f, " offset {offset}")?;
+        }
+        if let Some(length) = self.fields.length {
+            write!(f, " length {length}")?;
+        }
+        if let Some(extended_id) = self.fields.extended_id {
+            write!(f, " id {extended_id}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Messages summarized for the ring, payload is never copied
+pub trait Summarize {
+    fn summarize(&self) -> (MessageKind, MessageFields);
+}
+
+/// Last [`MESSAGE_RING_CAPACITY`] messages of one connection, both directions
+#[derive(Debug)]
+pub struct MessageRing {
+    opened: Instant,
+    messages: VecDeque<MessageSummary>,
+}
+
+impl MessageRing {
+    pub fn new() -> Self {
+        Self {
+            opened: Instant::now(),
+            messages: VecDeque::with_capacity(MESSAGE_RING_CAPACITY),
+        }
+    }
+
+    pub fn record(&mut self, direction: Direction, message: &impl Summarize) {
+        if self.messages.len() == MESSAGE_RING_CAPACITY {
+            self.messages.pop_front();
+        }
+        let (kind, fields) = message.summarize();
+        self.messages.push_back(MessageSummary {
+            at_ms: self
+                .opened
+                .elapsed()
+                .as_millis()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            direction,
+            kind,
+            fields,
+        });
+    }
+
+    /// Oldest first
+    pub fn messages(&self) -> Vec<MessageSummary> {
+        self.messages.iter().copied().collect()
+    }
+}
+
+impl Default for MessageRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+use futures::StreamExt;
+use futures_util::stream::FuturesUnordered;
+
+use crate::{
+    bencode::{from_bytes_with_config, DecodeBudget, ParseConfig},
+    prelude::*,
+};
+
+use super::{MalformedTorrent, Peer, TorrentInfo, METADATA_PIECE_SIZE};
+
+/// Allowance on top of advertised metadata size when decoding it
+const METADATA_BUDGET_SLACK: usize = 1024;
+
+/// Metadata pieces collected so far for one advertised size
+#[derive(Debug)]
+struct PartialMetadata {
+    pieces: Vec<Option<Vec<u8>>>,
+    sources: BTreeSet<SocketAddr>,
+}
+
+impl PartialMetadata {
+    fn new(metadata_size: usize) -> Self {
+        Self {
+            pieces: vec![None; metadata_size.div_ceil(METADATA_PIECE_SIZE)],
+            sources: BTreeSet::new(),
+        }
+    }
+
+    fn missing(&self) -> VecDeque<usize> {
+        (0..self.pieces.len())
+            .filter(|piece| self.pieces[*piece].is_none())
+            .collect()
+    }
+
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let mut metadata = Vec::new();
+        for piece in &self.pieces {
+            metadata.extend_from_slice(piece.as_deref()?);
+        }
+        Some(metadata)
+    }
+}
+
+/// Fetches info dict (BEP 9) from many peers at once.
+/// Peers are grouped by advertised metadata size, majority size is tried first,
+/// so peers lying about size don't get a say until honest ones fail
+pub struct MetadataFetch {
+    info_hash: Bytes20,
+    /// Keyed by (info hash, metadata size), survives peers dropping mid fetch
+    partial: HashMap<(Bytes20, usize), PartialMetadata>,
+    banned: HashSet<SocketAddr>,
+    /// Last reason each failing peer gave
+    failures: BTreeMap<SocketAddr, String>,
+    /// Peers whose pieces made up fetched metadata
+    suppliers: BTreeSet<SocketAddr>,
+}
+
+impl MetadataFetch {
+    pub fn new(info_hash: Bytes20) -> Self {
+        Self {
+            info_hash,
+            partial: HashMap::new(),
+            banned: HashSet::new(),
+            failures: BTreeMap::new(),
+            suppliers: BTreeSet::new(),
+        }
+    }
+
+    pub fn failures(&self) -> &BTreeMap<SocketAddr, String> {
+        &self.failures
+    }
+
+    pub fn suppliers(&self) -> &BTreeSet<SocketAddr> {
+        &self.suppliers
+    }
+
+    pub fn is_banned(&self, peer: &SocketAddr) -> bool {
+        self.banned.contains(peer)
+    }
+
+    #[instrument(skip(self, peers))]
+    pub async fn fetch(&mut self, peers: Vec<Peer<'_>>) -> Result<TorrentInfo> {
+        let mut by_size: BTreeMap<usize, Vec<Peer>> = BTreeMap::new();
+        let mut sized_peers = peers
+            .into_iter()
+            .filter(|peer| !self.is_banned(&peer.socket_addr()))
+            .map(|mut peer| async move {
+                let size = peer.metadata_size().await;
+                (peer, size)
+            })
+            .collect::<FuturesUnordered<_>>();
+        while let Some((peer, size)) = sized_peers.next().await {
+            match size {
+                Ok(size) => by_size.entry(size).or_default().push(peer),
+                Err(e) => {
+                    debug!("peer {} can't serve metadata: {e:#}", peer.socket_addr());
+                    self.failures
+                        .insert(peer.socket_addr(), format!("can't serve metadata: {e:#}"));
+                }
+            }
+        }
+        drop(sized_peers);
+
+        let mut groups: Vec<_> = by_size.into_iter().collect();
+        groups.sort_by_key(|(_, peers)| std::cmp::Reverse(peers.len()));
+        for (metadata_size, peers) in groups {
+            debug!(
+                "trying metadata size {metadata_size} advertised by {} peers",
+                peers.len()
+            );
+            match self.fetch_sized(metadata_size, peers).await {
+                Ok(info) => return Ok(info),
+                Err(e) if e.is::<MalformedTorrent>() => return Err(e),
+                Err(e) => debug!("metadata of size {metadata_size} failed: {e:#}"),
+            }
+        }
+
+        bail!("no peer provided metadata")
+    }
+
+    async fn fetch_sized(
+        &mut self,
+        metadata_size: usize,
+        mut peers: Vec<Peer<'_>>,
+    ) -> Result<TorrentInfo> {
+        let key = (self.info_hash, metadata_size);
+        let partial = Mutex::new(
+            self.partial
+                .remove(&key)
+                .unwrap_or_else(|| PartialMetadata::new(metadata_size)),
+        );
+
+        // NOTE: peer failing a piece puts it back and drops out, so no piece
+        // goes twice to the same peer, survivors pick up leftovers next round
+        loop {
+            let missing = partial.lock().expect("partial lock").missing();
+            if missing.is_empty() || peers.is_empty() {
+                break;
+            }
+            let queue = Mutex::new(missing);
+            let mut workers = peers
+                .into_iter()
+                .map(|peer| fetch_pieces(peer, metadata_size, &queue, &partial))
+                .collect::<FuturesUnordered<_>>();
+            let mut survivors = Vec::new();
+            while let Some(peer) = workers.next().await {
+                match peer {
+                    Ok(peer) => survivors.push(peer),
+                    Err((socket_addr, e)) => {
+                        self.failures.insert(socket_addr, format!("{e:#}"));
+                    }
+                }
+            }
+            drop(workers);
+            peers = survivors;
+        }
+
+        let partial = partial.into_inner().expect("partial lock");
+        let Some(metadata) = partial.assemble() else {
+            // NOTE: kept for later rounds with fresh peers
+            self.partial.insert(key, partial);
+            bail!("all peers dropped before metadata completed");
+        };
+
+        if sha1_hash(&metadata) != self.info_hash {
+            warn!(
+                "metadata doesn't match info hash, banning {:?}",
+                partial.sources
+            );
+            for source in &partial.sources {
+                self.failures
+                    .insert(*source, "sent metadata not matching info hash".to_string());
+            }
+            self.banned.extend(partial.sources);
+            bail!("metadata doesn't match info hash");
+        }
+
+        let budget = DecodeBudget {
+            max_string_bytes: metadata_size + METADATA_BUDGET_SLACK,
+            max_elements: metadata_size / 2 + METADATA_BUDGET_SLACK,
+        };
+        self.suppliers = partial.sources;
+        // NOTE: hash matched, so the magnet itself points at broken metadata
+        let info = from_bytes_with_config(&metadata, ParseConfig::default().with_budget(budget))
+            .context("parse metadata")
+            .and_then(|info: TorrentInfo| info.validate().map(|()| info))
+            .context(MalformedTorrent("metadata of magnet"))?;
+        Ok(info)
+    }
+}
+
+/// Takes pieces from shared queue until it's empty, peer is returned if still healthy
+async fn fetch_pieces<'a>(
+    mut peer: Peer<'a>,
+    metadata_size: usize,
+    queue: &Mutex<VecDeque<usize>>,
+    partial: &Mutex<PartialMetadata>,
+) -> std::result::Result<Peer<'a>, (SocketAddr, anyhow::Error)> {
+    loop {
+        let Some(piece) = queue.lock().expect("queue lock").pop_front() else {
+            return Ok(peer);
+        };
+        match peer.fetch_metadata_piece(piece, metadata_size).await {
+            Ok(data) => {
+                let mut partial = partial.lock().expect("partial lock");
+                partial.pieces[piece] = Some(data);
+                partial.sources.insert(peer.socket_addr());
+            }
+            Err(e) => {
+                debug!(
+                    "peer {} failed metadata piece {piece}: {e:#}",
+                    peer.socket_addr()
+                );
+                queue.lock().expect("queue lock").push_back(piece);
+                return Err((peer.socket_addr(), e));
+            }
+        }
+    }
+}
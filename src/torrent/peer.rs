@@ -1,30 +1,78 @@
 use core::fmt;
-use std::{assert_eq, fmt::Debug, format, net::SocketAddrV4, time::Duration};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Debug,
+    format,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_channel::{Receiver, Sender};
 use bitvec::{order::Msb0, vec::BitVec};
 use bytes::{Buf, BufMut};
-use futures::{sink::SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use futures::{sink::SinkExt, FutureExt, StreamExt};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{watch, Semaphore},
+};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
 
 use crate::prelude::*;
 
-use super::{piece::PieceBlock, TorrentInfo};
-
-const BITTORRENT_PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
-const BITTORRENT_PROTOCOL_LENGTH: u8 = BITTORRENT_PROTOCOL.len() as u8;
-const HANDSHAKE_MEM_SIZE: u8 = 40;
-const HANDSHAKE_RESERVED: &[u8; 8] = &[0; 8];
+use super::{
+    piece::PieceBlock, record_to, Direction, ExtendedHandshake, MessageFields, MessageId,
+    MessageKind, MessageRing, MetadataMessage, MetadataMessageType, PeerSummary, RateLimiter,
+    ReservedBits, SessionEvent, SessionLog, Storage, Summarize, TorrentInfo, BITTORRENT_PROTOCOL,
+    BITTORRENT_PROTOCOL_LENGTH, CLOSED_REASON, DEFAULT_BLOCK_REQUEST_TIMEOUT_SECONDS,
+    EXTENDED_HANDSHAKE_ID, HANDSHAKE_LENGTH, HAVE_PAYLOAD_LENGTH, MAX_METADATA_SIZE,
+    MAX_STANDARD_BLOCK_SIZE, MESSAGE_LENGTH_PREFIX, METADATA_PIECE_SIZE, PIECE_HEADER_LENGTH,
+    REQUEST_PAYLOAD_LENGTH, RESERVED_LENGTH, UT_METADATA_LOCAL_ID,
+};
 
 const TIMOUT_DURATION_SECONDS: u8 = 5;
+const SHUTDOWN_LINGER_SECONDS: u64 = 2;
+/// How long peer gets to send its first message right after handshake
+const BITFIELD_WAIT: Duration = Duration::from_secs(2);
+/// Chars of foreign protocol kept for the error
+const WRONG_PROTOCOL_CAPTURE: usize = 32;
+/// Idle time after which heartbeat is sent, a bit under common two minute cutoff
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(110);
+/// Peer that sent nothing for this long, not even a heartbeat, is gone
+const PEER_SILENCE_TIMEOUT: Duration = Duration::from_secs(150);
+/// Peer losing this many reassigned blocks in a row is dropped
+const MAX_MISSED_BLOCKS: u32 = 3;
+/// Disconnect this soon after first oversized request hints at its rejection
+const OVERSIZED_REJECTION_WINDOW: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 pub struct Handshake {
+    pub reserved: ReservedBits,
     pub info_hash: Bytes20,
     pub peer_id: PeerId,
 }
 
+impl Handshake {
+    fn ensure_info_hash(&self, expected: Bytes20) -> Result<()> {
+        if self.info_hash != expected {
+            return Err(InfoHashMismatch {
+                expected,
+                received: self.info_hash,
+                peer_id: self.peer_id,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Summarize for Handshake {
+    fn summarize(&self) -> (MessageKind, MessageFields) {
+        (MessageKind::Handshake, MessageFields::default())
+    }
+}
+
 struct HandshakeFramer;
 
 impl Encoder<Handshake> for HandshakeFramer {
@@ -37,7 +85,7 @@ impl Encoder<Handshake> for HandshakeFramer {
     ) -> std::prelude::v1::Result<(), Self::Error> {
         dst.put_u8(BITTORRENT_PROTOCOL_LENGTH);
         dst.put_slice(BITTORRENT_PROTOCOL);
-        dst.put_slice(HANDSHAKE_RESERVED);
+        dst.put_slice(&item.reserved.to_bytes());
         dst.put_slice(&item.info_hash);
         dst.put_slice(&std::convert::Into::<Bytes20>::into(item.peer_id));
 
@@ -55,31 +103,37 @@ impl Decoder for HandshakeFramer {
         src: &mut bytes::BytesMut,
     ) -> std::prelude::v1::Result<Option<Self::Item>, Self::Error> {
         if src.is_empty() {
+            src.reserve(HANDSHAKE_LENGTH);
             return Ok(None);
         }
 
-        let length = src.get_u8();
-        if length != BITTORRENT_PROTOCOL_LENGTH {
-            bail!("Invalid length");
+        // NOTE: foreign protocols rarely start with our length byte, so judge by what's buffered
+        if src[0] != BITTORRENT_PROTOCOL_LENGTH {
+            return Err(HandshakeError::wrong_protocol(src).into());
         }
 
-        if src.remaining() < HANDSHAKE_MEM_SIZE as usize {
+        // NOTE: nothing is consumed until whole handshake is buffered
+        if src.remaining() < HANDSHAKE_LENGTH {
+            src.reserve(HANDSHAKE_LENGTH - src.len());
             return Ok(None);
         }
 
-        let mut protocol = [0; 19];
+        src.advance(1);
+
+        let mut protocol = [0; BITTORRENT_PROTOCOL_LENGTH as usize];
         src.copy_to_slice(&mut protocol);
         if protocol != *BITTORRENT_PROTOCOL {
-            bail!("wrong protocol");
+            return Err(HandshakeError::wrong_protocol(&protocol).into());
         }
-        let mut _reserved = [0; 8];
-        src.advance(HANDSHAKE_RESERVED.len());
+        let mut reserved = [0; RESERVED_LENGTH];
+        src.copy_to_slice(&mut reserved);
         let mut info_hash = [0; 20];
         src.copy_to_slice(&mut info_hash);
         let mut peer_id = [0; 20];
         src.copy_to_slice(&mut peer_id);
 
         Ok(Some(Handshake {
+            reserved: ReservedBits::from_bytes(reserved),
             info_hash,
             peer_id: peer_id.into(),
         }))
@@ -95,14 +149,74 @@ enum PeerMessage {
     Unchoke,
     Interested,
     NotInterested,
-    Have(u8),
+    /// Piece index
+    Have(u32),
     Bitfield(PiecesIndexes),
     Request(RequestBlock),
     Piece(ReceivedBlock),
-    Cancel,
+    Cancel(RequestBlock),
+    /// BEP 6 reject request, payload is kept raw
+    RejectRequest(Vec<u8>),
+    /// BEP 10 extended message id and its payload
+    Extended(u8, Vec<u8>),
     Heartbeat,
 }
 
+impl Summarize for PeerMessage {
+    fn summarize(&self) -> (MessageKind, MessageFields) {
+        let payload = |length: usize, extended_id| MessageFields {
+            length: Some(length.try_into().unwrap_or(u32::MAX)),
+            extended_id,
+            ..Default::default()
+        };
+        match self {
+            PeerMessage::Choke => (MessageKind::Choke, MessageFields::default()),
+            PeerMessage::Unchoke => (MessageKind::Unchoke, MessageFields::default()),
+            PeerMessage::Interested => (MessageKind::Interested, MessageFields::default()),
+            PeerMessage::NotInterested => (MessageKind::NotInterested, MessageFields::default()),
+            PeerMessage::Have(piece) => (
+                MessageKind::Have,
+                MessageFields {
+                    piece: Some(*piece),
+                    ..Default::default()
+                },
+            ),
+            PeerMessage::Bitfield(bitfield) => {
+                (MessageKind::Bitfield, payload(bitfield.len(), None))
+            }
+            PeerMessage::Request(request) => (
+                MessageKind::Request,
+                MessageFields::block(request.index(), request.begin(), request.length()),
+            ),
+            PeerMessage::Piece(block) => (
+                MessageKind::Piece,
+                MessageFields::block(
+                    block.index(),
+                    block.begin(),
+                    block.block.len().try_into().unwrap_or(u32::MAX),
+                ),
+            ),
+            PeerMessage::Cancel(request) => (
+                MessageKind::Cancel,
+                MessageFields::block(request.index(), request.begin(), request.length()),
+            ),
+            PeerMessage::RejectRequest(payload) => {
+                let fields = match RequestBlock::try_from(payload.as_slice()) {
+                    Ok(request) => {
+                        MessageFields::block(request.index(), request.begin(), request.length())
+                    }
+                    Err(_) => MessageFields::default(),
+                };
+                (MessageKind::RejectRequest, fields)
+            }
+            PeerMessage::Extended(id, data) => {
+                (MessageKind::Extended, payload(data.len(), Some(*id)))
+            }
+            PeerMessage::Heartbeat => (MessageKind::KeepAlive, MessageFields::default()),
+        }
+    }
+}
+
 impl From<PieceBlock> for RequestBlock {
     fn from(val: PieceBlock) -> Self {
         RequestBlock::new(val.piece_index, val.block_offset, val.block_size)
@@ -135,6 +249,18 @@ impl RequestBlock {
         }
     }
 
+    fn index(&self) -> u32 {
+        u32::from_be_bytes(self.index)
+    }
+
+    fn begin(&self) -> u32 {
+        u32::from_be_bytes(self.begin)
+    }
+
+    fn length(&self) -> u32 {
+        u32::from_be_bytes(self.length)
+    }
+
     fn into_vec(self) -> Vec<u8> {
         vec![self.index, self.begin, self.length]
             .into_iter()
@@ -143,25 +269,26 @@ impl RequestBlock {
     }
 }
 
-impl From<&[u8]> for RequestBlock {
-    fn from(value: &[u8]) -> Self {
-        RequestBlock {
-            index: {
-                let mut index = [0; 4];
-                index.copy_from_slice(&value[..4]);
-                index
-            },
-            begin: {
-                let mut begin = [0; 4];
-                begin.copy_from_slice(&value[4..9]);
-                begin
-            },
-            length: {
-                let mut length = [0; 4];
-                length.copy_from_slice(&value[9..12]);
-                length
-            },
-        }
+impl TryFrom<&[u8]> for RequestBlock {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            value.len() == REQUEST_PAYLOAD_LENGTH,
+            "request payload of {} bytes, expected {REQUEST_PAYLOAD_LENGTH}",
+            value.len()
+        );
+        let mut index = [0; 4];
+        index.copy_from_slice(&value[..4]);
+        let mut begin = [0; 4];
+        begin.copy_from_slice(&value[4..8]);
+        let mut length = [0; 4];
+        length.copy_from_slice(&value[8..12]);
+        Ok(RequestBlock {
+            index,
+            begin,
+            length,
+        })
     }
 }
 
@@ -201,12 +328,20 @@ impl From<&[u8]> for ReceivedBlock {
                 begin.copy_from_slice(&value[4..8]);
                 begin
             },
-            block: value[8..].to_vec(),
+            block: value[PIECE_HEADER_LENGTH..].to_vec(),
         }
     }
 }
 
 impl ReceivedBlock {
+    fn new(index: u32, begin: u32, block: Vec<u8>) -> Self {
+        ReceivedBlock {
+            index: index.to_be_bytes(),
+            begin: begin.to_be_bytes(),
+            block,
+        }
+    }
+
     fn into_vec(self) -> Vec<u8> {
         vec![
             self.index.as_slice(),
@@ -219,7 +354,6 @@ impl ReceivedBlock {
         .collect()
     }
 
-    #[allow(dead_code)]
     pub fn index(&self) -> u32 {
         u32::from_be_bytes(self.index)
     }
@@ -231,46 +365,86 @@ impl ReceivedBlock {
     pub fn data(&self) -> &[u8] {
         &self.block
     }
+
+    fn matches(&self, requested: &PieceBlock) -> bool {
+        self.index() == requested.piece_index
+            && self.begin() == requested.block_offset
+            && self.block.len() == requested.block_size as usize
+    }
 }
 
 impl PeerMessage {
-    fn new(message_id: u8, payload: Option<Vec<u8>>) -> Result<PeerMessage> {
+    fn new(message_id: MessageId, payload: Option<Vec<u8>>) -> Result<PeerMessage> {
         let message = match message_id {
-            0 => PeerMessage::Choke,
-            1 => PeerMessage::Unchoke,
-            2 => PeerMessage::Interested,
-            3 => PeerMessage::NotInterested,
-            4 => PeerMessage::Have(payload.context("payload expected")?[0]),
-            5 => PeerMessage::Bitfield(payload.context("payload expected")?),
-            6 => PeerMessage::Request(payload.context("payload expected")?.as_slice().into()),
-            7 => PeerMessage::Piece(payload.context("payload expected")?.as_slice().into()),
-            8 => PeerMessage::Cancel,
-            _ => bail!("Unknown message id {message_id}"),
+            MessageId::Choke => PeerMessage::Choke,
+            MessageId::Unchoke => PeerMessage::Unchoke,
+            MessageId::Interested => PeerMessage::Interested,
+            MessageId::NotInterested => PeerMessage::NotInterested,
+            MessageId::Have => {
+                let payload = payload.context("payload expected")?;
+                let index: [u8; HAVE_PAYLOAD_LENGTH] =
+                    payload.as_slice().try_into().map_err(|_| {
+                        anyhow!(
+                            "have payload of {} bytes, expected {HAVE_PAYLOAD_LENGTH}",
+                            payload.len()
+                        )
+                    })?;
+                PeerMessage::Have(u32::from_be_bytes(index))
+            }
+            MessageId::Bitfield => PeerMessage::Bitfield(payload.context("payload expected")?),
+            MessageId::Request => {
+                PeerMessage::Request(payload.context("payload expected")?.as_slice().try_into()?)
+            }
+            MessageId::Piece => {
+                let payload = payload.context("payload expected")?;
+                anyhow::ensure!(
+                    payload.len() >= PIECE_HEADER_LENGTH,
+                    "piece payload of {} bytes is too short",
+                    payload.len()
+                );
+                PeerMessage::Piece(payload.as_slice().into())
+            }
+            MessageId::Cancel => {
+                PeerMessage::Cancel(payload.context("payload expected")?.as_slice().try_into()?)
+            }
+            MessageId::RejectRequest => {
+                PeerMessage::RejectRequest(payload.context("payload expected")?)
+            }
+            MessageId::Extended => {
+                let payload = payload.context("payload expected")?;
+                let (id, payload) = payload.split_first().context("empty extended payload")?;
+                PeerMessage::Extended(*id, payload.to_vec())
+            }
         };
         Ok(message)
     }
 
     fn get_message_bytes(self) -> Vec<u8> {
         match self {
-            PeerMessage::Have(byte) => vec![byte],
+            PeerMessage::Have(index) => index.to_be_bytes().to_vec(),
             PeerMessage::Request(bytes) => bytes.into_vec(),
+            PeerMessage::Cancel(bytes) => bytes.into_vec(),
             PeerMessage::Piece(bytes) => bytes.into_vec(),
             PeerMessage::Bitfield(vec) => vec,
+            PeerMessage::RejectRequest(vec) => vec,
+            PeerMessage::Extended(id, payload) => [&[id], payload.as_slice()].concat(),
             _ => Vec::new(),
         }
     }
 
-    fn get_message_id(&self) -> Result<u8> {
+    fn get_message_id(&self) -> Result<MessageId> {
         let message_id = match self {
-            PeerMessage::Choke => 0,
-            PeerMessage::Unchoke => 1,
-            PeerMessage::Interested => 2,
-            PeerMessage::NotInterested => 3,
-            PeerMessage::Have(_) => 4,
-            PeerMessage::Bitfield(_) => 5,
-            PeerMessage::Request(_) => 6,
-            PeerMessage::Piece(_) => 7,
-            PeerMessage::Cancel => 8,
+            PeerMessage::Choke => MessageId::Choke,
+            PeerMessage::Unchoke => MessageId::Unchoke,
+            PeerMessage::Interested => MessageId::Interested,
+            PeerMessage::NotInterested => MessageId::NotInterested,
+            PeerMessage::Have(_) => MessageId::Have,
+            PeerMessage::Bitfield(_) => MessageId::Bitfield,
+            PeerMessage::Request(_) => MessageId::Request,
+            PeerMessage::Piece(_) => MessageId::Piece,
+            PeerMessage::Cancel(_) => MessageId::Cancel,
+            PeerMessage::RejectRequest(_) => MessageId::RejectRequest,
+            PeerMessage::Extended(..) => MessageId::Extended,
             PeerMessage::Heartbeat => bail!("Heartbeat has no message"),
         };
 
@@ -286,7 +460,8 @@ impl fmt::Display for PeerMessage {
 
 struct PeerProtocolFramer;
 
-const PEER_MESSAGE_LENGTH: usize = 4;
+/// Way above any sane block or bitfield, bigger length is a broken or hostile peer
+const MAX_PEER_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
 
 impl Decoder for PeerProtocolFramer {
     type Item = PeerMessage;
@@ -300,39 +475,47 @@ impl Decoder for PeerProtocolFramer {
     ) -> std::result::Result<Option<Self::Item>, Self::Error> {
         trace!("buf len is {}", src.len());
 
-        if src.len() < PEER_MESSAGE_LENGTH {
+        if src.len() < MESSAGE_LENGTH_PREFIX {
+            src.reserve(MESSAGE_LENGTH_PREFIX - src.len());
             return Ok(None);
         }
 
-        let mut length = [0u8; PEER_MESSAGE_LENGTH];
-        length.copy_from_slice(&src[..PEER_MESSAGE_LENGTH]);
+        let mut length = [0u8; MESSAGE_LENGTH_PREFIX];
+        length.copy_from_slice(&src[..MESSAGE_LENGTH_PREFIX]);
 
         let length = u32::from_be_bytes(length) as usize;
         trace!("message len is {}", length);
 
         if length == 0 {
-            src.advance(PEER_MESSAGE_LENGTH);
+            src.advance(MESSAGE_LENGTH_PREFIX);
             return Ok(Some(PeerMessage::Heartbeat));
         }
 
-        if src.len() < PEER_MESSAGE_LENGTH + length {
+        anyhow::ensure!(
+            length <= MAX_PEER_MESSAGE_LENGTH,
+            "message length {length} is above {MAX_PEER_MESSAGE_LENGTH}"
+        );
+
+        if src.len() < MESSAGE_LENGTH_PREFIX + length {
             trace!("not enough data, re-running to query more");
+            src.reserve(MESSAGE_LENGTH_PREFIX + length - src.len());
             return Ok(None);
         }
 
-        let data = &src[4..length + PEER_MESSAGE_LENGTH];
+        // NOTE: buffer may hold following messages, only declared length is ours
+        let data = &src[MESSAGE_LENGTH_PREFIX..MESSAGE_LENGTH_PREFIX + length];
 
-        let message_id = data[0];
+        trace!("message_id is {}", data[0]);
+        let message_id = MessageId::try_from(data[0])?;
 
-        trace!("message_id is {message_id}");
-        let payload = if src.len() > 5 {
-            Some(data[1..].to_vec())
+        let payload = if length > 1 {
+            Some(data[1..length].to_vec())
         } else {
             None
         };
 
         let message = PeerMessage::new(message_id, payload).context("Peer message parse")?;
-        src.advance(PEER_MESSAGE_LENGTH + length);
+        src.advance(MESSAGE_LENGTH_PREFIX + length);
         Ok(Some(message))
     }
 }
@@ -346,19 +529,20 @@ impl Encoder<PeerMessage> for PeerProtocolFramer {
         dst: &mut bytes::BytesMut,
     ) -> std::result::Result<(), Self::Error> {
         if let PeerMessage::Heartbeat = item {
-            dst.copy_from_slice(&[0u8; 4]);
+            dst.put_u32(0);
             return Ok(());
         }
 
         let message_id = item.get_message_id().context("get message id")?;
         let payload_bytes = item.get_message_bytes();
         trace!("payload length {}", payload_bytes.len());
-        let length = PEER_MESSAGE_LENGTH + 1 + payload_bytes.len();
+        // NOTE: length prefix counts id and payload, not itself
+        let length = u32::try_from(1 + payload_bytes.len()).context("message too long")?;
         trace!("message len {length}");
 
-        let length = length.to_be_bytes();
-        dst.extend_from_slice(&length);
-        dst.put_u8(message_id);
+        dst.reserve(MESSAGE_LENGTH_PREFIX + length as usize);
+        dst.put_u32(length);
+        dst.put_u8(message_id as u8);
         dst.extend_from_slice(&payload_bytes);
 
         trace!("destination buf {:?}", dst);
@@ -369,19 +553,123 @@ impl Encoder<PeerMessage> for PeerProtocolFramer {
 
 #[allow(dead_code)]
 pub struct Peer<'a> {
-    socket_addr: SocketAddrV4,
+    socket_addr: SocketAddr,
     remote_peer_id: PeerId,
+    remote_extensions: ReservedBits,
     stream: PeerTcpStream<PeerProtocolFramer>,
     torrent_info_hash: Bytes20,
     torrent_info: &'a TorrentInfo,
     bitfield: bitvec::vec::BitVec<u8, Msb0>,
+    /// Pieces peer doesn't have, kept up to date so seed check is O(1) per have
+    missing_pieces: usize,
     chocked: bool,
+    interested: bool,
+    /// We choke remote, it gets no blocks until unchoked
+    choking: bool,
+    remote_interested: bool,
+    misbehaving: bool,
+    /// Set once processing failed, connection is not usable anymore
+    disconnected: bool,
+    /// Lowered to standard block size once peer rejects bigger request
+    max_block_size: u32,
+    first_request: Option<(Instant, u32)>,
+    blocks_received: usize,
+    /// Blocks not delivered within `block_timeout`, in a row
+    missed_blocks: u32,
+    block_timeout: Duration,
+    /// Request still waiting for its block and when it was sent, cancelled if we stop waiting
+    in_flight: Option<(PieceBlock, Instant)>,
+    /// Cancelled requests, peer could have answered them already
+    cancelled: Vec<PieceBlock>,
+    /// BEP 10 handshake of remote, once received
+    remote_extended: Option<ExtendedHandshake>,
+    session_log: Option<&'a SessionLog>,
+    download_limiter: Option<&'a RateLimiter>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DisconnectReason {
+    #[error("possible oversized-request rejection")]
+    PossibleOversizedRequestRejection,
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(String),
+}
+
+impl DisconnectReason {
+    /// Stable label without details, for aggregating
+    pub fn label(&self) -> &'static str {
+        match self {
+            DisconnectReason::PossibleOversizedRequestRejection => {
+                "possible oversized-request rejection"
+            }
+            DisconnectReason::ProtocolViolation(_) => "protocol violation",
+        }
+    }
+}
+
+/// Peer choked us with a request pending, it's dropped by peer
+#[derive(Debug, thiserror::Error)]
+#[error("choked by peer")]
+struct Choked;
+
+/// Peer answered handshake for a different torrent
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "peer serves info hash {}, expected {}",
+    hex::encode(received),
+    hex::encode(expected)
+)]
+pub struct InfoHashMismatch {
+    pub expected: Bytes20,
+    pub received: Bytes20,
+    pub peer_id: PeerId,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    /// Most likely not a BitTorrent peer at all, e.g. http server on that port
+    #[error("peer speaks {got:?} instead of BitTorrent protocol{}", http_hint(.got))]
+    WrongProtocol { got: String },
+}
+
+impl HandshakeError {
+    fn wrong_protocol(received: &[u8]) -> Self {
+        HandshakeError::WrongProtocol {
+            got: String::from_utf8_lossy(received)
+                .chars()
+                .take(WRONG_PROTOCOL_CAPTURE)
+                .collect(),
+        }
+    }
+}
+
+fn http_hint(got: &str) -> &'static str {
+    if got.starts_with("HTTP/") {
+        ", looks like http server, tracker may hand out junk addresses"
+    } else {
+        ""
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "piece {piece_index} of {piece_length} bytes hash mismatch, expected {} got {}, from {peers:?}",
+    hex::encode(expected),
+    hex::encode(received)
+)]
+pub struct HashMismatch {
+    pub piece_index: usize,
+    pub piece_length: usize,
+    pub expected: Bytes20,
+    pub received: Bytes20,
+    pub peers: Vec<SocketAddr>,
 }
 
 impl Debug for Peer<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Peer")
             .field("socket_addr", &self.socket_addr)
+            .field("remote_extensions", &self.remote_extensions)
             .finish()
     }
 }
@@ -389,20 +677,20 @@ impl Debug for Peer<'_> {
 impl<'a> Peer<'a> {
     #[instrument]
     pub async fn connect(
-        socket_addr: SocketAddrV4,
+        socket_addr: SocketAddr,
         peer_id: PeerId,
         torrent_info_hash: Bytes20,
         torrent_info: &'a TorrentInfo,
+        connect_timeout: Duration,
     ) -> Result<Peer<'a>> {
-        let stream = TcpStream::connect(socket_addr)
-            .await
-            .context("establishing connection")?;
+        let stream = dial(socket_addr, connect_timeout).await?;
         let mut stream = PeerTcpStream::new(
             stream,
             HandshakeFramer,
             Duration::from_secs(TIMOUT_DURATION_SECONDS as u64),
         );
         let handshake = Handshake {
+            reserved: ReservedBits::advertised(),
             info_hash: torrent_info_hash,
             peer_id,
         };
@@ -411,44 +699,408 @@ impl<'a> Peer<'a> {
             .await
             .context("sending handshake")?;
 
-        let handshake = stream.next_message().await.context("getting handshake")?;
+        let handshake: Handshake = stream.next_message().await.context("getting handshake")?;
+        handshake.ensure_info_hash(torrent_info_hash)?;
+
+        let mut peer = Peer::new(
+            socket_addr,
+            handshake,
+            stream.change_codec(PeerProtocolFramer),
+            torrent_info_hash,
+            torrent_info,
+        );
 
-        let mut stream = stream.change_codec(PeerProtocolFramer);
+        if peer.remote_extensions.contains(ReservedBits::LTEP) {
+            let payload = ExtendedHandshake::local().to_payload()?;
+            peer.stream
+                .send_message(PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, payload))
+                .await
+                .context("sending extended handshake")?;
+        }
 
-        let received_msg = stream.next_message().await?;
-        let PeerMessage::Bitfield(bitfield_bytes) = received_msg else {
-            bail!("Expected type of message bitfield got {}", received_msg)
-        };
+        // NOTE: bitfield is optional, peers may open with haves or unchoke instead.
+        // First message past extended handshake ends the wait, bitfield and haves
+        // coming later are folded in by next_message
+        let first_message_deadline = tokio::time::Instant::now() + BITFIELD_WAIT;
+        loop {
+            let timeout = peer.stream.timeout;
+            let message = match tokio::time::timeout_at(
+                first_message_deadline,
+                peer.stream.next_peer_message(timeout),
+            )
+            .await
+            {
+                Ok(message) => message?,
+                Err(_) => {
+                    trace!("nothing sent by {socket_addr} after handshake");
+                    break;
+                }
+            };
+            let extended_handshake =
+                matches!(message, PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, _));
+            match peer.fold(message) {
+                Some(PeerMessage::Unchoke) => peer.set_choked(false),
+                Some(PeerMessage::Choke) => peer.set_choked(true),
+                Some(message) => trace!("ignoring {message} sent first"),
+                None => {}
+            }
+            if !extended_handshake {
+                break;
+            }
+        }
+
+        Ok(peer)
+    }
+
+    /// Connection accepted from remote, it handshakes first. We announce
+    /// pieces we `have`, remote asks for blocks and we serve them
+    #[instrument(skip(stream, torrent_info, have))]
+    pub async fn accept(
+        stream: TcpStream,
+        socket_addr: SocketAddr,
+        peer_id: PeerId,
+        torrent_info_hash: Bytes20,
+        torrent_info: &'a TorrentInfo,
+        have: &BitVec<u8, Msb0>,
+    ) -> Result<Peer<'a>> {
+        let mut stream = PeerTcpStream::new(
+            stream,
+            HandshakeFramer,
+            Duration::from_secs(TIMOUT_DURATION_SECONDS as u64),
+        );
+        let handshake: Handshake = stream.next_message().await.context("getting handshake")?;
+        handshake.ensure_info_hash(torrent_info_hash)?;
+        stream
+            .send_message(Handshake {
+                reserved: ReservedBits::advertised(),
+                info_hash: torrent_info_hash,
+                peer_id,
+            })
+            .await
+            .context("sending handshake")?;
+
+        let mut peer = Peer::new(
+            socket_addr,
+            handshake,
+            stream.change_codec(PeerProtocolFramer),
+            torrent_info_hash,
+            torrent_info,
+        );
+        peer.stream
+            .send_message(PeerMessage::Bitfield(have.clone().into_vec()))
+            .await
+            .context("sending bitfield")?;
 
-        let bitfield = BitVec::<_, Msb0>::from_vec(bitfield_bytes);
+        Ok(peer)
+    }
 
-        Ok(Peer {
+    fn new(
+        socket_addr: SocketAddr,
+        handshake: Handshake,
+        stream: PeerTcpStream<PeerProtocolFramer>,
+        torrent_info_hash: Bytes20,
+        torrent_info: &'a TorrentInfo,
+    ) -> Peer<'a> {
+        Peer {
             socket_addr,
             remote_peer_id: handshake.peer_id,
+            remote_extensions: handshake.reserved,
             stream,
             torrent_info_hash,
             torrent_info,
-            bitfield,
+            bitfield: BitVec::repeat(false, torrent_info.pieces.len()),
+            missing_pieces: torrent_info.pieces.len(),
             chocked: true,
-        })
+            interested: false,
+            choking: true,
+            remote_interested: false,
+            misbehaving: false,
+            disconnected: false,
+            max_block_size: u32::MAX,
+            first_request: None,
+            blocks_received: 0,
+            missed_blocks: 0,
+            block_timeout: Duration::from_secs(DEFAULT_BLOCK_REQUEST_TIMEOUT_SECONDS),
+            in_flight: None,
+            cancelled: Vec::new(),
+            remote_extended: None,
+            session_log: None,
+            download_limiter: None,
+        }
+    }
+
+    /// Blocks are requested only as fast as `limiter` allows, shared with other peers
+    pub fn attach_download_limiter(&mut self, limiter: Option<&'a RateLimiter>) {
+        self.download_limiter = limiter;
+    }
+
+    /// Records handshake now, choke transitions, requests and disconnect later on
+    pub fn attach_session_log(&mut self, session_log: Option<&'a SessionLog>) {
+        self.session_log = session_log;
+        self.log_session(|| SessionEvent::PeerConnected {
+            peer: self.socket_addr,
+            peer_id: hex::encode(Bytes20::from(self.remote_peer_id)),
+            extensions: self
+                .remote_extensions
+                .known_names()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            pieces: self.bitfield.count_ones(),
+            choked: self.chocked,
+        });
+    }
+
+    fn log_session(&self, event: impl FnOnce() -> SessionEvent) {
+        record_to(self.session_log, event);
+    }
+
+    fn set_choked(&mut self, choked: bool) {
+        if self.chocked != choked {
+            let peer = self.socket_addr;
+            self.log_session(|| match choked {
+                true => SessionEvent::Choked { peer },
+                false => SessionEvent::Unchoked { peer },
+            });
+        }
+        self.chocked = choked;
+    }
+
+    /// Marks connection unusable, first reason is the one recorded
+    fn disconnect(&mut self, reason: impl FnOnce() -> String) {
+        if self.disconnected {
+            return;
+        }
+        self.disconnected = true;
+        self.log_session(|| SessionEvent::PeerDisconnected {
+            peer: self.socket_addr,
+            reason: reason(),
+            last_messages: self.stream.ring.messages(),
+        });
+    }
+
+    /// Next message, keep-alives, extended handshake, haves, bitfield and repeated
+    /// unchokes are consumed on the way
+    async fn next_message(&mut self) -> Result<PeerMessage> {
+        // NOTE: requested block is waited for longer than block timeout,
+        // so it's reassigned to other peers before this one is given up on
+        let timeout = match self.in_flight {
+            Some(_) => self.block_timeout + self.stream.timeout,
+            None => self.stream.timeout,
+        };
+        loop {
+            let message = self.stream.next_peer_message(timeout).await?;
+            if let Some(message) = self.fold(message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Applies message that only updates what is known of peer, others are handed back
+    fn fold(&mut self, message: PeerMessage) -> Option<PeerMessage> {
+        match message {
+            PeerMessage::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+                match ExtendedHandshake::from_payload(&payload) {
+                    Ok(handshake) => {
+                        trace!("extended handshake {:?}", handshake);
+                        self.remote_extended = Some(handshake);
+                    }
+                    Err(e) => warn!(
+                        "peer {} sent invalid extended handshake: {e:#}",
+                        self.socket_addr
+                    ),
+                }
+            }
+            PeerMessage::Have(index) => self.apply_have(index as usize),
+            // NOTE: peers opening with haves or unchoke may send bitfield after
+            PeerMessage::Bitfield(bitfield_bytes) => self.apply_bitfield(bitfield_bytes),
+            // NOTE: repeated unchoke changes nothing
+            PeerMessage::Unchoke if !self.chocked => trace!("peer is already unchoked"),
+            PeerMessage::Piece(block) if self.take_cancelled(|c| block.matches(c)) => {
+                trace!("late block of cancelled request {:?}", block)
+            }
+            // NOTE: BEP 6 peers answer cancel with reject
+            PeerMessage::RejectRequest(payload)
+                if self.take_cancelled(|c| RequestBlock::from(*c).into_vec() == payload) =>
+            {
+                trace!("cancelled request rejected")
+            }
+            message => return Some(message),
+        }
+        None
+    }
+
+    fn apply_bitfield(&mut self, bitfield_bytes: Vec<u8>) {
+        let mut bitfield = BitVec::<_, Msb0>::from_vec(bitfield_bytes);
+        if self.torrent_info.is_resolved() {
+            // NOTE: spare bits of last byte aren't pieces
+            bitfield.resize(self.torrent_info.pieces.len(), false);
+        }
+        if bitfield.len() > self.bitfield.len() {
+            self.bitfield.resize(bitfield.len(), false);
+        }
+        for piece in bitfield.iter_ones() {
+            self.bitfield.set(piece, true);
+        }
+        self.missing_pieces = self
+            .torrent_info
+            .pieces
+            .len()
+            .saturating_sub(self.bitfield.count_ones());
+        if self.is_seed() {
+            debug!("peer {} is a seed", self.socket_addr);
+        }
+    }
+
+    /// Tells peer we're interested once and waits for unchoke if choked
+    async fn ensure_unchoked(&mut self) -> Result<()> {
+        if !self.interested {
+            self.stream
+                .send_message(PeerMessage::Interested)
+                .await
+                .context("Send interested")?;
+            self.interested = true;
+            self.log_session(|| SessionEvent::Interested {
+                peer: self.socket_addr,
+            });
+        }
+
+        if self.chocked {
+            let received_msg = self.next_message().await?;
+
+            let PeerMessage::Unchoke = received_msg else {
+                bail!("Expected type of message unchoke got {}", received_msg)
+            };
+        }
+
+        self.set_choked(false);
+        Ok(())
+    }
+
+    fn apply_have(&mut self, piece: usize) {
+        if self.torrent_info.is_resolved() && piece >= self.torrent_info.pieces.len() {
+            warn!("peer {} has piece {piece} out of range", self.socket_addr);
+            return;
+        }
+        if piece >= self.bitfield.len() {
+            self.bitfield.resize(piece + 1, false);
+        }
+        if self.bitfield.replace(piece, true) || !self.torrent_info.is_resolved() {
+            return;
+        }
+        self.missing_pieces -= 1;
+        if self.missing_pieces == 0 {
+            debug!("peer {} became a seed", self.socket_addr);
+            self.log_session(|| SessionEvent::PeerBecameSeed {
+                peer: self.socket_addr,
+            });
+        }
+    }
+
+    /// Peer has every piece, unknown until metadata is resolved
+    pub fn is_seed(&self) -> bool {
+        self.torrent_info.is_resolved() && self.missing_pieces == 0
+    }
+
+    pub fn summary(&self, active: bool) -> PeerSummary {
+        PeerSummary {
+            socket_addr: self.socket_addr,
+            seed: self.is_seed(),
+            choked: self.chocked,
+            blocks_received: self.blocks_received,
+            active,
+            last_messages: self.stream.ring.messages(),
+        }
+    }
+
+    /// Metadata size peer advertises in extended handshake, waiting for it if needed
+    #[instrument(skip(self), fields(self.socket_addr = %self.socket_addr))]
+    pub async fn metadata_size(&mut self) -> Result<usize> {
+        anyhow::ensure!(
+            self.remote_extensions.contains(ReservedBits::LTEP),
+            "peer doesn't support extension protocol"
+        );
+        while self.remote_extended.is_none() {
+            let message = self.next_message().await?;
+            trace!("ignoring {message} while waiting for extended handshake");
+        }
+        let remote_extended = self.remote_extended.as_ref().expect("checked above");
+        remote_extended
+            .ut_metadata_id()
+            .context("peer doesn't support ut_metadata")?;
+        let metadata_size = remote_extended
+            .metadata_size
+            .context("peer didn't send metadata size")?;
+        anyhow::ensure!(
+            metadata_size > 0 && metadata_size <= MAX_METADATA_SIZE,
+            "invalid metadata size {metadata_size}"
+        );
+        Ok(metadata_size)
+    }
+
+    /// Requests single ut_metadata (BEP 9) piece, data is checked against expected size only
+    #[instrument(skip(self), fields(self.socket_addr = %self.socket_addr))]
+    pub async fn fetch_metadata_piece(
+        &mut self,
+        piece: usize,
+        metadata_size: usize,
+    ) -> Result<Vec<u8>> {
+        let remote_id = self
+            .remote_extended
+            .as_ref()
+            .and_then(|f| f.ut_metadata_id())
+            .context("peer doesn't support ut_metadata")?;
+        let payload = MetadataMessage::request(piece).to_payload()?;
+        self.stream
+            .send_message(PeerMessage::Extended(remote_id, payload))
+            .await
+            .context("sending metadata request")?;
+
+        let expected_length = metadata_size
+            .saturating_sub(piece * METADATA_PIECE_SIZE)
+            .min(METADATA_PIECE_SIZE);
+        let message = loop {
+            match self.next_message().await? {
+                PeerMessage::Extended(UT_METADATA_LOCAL_ID, payload) => {
+                    let message = MetadataMessage::from_payload(&payload)?;
+                    if message.msg_type != MetadataMessageType::Request {
+                        break message;
+                    }
+                    trace!("ignoring metadata request of peer");
+                }
+                message => trace!("ignoring {message} while fetching metadata"),
+            }
+        };
+
+        anyhow::ensure!(
+            message.msg_type == MetadataMessageType::Data,
+            "peer rejected metadata piece {piece}"
+        );
+        anyhow::ensure!(
+            message.piece == piece && message.data.len() == expected_length,
+            "expected metadata piece {piece} of {expected_length} bytes got piece {} of {} bytes",
+            message.piece,
+            message.data.len()
+        );
+        Ok(message.data)
     }
 
     #[instrument]
     pub async fn handshake(
-        socket_addr: SocketAddrV4,
+        socket_addr: SocketAddr,
         peer_id: PeerId,
         torrent_info_hash: Bytes20,
         torrent_info: &'a TorrentInfo,
-    ) -> Result<PeerId> {
-        let stream = TcpStream::connect(socket_addr)
-            .await
-            .context("establishing connection")?;
+        connect_timeout: Duration,
+    ) -> Result<Handshake> {
+        let stream = dial(socket_addr, connect_timeout).await?;
         let mut stream = PeerTcpStream::new(
             stream,
             HandshakeFramer,
             Duration::from_secs(TIMOUT_DURATION_SECONDS as u64),
         );
         let handshake = Handshake {
+            reserved: ReservedBits::advertised(),
             info_hash: torrent_info_hash,
             peer_id,
         };
@@ -457,9 +1109,10 @@ impl<'a> Peer<'a> {
             .await
             .context("sending handshake")?;
 
-        let handshake = stream.next_message().await.context("getting handshake")?;
+        let handshake: Handshake = stream.next_message().await.context("getting handshake")?;
+        handshake.ensure_info_hash(torrent_info_hash)?;
 
-        Ok(handshake.peer_id)
+        Ok(handshake)
     }
 
     #[instrument(skip(self))]
@@ -467,16 +1120,107 @@ impl<'a> Peer<'a> {
         *self.bitfield.get(piece).as_deref().unwrap_or(&false)
     }
 
+    /// Pieces peer announced so far, grows as haves and late bitfield come in
+    pub fn known_pieces(&self) -> usize {
+        self.bitfield.count_ones()
+    }
+
     pub fn available_pieces(&self) -> Vec<usize> {
-        (0..=self.torrent_info.pieces.len())
+        (0..self.torrent_info.pieces.len())
             .filter(|piece_number| self.has_piece(*piece_number))
             .collect()
     }
 
-    pub fn socket_addr(&self) -> SocketAddrV4 {
+    /// Resolves once peer sent more, without consuming it
+    pub async fn readable(&self) -> Result<()> {
+        self.stream.readable().await
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
         self.socket_addr
     }
 
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Keeps idle connection alive both ways. Heartbeat is sent if nothing was
+    /// sent for a while, peers drop connections silent for longer than two minutes.
+    /// Whatever peer sent meanwhile is consumed, peer silent for too long is dropped
+    pub async fn keep_alive(&mut self) {
+        if self.disconnected {
+            return;
+        }
+        self.drain_idle();
+        self.heartbeat().await;
+    }
+
+    /// Drops peer silent for too long, sends heartbeat if we were
+    async fn heartbeat(&mut self) {
+        if self.disconnected {
+            return;
+        }
+        if self.stream.last_received.elapsed() >= PEER_SILENCE_TIMEOUT {
+            debug!(
+                "peer {} sent nothing for {:?}, dropping",
+                self.socket_addr,
+                self.stream.last_received.elapsed()
+            );
+            self.disconnect(|| "silent for too long".to_string());
+            return;
+        }
+        if self.stream.last_sent.elapsed() < KEEP_ALIVE_INTERVAL {
+            return;
+        }
+        trace!("sending heartbeat to {}", self.socket_addr);
+        if let Err(e) = self.stream.send_message(PeerMessage::Heartbeat).await {
+            debug!("heartbeat to {} failed: {e:#}", self.socket_addr);
+            self.disconnect(|| format!("heartbeat failed: {e:#}"));
+        }
+    }
+
+    /// Consumes messages already received without waiting for more
+    pub fn drain_idle(&mut self) {
+        while let Some(message) = self.next_message().now_or_never() {
+            match message {
+                Ok(PeerMessage::Choke) => self.set_choked(true),
+                Ok(PeerMessage::Unchoke) => self.set_choked(false),
+                Ok(message) => trace!("ignoring {message} from idle peer"),
+                Err(e) => {
+                    debug!("idle peer {} failed: {e:#}", self.socket_addr);
+                    self.disconnect(|| format!("{e:#}"));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Catches up on choke sent meanwhile and waits it out before next request.
+    /// Choke may last long so only keep-alive deadlines apply
+    async fn wait_unchoked(&mut self) -> Result<()> {
+        self.drain_idle();
+        if self.chocked {
+            trace!("choked by {}, pausing requests", self.socket_addr);
+        }
+        while self.chocked && !self.disconnected {
+            let keep_alive_due = self.keep_alive_due();
+            tokio::select! {
+                readable = self.stream.readable() => readable.context("waiting for unchoke")?,
+                () = tokio::time::sleep_until(keep_alive_due) => self.keep_alive().await,
+            }
+            self.drain_idle();
+        }
+        anyhow::ensure!(!self.disconnected, "lost choking peer {}", self.socket_addr);
+        Ok(())
+    }
+
+    /// When idle peer needs attention of [`Peer::keep_alive`]
+    pub fn keep_alive_due(&self) -> tokio::time::Instant {
+        let due = (self.stream.last_sent + KEEP_ALIVE_INTERVAL)
+            .min(self.stream.last_received + PEER_SILENCE_TIMEOUT);
+        tokio::time::Instant::from_std(due)
+    }
+
     #[instrument(skip(self))]
     fn get_piece_hash(&self, piece: usize) -> Result<&[u8]> {
         self.torrent_info
@@ -486,46 +1230,153 @@ impl<'a> Peer<'a> {
             .ok_or(anyhow!("Piece not found"))
     }
 
-    #[instrument(skip(self, requested_block, save_block), fields(self.socket_addr = %self.socket_addr))]
+    #[instrument(skip(self, request_block, requested_block, save_block, saved_blocks), fields(self.socket_addr = %self.socket_addr))]
     pub async fn process(
         &mut self,
-        _: Sender<PieceBlock>,
+        request_block: Sender<PieceBlock>,
         requested_block: Receiver<PieceBlock>,
         save_block: Sender<ReceivedBlock>,
+        saved_blocks: watch::Receiver<BTreeSet<u32>>,
+        block_timeout: Duration,
     ) -> Result<PeerId> {
-        if self.chocked {
-            self.stream
-                .send_message(PeerMessage::Interested)
-                .await
-                .context("Send interested")?;
-
-            let received_msg = self.stream.next_message().await?;
-
-            let PeerMessage::Unchoke = received_msg else {
-                bail!("Expected type of message unchoke got {}", received_msg)
-            };
+        self.block_timeout = block_timeout;
+        let result = self
+            .process_blocks(
+                request_block,
+                requested_block,
+                save_block,
+                saved_blocks,
+                block_timeout,
+            )
+            .await;
+        if let Err(e) = &result {
+            self.disconnect(|| format!("{e:#}"));
         }
+        result
+    }
 
-        self.chocked = false;
+    async fn process_blocks(
+        &mut self,
+        request_block: Sender<PieceBlock>,
+        requested_block: Receiver<PieceBlock>,
+        save_block: Sender<ReceivedBlock>,
+        mut saved_blocks: watch::Receiver<BTreeSet<u32>>,
+        block_timeout: Duration,
+    ) -> Result<PeerId> {
+        self.ensure_unchoked().await?;
 
-        while let Ok(block) = requested_block.recv().await {
+        let mut requested = HashSet::new();
+        loop {
+            // NOTE: checked before taking a block, so it isn't lost with the peer
+            anyhow::ensure!(
+                self.missed_blocks < MAX_MISSED_BLOCKS,
+                "peer {} missed {MAX_MISSED_BLOCKS} blocks in a row",
+                self.socket_addr
+            );
+            self.wait_unchoked().await?;
+            let block = tokio::select! {
+                block = requested_block.recv() => match block {
+                    Ok(block) => block,
+                    Err(_) => break,
+                },
+                () = tokio::time::sleep_until(self.keep_alive_due()) => {
+                    self.keep_alive().await;
+                    anyhow::ensure!(!self.disconnected, "lost idle peer {}", self.socket_addr);
+                    continue;
+                }
+            };
             trace!("received to process {}", block.piece_index,);
-            let piece_index = block.piece_index;
-            let request_block = PeerMessage::Request(block.into());
-            self.stream
-                .send_message(request_block)
-                .await
-                .context("sending request message")?;
+            // NOTE: endgame hands out copies, each peer asks for a block once
+            if saved_blocks.borrow().contains(&block.block_offset)
+                || !requested.insert(block.block_offset)
+            {
+                continue;
+            }
+            // NOTE: waiting for the rate isn't counted against block timeout
+            if let Some(limiter) = self.download_limiter {
+                limiter.acquire(block.block_size).await;
+            }
+            let socket_addr = self.socket_addr;
+            let mut reassigned = false;
+            let mut abandoned = false;
+            let delivered = {
+                let request = self.request_block(block);
+                tokio::pin!(request);
+                let timeout = tokio::time::sleep(block_timeout);
+                tokio::pin!(timeout);
+                loop {
+                    tokio::select! {
+                        piece_data = &mut request => break Some(piece_data),
+                        () = block_saved(&mut saved_blocks, block.block_offset) => break None,
+                        // NOTE: peer keeps talking but not delivering, other peers get
+                        // a copy while we keep waiting, whoever is first wins
+                        () = &mut timeout, if !reassigned => {
+                            debug!(
+                                "peer {socket_addr} didn't deliver {:?} in {:?}, reassigning",
+                                block, block_timeout
+                            );
+                            reassigned = true;
+                            request_block
+                                .send(block)
+                                .await
+                                .context("reassigning block")?;
+                            // NOTE: nobody else works on the piece, leave it so it's
+                            // queued again for other peers instead of waiting for stall timeout
+                            if requested_block.receiver_count() == 1 {
+                                abandoned = true;
+                                break None;
+                            }
+                        }
+                    }
+                }
+            };
+            let piece_data = match delivered {
+                Some(Ok(piece_data)) => piece_data,
+                Some(Err(err)) if err.is::<Choked>() => {
+                    // NOTE: other peers take the block meanwhile, unless they have a copy already
+                    requested.remove(&block.block_offset);
+                    if !reassigned && request_block.send(block).await.is_err() {
+                        trace!("piece is gone, block {:?} not returned", block);
+                    }
+                    continue;
+                }
+                Some(Err(err)) => {
+                    // NOTE: block goes back for other peers, piece would never complete otherwise
+                    if request_block.send(block).await.is_err() {
+                        trace!("piece is gone, block {:?} not returned", block);
+                    }
+                    return Err(self.disconnect_reason(err));
+                }
+                None if abandoned => {
+                    debug!(
+                        "peer {socket_addr} is alone on piece {}, leaving it",
+                        block.piece_index
+                    );
+                    return Ok(self.remote_peer_id);
+                }
+                None => {
+                    trace!("block {} came from another peer", block.block_offset);
+                    self.cancel_in_flight().await?;
+                    continue;
+                }
+            };
 
-            let received_msg = self.stream.next_message().await?;
-
-            let PeerMessage::Piece(piece_data) = received_msg else {
-                bail!("Expected type of message piece got {}", received_msg)
-            };
-
-            assert_eq!(u32::from_be_bytes(piece_data.index), piece_index);
+            if !piece_data.matches(&block) {
+                // NOTE: give block back for other peers and drop misbehaving one
+                self.misbehaving = true;
+                request_block
+                    .send(block)
+                    .await
+                    .context("returning block to process")?;
+                return Err(DisconnectReason::ProtocolViolation(format!(
+                    "received block {:?} doesn't match requested {:?}",
+                    piece_data, block
+                ))
+                .into());
+            }
 
             trace!("piece downloaded");
+            self.blocks_received += 1;
             save_block
                 .send(piece_data)
                 .await
@@ -536,26 +1387,301 @@ impl<'a> Peer<'a> {
         Ok(self.remote_peer_id)
     }
 
-    #[instrument(skip(self, piece_blocks))]
-    pub async fn receive_file_piece(
+    /// Requests a block, splitting it into standard sized blocks
+    /// if peer rejected oversized requests before
+    async fn request_block(&mut self, block: PieceBlock) -> Result<ReceivedBlock> {
+        if block.block_size <= self.max_block_size {
+            match self.send_request(block).await? {
+                PeerMessage::Piece(piece_data) => return Ok(piece_data),
+                PeerMessage::RejectRequest(_) if block.block_size > MAX_STANDARD_BLOCK_SIZE => {
+                    warn!(
+                        "peer {} rejected {} bytes request, falling back to {MAX_STANDARD_BLOCK_SIZE}",
+                        self.socket_addr, block.block_size
+                    );
+                    self.max_block_size = MAX_STANDARD_BLOCK_SIZE;
+                }
+                received_msg => bail!("Expected type of message piece got {}", received_msg),
+            }
+        }
+
+        let mut data = Vec::with_capacity(block.block_size as usize);
+        for offset in (0..block.block_size).step_by(self.max_block_size as usize) {
+            let sub_block = PieceBlock {
+                piece_index: block.piece_index,
+                block_offset: block.block_offset + offset,
+                block_size: (block.block_size - offset).min(self.max_block_size),
+            };
+            let received_msg = self.send_request(sub_block).await?;
+            let PeerMessage::Piece(piece_data) = received_msg else {
+                bail!("Expected type of message piece got {}", received_msg)
+            };
+            if !piece_data.matches(&sub_block) {
+                self.misbehaving = true;
+                return Err(DisconnectReason::ProtocolViolation(format!(
+                    "received block {:?} doesn't match requested {:?}",
+                    piece_data, sub_block
+                ))
+                .into());
+            }
+            data.extend_from_slice(piece_data.data());
+        }
+
+        Ok(ReceivedBlock::new(
+            block.piece_index,
+            block.block_offset,
+            data,
+        ))
+    }
+
+    async fn send_request(&mut self, block: PieceBlock) -> Result<PeerMessage> {
+        self.first_request
+            .get_or_insert_with(|| (Instant::now(), block.block_size));
+        // NOTE: answer to a cancel of the same block is as good as a fresh one
+        self.cancelled.retain(|cancelled| *cancelled != block);
+        let sent_at = Instant::now();
+        self.in_flight = Some((block, sent_at));
+        self.stream
+            .send_message(PeerMessage::Request(block.into()))
+            .await
+            .context("sending request message")?;
+        self.log_session(|| SessionEvent::BlockRequested {
+            peer: self.socket_addr,
+            piece: block.piece_index as usize,
+            offset: block.block_offset,
+            length: block.block_size,
+        });
+
+        let message = self.next_message().await?;
+        if let PeerMessage::Choke = message {
+            // NOTE: block might still come, or be rejected by BEP 6 peer
+            self.set_choked(true);
+            self.cancelled.push(block);
+            self.in_flight = None;
+            return Err(Choked.into());
+        }
+        self.in_flight = None;
+        self.missed_blocks = if self.is_late(sent_at) {
+            self.missed_blocks + 1
+        } else {
+            0
+        };
+        Ok(message)
+    }
+
+    fn is_late(&self, sent_at: Instant) -> bool {
+        sent_at.elapsed() >= self.block_timeout
+    }
+
+    /// Cancels request we stopped waiting for, its late block
+    /// is dropped instead of taken as answer to the next request
+    pub async fn cancel_in_flight(&mut self) -> Result<()> {
+        let Some((block, sent_at)) = self.in_flight.take() else {
+            return Ok(());
+        };
+        // NOTE: counted here too, late request is usually cancelled once others deliver
+        if self.is_late(sent_at) {
+            self.missed_blocks += 1;
+        }
+        trace!("cancelling {:?}", block);
+        self.cancelled.push(block);
+        let result = self
+            .stream
+            .send_message(PeerMessage::Cancel(block.into()))
+            .await
+            .context("sending cancel message");
+        if let Err(e) = &result {
+            self.disconnect(|| format!("{e:#}"));
+        }
+        result
+    }
+
+    fn take_cancelled(&mut self, answers: impl Fn(&PieceBlock) -> bool) -> bool {
+        match self.cancelled.iter().position(answers) {
+            Some(position) => {
+                self.cancelled.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Peer dropping connection right after our first oversized request
+    /// most likely didn't like its size
+    fn disconnect_reason(&self, err: anyhow::Error) -> anyhow::Error {
+        match self.first_request {
+            Some((requested_at, block_size))
+                if block_size > MAX_STANDARD_BLOCK_SIZE
+                    && self.blocks_received == 0
+                    && requested_at.elapsed() < OVERSIZED_REJECTION_WINDOW =>
+            {
+                err.context(DisconnectReason::PossibleOversizedRequestRejection)
+            }
+            _ => err,
+        }
+    }
+
+    /// Serves blocks of pieces we `have` until remote leaves. Remote is unchoked
+    /// once interested and one of upload `slots` is free, the slot is kept
+    /// until remote loses interest. Size of every block sent goes to `uploaded`
+    #[instrument(skip_all, fields(self.socket_addr = %self.socket_addr))]
+    pub async fn serve(
         &mut self,
-        piece_num: usize,
-        piece_blocks: Vec<PieceBlock>,
-    ) -> Result<Vec<u8>> {
-        if self.chocked {
+        storage: &std::sync::Mutex<Storage>,
+        have: &BitVec<u8, Msb0>,
+        slots: Arc<Semaphore>,
+        uploaded: impl Fn(u64),
+    ) -> Result<()> {
+        let mut slot = None;
+        loop {
+            while let Some(message) = self.next_message().now_or_never() {
+                match message? {
+                    PeerMessage::Interested => self.remote_interested = true,
+                    PeerMessage::NotInterested => {
+                        self.remote_interested = false;
+                        if slot.take().is_some() {
+                            self.set_choking(true).await?;
+                        }
+                    }
+                    PeerMessage::Request(request) => {
+                        let sent = self.serve_block(request, storage, have).await?;
+                        if sent > 0 {
+                            uploaded(sent);
+                        }
+                    }
+                    // NOTE: blocks are sent right away, nothing is queued to cancel
+                    PeerMessage::Cancel(request) => trace!("cancel of sent block {:?}", request),
+                    message => trace!("ignoring {message} from leecher"),
+                }
+            }
+            self.heartbeat().await;
+            anyhow::ensure!(!self.disconnected, "lost leecher {}", self.socket_addr);
+
+            let keep_alive_due = self.keep_alive_due();
+            tokio::select! {
+                readable = self.stream.readable() => readable.context("waiting for requests")?,
+                permit = slots.clone().acquire_owned(), if self.remote_interested && slot.is_none() => {
+                    slot = Some(permit.context("upload slots closed")?);
+                    self.set_choking(false).await?;
+                }
+                () = tokio::time::sleep_until(keep_alive_due) => {}
+            }
+        }
+    }
+
+    async fn set_choking(&mut self, choking: bool) -> Result<()> {
+        let message = if choking {
+            PeerMessage::Choke
+        } else {
+            PeerMessage::Unchoke
+        };
+        trace!("sending {message} to {}", self.socket_addr);
+        self.stream.send_message(message).await?;
+        self.choking = choking;
+        Ok(())
+    }
+
+    async fn serve_block(
+        &mut self,
+        request: RequestBlock,
+        storage: &std::sync::Mutex<Storage>,
+        have: &BitVec<u8, Msb0>,
+    ) -> Result<u64> {
+        // NOTE: requests sent before remote saw our choke are dropped, as BEP 3 says
+        if self.choking {
+            trace!("request {:?} while choked, dropped", request);
+            return Ok(0);
+        }
+        let (index, begin, length) = (request.index(), request.begin(), request.length());
+        if length == 0 || length > MAX_STANDARD_BLOCK_SIZE {
+            // NOTE: without BEP 6 there is no way to refuse, same as most clients we disconnect
+            anyhow::ensure!(
+                self.remote_extensions.contains(ReservedBits::FAST),
+                DisconnectReason::ProtocolViolation(format!("request of {length} bytes"))
+            );
+            debug!("rejecting request of {length} bytes");
             self.stream
-                .send_message(PeerMessage::Interested)
-                .await
-                .context("Send interested")?;
+                .send_message(PeerMessage::RejectRequest(request.into_vec()))
+                .await?;
+            return Ok(0);
+        }
+        let index = index as usize;
+        anyhow::ensure!(
+            *have.get(index).as_deref().unwrap_or(&false),
+            DisconnectReason::ProtocolViolation(format!("request of piece {index} we don't have"))
+        );
+        anyhow::ensure!(
+            begin as usize + length as usize <= self.torrent_info.piece_size(index),
+            DisconnectReason::ProtocolViolation(format!(
+                "request of {length} bytes at {begin} is past end of piece {index}"
+            ))
+        );
 
-            let received_msg = self.stream.next_message().await?;
+        let mut block = vec![0; length as usize];
+        let offset = (index * self.torrent_info.piece_length) as u64 + begin as u64;
+        storage
+            .lock()
+            .expect("storage lock")
+            .read(offset, &mut block)
+            .context("reading block to serve")?;
+        trace!("serving {length} bytes of piece {index} at {begin}");
+        self.stream
+            .send_message(PeerMessage::Piece(ReceivedBlock::new(
+                index as u32,
+                begin,
+                block,
+            )))
+            .await?;
+        Ok(length as u64)
+    }
 
-            let PeerMessage::Unchoke = received_msg else {
-                bail!("Expected type of message unchoke got {}", received_msg)
-            };
+    /// Politely closes connection: withdraws our interest and unchoke if
+    /// they were sent, shuts down write half and lingers for peer to close its side.
+    /// Misbehaving peers and dead connections are just dropped
+    #[instrument(skip(self), fields(self.socket_addr = %self.socket_addr))]
+    pub async fn shutdown(mut self) {
+        let usable = !self.disconnected && !self.stream.closed;
+        self.disconnect(|| CLOSED_REASON.to_string());
+        if self.misbehaving {
+            trace!("dropping misbehaving peer");
+            return;
+        }
+        if !usable {
+            trace!("dropping dead connection");
+            return;
         }
 
-        self.chocked = false;
+        let teardown = async {
+            if self.interested {
+                self.stream
+                    .send_message(PeerMessage::NotInterested)
+                    .await
+                    .context("sending not interested")?;
+            }
+            if !self.choking {
+                self.stream
+                    .send_message(PeerMessage::Choke)
+                    .await
+                    .context("sending choke")?;
+            }
+            self.stream.shutdown_write().await?;
+            self.stream.wait_closed().await;
+            Ok::<_, anyhow::Error>(())
+        };
+
+        match tokio::time::timeout(Duration::from_secs(SHUTDOWN_LINGER_SECONDS), teardown).await {
+            Ok(Ok(())) => trace!("peer closed connection"),
+            Ok(Err(e)) => trace!("teardown failed {e:#}"),
+            Err(_) => trace!("peer didn't close connection in time"),
+        }
+    }
+
+    #[instrument(skip(self, piece_blocks))]
+    pub async fn receive_file_piece(
+        &mut self,
+        piece_num: usize,
+        piece_blocks: Vec<PieceBlock>,
+    ) -> Result<Vec<u8>> {
+        self.ensure_unchoked().await?;
 
         let blocks_len = piece_blocks.len();
         let mut result = Vec::new();
@@ -571,13 +1697,20 @@ impl<'a> Peer<'a> {
                 .await
                 .context("request block {i}")?;
 
-            let received_msg = self.stream.next_message().await?;
+            let received_msg = self.next_message().await?;
 
             let PeerMessage::Piece(piece_data) = received_msg else {
-                bail!("Expected type of mesrsage unchoke got {}", received_msg)
+                bail!("Expected type of message piece got {}", received_msg)
             };
 
-            assert_eq!(u32::from_be_bytes(piece_data.index), piece_num as u32);
+            if !piece_data.matches(&block) {
+                self.misbehaving = true;
+                return Err(DisconnectReason::ProtocolViolation(format!(
+                    "received block {:?} doesn't match requested {:?}",
+                    piece_data, block
+                ))
+                .into());
+            }
 
             result.extend_from_slice(&piece_data.block);
         }
@@ -586,15 +1719,61 @@ impl<'a> Peer<'a> {
 
         let piece_hash = self.get_piece_hash(piece_num).context("get piece hash")?;
 
-        anyhow::ensure!(piece_hash == received_hash, "Hash incorrect");
+        if piece_hash != received_hash {
+            let mut expected = [0u8; 20];
+            expected.copy_from_slice(piece_hash);
+            return Err(HashMismatch {
+                piece_index: piece_num,
+                piece_length: result.len(),
+                expected,
+                received: received_hash,
+                peers: vec![self.socket_addr],
+            }
+            .into());
+        }
 
         Ok(result)
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DialError {
+    #[error("peer {0} is unreachable, connect timed out after {1:?}")]
+    Unreachable(SocketAddr, Duration),
+    #[error("peer {0} refused connection")]
+    Refused(SocketAddr, #[source] std::io::Error),
+}
+
+/// Resolves once block at `block_offset` is saved, never if piece is gone
+async fn block_saved(saved_blocks: &mut watch::Receiver<BTreeSet<u32>>, block_offset: u32) {
+    if saved_blocks
+        .wait_for(|saved| saved.contains(&block_offset))
+        .await
+        .is_err()
+    {
+        std::future::pending().await
+    }
+}
+
+async fn dial(socket_addr: SocketAddr, connect_timeout: Duration) -> Result<TcpStream> {
+    let stream = tokio::time::timeout(connect_timeout, TcpStream::connect(socket_addr))
+        .await
+        .map_err(|_| DialError::Unreachable(socket_addr, connect_timeout))?
+        .map_err(|e| DialError::Refused(socket_addr, e))?;
+
+    Ok(stream)
+}
+
+/// Every message in and out goes through here, so ring sees all of them.
+/// A new connection starts with an empty ring
 struct PeerTcpStream<C> {
     stream: Framed<TcpStream, C>,
     timeout: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    ring: MessageRing,
+    /// Remote closed its side or stream failed, nothing more can be exchanged
+    closed: bool,
 }
 
 impl<C> PeerTcpStream<C> {
@@ -602,58 +1781,1200 @@ impl<C> PeerTcpStream<C> {
         Self {
             stream: Framed::new(stream, framer),
             timeout,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            ring: MessageRing::new(),
+            closed: false,
         }
     }
 
-    fn change_codec<NC>(self, framer: NC) -> PeerTcpStream<NC> {
-        let stream = self.stream.into_inner();
+    /// Swaps codec keeping already buffered bytes, peer may send
+    /// bitfield in the same segment as handshake
+    fn change_codec<NC, I>(self, framer: NC) -> PeerTcpStream<NC>
+    where
+        NC: Encoder<I>,
+    {
+        let parts = self.stream.into_parts();
+        let mut new_parts = FramedParts::new(parts.io, framer);
+        new_parts.read_buf = parts.read_buf;
+        new_parts.write_buf = parts.write_buf;
         PeerTcpStream {
-            stream: Framed::new(stream, framer),
+            stream: Framed::from_parts(new_parts),
             timeout: self.timeout,
+            last_sent: self.last_sent,
+            last_received: self.last_received,
+            ring: self.ring,
+            closed: self.closed,
         }
     }
 
+    /// Keep-alives only prove the peer is there, they are skipped and
+    /// every one of them restarts the read timeout
     #[instrument(skip(self))]
-    async fn next_message_without_skip_heart_beat(&mut self) -> Result<PeerMessage>
+    async fn next_peer_message(&mut self, timeout: Duration) -> Result<PeerMessage>
     where
         C: Decoder<Item = PeerMessage, Error = anyhow::Error>,
     {
         loop {
-            let message: PeerMessage = self.next_message().await?;
-            if let PeerMessage::Heartbeat = message {
-                continue;
+            match self.next_message_within(timeout).await? {
+                PeerMessage::Heartbeat => trace!("keep-alive"),
+                message => return Ok(message),
             }
-
-            return Ok(message);
         }
     }
 
-    #[instrument(skip(self))]
+    /// Raw frame read, keep-alives included
     async fn next_message<U>(&mut self) -> Result<U>
     where
-        U: Debug,
+        U: Debug + Summarize,
         C: Decoder<Item = U, Error = anyhow::Error>,
     {
-        let message = tokio::time::timeout(self.timeout, self.stream.next())
+        self.next_message_within(self.timeout).await
+    }
+
+    #[instrument(skip(self))]
+    async fn next_message_within<U>(&mut self, timeout: Duration) -> Result<U>
+    where
+        U: Debug + Summarize,
+        C: Decoder<Item = U, Error = anyhow::Error>,
+    {
+        let message = tokio::time::timeout(timeout, self.stream.next())
             .await
-            .map(|m| m.context("stream closed"))
-            .context(format!("timeout at {}", line!()))?
-            .context("message expected")??;
+            .context(format!("timeout at {}", line!()))?;
+        self.closed = !matches!(message, Some(Ok(_)));
+        let message = message.context("stream closed")??;
+        self.last_received = Instant::now();
+        self.ring.record(Direction::Received, &message);
         trace!("message is {:?}", message);
 
         return Ok(message);
     }
 
+    /// Resolves once more bytes can be read, without consuming them
+    async fn readable(&self) -> Result<()> {
+        self.stream
+            .get_ref()
+            .readable()
+            .await
+            .context("polling readiness")
+    }
+
+    async fn shutdown_write(&mut self) -> Result<()> {
+        // NOTE: framed sink is flushed on every send
+        let stream = self.stream.get_mut();
+        stream.flush().await.context("flushing stream")?;
+        stream.shutdown().await.context("shutting down write half")
+    }
+
+    /// Reads until remote closes the connection
+    async fn wait_closed<U>(&mut self)
+    where
+        U: Summarize,
+        C: Decoder<Item = U, Error = anyhow::Error>,
+    {
+        while let Some(Ok(message)) = self.stream.next().await {
+            self.ring.record(Direction::Received, &message);
+        }
+    }
+
     #[instrument(skip(self))]
     async fn send_message<U>(&mut self, message: U) -> Result<()>
     where
-        U: Debug,
+        U: Debug + Summarize,
         C: Encoder<U, Error = anyhow::Error>,
     {
-        self.stream
-            .send(message)
-            .await
-            .context("peer message send")?;
+        self.ring.record(Direction::Sent, &message);
+        if let Err(e) = self.stream.send(message).await {
+            self.closed = true;
+            return Err(e).context("peer message send");
+        }
+        self.last_sent = Instant::now();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::torrent::{
+        select_piece_peers, shutdown_peers,
+        testing::{
+            fixture, foreign_listener, metadata, metadata_seeder, seeder, MockPeer, Pace,
+            Recording, ReplayPeer, Reply, Session, WireMessage, LTEP_RESERVED,
+        },
+        BufferBudget, MessageSummary, Piece, PieceBuffer, SessionReport, TorrentMetadataInfo,
+        MESSAGE_RING_CAPACITY,
+    };
+
+    const PIECE_LENGTH: usize = 64;
+    const TEST_BLOCK: u32 = 16;
+
+    fn piece_data() -> Vec<u8> {
+        (0..PIECE_LENGTH as u8).collect()
+    }
+
+    fn test_metadata() -> TorrentMetadataInfo {
+        metadata("http://127.0.0.1:1/announce", PIECE_LENGTH, &piece_data())
+    }
+
+    async fn connect<'a>(mock: &MockPeer, metadata: &'a TorrentMetadataInfo) -> Peer<'a> {
+        Peer::connect(
+            mock.addr(),
+            PeerId::from([2; 20]),
+            metadata.info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await
+        .expect("mock peer connects")
+    }
+
+    /// Channels of a piece with all of its blocks queued
+    struct PieceChannels {
+        blocks: Vec<PieceBlock>,
+        request_block: Sender<PieceBlock>,
+        requested_block: Receiver<PieceBlock>,
+        save_block: Sender<ReceivedBlock>,
+        saved_block: Receiver<ReceivedBlock>,
+        saved_blocks: watch::Receiver<BTreeSet<u32>>,
+        _saved_sender: watch::Sender<BTreeSet<u32>>,
+    }
+
+    impl PieceChannels {
+        fn new(metadata: &TorrentMetadataInfo) -> Self {
+            let blocks = metadata.info.blocks_for_piece(0, TEST_BLOCK).unwrap();
+            let (request_block, requested_block) = async_channel::unbounded();
+            for block in &blocks {
+                request_block.try_send(*block).unwrap();
+            }
+            let (save_block, saved_block) = async_channel::unbounded();
+            let (_saved_sender, saved_blocks) = watch::channel(BTreeSet::new());
+            Self {
+                blocks,
+                request_block,
+                requested_block,
+                save_block,
+                saved_block,
+                saved_blocks,
+                _saved_sender,
+            }
+        }
+
+        async fn process(&self, peer: &mut Peer<'_>, block_timeout: Duration) -> Result<PeerId> {
+            peer.process(
+                self.request_block.clone(),
+                self.requested_block.clone(),
+                self.save_block.clone(),
+                self.saved_blocks.clone(),
+                block_timeout,
+            )
+            .await
+        }
+
+        /// Assembles piece from saved blocks
+        async fn assemble(&self) -> Vec<u8> {
+            let reservation = BufferBudget::new(PIECE_LENGTH)
+                .try_reserve(PIECE_LENGTH)
+                .unwrap()
+                .unwrap();
+            let mut buffer = PieceBuffer::new(0, TEST_BLOCK, &self.blocks, reservation);
+            while !buffer.is_complete() {
+                let block = self.saved_block.recv().await.unwrap();
+                buffer.insert(&block).unwrap();
+            }
+            buffer.into_data()
+        }
+    }
+
+    /// Serves first request only, later ones are never answered
+    fn stalls_after_first_block() -> impl FnMut(&WireMessage) -> Vec<Reply> {
+        let mut seeder = seeder(piece_data(), PIECE_LENGTH);
+        let mut served = 0;
+        move |message| {
+            if message.id == 6 {
+                served += 1;
+                if served > 1 {
+                    return Vec::new();
+                }
+            }
+            seeder(message)
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_block_is_reassigned_to_other_peer() {
+        let metadata = test_metadata();
+        let stalling = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            stalls_after_first_block(),
+        )
+        .await;
+        let honest = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            seeder(piece_data(), PIECE_LENGTH),
+        )
+        .await;
+        let mut stalling_peer = connect(&stalling, &metadata).await;
+        let mut honest_peer = connect(&honest, &metadata).await;
+        let channels = PieceChannels::new(&metadata);
+        let block_timeout = Duration::from_millis(500);
+
+        let piece = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                piece = channels.assemble() => piece,
+                result = channels.process(&mut stalling_peer, block_timeout) => {
+                    panic!("stalling peer exited: {result:?}")
+                }
+                result = async {
+                    // NOTE: stalling peer gets to take first blocks
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    channels.process(&mut honest_peer, block_timeout).await
+                } => panic!("honest peer exited: {result:?}"),
+            }
+        })
+        .await
+        .expect("piece completes");
+
+        assert_eq!(sha1_hash(&piece), sha1_hash(&piece_data()));
+        let requests = stalling
+            .received_ids()
+            .iter()
+            .filter(|id| **id == 6)
+            .count();
+        assert_eq!(requests, 2);
+        assert!(!stalling_peer.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn slow_block_within_block_timeout_keeps_peer() {
+        let metadata = test_metadata();
+        let mut seeder = seeder(piece_data(), PIECE_LENGTH);
+        let mut delayed = false;
+        let slow = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            move |message| {
+                let mut replies = Vec::new();
+                if message.id == 6 && !delayed {
+                    delayed = true;
+                    // NOTE: longer than idle read timeout, shorter than block timeout
+                    replies.push(Reply::Delay(Duration::from_secs(
+                        TIMOUT_DURATION_SECONDS as u64 + 1,
+                    )));
+                }
+                replies.extend(seeder(message));
+                replies
+            },
+        )
+        .await;
+        let mut peer = connect(&slow, &metadata).await;
+        let channels = PieceChannels::new(&metadata);
+
+        let piece = tokio::select! {
+            piece = channels.assemble() => piece,
+            result = channels.process(&mut peer, Duration::from_secs(30)) => {
+                panic!("slow peer exited: {result:?}")
+            }
+        };
+
+        assert_eq!(piece, piece_data());
+        assert!(!peer.is_disconnected());
+    }
+
+    /// Next request of scripted peer's remote, answering interest with unchoke
+    async fn next_request(wire: &mut Framed<TcpStream, PeerProtocolFramer>) -> RequestBlock {
+        loop {
+            match wire.next().await.expect("peer stays").unwrap() {
+                PeerMessage::Request(request) => return request,
+                PeerMessage::Interested => wire.send(PeerMessage::Unchoke).await.unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    fn piece_reply(request: &RequestBlock) -> PeerMessage {
+        let begin = request.begin() as usize;
+        let block = piece_data()[begin..begin + request.length() as usize].to_vec();
+        PeerMessage::Piece(ReceivedBlock::new(request.index(), request.begin(), block))
+    }
+
+    #[tokio::test]
+    async fn choke_after_first_block_pauses_requests_until_unchoke() {
+        const CHOKED_FOR: Duration = Duration::from_millis(300);
+        let metadata = test_metadata();
+        let info_hash = metadata.info_hash;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            handshake[1 + BITTORRENT_PROTOCOL.len()..][..RESERVED_LENGTH].fill(0);
+            handshake[HANDSHAKE_LENGTH - 40..][..20].copy_from_slice(&info_hash);
+            handshake[HANDSHAKE_LENGTH - 20..].fill(9);
+            stream.write_all(&handshake).await.unwrap();
+            let mut wire = Framed::new(stream, PeerProtocolFramer);
+            wire.send(PeerMessage::Bitfield(vec![0x80])).await.unwrap();
+
+            let first = next_request(&mut wire).await;
+            // NOTE: one write, so choke is buffered by the time the block is taken
+            wire.feed(piece_reply(&first)).await.unwrap();
+            wire.feed(PeerMessage::Choke).await.unwrap();
+            wire.flush().await.unwrap();
+            let mut while_choked = Vec::new();
+            let _ = tokio::time::timeout(CHOKED_FOR, async {
+                while let Some(Ok(message)) = wire.next().await {
+                    while_choked.push(message);
+                }
+            })
+            .await;
+            let unchoked_at = Instant::now();
+            wire.send(PeerMessage::Unchoke).await.unwrap();
+            for _ in 1..PIECE_LENGTH / TEST_BLOCK as usize {
+                let request = next_request(&mut wire).await;
+                wire.send(piece_reply(&request)).await.unwrap();
+            }
+            // NOTE: connection is handed back, so it outlives the download
+            (while_choked, unchoked_at, wire)
+        });
+        let mut peer = Peer::connect(
+            addr,
+            PeerId::from([2; 20]),
+            info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await
+        .expect("scripted peer connects");
+        let channels = PieceChannels::new(&metadata);
+
+        let piece = tokio::select! {
+            piece = channels.assemble() => piece,
+            result = channels.process(&mut peer, Duration::from_secs(30)) => {
+                panic!("choking peer exited: {result:?}")
+            }
+        };
+        let (while_choked, unchoked_at, _wire) = remote.await.unwrap();
+
+        assert_eq!(piece, piece_data());
+        assert!(
+            !while_choked
+                .iter()
+                .any(|f| matches!(f, PeerMessage::Request(_))),
+            "requested while choked: {while_choked:?}"
+        );
+        assert!(unchoked_at.elapsed() < CHOKED_FOR, "resumed late");
+        assert!(!peer.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn shutdown_of_fresh_peer_sends_nothing() {
+        let metadata = test_metadata();
+        let mock = MockPeer::start(metadata.info_hash, Vec::new(), |_| Vec::new()).await;
+        let peer = connect(&mock, &metadata).await;
+
+        peer.shutdown().await;
+        mock.closed().await;
+
+        assert_eq!(mock.received_ids(), Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn shutdown_withdraws_interest_and_unchoke() {
+        let metadata = test_metadata();
+        let mock = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            seeder(piece_data(), PIECE_LENGTH),
+        )
+        .await;
+        let mut peer = connect(&mock, &metadata).await;
+        let blocks = metadata.info.blocks_for_piece(0, TEST_BLOCK).unwrap();
+        let piece = peer.receive_file_piece(0, blocks).await.unwrap();
+        peer.set_choking(false).await.unwrap();
+
+        peer.shutdown().await;
+        mock.closed().await;
+
+        assert_eq!(piece, piece_data());
+        let ids = mock.received_ids();
+        assert_eq!(ids.first(), Some(&2));
+        assert_eq!(ids[ids.len() - 3..], [1, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_of_disconnected_peer_does_not_linger() {
+        let metadata = test_metadata();
+        let mock = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            // NOTE: never closes its side on its own
+            |_| vec![Reply::Delay(Duration::from_secs(60))],
+        )
+        .await;
+        let mut peer = connect(&mock, &metadata).await;
+        peer.set_choking(false).await.unwrap();
+        peer.disconnect(|| "test".to_string());
+
+        let started = Instant::now();
+        peer.shutdown().await;
+
+        assert!(started.elapsed() < Duration::from_secs(SHUTDOWN_LINGER_SECONDS));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mock.received_ids(), vec![1]);
+    }
+
+    /// Moves `instant` back as if `by` had passed since
+    fn rewind(instant: &mut Instant, by: Duration) {
+        *instant = instant.checked_sub(by).expect("uptime covers rewind");
+    }
+
+    // NOTE: no paused clock without tokio test-util, timestamps are rewound instead
+    #[tokio::test]
+    async fn idle_peer_gets_heartbeat_once_interval_passes() {
+        let metadata = test_metadata();
+        let mock =
+            MockPeer::start(metadata.info_hash, vec![Reply::bitfield(1)], |_| Vec::new()).await;
+        let mut peer = connect(&mock, &metadata).await;
+
+        peer.keep_alive().await;
+        rewind(&mut peer.stream.last_sent, KEEP_ALIVE_INTERVAL);
+        rewind(&mut peer.stream.last_received, KEEP_ALIVE_INTERVAL);
+        assert!(peer.keep_alive_due() <= tokio::time::Instant::now());
+        peer.keep_alive().await;
+
+        assert!(!peer.is_disconnected());
+        assert!(peer.stream.last_sent.elapsed() < KEEP_ALIVE_INTERVAL);
+        assert!(peer.keep_alive_due() > tokio::time::Instant::now());
+        peer.shutdown().await;
+        mock.closed().await;
+        assert_eq!(mock.keep_alives(), 1);
+        assert_eq!(mock.received_ids(), Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_dropped_without_heartbeat() {
+        let metadata = test_metadata();
+        let mock =
+            MockPeer::start(metadata.info_hash, vec![Reply::bitfield(1)], |_| Vec::new()).await;
+        let mut peer = connect(&mock, &metadata).await;
+
+        rewind(&mut peer.stream.last_sent, PEER_SILENCE_TIMEOUT);
+        rewind(&mut peer.stream.last_received, PEER_SILENCE_TIMEOUT);
+        peer.keep_alive().await;
+
+        assert!(peer.is_disconnected());
+        peer.shutdown().await;
+        mock.closed().await;
+        assert_eq!(mock.keep_alives(), 0);
+    }
+
+    #[tokio::test]
+    async fn last_have_makes_peer_a_seed_and_scheduler_spares_it() {
+        const PIECES: usize = 100;
+        let metadata = metadata(
+            "http://127.0.0.1:1/announce",
+            TEST_BLOCK as usize,
+            &vec![1; PIECES * TEST_BLOCK as usize],
+        );
+        let mut leechers = Vec::new();
+        for _ in 0..2 {
+            let mock = MockPeer::start(
+                metadata.info_hash,
+                vec![Reply::bitfield_but_last(PIECES)],
+                |_| Vec::new(),
+            )
+            .await;
+            leechers.push(mock);
+        }
+        let finishing = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield_but_last(PIECES)],
+            |message| match message.id {
+                2 => vec![Reply::have(PIECES as u32 - 1), Reply::Send(1, Vec::new())],
+                _ => Vec::new(),
+            },
+        )
+        .await;
+        let log_path = std::env::temp_dir().join(format!("seed-{}.ndjson", std::process::id()));
+        let log = SessionLog::create(&log_path).unwrap();
+        let mut peers = Vec::new();
+        for mock in leechers.iter().chain([&finishing]) {
+            peers.push(connect(mock, &metadata).await);
+        }
+        peers[2].attach_session_log(Some(&log));
+        let piece = Piece::new(0, &metadata, peers.len()).unwrap();
+
+        assert!(!peers[2].is_seed());
+        assert_eq!(select_piece_peers(&piece, &peers), [true, true, true]);
+
+        peers[2].ensure_unchoked().await.unwrap();
+
+        assert!(peers[2].is_seed());
+        assert_eq!(select_piece_peers(&piece, &peers), [true, true, false]);
+        let report = SessionReport::load(&log_path).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+        assert!(report.peers[&finishing.addr()].became_seed_us.is_some());
+    }
+
+    /// Disconnects `peer` logging to `log` at `log_path`, ring of the logged event
+    fn disconnected_ring<'a>(
+        mut peer: Peer<'a>,
+        log: &'a SessionLog,
+        log_path: &std::path::Path,
+    ) -> Vec<MessageSummary> {
+        let addr = peer.socket_addr;
+        peer.attach_session_log(Some(log));
+        peer.disconnect(|| "test".to_string());
+        let report = SessionReport::load(log_path).unwrap();
+        std::fs::remove_file(log_path).unwrap();
+        let timeline = &report.peers[&addr];
+        assert_eq!(timeline.disconnect_reason.as_deref(), Some("test"));
+        timeline.last_messages.clone()
+    }
+
+    #[tokio::test]
+    async fn disconnect_event_carries_exchange_in_order() {
+        let metadata = test_metadata();
+        let log_path =
+            std::env::temp_dir().join(format!("ring-exchange-{}.ndjson", std::process::id()));
+        let log = SessionLog::create(&log_path).unwrap();
+        let mock =
+            MockPeer::start(
+                metadata.info_hash,
+                vec![Reply::bitfield(1)],
+                |message| match message.id {
+                    2 => vec![Reply::Send(1, Vec::new())],
+                    _ => Vec::new(),
+                },
+            )
+            .await;
+        let mut peer = connect(&mock, &metadata).await;
+        peer.stream
+            .send_message(PeerMessage::Interested)
+            .await
+            .unwrap();
+        let unchoke: PeerMessage = peer.stream.next_message().await.unwrap();
+        assert!(matches!(unchoke, PeerMessage::Unchoke));
+        peer.stream
+            .send_message(PeerMessage::Request(RequestBlock::new(0, 16, 32)))
+            .await
+            .unwrap();
+
+        let ring = disconnected_ring(peer, &log, &log_path);
+
+        let entries: Vec<_> = ring
+            .iter()
+            .map(|entry| (entry.direction, entry.kind, entry.fields))
+            .collect();
+        let length = |length| MessageFields {
+            length: Some(length),
+            ..Default::default()
+        };
+        assert_eq!(
+            entries,
+            [
+                (
+                    Direction::Sent,
+                    MessageKind::Handshake,
+                    MessageFields::default()
+                ),
+                (
+                    Direction::Received,
+                    MessageKind::Handshake,
+                    MessageFields::default()
+                ),
+                (Direction::Received, MessageKind::Bitfield, length(1)),
+                (
+                    Direction::Sent,
+                    MessageKind::Interested,
+                    MessageFields::default()
+                ),
+                (
+                    Direction::Received,
+                    MessageKind::Unchoke,
+                    MessageFields::default()
+                ),
+                (
+                    Direction::Sent,
+                    MessageKind::Request,
+                    MessageFields::block(0, 16, 32)
+                ),
+            ]
+        );
+        assert!(ring.windows(2).all(|pair| pair[0].at_ms <= pair[1].at_ms));
+    }
+
+    #[tokio::test]
+    async fn disconnect_event_keeps_only_last_messages() {
+        const HAVES: u32 = 40;
+        let metadata = test_metadata();
+        let log_path =
+            std::env::temp_dir().join(format!("ring-truncated-{}.ndjson", std::process::id()));
+        let log = SessionLog::create(&log_path).unwrap();
+        let mock =
+            MockPeer::start(metadata.info_hash, vec![Reply::bitfield(1)], |_| Vec::new()).await;
+        let mut peer = connect(&mock, &metadata).await;
+        for piece in 0..HAVES {
+            peer.stream
+                .send_message(PeerMessage::Have(piece))
+                .await
+                .unwrap();
+        }
+
+        let ring = disconnected_ring(peer, &log, &log_path);
+
+        // NOTE: handshakes and bitfield are pushed out along with the first haves
+        assert_eq!(ring.len(), MESSAGE_RING_CAPACITY);
+        let pieces: Vec<_> = ring
+            .iter()
+            .map(|entry| {
+                assert_eq!(
+                    (entry.direction, entry.kind),
+                    (Direction::Sent, MessageKind::Have)
+                );
+                entry.fields.piece.unwrap()
+            })
+            .collect();
+        let first = HAVES - MESSAGE_RING_CAPACITY as u32;
+        assert_eq!(pieces, (first..HAVES).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn request_block_round_trips_through_payload() {
+        let request = RequestBlock::new(7, 0x0102_0304, 16 * 1024);
+
+        let parsed = RequestBlock::try_from(request.into_vec().as_slice()).unwrap();
+
+        assert_eq!(parsed.index(), 7);
+        assert_eq!(parsed.begin(), 0x0102_0304);
+        assert_eq!(parsed.length(), 16 * 1024);
+    }
+
+    #[test]
+    fn request_payload_of_wrong_length_is_rejected() {
+        assert!(RequestBlock::try_from(&[0u8; 11][..]).is_err());
+        assert!(RequestBlock::try_from(&[0u8; 13][..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn request_and_bitfield_round_trip_through_framed() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let mut sender = Framed::new(ours, PeerProtocolFramer);
+        let mut receiver = Framed::new(theirs, PeerProtocolFramer);
+
+        sender
+            .send(PeerMessage::Request(RequestBlock::new(1, 2, 3)))
+            .await
+            .unwrap();
+        sender
+            .send(PeerMessage::Bitfield(vec![0b1010_0000, 0xff]))
+            .await
+            .unwrap();
+
+        let Some(Ok(PeerMessage::Request(request))) = receiver.next().await else {
+            panic!("request expected");
+        };
+        assert_eq!(
+            (request.index(), request.begin(), request.length()),
+            (1, 2, 3)
+        );
+        let Some(Ok(PeerMessage::Bitfield(bitfield))) = receiver.next().await else {
+            panic!("bitfield expected");
+        };
+        assert_eq!(bitfield, [0b1010_0000, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn handshake_for_other_torrent_is_rejected_with_both_hashes() {
+        let metadata = test_metadata();
+        let mock = MockPeer::start([0xab; 20], vec![Reply::bitfield(1)], |_| Vec::new()).await;
+
+        let Err(e) = Peer::connect(
+            mock.addr(),
+            PeerId::from([2; 20]),
+            metadata.info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await
+        else {
+            panic!("peer of other torrent connected");
+        };
+
+        let mismatch = e
+            .downcast_ref::<InfoHashMismatch>()
+            .expect("info hash mismatch");
+        assert_eq!(mismatch.expected, metadata.info_hash);
+        assert_eq!(mismatch.received, [0xab; 20]);
+        let message = format!("{e:#}");
+        assert!(message.contains(&"ab".repeat(20)), "{message}");
+        assert!(
+            message.contains(&hex::encode(metadata.info_hash)),
+            "{message}"
+        );
+    }
+
+    async fn connect_foreign(answer: &'static [u8]) -> anyhow::Error {
+        let metadata = test_metadata();
+        let (addr, _) = foreign_listener(answer).await;
+        let Err(e) = Peer::connect(
+            addr,
+            PeerId::from([2; 20]),
+            metadata.info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await
+        else {
+            panic!("foreign peer connected");
+        };
+        e
+    }
+
+    #[tokio::test]
+    async fn http_answer_is_wrong_protocol_with_hint() {
+        let e = connect_foreign(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await;
+
+        let HandshakeError::WrongProtocol { got } =
+            e.downcast_ref::<HandshakeError>().expect("handshake error");
+        assert!(got.starts_with("HTTP/"), "{got}");
+        assert!(got.chars().count() <= WRONG_PROTOCOL_CAPTURE, "{got}");
+        let message = format!("{e:#}");
+        assert!(message.contains("looks like http server"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn made_up_protocol_is_wrong_protocol_without_hint() {
+        let mut answer = vec![19];
+        answer.extend_from_slice(b"BitTorrent protocoX");
+        answer.extend_from_slice(&[0; 48]);
+        let e = connect_foreign(answer.leak()).await;
+
+        let HandshakeError::WrongProtocol { got } =
+            e.downcast_ref::<HandshakeError>().expect("handshake error");
+        assert_eq!(got, "BitTorrent protocoX");
+        let message = format!("{e:#}");
+        assert!(!message.contains("http"), "{message}");
+    }
+
+    #[test]
+    fn handshake_fed_byte_by_byte_decodes_once_complete() {
+        let mut encoded = bytes::BytesMut::new();
+        HandshakeFramer
+            .encode(
+                Handshake {
+                    reserved: ReservedBits::from_bytes([0, 0, 0, 0, 0, 0x10, 0, 0x05]),
+                    info_hash: [7; 20],
+                    peer_id: PeerId::from([9; 20]),
+                },
+                &mut encoded,
+            )
+            .unwrap();
+        assert_eq!(encoded.len(), HANDSHAKE_LENGTH);
+
+        let mut wire = bytes::BytesMut::new();
+        for byte in &encoded[..HANDSHAKE_LENGTH - 1] {
+            wire.put_u8(*byte);
+            assert!(HandshakeFramer.decode(&mut wire).unwrap().is_none());
+        }
+        wire.put_u8(encoded[HANDSHAKE_LENGTH - 1]);
+        let handshake = HandshakeFramer.decode(&mut wire).unwrap().unwrap();
+
+        assert!(wire.is_empty());
+        assert_eq!(
+            handshake.reserved.to_bytes(),
+            [0, 0, 0, 0, 0, 0x10, 0, 0x05]
+        );
+        assert_eq!(handshake.info_hash, [7; 20]);
+        assert_eq!(Bytes20::from(handshake.peer_id), [9; 20]);
+    }
+
+    #[test]
+    fn length_prefix_counts_id_and_payload_only() {
+        let mut encoded = bytes::BytesMut::new();
+
+        PeerProtocolFramer
+            .encode(
+                PeerMessage::Request(RequestBlock::new(1, 2, 3)),
+                &mut encoded,
+            )
+            .unwrap();
+
+        assert_eq!(encoded.len(), MESSAGE_LENGTH_PREFIX + 13);
+        assert_eq!(encoded[..MESSAGE_LENGTH_PREFIX], 13u32.to_be_bytes());
+        assert_eq!(encoded[MESSAGE_LENGTH_PREFIX], MessageId::Request as u8);
+    }
+
+    #[test]
+    fn every_message_round_trips_through_framer() {
+        let messages = || {
+            vec![
+                PeerMessage::Choke,
+                PeerMessage::Unchoke,
+                PeerMessage::Interested,
+                PeerMessage::NotInterested,
+                PeerMessage::Have(70000),
+                PeerMessage::Bitfield(vec![0b1010_0000, 0xff]),
+                PeerMessage::Request(RequestBlock::new(1, 16384, 16384)),
+                PeerMessage::Piece(ReceivedBlock::new(2, 32, (0..40).collect())),
+                PeerMessage::Cancel(RequestBlock::new(3, 0, 16)),
+                PeerMessage::RejectRequest(RequestBlock::new(4, 16, 16).into_vec()),
+                PeerMessage::Extended(1, b"d8:msg_typei0e5:piecei0ee".to_vec()),
+                PeerMessage::Heartbeat,
+            ]
+        };
+        let mut wire = bytes::BytesMut::new();
+        for message in messages() {
+            PeerProtocolFramer.encode(message, &mut wire).unwrap();
+        }
+        let encoded = wire.clone();
+
+        let mut decoded = Vec::new();
+        while let Some(message) = PeerProtocolFramer.decode(&mut wire).unwrap() {
+            decoded.push(message);
+        }
+
+        assert!(wire.is_empty());
+        assert_eq!(format!("{decoded:?}"), format!("{:?}", messages()));
+        let mut reencoded = bytes::BytesMut::new();
+        for message in decoded {
+            PeerProtocolFramer.encode(message, &mut reencoded).unwrap();
+        }
+        assert_eq!(reencoded, encoded);
+    }
+
+    #[test]
+    fn back_to_back_unchoke_and_piece_decode_apart() {
+        let mut wire = bytes::BytesMut::from(&[0, 0, 0, 1, MessageId::Unchoke as u8][..]);
+        wire.extend_from_slice(&[0, 0, 0, 12, MessageId::Piece as u8]);
+        wire.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 16, 0xaa, 0xbb, 0xcc]);
+
+        let unchoke = PeerProtocolFramer.decode(&mut wire).unwrap();
+        let piece = PeerProtocolFramer.decode(&mut wire).unwrap();
+
+        assert!(matches!(unchoke, Some(PeerMessage::Unchoke)), "{unchoke:?}");
+        let Some(PeerMessage::Piece(block)) = piece else {
+            panic!("piece expected, got {piece:?}");
+        };
+        assert_eq!((block.index(), block.begin()), (2, 16));
+        assert_eq!(block.block, [0xaa, 0xbb, 0xcc]);
+        assert!(wire.is_empty());
+        assert!(PeerProtocolFramer.decode(&mut wire).unwrap().is_none());
+    }
+
+    #[test]
+    fn heartbeat_is_four_zero_bytes() {
+        let mut wire = bytes::BytesMut::new();
+
+        PeerProtocolFramer
+            .encode(PeerMessage::Heartbeat, &mut wire)
+            .unwrap();
+
+        assert_eq!(wire[..], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn cancel_carries_request_payload() {
+        let mut wire = bytes::BytesMut::new();
+
+        PeerProtocolFramer
+            .encode(
+                PeerMessage::Cancel(RequestBlock::new(3, 16384, 16384)),
+                &mut wire,
+            )
+            .unwrap();
+
+        assert_eq!(
+            wire[..],
+            [
+                0, 0, 0, 13, 8, // length and id
+                0, 0, 0, 3, // index
+                0, 0, 0x40, 0, // begin
+                0, 0, 0x40, 0, // length
+            ]
+        );
+    }
+
+    #[test]
+    fn have_decodes_four_byte_piece_index() {
+        let mut wire = bytes::BytesMut::from(&[0, 0, 0, 5, 4, 0, 0, 0x01, 0x2c][..]);
+
+        let message = PeerProtocolFramer.decode(&mut wire).unwrap();
+
+        assert!(
+            matches!(message, Some(PeerMessage::Have(300))),
+            "{message:?}"
+        );
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn have_round_trips_indexes_past_one_byte() {
+        for index in [0u32, 255, 256, 70000] {
+            let mut wire = bytes::BytesMut::new();
+            PeerProtocolFramer
+                .encode(PeerMessage::Have(index), &mut wire)
+                .unwrap();
+            let mut expected = vec![0, 0, 0, 5, MessageId::Have as u8];
+            expected.extend_from_slice(&index.to_be_bytes());
+            assert_eq!(wire[..], expected[..], "have {index}");
+
+            let message = PeerProtocolFramer.decode(&mut wire).unwrap();
+
+            assert!(
+                matches!(message, Some(PeerMessage::Have(decoded)) if decoded == index),
+                "have {index} decoded as {message:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_sending_unchoke_and_haves_without_bitfield_is_kept() {
+        let metadata = metadata(
+            "http://127.0.0.1:1/announce",
+            TEST_BLOCK as usize,
+            &vec![1; 4 * TEST_BLOCK as usize],
+        );
+        // NOTE: bitfield after a have, as a late bitfield
+        let mock = MockPeer::start(
+            metadata.info_hash,
+            vec![
+                Reply::Send(1, Vec::new()),
+                Reply::have(0),
+                Reply::Send(5, vec![0b0010_0000]),
+            ],
+            |_| Vec::new(),
+        )
+        .await;
+
+        let started = Instant::now();
+        let mut peer = connect(&mock, &metadata).await;
+
+        assert!(started.elapsed() < BITFIELD_WAIT, "{:?}", started.elapsed());
+        assert!(!peer.chocked);
+        // NOTE: haves may be buffered already or still on their way
+        peer.drain_idle();
+        while peer.known_pieces() < 2 {
+            peer.readable().await.unwrap();
+            peer.drain_idle();
+        }
+        assert!(peer.has_piece(0) && peer.has_piece(2));
+        assert!(!peer.has_piece(1) && !peer.has_piece(3));
+        assert_eq!(peer.available_pieces(), [0, 2]);
+    }
+
+    #[tokio::test]
+    async fn have_of_piece_300_sets_its_bit() {
+        const PIECES: usize = 400;
+        let metadata = metadata(
+            "http://127.0.0.1:1/announce",
+            TEST_BLOCK as usize,
+            &vec![1; PIECES * TEST_BLOCK as usize],
+        );
+        let mock = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::Send(5, vec![0; PIECES.div_ceil(8)])],
+            |message| match message.id {
+                2 => vec![Reply::have(300), Reply::Send(1, Vec::new())],
+                _ => Vec::new(),
+            },
+        )
+        .await;
+        let mut peer = connect(&mock, &metadata).await;
+        assert!(!peer.has_piece(300));
+
+        peer.ensure_unchoked().await.unwrap();
+
+        assert!(peer.has_piece(300));
+        assert!(!peer.has_piece(299) && !peer.has_piece(301));
+    }
+
+    #[tokio::test]
+    async fn simultaneous_teardowns_share_one_linger() {
+        let metadata = test_metadata();
+        let mut mocks = Vec::new();
+        for _ in 0..50 {
+            // NOTE: stops reading once unchoked, so it never closes its side
+            let mock = MockPeer::start(metadata.info_hash, vec![Reply::bitfield(1)], |_| {
+                vec![Reply::Delay(Duration::from_secs(60))]
+            })
+            .await;
+            mocks.push(mock);
+        }
+        let mut peers = Vec::new();
+        for mock in &mocks {
+            let mut peer = connect(mock, &metadata).await;
+            peer.set_choking(false).await.unwrap();
+            peers.push(peer);
+        }
+
+        let started = Instant::now();
+        shutdown_peers(peers).await;
+
+        let linger = Duration::from_secs(SHUTDOWN_LINGER_SECONDS);
+        assert!(started.elapsed() >= linger);
+        // NOTE: lingering one after another would take 50 lingers
+        assert!(started.elapsed() < linger * 10, "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn bitfield_split_across_writes_is_reassembled() {
+        let bitfield: Vec<u8> = (0..4000).map(|i| (i % 256) as u8).collect();
+        let mut wire = (bitfield.len() as u32 + 1).to_be_bytes().to_vec();
+        wire.push(MessageId::Bitfield as u8);
+        wire.extend_from_slice(&bitfield);
+        let (ours, mut theirs) = tokio::io::duplex(8 * 1024);
+        let mut receiver = Framed::new(ours, PeerProtocolFramer);
+
+        let (first, second) = wire.split_at(1000);
+        theirs.write_all(first).await.unwrap();
+        let partial = tokio::time::timeout(Duration::from_millis(100), receiver.next()).await;
+        assert!(partial.is_err(), "half a frame decoded: {partial:?}");
+        theirs.write_all(second).await.unwrap();
+
+        let Some(Ok(PeerMessage::Bitfield(received))) = receiver.next().await else {
+            panic!("bitfield expected");
+        };
+        assert_eq!(received, bitfield);
+    }
+
+    /// Decodes frames until decoder wants more bytes or fails
+    fn decode_all<D: Decoder>(mut decoder: D, input: &[u8]) {
+        let mut buffer = bytes::BytesMut::from(input);
+        while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+    }
+
+    #[test]
+    fn corpus_never_panics_wire_decoders() {
+        crate::torrent::testing::assert_no_panics(|input| {
+            decode_all(HandshakeFramer, input);
+            decode_all(PeerProtocolFramer, input);
+        });
+    }
+
+    /// Session `name` of `tests/fixtures`. With `RECORD_SESSIONS` set it is
+    /// recorded anew first, running `scenario` through a proxy to `remote`
+    async fn recorded_session<F: std::future::Future<Output = Result<Vec<u8>>>>(
+        name: &str,
+        remote: &MockPeer,
+        scenario: impl FnOnce(SocketAddr) -> F,
+    ) -> Session {
+        let path = fixture(name);
+        if std::env::var_os("RECORD_SESSIONS").is_some() {
+            let recording = Recording::start(remote.addr()).await;
+            scenario(recording.addr()).await.unwrap();
+            recording.finish().await.save(&path).unwrap();
+        }
+        Session::load(&path).unwrap()
+    }
+
+    /// Downloads first piece from `addr` as `peer_id`
+    async fn download_first_piece(
+        addr: SocketAddr,
+        peer_id: PeerId,
+        metadata: &TorrentMetadataInfo,
+    ) -> Result<Vec<u8>> {
+        let mut peer = Peer::connect(
+            addr,
+            peer_id,
+            metadata.info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await?;
+        let channels = PieceChannels::new(metadata);
+        tokio::select! {
+            piece = channels.assemble() => Ok(piece),
+            result = channels.process(&mut peer, Duration::from_secs(5)) => {
+                bail!("peer exited before piece was done: {result:?}")
+            }
+        }
+    }
+
+    /// Fetches every ut_metadata piece from `addr`
+    async fn fetch_metadata(addr: SocketAddr, metadata: &TorrentMetadataInfo) -> Result<Vec<u8>> {
+        let mut peer = Peer::connect(
+            addr,
+            PeerId::from([2; 20]),
+            metadata.info_hash,
+            &metadata.info,
+            Duration::from_secs(1),
+        )
+        .await?;
+        let size = peer.metadata_size().await?;
+        let mut info = Vec::new();
+        for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+            info.extend(peer.fetch_metadata_piece(piece, size).await?);
+        }
+        Ok(info)
+    }
+
+    /// Two ut_metadata pieces worth of made up info dict
+    fn metadata_info() -> Vec<u8> {
+        (0..METADATA_PIECE_SIZE + 100).map(|i| i as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn recorded_download_replays_against_peer() {
+        let metadata = test_metadata();
+        let seeding = MockPeer::start(
+            metadata.info_hash,
+            vec![Reply::bitfield(1)],
+            seeder(piece_data(), PIECE_LENGTH),
+        )
+        .await;
+        let session = recorded_session("download.session", &seeding, |addr| {
+            download_first_piece(addr, PeerId::from([2; 20]), &metadata)
+        })
+        .await;
+
+        let replay = ReplayPeer::start(&session, Pace::Recorded).await;
+        let piece = download_first_piece(replay.addr(), PeerId::from([2; 20]), &metadata).await;
+
+        replay.finished().await.unwrap();
+        assert_eq!(piece.unwrap(), piece_data());
+    }
+
+    #[tokio::test]
+    async fn recorded_metadata_fetch_replays_against_peer() {
+        let metadata = test_metadata();
+        let supplier = MockPeer::start_with_reserved(
+            metadata.info_hash,
+            LTEP_RESERVED,
+            vec![
+                Reply::extended_handshake(metadata_info().len()),
+                Reply::bitfield(1),
+            ],
+            metadata_seeder(metadata_info()),
+        )
+        .await;
+        let session = recorded_session("metadata.session", &supplier, |addr| {
+            fetch_metadata(addr, &metadata)
+        })
+        .await;
+
+        let replay = ReplayPeer::start(&session, Pace::AsFastAsPossible).await;
+        let info = fetch_metadata(replay.addr(), &metadata).await;
+
+        replay.finished().await.unwrap();
+        assert_eq!(info.unwrap(), metadata_info());
+    }
+
+    #[tokio::test]
+    async fn replay_fails_on_message_differing_from_recording() {
+        let metadata = test_metadata();
+        let session = Session::load(&fixture("download.session")).unwrap();
+
+        let replay = ReplayPeer::start(&session, Pace::AsFastAsPossible).await;
+        let piece = download_first_piece(replay.addr(), PeerId::from([3; 20]), &metadata).await;
+
+        let err = replay.finished().await.unwrap_err();
+        assert!(format!("{err:#}").contains("message 0 differs"), "{err:#}");
+        assert!(piece.is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::prelude::*;
+
+/// Where address of a peer came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSourceKind {
+    Tracker,
+    /// Given up front, by cli, builder or magnet link
+    Manual,
+    /// Connected to us while seeding
+    Incoming,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Allow,
+    Deny {
+        reason: String,
+    },
+    /// Dropped without logging, still counted
+    #[allow(dead_code)]
+    DenySilently,
+}
+
+/// Consulted before any peer address is dialed
+#[derive(Clone)]
+pub struct PeerFilter(Arc<dyn Fn(SocketAddr, PeerSourceKind) -> FilterDecision + Send + Sync>);
+
+impl PeerFilter {
+    pub fn new(
+        filter: impl Fn(SocketAddr, PeerSourceKind) -> FilterDecision + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(filter))
+    }
+
+    pub fn decide(&self, addr: SocketAddr, source: PeerSourceKind) -> FilterDecision {
+        (self.0)(addr, source)
+    }
+}
+
+impl fmt::Debug for PeerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PeerFilter")
+    }
+}
+
+impl From<Blocklist> for PeerFilter {
+    fn from(blocklist: Blocklist) -> Self {
+        PeerFilter::new(move |addr, _| {
+            if blocklist.contains(addr.ip()) {
+                FilterDecision::Deny {
+                    reason: "blocklist".to_string(),
+                }
+            } else {
+                FilterDecision::Allow
+            }
+        })
+    }
+}
+
+/// Ip ranges to never connect to, sorted and merged for binary search,
+/// so lists of hundreds of thousands of ranges stay cheap to query
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading blocklist {}", path.display()))?;
+        let blocklist = Self::parse(&text);
+        debug!(
+            "blocklist {} has {} ipv4 and {} ipv6 ranges",
+            path.display(),
+            blocklist.v4.len(),
+            blocklist.v6.len()
+        );
+        Ok(blocklist)
+    }
+
+    /// Takes emule `name:start-end` lines, plain `start-end` and cidr lines.
+    /// Lists often carry junk, so invalid lines are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut blocklist = Blocklist::default();
+        let mut invalid = 0;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_range(line) {
+                Some((IpAddr::V4(start), IpAddr::V4(end))) if start <= end => {
+                    blocklist.v4.push((start.into(), end.into()))
+                }
+                Some((IpAddr::V6(start), IpAddr::V6(end))) if start <= end => {
+                    blocklist.v6.push((start.into(), end.into()))
+                }
+                _ => invalid += 1,
+            }
+        }
+        if invalid > 0 {
+            warn!("skipped {invalid} invalid blocklist lines");
+        }
+        merge_ranges(&mut blocklist.v4);
+        merge_ranges(&mut blocklist.v6);
+        blocklist
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => range_contains(&self.v4, ip.into()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => range_contains(&self.v4, ip.into()),
+                None => range_contains(&self.v6, ip.into()),
+            },
+        }
+    }
+}
+
+fn parse_range(line: &str) -> Option<(IpAddr, IpAddr)> {
+    if let Some((ip, prefix)) = line.split_once('/') {
+        let ip: IpAddr = ip.trim().parse().ok()?;
+        let prefix: u32 = prefix.trim().parse().ok()?;
+        return match ip {
+            IpAddr::V4(ip) => {
+                let host_bits = 32u32.checked_sub(prefix)?;
+                let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                Some((
+                    Ipv4Addr::from(start).into(),
+                    Ipv4Addr::from(start | !mask).into(),
+                ))
+            }
+            IpAddr::V6(ip) => {
+                let host_bits = 128u32.checked_sub(prefix)?;
+                let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                Some((
+                    Ipv6Addr::from(start).into(),
+                    Ipv6Addr::from(start | !mask).into(),
+                ))
+            }
+        };
+    }
+
+    let (start, end) = line.rsplit_once('-')?;
+    let end = end.trim().parse().ok()?;
+    let start = start.trim();
+    // NOTE: emule names may contain ':' themselves, address is after the last one
+    let start = match start.parse() {
+        Ok(start) => start,
+        Err(_) => start.rsplit_once(':')?.1.trim().parse().ok()?,
+    };
+    Some((start, end))
+}
+
+fn merge_ranges<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn range_contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::testing::fixture;
+
+    fn blocked(blocklist: &Blocklist, ip: &str) -> bool {
+        blocklist.contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn fixture_ranges_of_every_format_are_blocked() {
+        let blocklist = Blocklist::load(&fixture("blocklist.p2p")).unwrap();
+
+        for ip in [
+            "10.1.0.0",
+            "10.1.200.7",
+            "10.1.255.255",
+            "127.0.0.2",
+            "192.168.7.15",
+            "172.31.255.255",
+            "::ffff:172.16.0.1",
+            "2001:db8::42",
+        ] {
+            assert!(blocked(&blocklist, ip), "{ip} passed");
+        }
+        for ip in [
+            "10.0.255.255",
+            "10.2.0.0",
+            "10.9.9.9",
+            "127.0.0.1",
+            "192.168.7.21",
+            "172.32.0.0",
+            "2001:db9::1",
+        ] {
+            assert!(!blocked(&blocklist, ip), "{ip} blocked");
+        }
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let blocklist = Blocklist::parse(
+            "a:10.0.0.0-10.0.0.50\nb:10.0.0.20-10.0.0.90\n10.0.0.91-10.0.0.91\n10.0.0.200/30",
+        );
+
+        assert_eq!(
+            blocklist.v4,
+            [
+                (
+                    u32::from(Ipv4Addr::new(10, 0, 0, 0)),
+                    u32::from(Ipv4Addr::new(10, 0, 0, 90))
+                ),
+                (
+                    u32::from(Ipv4Addr::new(10, 0, 0, 91)),
+                    u32::from(Ipv4Addr::new(10, 0, 0, 91))
+                ),
+                (
+                    u32::from(Ipv4Addr::new(10, 0, 0, 200)),
+                    u32::from(Ipv4Addr::new(10, 0, 0, 203))
+                ),
+            ]
+        );
+    }
+}
@@ -1,85 +1,149 @@
-use rand::Rng;
+use bitvec::vec::BitVec;
 
 use crate::prelude::*;
-use std::{cmp::Ordering, collections::HashSet, net::SocketAddrV4, usize};
+use std::{cmp::Ordering, fmt, sync::Arc};
 
-use super::{Peer, TorrentInfo};
-#[derive(Debug, PartialEq, Eq)]
+use super::{BufferReservation, Peer, ReceivedBlock, TorrentInfo, TorrentMetadataInfo};
+
+// NOTE: kept small, all pieces of torrent are queued up front,
+// hashes are shared and blocks are computed only when piece is picked
 pub struct Piece {
-    peers: HashSet<SocketAddrV4>,
     piece_index: usize,
-    hash: Vec<u8>,
+    availability: usize,
+    /// Picked once, spreads equally rare pieces so peers don't all start on the same ones
+    tiebreak: u32,
+    hashes: Arc<[Bytes20]>,
+    info_hash: Bytes20,
+}
+
+impl fmt::Debug for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Piece")
+            .field("piece_index", &self.piece_index)
+            .field("availability", &self.availability)
+            .finish()
+    }
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PieceBlock {
     pub piece_index: u32,
     pub block_offset: u32,
     pub block_size: u32,
 }
 
-// NOTE: Introduce randomness into generation
-impl Ord for Piece {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        let mut rng = rand::thread_rng();
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
-            .then_with(|| match rng.gen_range(0..=2) {
-                0 => Ordering::Less,
-                1 => Ordering::Greater,
-                _ => Ordering::Equal,
-            })
-            .then(self.piece_index.cmp(&other.piece_index))
+/// Piece as ordered in download queue, a min heap of `Reverse<QueueKey>`.
+/// Piece equality is its identity, key equality has to agree with ordering instead
+#[derive(Debug)]
+pub struct QueueKey(pub Piece);
+
+impl QueueKey {
+    // NOTE: rarest first, pieces no peer has go last, new peers may bring them.
+    // Ordering has to stay total and stable, heap breaks otherwise
+    fn key(&self) -> (bool, usize, u32, usize, &Bytes20) {
+        let piece = &self.0;
+        (
+            !piece.has_peers(),
+            piece.availability,
+            piece.tiebreak,
+            piece.piece_index,
+            &piece.info_hash,
+        )
     }
 }
 
-impl PartialOrd for Piece {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+impl Ord for QueueKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for QueueKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
+impl PartialEq for QueueKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for QueueKey {}
+
+// NOTE: identity is the piece position in a particular torrent,
+// same piece hash could be shared across torrents
+impl PartialEq for Piece {
+    fn eq(&self, other: &Self) -> bool {
+        self.info_hash == other.info_hash
+            && self.piece_index == other.piece_index
+            && self.hash() == other.hash()
+    }
+}
+
+impl Eq for Piece {}
+
 impl std::hash::Hash for Piece {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.hash.hash(state);
+        self.info_hash.hash(state);
+        self.piece_index.hash(state);
+        self.hash().hash(state);
     }
 }
 
 impl Piece {
-    #[instrument]
     pub fn new(
         piece_index: usize,
-        torrent_info: &TorrentInfo,
-        peers: HashSet<SocketAddrV4>,
+        metadata: &TorrentMetadataInfo,
+        availability: usize,
     ) -> Result<Self> {
-        let hash = torrent_info
-            .pieces
-            .get(piece_index)
-            .context("get piece hash")?;
+        anyhow::ensure!(
+            piece_index < metadata.info.pieces.len(),
+            "piece {piece_index} is out of range"
+        );
 
         Ok(Self {
-            peers,
             piece_index,
-            hash: hash.clone(),
+            availability,
+            tiebreak: rand::random(),
+            hashes: metadata.info.pieces.clone(),
+            info_hash: metadata.info_hash,
         })
     }
 
+    pub fn hash(&self) -> &Bytes20 {
+        &self.hashes[self.piece_index]
+    }
+
     // TODO: get rid from torrent_info
-    pub fn piece_blocks(
+    /// Fills `out` with blocks of the piece, reusing its allocation
+    pub fn piece_blocks_into(
         &self,
         up_to_piece_size: u32,
         torrent_info: &TorrentInfo,
-    ) -> Vec<PieceBlock> {
-        torrent_info.piece_blocks(self.piece_index, up_to_piece_size)
+        out: &mut Vec<PieceBlock>,
+    ) {
+        torrent_info.piece_blocks_into(self.piece_index, up_to_piece_size, out)
     }
 
     pub fn has_peers(&self) -> bool {
-        !self.peers.is_empty()
+        self.availability > 0
+    }
+
+    /// Connected peers having the piece
+    pub fn availability(&self) -> usize {
+        self.availability
+    }
+
+    /// Only for pieces out of queue, changing key of a queued piece breaks the heap
+    pub fn set_availability(&mut self, availability: usize) {
+        self.availability = availability;
     }
 
     pub fn peer_has_piece(&self, peer: &Peer) -> bool {
-        self.peers.contains(&peer.socket_addr())
+        peer.has_piece(self.piece_index)
     }
 
     pub fn piece_index(&self) -> usize {
@@ -88,11 +152,66 @@ impl Piece {
 }
 
 impl TorrentInfo {
-    fn piece_blocks(&self, piece_index: usize, up_to_piece_size: u32) -> Vec<PieceBlock> {
+    /// Bytes in piece, only the last one could be shorter
+    pub fn piece_size(&self, piece_index: usize) -> usize {
+        self.piece_length
+            .min(self.total_length() - piece_index * self.piece_length)
+    }
+
+    /// Bytes of real data in pieces, padding files (BEP 47) excluded
+    pub fn payload_size_of(&self, pieces: impl IntoIterator<Item = usize>) -> usize {
+        let mut padding = Vec::new();
+        let mut offset = 0;
+        for file in self.files.iter().flatten() {
+            if file.is_padding() {
+                padding.push(offset..offset + file.length);
+            }
+            offset += file.length;
+        }
+
+        pieces
+            .into_iter()
+            .map(|piece_index| {
+                let start = piece_index * self.piece_length;
+                let end = start + self.piece_size(piece_index);
+                let first = padding.partition_point(|range| range.end <= start);
+                let padded: usize = padding[first..]
+                    .iter()
+                    .take_while(|range| range.start < end)
+                    .map(|range| range.end.min(end) - range.start.max(start))
+                    .sum();
+                end - start - padded
+            })
+            .sum()
+    }
+
+    /// Blocks to request for a piece, tiling it exactly, last block could be shorter
+    pub fn blocks_for_piece(&self, piece_index: usize, block_size: u32) -> Result<Vec<PieceBlock>> {
+        anyhow::ensure!(
+            piece_index < self.pieces.len(),
+            "piece {piece_index} is out of range, torrent has {} pieces",
+            self.pieces.len()
+        );
+        anyhow::ensure!(block_size > 0, "block size is zero");
+
+        let mut blocks = Vec::new();
+        self.piece_blocks_into(piece_index, block_size, &mut blocks);
+        Ok(blocks)
+    }
+
+    /// Clears `out` and fills it with blocks of the piece,
+    /// doesn't allocate once `out` has grown to blocks per piece
+    pub fn piece_blocks_into(
+        &self,
+        piece_index: usize,
+        up_to_piece_size: u32,
+        out: &mut Vec<PieceBlock>,
+    ) {
+        out.clear();
         let piece_index = piece_index as u32;
         trace!(
             "length: {}, piece_length: {}, number of pieces: {}",
-            self.length,
+            self.total_length(),
             self.piece_length,
             self.pieces.len()
         );
@@ -103,27 +222,26 @@ impl TorrentInfo {
             last_block_size,
         } = calc_block_size(
             piece_index,
-            self.length,
+            self.total_length(),
             self.piece_length,
             number_of_pieces,
+            up_to_piece_size,
         );
 
-        (0..block_count)
-            .map(|index| {
-                let is_last_block = index == block_count - 1;
-                let begin = index as u32 * up_to_piece_size;
-                let block_size = if is_last_block {
-                    last_block_size
-                } else {
-                    up_to_piece_size
-                };
-                PieceBlock {
-                    piece_index,
-                    block_offset: begin,
-                    block_size,
-                }
-            })
-            .collect()
+        out.extend((0..block_count).map(|index| {
+            let is_last_block = index == block_count - 1;
+            let begin = index as u32 * up_to_piece_size;
+            let block_size = if is_last_block {
+                last_block_size
+            } else {
+                up_to_piece_size
+            };
+            PieceBlock {
+                piece_index,
+                block_offset: begin,
+                block_size,
+            }
+        }));
     }
 }
 
@@ -132,14 +250,12 @@ fn calc_block_size(
     length: usize,
     piece_length: usize,
     number_of_pieces: usize,
+    block_size: u32,
 ) -> BlocksInfo {
     let indexes_of_pieces = number_of_pieces - 1;
     let full_pieces_count = number_of_pieces - 1;
-    let last_piece_size = if number_of_pieces == 1 {
-        piece_length
-    } else {
-        length - (full_pieces_count * piece_length)
-    };
+    // NOTE: for single piece torrent length could be less than piece length
+    let last_piece_size = length - (full_pieces_count * piece_length);
 
     let is_last_piece = piece_index == indexes_of_pieces as u32;
 
@@ -149,13 +265,17 @@ fn calc_block_size(
         piece_length
     };
 
-    let block_count = (current_piece_length as f32 / BLOCK_SIZE as f32).ceil() as usize;
+    // NOTE: metadata is validated on load, empty piece would never complete
+    debug_assert!(current_piece_length > 0, "piece {piece_index} is empty");
+
+    let block_count = current_piece_length.div_ceil(block_size as usize);
 
     trace!("bloc count: {block_count}");
 
-    let full_blocks = block_count - 1;
+    let full_blocks = block_count.saturating_sub(1);
 
-    let last_block_size: u32 = current_piece_length as u32 - BLOCK_SIZE * full_blocks as u32;
+    // NOTE: piece exactly multiple of block size ends with full block, not an empty one
+    let last_block_size: u32 = current_piece_length as u32 - block_size * full_blocks as u32;
     trace!("last block size {last_block_size}");
 
     BlocksInfo {
@@ -164,7 +284,426 @@ fn calc_block_size(
     }
 }
 
+/// Assembly buffer of a piece downloaded block by block,
+/// only accepts blocks matching exactly what was requested
+#[derive(Debug)]
+pub struct PieceBuffer {
+    piece_index: u32,
+    block_size: u32,
+    block_lengths: Vec<u32>,
+    received: BitVec,
+    data: Vec<u8>,
+    _reservation: BufferReservation,
+}
+
+impl PieceBuffer {
+    pub fn new(
+        piece_index: usize,
+        block_size: u32,
+        blocks: &[PieceBlock],
+        reservation: BufferReservation,
+    ) -> Self {
+        let block_lengths: Vec<u32> = blocks.iter().map(|f| f.block_size).collect();
+        let piece_length = block_lengths.iter().sum::<u32>() as usize;
+        Self {
+            piece_index: piece_index as u32,
+            block_size,
+            received: BitVec::repeat(false, block_lengths.len()),
+            block_lengths,
+            data: vec![0u8; piece_length],
+            _reservation: reservation,
+        }
+    }
+
+    /// Copies block into the buffer, returns false if block was already received
+    pub fn insert(&mut self, block: &ReceivedBlock) -> Result<bool> {
+        anyhow::ensure!(
+            block.index() == self.piece_index,
+            "block of piece {} received for piece {}",
+            block.index(),
+            self.piece_index
+        );
+
+        let begin = block.begin();
+        anyhow::ensure!(
+            begin.is_multiple_of(self.block_size),
+            "block begin {begin} is not aligned to block size {}",
+            self.block_size
+        );
+
+        let block_number = (begin / self.block_size) as usize;
+        let expected_length = *self
+            .block_lengths
+            .get(block_number)
+            .context(format!("block begin {begin} is out of piece bounds"))?;
+
+        anyhow::ensure!(
+            block.data().len() == expected_length as usize,
+            "block at {begin} has length {}, requested {expected_length}",
+            block.data().len()
+        );
+
+        if self.received[block_number] {
+            return Ok(false);
+        }
+
+        let begin = begin as usize;
+        self.data[begin..begin + block.data().len()].copy_from_slice(block.data());
+        self.received.set(block_number, true);
+
+        Ok(true)
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.received
+            .iter_ones()
+            .map(|block_number| self.block_lengths[block_number] as usize)
+            .sum()
+    }
+
+    /// Blocks not received yet
+    pub fn missing_blocks(&self) -> Vec<PieceBlock> {
+        self.received
+            .iter_zeros()
+            .map(|block_number| PieceBlock {
+                piece_index: self.piece_index,
+                block_offset: block_number as u32 * self.block_size,
+                block_size: self.block_lengths[block_number],
+            })
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.all()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
 struct BlocksInfo {
     block_count: usize,
     last_block_size: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cmp::Reverse,
+        collections::{BinaryHeap, HashSet},
+    };
+
+    use super::*;
+    use crate::torrent::{testing::metadata, BufferBudget, TorrentFile};
+
+    const TEST_BLOCK: u32 = 4;
+
+    fn received(index: u32, begin: u32, data: &[u8]) -> ReceivedBlock {
+        let mut wire = Vec::new();
+        wire.extend_from_slice(&index.to_be_bytes());
+        wire.extend_from_slice(&begin.to_be_bytes());
+        wire.extend_from_slice(data);
+        ReceivedBlock::from(wire.as_slice())
+    }
+
+    /// Piece of 10 bytes, blocks of 4, 4 and 2
+    fn buffer(piece: &[u8]) -> PieceBuffer {
+        let blocks: Vec<PieceBlock> = (0..piece.len() as u32)
+            .step_by(TEST_BLOCK as usize)
+            .map(|offset| PieceBlock {
+                piece_index: 3,
+                block_offset: offset,
+                block_size: TEST_BLOCK.min(piece.len() as u32 - offset),
+            })
+            .collect();
+        let reservation = BufferBudget::new(piece.len())
+            .try_reserve(piece.len())
+            .unwrap()
+            .unwrap();
+        PieceBuffer::new(3, TEST_BLOCK, &blocks, reservation)
+    }
+
+    fn honest_blocks(buffer: &mut PieceBuffer, piece: &[u8]) {
+        for (number, chunk) in piece.chunks(TEST_BLOCK as usize).enumerate() {
+            let begin = number as u32 * TEST_BLOCK;
+            buffer.insert(&received(3, begin, chunk)).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_block_without_touching_neighbours() {
+        let piece = b"0123456789";
+        let mut buffer = buffer(piece);
+        buffer.insert(&received(3, 4, b"4567")).unwrap();
+
+        let err = buffer.insert(&received(3, 0, b"XXXXXXXX")).unwrap_err();
+        assert!(err.to_string().contains("requested 4"), "{err}");
+        assert_eq!(buffer.bytes_written(), 4);
+
+        honest_blocks(&mut buffer, piece);
+        assert!(buffer.is_complete());
+        assert_eq!(sha1_hash(&buffer.into_data()), sha1_hash(piece));
+    }
+
+    #[test]
+    fn rejects_short_block() {
+        let piece = b"0123456789";
+        let mut buffer = buffer(piece);
+
+        assert!(buffer.insert(&received(3, 0, b"01")).is_err());
+        assert!(buffer.insert(&received(3, 8, b"8")).is_err());
+        assert_eq!(buffer.bytes_written(), 0);
+        assert_eq!(buffer.missing_blocks().len(), 3);
+
+        honest_blocks(&mut buffer, piece);
+        assert_eq!(sha1_hash(&buffer.into_data()), sha1_hash(piece));
+    }
+
+    #[test]
+    fn rejects_misaligned_and_out_of_bounds_blocks() {
+        let piece = b"0123456789";
+        let mut buffer = buffer(piece);
+
+        let err = buffer.insert(&received(3, 2, b"2345")).unwrap_err();
+        assert!(err.to_string().contains("not aligned"), "{err}");
+        assert!(buffer.insert(&received(3, 12, b"XX")).is_err());
+        assert!(buffer.insert(&received(4, 0, b"0123")).is_err());
+        assert_eq!(buffer.bytes_written(), 0);
+
+        honest_blocks(&mut buffer, piece);
+        assert!(buffer.is_complete());
+        assert_eq!(sha1_hash(&buffer.into_data()), sha1_hash(piece));
+    }
+
+    #[test]
+    fn duplicate_block_is_not_counted_twice() {
+        let piece = b"0123456789";
+        let mut buffer = buffer(piece);
+
+        assert!(buffer.insert(&received(3, 0, b"0123")).unwrap());
+        assert!(!buffer.insert(&received(3, 0, b"0123")).unwrap());
+        assert_eq!(buffer.bytes_written(), 4);
+        assert_eq!(
+            buffer.missing_blocks(),
+            vec![
+                PieceBlock {
+                    piece_index: 3,
+                    block_offset: 4,
+                    block_size: 4
+                },
+                PieceBlock {
+                    piece_index: 3,
+                    block_offset: 8,
+                    block_size: 2
+                },
+            ]
+        );
+    }
+
+    fn padded_info() -> TorrentInfo {
+        let file = |length, attr: Option<&str>| TorrentFile {
+            length,
+            path: vec!["f".to_string()],
+            attr: attr.map(str::to_string),
+        };
+        TorrentInfo {
+            length: None,
+            files: Some(vec![file(5, None), file(3, Some("p")), file(6, None)]),
+            name: "padded".to_string(),
+            piece_length: 4,
+            pieces: vec![[0; 20]; 4].into(),
+            info_extra: Default::default(),
+        }
+    }
+
+    #[test]
+    fn payload_size_excludes_padding_of_each_piece() {
+        let info = padded_info();
+
+        let sizes: Vec<usize> = (0..4).map(|piece| info.payload_size_of([piece])).collect();
+        assert_eq!(sizes, [4, 1, 4, 2]);
+        assert_eq!(info.payload_size_of(0..4), info.payload_length());
+        assert_eq!(info.payload_length() - info.payload_size_of([0, 1]), 6);
+    }
+
+    #[test]
+    fn pieces_with_same_hash_stay_distinct_by_index() {
+        let metadata = metadata("http://127.0.0.1:1/announce", 4, &[7; 8]);
+        let first = Piece::new(0, &metadata, 1).unwrap();
+        let second = Piece::new(1, &metadata, 1).unwrap();
+        assert_eq!(first.hash(), second.hash());
+
+        let pieces: HashSet<Piece> = [first, second, Piece::new(0, &metadata, 5).unwrap()]
+            .into_iter()
+            .collect();
+
+        assert_eq!(pieces.len(), 2);
+    }
+
+    #[test]
+    fn queue_pops_rarest_piece_first_and_unavailable_last() {
+        let metadata = metadata("http://127.0.0.1:1/announce", 4, &[7; 24]);
+        let availability = [3, 0, 1, 5, 2, 4];
+        let mut queue: BinaryHeap<Reverse<QueueKey>> = availability
+            .iter()
+            .enumerate()
+            .map(|(index, peers)| Reverse(QueueKey(Piece::new(index, &metadata, *peers).unwrap())))
+            .collect();
+
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|Reverse(QueueKey(piece))| piece.piece_index())
+            .collect();
+
+        assert_eq!(popped, [2, 4, 0, 5, 3, 1]);
+    }
+
+    #[test]
+    fn piece_order_is_stable_across_comparisons() {
+        let metadata = metadata("http://127.0.0.1:1/announce", 4, &[7; 8]);
+        let (first, second) = (
+            QueueKey(Piece::new(0, &metadata, 2).unwrap()),
+            QueueKey(Piece::new(1, &metadata, 2).unwrap()),
+        );
+
+        let order = first.cmp(&second);
+
+        assert_ne!(order, Ordering::Equal);
+        assert!(
+            (0..100).all(|_| first.cmp(&second) == order && second.cmp(&first) == order.reverse())
+        );
+        assert_eq!(first.cmp(&first), Ordering::Equal);
+    }
+
+    /// Piece of `metadata` with fixed tiebreak, so ordering doesn't depend on chance
+    fn keyed(
+        metadata: &TorrentMetadataInfo,
+        index: usize,
+        availability: usize,
+        tiebreak: u32,
+    ) -> QueueKey {
+        QueueKey(Piece {
+            tiebreak,
+            ..Piece::new(index, metadata, availability).unwrap()
+        })
+    }
+
+    #[test]
+    fn piece_order_is_antisymmetric_and_transitive() {
+        let first = metadata("http://127.0.0.1:1/announce", 4, &[7; 16]);
+        let second = metadata("http://127.0.0.1:1/announce", 4, &[8; 16]);
+        // NOTE: same index with other availability, and same index and tiebreak of other torrent
+        let pieces = [
+            keyed(&first, 0, 1, 5),
+            keyed(&first, 0, 5, 5),
+            keyed(&first, 0, 1, 5),
+            keyed(&first, 1, 1, 5),
+            keyed(&first, 1, 0, 2),
+            keyed(&first, 2, 3, 1),
+            keyed(&first, 3, 1, 0),
+            keyed(&second, 0, 1, 5),
+            keyed(&second, 1, 0, 2),
+        ];
+
+        for a in &pieces {
+            for b in &pieces {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{a:?} {b:?}");
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b, "{a:?} {b:?}");
+                for c in &pieces {
+                    if a < b && b < c {
+                        assert!(a < c, "{a:?} {b:?} {c:?}");
+                    }
+                }
+            }
+        }
+        assert_eq!(pieces[0], pieces[2]);
+        assert_ne!(pieces[0], pieces[1]);
+        assert_eq!(pieces[0].0, pieces[1].0);
+        assert_ne!(pieces[0], pieces[7]);
+        assert_ne!(pieces[0].0, pieces[7].0);
+    }
+
+    /// Block sizes of every piece of a torrent of `length` bytes
+    fn block_sizes(length: usize, piece_length: usize, block_size: u32) -> Vec<Vec<u32>> {
+        let metadata = metadata(
+            "http://127.0.0.1:1/announce",
+            piece_length,
+            &vec![0; length],
+        );
+        (0..metadata.info.pieces.len())
+            .map(|index| {
+                metadata
+                    .info
+                    .blocks_for_piece(index, block_size)
+                    .unwrap()
+                    .iter()
+                    .map(|block| block.block_size)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_piece_of_whole_blocks_has_no_empty_block() {
+        assert_eq!(block_sizes(32, 32, 16), [vec![16, 16]]);
+    }
+
+    #[test]
+    fn length_multiple_of_piece_and_block_has_no_empty_last_block() {
+        assert_eq!(
+            block_sizes(96, 32, 16),
+            [vec![16, 16], vec![16, 16], vec![16, 16]]
+        );
+        assert_eq!(block_sizes(64, 32, 32), [vec![32], vec![32]]);
+    }
+
+    #[test]
+    fn blocks_tile_every_piece_exactly() {
+        let metadata = metadata("http://127.0.0.1:1/announce", 40, &[0; 100]);
+
+        for index in 0..metadata.info.pieces.len() {
+            let blocks = metadata.info.blocks_for_piece(index, 16).unwrap();
+            let mut next_offset = 0;
+            for block in &blocks {
+                assert_eq!(block.piece_index, index as u32);
+                assert_eq!(block.block_offset, next_offset);
+                assert!(block.block_size > 0 && block.block_size <= 16);
+                next_offset += block.block_size;
+            }
+            assert_eq!(next_offset as usize, metadata.info.piece_size(index));
+        }
+        assert_eq!(block_sizes(100, 40, 16)[2], [16, 4]);
+        assert!(metadata.info.blocks_for_piece(3, 16).is_err());
+    }
+
+    #[test]
+    fn scratch_fill_of_100k_pieces_never_reallocates() {
+        const PIECES: usize = 100_000;
+        let info = TorrentInfo {
+            length: Some(PIECES * 64 - 10),
+            files: None,
+            name: "large".to_string(),
+            piece_length: 64,
+            pieces: vec![[0; 20]; PIECES].into(),
+            info_extra: Default::default(),
+        };
+        let mut scratch = Vec::new();
+        info.piece_blocks_into(0, 16, &mut scratch);
+        let (buffer, capacity) = (scratch.as_ptr(), scratch.capacity());
+
+        for index in 0..PIECES {
+            info.piece_blocks_into(index, 16, &mut scratch);
+
+            assert_eq!((scratch.as_ptr(), scratch.capacity()), (buffer, capacity));
+            let expected: Vec<PieceBlock> = (0..info.piece_size(index) as u32)
+                .step_by(16)
+                .map(|offset| PieceBlock {
+                    piece_index: index as u32,
+                    block_offset: offset,
+                    block_size: (info.piece_size(index) as u32 - offset).min(16),
+                })
+                .collect();
+            assert_eq!(scratch, expected);
+        }
+    }
+}
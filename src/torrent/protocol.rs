@@ -0,0 +1,158 @@
+use crate::prelude::*;
+
+// NOTE: wire format of peer protocol, encoders and decoders take sizes and ids
+// from here only, so they can't disagree. Reserved bits are in `ReservedBits`
+
+/// BEP 3 protocol string, preceded by its length in handshake
+pub const BITTORRENT_PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const BITTORRENT_PROTOCOL_LENGTH: u8 = BITTORRENT_PROTOCOL.len() as u8;
+pub const RESERVED_LENGTH: usize = 8;
+/// Info hash and peer id
+pub const HANDSHAKE_MEM_SIZE: usize = 40;
+/// 68 bytes: protocol length, protocol, reserved, info hash and peer id
+pub const HANDSHAKE_LENGTH: usize =
+    1 + BITTORRENT_PROTOCOL_LENGTH as usize + RESERVED_LENGTH + HANDSHAKE_MEM_SIZE;
+
+/// Big endian length in front of every message after handshake, zero is keep-alive
+pub const MESSAGE_LENGTH_PREFIX: usize = 4;
+/// Piece index
+pub const HAVE_PAYLOAD_LENGTH: usize = 4;
+/// Index, begin and length of request, cancel and reject
+pub const REQUEST_PAYLOAD_LENGTH: usize = 12;
+/// Index and begin in front of piece data
+pub const PIECE_HEADER_LENGTH: usize = 8;
+
+/// BEP 10 extended message id reserved for the extended handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+pub const UT_METADATA: &str = "ut_metadata";
+/// BEP 9 metadata is exchanged in pieces of 16 KiB
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Ids of messages we speak
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageId {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    /// BEP 6
+    RejectRequest = 16,
+    /// BEP 10
+    Extended = 20,
+}
+
+impl TryFrom<u8> for MessageId {
+    type Error = anyhow::Error;
+
+    fn try_from(id: u8) -> Result<Self> {
+        let message_id = match id {
+            0 => MessageId::Choke,
+            1 => MessageId::Unchoke,
+            2 => MessageId::Interested,
+            3 => MessageId::NotInterested,
+            4 => MessageId::Have,
+            5 => MessageId::Bitfield,
+            6 => MessageId::Request,
+            7 => MessageId::Piece,
+            8 => MessageId::Cancel,
+            16 => MessageId::RejectRequest,
+            20 => MessageId::Extended,
+            _ => bail!("Unknown message id {id}"),
+        };
+        Ok(message_id)
+    }
+}
+
+/// BEP 9 `msg_type` of ut_metadata messages
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+impl TryFrom<u8> for MetadataMessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(msg_type: u8) -> Result<Self> {
+        let msg_type = match msg_type {
+            0 => MetadataMessageType::Request,
+            1 => MetadataMessageType::Data,
+            2 => MetadataMessageType::Reject,
+            _ => bail!("unknown ut_metadata message type {msg_type}"),
+        };
+        Ok(msg_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every message id with its value in BEP 3, 6 and 10
+    const MESSAGE_IDS: [(MessageId, u8); 11] = [
+        (MessageId::Choke, 0),
+        (MessageId::Unchoke, 1),
+        (MessageId::Interested, 2),
+        (MessageId::NotInterested, 3),
+        (MessageId::Have, 4),
+        (MessageId::Bitfield, 5),
+        (MessageId::Request, 6),
+        (MessageId::Piece, 7),
+        (MessageId::Cancel, 8),
+        (MessageId::RejectRequest, 16),
+        (MessageId::Extended, 20),
+    ];
+
+    #[test]
+    fn sizes_match_spec() {
+        assert_eq!(BITTORRENT_PROTOCOL, b"BitTorrent protocol");
+        assert_eq!(BITTORRENT_PROTOCOL_LENGTH, 19);
+        assert_eq!(RESERVED_LENGTH, 8);
+        assert_eq!(HANDSHAKE_MEM_SIZE, 40);
+        assert_eq!(HANDSHAKE_LENGTH, 68);
+        assert_eq!(MESSAGE_LENGTH_PREFIX, 4);
+        assert_eq!(HAVE_PAYLOAD_LENGTH, 4);
+        assert_eq!(REQUEST_PAYLOAD_LENGTH, 12);
+        assert_eq!(PIECE_HEADER_LENGTH, 8);
+        assert_eq!(EXTENDED_HANDSHAKE_ID, 0);
+        assert_eq!(UT_METADATA, "ut_metadata");
+        assert_eq!(METADATA_PIECE_SIZE, 16384);
+    }
+
+    #[test]
+    fn message_ids_match_spec_and_round_trip() {
+        for (id, value) in MESSAGE_IDS {
+            assert_eq!(id as u8, value, "{id:?}");
+            assert_eq!(MessageId::try_from(value).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn unknown_message_ids_are_rejected() {
+        for value in 0..=u8::MAX {
+            let known = MESSAGE_IDS.iter().any(|(_, f)| *f == value);
+            assert_eq!(MessageId::try_from(value).is_ok(), known, "id {value}");
+        }
+    }
+
+    #[test]
+    fn metadata_message_types_match_spec_and_round_trip() {
+        for (msg_type, value) in [
+            (MetadataMessageType::Request, 0),
+            (MetadataMessageType::Data, 1),
+            (MetadataMessageType::Reject, 2),
+        ] {
+            assert_eq!(msg_type as u8, value);
+            assert_eq!(MetadataMessageType::try_from(value).unwrap(), msg_type);
+        }
+        assert!(MetadataMessageType::try_from(3).is_err());
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::DisconnectReason;
+
+/// Number of recently completed pieces kept for latency aggregates
+const PIECE_TIMINGS_CAPACITY: usize = 1024;
+const SLOWEST_PIECES: usize = 10;
+/// Seconds current rates are averaged over
+const RATE_WINDOW_SECONDS: usize = 5;
+
+/// Time from first block request to piece completion
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceTiming {
+    pub piece_index: usize,
+    pub latency_ms: u64,
+    pub peers: usize,
+    pub retries: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LatencySummary {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Bytes per second over the last few seconds, kept in one second buckets
+#[derive(Debug)]
+pub struct RateMeter {
+    started: Instant,
+    /// Second since start and bytes in it
+    buckets: [(u64, u64); RATE_WINDOW_SECONDS],
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            buckets: [(0, 0); RATE_WINDOW_SECONDS],
+        }
+    }
+}
+
+impl RateMeter {
+    pub fn record(&mut self, bytes: u64) {
+        let second = self.started.elapsed().as_secs();
+        let bucket = &mut self.buckets[second as usize % RATE_WINDOW_SECONDS];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 += bytes;
+    }
+
+    pub fn rate(&self) -> f64 {
+        let second = self.started.elapsed().as_secs();
+        let bytes: u64 = self
+            .buckets
+            .iter()
+            .filter(|(bucket_second, _)| second - bucket_second < RATE_WINDOW_SECONDS as u64)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        // NOTE: current second is partial, window is shorter right after start
+        let window = self
+            .started
+            .elapsed()
+            .as_secs_f64()
+            .min(RATE_WINDOW_SECONDS as f64);
+        bytes as f64 / window.max(1.0)
+    }
+}
+
+/// Counters collected while downloading
+#[derive(Debug)]
+pub struct TransferStats {
+    started: Instant,
+    last_progress: Instant,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_wasted: u64,
+    pub hash_failures: u32,
+    pub endgame_waste: u64,
+    pub peak_rate: f64,
+    pub peers_used: HashSet<SocketAddr>,
+    /// Peers seen having every piece
+    pub seeds_used: HashSet<SocketAddr>,
+    pub completed_pieces: BTreeSet<usize>,
+    pub disconnect_reasons: BTreeMap<String, u32>,
+    pub piece_retries: BTreeMap<usize, u32>,
+    pub abandoned_pieces: BTreeSet<usize>,
+    /// Failed attempts each peer sent blocks to, by piece
+    pub failed_piece_peers: BTreeMap<usize, BTreeMap<SocketAddr, u32>>,
+    /// Pieces each peer corrupted, counted once piece verified from others
+    pub hash_strikes: HashMap<SocketAddr, u32>,
+    pub banned_peers: BTreeSet<SocketAddr>,
+    /// Pieces found verified on disk before download started
+    pub resumed_pieces: usize,
+    /// Ring buffer of the last completed pieces
+    pub piece_timings: VecDeque<PieceTiming>,
+    /// Slowest pieces of the whole download, slowest first
+    pub slowest_pieces: Vec<PieceTiming>,
+    /// Pieces not verified by their deadline
+    pub missed_deadlines: BTreeSet<usize>,
+    pub download_rate: RateMeter,
+    pub upload_rate: RateMeter,
+}
+
+impl Default for TransferStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            last_progress: Instant::now(),
+            bytes_downloaded: 0,
+            bytes_uploaded: 0,
+            bytes_wasted: 0,
+            hash_failures: 0,
+            endgame_waste: 0,
+            peak_rate: 0.0,
+            peers_used: HashSet::new(),
+            seeds_used: HashSet::new(),
+            completed_pieces: BTreeSet::new(),
+            disconnect_reasons: BTreeMap::new(),
+            piece_retries: BTreeMap::new(),
+            abandoned_pieces: BTreeSet::new(),
+            failed_piece_peers: BTreeMap::new(),
+            hash_strikes: HashMap::new(),
+            banned_peers: BTreeSet::new(),
+            resumed_pieces: 0,
+            piece_timings: VecDeque::with_capacity(PIECE_TIMINGS_CAPACITY),
+            slowest_pieces: Vec::with_capacity(SLOWEST_PIECES + 1),
+            missed_deadlines: BTreeSet::new(),
+            download_rate: RateMeter::default(),
+            upload_rate: RateMeter::default(),
+        }
+    }
+}
+
+impl TransferStats {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn block_downloaded(&mut self, bytes: u64) {
+        self.bytes_downloaded += bytes;
+        self.download_rate.record(bytes);
+    }
+
+    pub fn block_uploaded(&mut self, bytes: u64) {
+        self.bytes_uploaded += bytes;
+        self.upload_rate.record(bytes);
+    }
+
+    /// Download is considered stalled after this point without a completed piece
+    pub fn progress_deadline(&self, stall_timeout: Duration) -> tokio::time::Instant {
+        (self.last_progress + stall_timeout).into()
+    }
+
+    pub fn piece_completed(
+        &mut self,
+        piece_index: usize,
+        piece_length: usize,
+        took: Duration,
+        peers: usize,
+    ) {
+        self.completed_pieces.insert(piece_index);
+        self.last_progress = Instant::now();
+        let rate = piece_length as f64 / took.as_secs_f64().max(f64::EPSILON);
+        self.peak_rate = self.peak_rate.max(rate);
+
+        let timing = PieceTiming {
+            piece_index,
+            latency_ms: took.as_millis() as u64,
+            peers,
+            retries: self.piece_retries.get(&piece_index).copied().unwrap_or(0),
+        };
+        if self.piece_timings.len() == PIECE_TIMINGS_CAPACITY {
+            self.piece_timings.pop_front();
+        }
+        self.piece_timings.push_back(timing.clone());
+
+        let position = self
+            .slowest_pieces
+            .partition_point(|f| f.latency_ms >= timing.latency_ms);
+        if position < SLOWEST_PIECES {
+            self.slowest_pieces.insert(position, timing);
+            self.slowest_pieces.truncate(SLOWEST_PIECES);
+        }
+    }
+
+    /// Latency percentiles over recently completed pieces
+    pub fn latency_summary(&self) -> LatencySummary {
+        let mut latencies: Vec<u64> = self.piece_timings.iter().map(|f| f.latency_ms).collect();
+        if latencies.is_empty() {
+            return LatencySummary::default();
+        }
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        LatencySummary {
+            p50_ms: percentile(50),
+            p95_ms: percentile(95),
+            max_ms: latencies[latencies.len() - 1],
+        }
+    }
+
+    /// Counts a failed attempt, returns false once piece exceeded retries and is abandoned
+    pub fn piece_failed(&mut self, piece_index: usize, max_retries: u32) -> bool {
+        let retries = self.piece_retries.entry(piece_index).or_default();
+        *retries += 1;
+        if *retries > max_retries {
+            self.abandoned_pieces.insert(piece_index);
+            return false;
+        }
+        true
+    }
+
+    /// Peers of a failed attempt become suspects of the piece
+    pub fn piece_corrupted(&mut self, piece_index: usize, peers: &[SocketAddr]) {
+        let suspects = self.failed_piece_peers.entry(piece_index).or_default();
+        for peer in peers {
+            *suspects.entry(*peer).or_default() += 1;
+        }
+    }
+
+    /// Suspects of a piece that verified without them get a strike,
+    /// returns those reaching `max_strikes`
+    pub fn piece_verified(
+        &mut self,
+        piece_index: usize,
+        peers: &[SocketAddr],
+        max_strikes: u32,
+    ) -> Vec<SocketAddr> {
+        let Some(suspects) = self.failed_piece_peers.remove(&piece_index) else {
+            return Vec::new();
+        };
+        suspects
+            .into_keys()
+            .filter(|suspect| !peers.contains(suspect))
+            .filter(|culprit| {
+                let strikes = self.hash_strikes.entry(*culprit).or_default();
+                *strikes += 1;
+                *strikes >= max_strikes
+            })
+            .collect()
+    }
+
+    pub fn peer_disconnected(&mut self, error: &anyhow::Error) {
+        let reason = match error.downcast_ref::<DisconnectReason>() {
+            Some(reason) => reason.label().to_string(),
+            None => "error".to_string(),
+        };
+        *self.disconnect_reasons.entry(reason).or_default() += 1;
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DownloadOutcome {
+    Completed,
+    Partial {
+        missing_pieces: Vec<usize>,
+        abandoned_pieces: Vec<usize>,
+        /// Peers that sent abandoned pieces, by piece
+        abandoned_piece_peers: BTreeMap<usize, Vec<SocketAddr>>,
+    },
+    Failed {
+        error: String,
+        missing_pieces: Vec<usize>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadReport {
+    pub elapsed_seconds: f64,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_wasted: u64,
+    pub average_rate: f64,
+    pub peak_rate: f64,
+    pub peers_used: usize,
+    pub seeds_used: usize,
+    pub resumed_pieces: usize,
+    pub tracker_announces: BTreeMap<String, u32>,
+    /// Compact or dictionary peers, per tracker
+    pub tracker_peer_modes: BTreeMap<String, &'static str>,
+    /// Peer addresses dropped by peer filter, by reason
+    pub denied_peers: BTreeMap<String, u32>,
+    /// Addresses that answered handshake in another protocol
+    pub wrong_protocol_peers: usize,
+    pub hash_failures: u32,
+    /// Addresses banned for sending pieces failing their hash
+    pub corrupt_peers: Vec<SocketAddr>,
+    pub endgame_waste: u64,
+    pub disconnect_reasons: BTreeMap<String, u32>,
+    pub piece_latency: LatencySummary,
+    pub slowest_pieces: Vec<PieceTiming>,
+    pub missed_deadlines: Vec<usize>,
+    pub outcome: DownloadOutcome,
+}
+
+impl DownloadReport {
+    pub fn new(
+        stats: &TransferStats,
+        tracker_announces: BTreeMap<String, u32>,
+        tracker_peer_modes: BTreeMap<String, &'static str>,
+        denied_peers: BTreeMap<String, u32>,
+        wrong_protocol_peers: usize,
+        number_of_pieces: usize,
+        error: Option<anyhow::Error>,
+    ) -> Self {
+        let elapsed = stats.elapsed();
+        let missing_pieces: Vec<usize> = (0..number_of_pieces)
+            .filter(|piece_index| !stats.completed_pieces.contains(piece_index))
+            .collect();
+
+        let outcome = match error {
+            Some(error) => DownloadOutcome::Failed {
+                error: format!("{error:#}"),
+                missing_pieces,
+            },
+            None if missing_pieces.is_empty() => DownloadOutcome::Completed,
+            None => DownloadOutcome::Partial {
+                missing_pieces,
+                abandoned_pieces: stats.abandoned_pieces.iter().copied().collect(),
+                abandoned_piece_peers: stats
+                    .abandoned_pieces
+                    .iter()
+                    .filter_map(|piece_index| {
+                        let peers = stats.failed_piece_peers.get(piece_index)?;
+                        Some((*piece_index, peers.keys().copied().collect()))
+                    })
+                    .collect(),
+            },
+        };
+
+        Self {
+            elapsed_seconds: elapsed.as_secs_f64(),
+            bytes_downloaded: stats.bytes_downloaded,
+            bytes_uploaded: stats.bytes_uploaded,
+            bytes_wasted: stats.bytes_wasted,
+            average_rate: stats.bytes_downloaded as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            peak_rate: stats.peak_rate,
+            peers_used: stats.peers_used.len(),
+            seeds_used: stats.seeds_used.len(),
+            resumed_pieces: stats.resumed_pieces,
+            tracker_announces,
+            tracker_peer_modes,
+            denied_peers,
+            wrong_protocol_peers,
+            hash_failures: stats.hash_failures,
+            corrupt_peers: stats.banned_peers.iter().copied().collect(),
+            endgame_waste: stats.endgame_waste,
+            disconnect_reasons: stats.disconnect_reasons.clone(),
+            piece_latency: stats.latency_summary(),
+            slowest_pieces: stats.slowest_pieces.clone(),
+            missed_deadlines: stats.missed_deadlines.iter().copied().collect(),
+            outcome,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        matches!(self.outcome, DownloadOutcome::Completed)
+    }
+}
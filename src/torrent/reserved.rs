@@ -0,0 +1,107 @@
+use std::fmt;
+
+/// Extension bits of handshake reserved bytes, bit 0 is the last bit of the last byte
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReservedBits(u64);
+
+impl ReservedBits {
+    /// BEP 5, reserved[7] & 0x01
+    pub const DHT: ReservedBits = ReservedBits(1 << 0);
+    /// BEP 6, reserved[7] & 0x04
+    pub const FAST: ReservedBits = ReservedBits(1 << 2);
+    /// BEP 10, reserved[5] & 0x10
+    pub const LTEP: ReservedBits = ReservedBits(1 << 20);
+
+    pub const fn empty() -> Self {
+        ReservedBits(0)
+    }
+
+    /// The only place deciding which extensions we advertise
+    pub fn advertised() -> Self {
+        Self::empty() | Self::LTEP
+    }
+
+    pub const fn contains(self, other: ReservedBits) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn to_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub const fn from_bytes(bytes: [u8; 8]) -> Self {
+        ReservedBits(u64::from_be_bytes(bytes))
+    }
+
+    /// Names of set bits we know, unknown ones are left out
+    pub fn known_names(self) -> Vec<&'static str> {
+        [
+            ("DHT", Self::DHT),
+            ("FAST", Self::FAST),
+            ("LTEP", Self::LTEP),
+        ]
+        .into_iter()
+        .filter(|(_, bit)| self.contains(*bit))
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+impl std::ops::BitOr for ReservedBits {
+    type Output = ReservedBits;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        ReservedBits(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for ReservedBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReservedBits({:#018x} {:?})", self.0, self.known_names())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reserved bytes of a handshake advertising DHT, Fast and extension protocol,
+    /// as libtorrent based clients send them
+    const DHT_FAST_LTEP: [u8; 8] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x05];
+
+    #[test]
+    fn known_bits_sit_where_beps_put_them() {
+        assert_eq!(ReservedBits::DHT.to_bytes(), [0, 0, 0, 0, 0, 0, 0, 0x01]);
+        assert_eq!(ReservedBits::FAST.to_bytes(), [0, 0, 0, 0, 0, 0, 0, 0x04]);
+        assert_eq!(ReservedBits::LTEP.to_bytes(), [0, 0, 0, 0, 0, 0x10, 0, 0]);
+    }
+
+    #[test]
+    fn captured_handshake_bits_round_trip() {
+        let bits = ReservedBits::from_bytes(DHT_FAST_LTEP);
+
+        assert!(bits.contains(ReservedBits::DHT));
+        assert!(bits.contains(ReservedBits::FAST));
+        assert!(bits.contains(ReservedBits::LTEP));
+        assert_eq!(bits.known_names(), ["DHT", "FAST", "LTEP"]);
+        assert_eq!(bits.to_bytes(), DHT_FAST_LTEP);
+    }
+
+    #[test]
+    fn unknown_bits_are_kept_but_not_named() {
+        let bytes = [0x80, 0, 0, 0, 0, 0x10, 0, 0x10];
+        let bits = ReservedBits::from_bytes(bytes);
+
+        assert_eq!(bits.known_names(), ["LTEP"]);
+        assert!(!bits.contains(ReservedBits::DHT));
+        assert_eq!(bits.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn advertised_is_extension_protocol_only() {
+        assert_eq!(
+            ReservedBits::advertised().to_bytes(),
+            [0, 0, 0, 0, 0, 0x10, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bitvec::{order::Msb0, vec::BitVec};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::prelude::*;
+
+use super::{sidecar, Storage};
+
+/// Resume file is saved at most this often while pieces are written
+pub const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Pieces of a trusted resume file hashed again before it's believed
+const RESUME_SPOT_CHECKS: usize = 4;
+
+/// Pieces written and synced to disk, kept as `<output>.resume` next to download
+/// so resuming doesn't hash the whole download again
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ResumeState {
+    info_hash: ByteBuf,
+    /// Bitfield in wire order, spare bits are zero
+    pieces: ByteBuf,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl ResumeState {
+    pub fn new(info_hash: Bytes20, have: &BitVec<u8, Msb0>) -> Self {
+        Self {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            pieces: ByteBuf::from(have.clone().into_vec()),
+            downloaded: 0,
+            uploaded: 0,
+        }
+    }
+
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = output.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// Missing file gives empty state, corrupt one is moved aside
+    pub fn load(path: &Path) -> Result<Self> {
+        sidecar::load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        sidecar::save(path, self)
+    }
+
+    pub fn is_for(&self, info_hash: Bytes20) -> bool {
+        *self.info_hash == info_hash
+    }
+
+    /// Bitfield of state, none if state belongs to another torrent or has other size
+    pub fn pieces_of(&self, info_hash: Bytes20, num_pieces: usize) -> Option<BitVec<u8, Msb0>> {
+        if !self.is_for(info_hash) || self.pieces.len() != num_pieces.div_ceil(8) {
+            return None;
+        }
+        let mut pieces = BitVec::from_vec(self.pieces.to_vec());
+        if pieces[num_pieces..].any() {
+            return None;
+        }
+        pieces.truncate(num_pieces);
+        Some(pieces)
+    }
+
+    /// Syncs download first, state never claims pieces that could still be lost.
+    /// Resume file only saves hashing, failing to keep it isn't fatal
+    pub fn save_synced(&self, storage: &mut Storage, path: &Path) {
+        if let Err(e) = storage.sync().and_then(|()| self.save(path)) {
+            warn!("saving resume state failed: {e:#}");
+        }
+    }
+
+    pub fn piece_written(&mut self, piece_index: usize, length: usize) {
+        self.pieces[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        self.downloaded += length as u64;
+    }
+}
+
+/// Records piece written over existing download. Without resume file of the
+/// torrent nothing is recorded, next download hashes every piece anyway
+pub fn piece_repaired(
+    storage: &mut Storage,
+    output: &Path,
+    info_hash: Bytes20,
+    num_pieces: usize,
+    piece_index: usize,
+    length: usize,
+) {
+    let path = ResumeState::path(output);
+    let mut state = match ResumeState::load(&path) {
+        Ok(state) if state.pieces_of(info_hash, num_pieces).is_some() => state,
+        Ok(_) => {
+            trace!("no resume state to update");
+            return;
+        }
+        Err(e) => {
+            warn!("{e:#}");
+            return;
+        }
+    };
+    state.piece_written(piece_index, length);
+    state.save_synced(storage, &path);
+}
+
+/// Hashes a few pieces state claims, spread over the download
+pub fn spot_check(
+    storage: &mut Storage,
+    pieces: &BitVec<u8, Msb0>,
+    hashes: &[Bytes20],
+    piece_length: usize,
+) -> Result<bool> {
+    let claimed: Vec<usize> = pieces.iter_ones().collect();
+    let step = claimed.len().div_ceil(RESUME_SPOT_CHECKS).max(1);
+    let checked = claimed.iter().step_by(step).chain(claimed.last());
+    let total_length = storage.total_length();
+    let mut piece = Vec::with_capacity(piece_length);
+    for &index in checked {
+        let offset = (index * piece_length) as u64;
+        piece.resize((piece_length as u64).min(total_length - offset) as usize, 0);
+        storage
+            .read(offset, &mut piece)
+            .with_context(|| format!("reading piece {index}"))?;
+        if sha1_hash(&piece) != hashes[index] {
+            debug!("resumed piece {index} doesn't match its hash");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use super::*;
+    use crate::torrent::{testing::metadata, TorrentMetadataInfo};
+
+    const PIECE_LENGTH: usize = 16;
+
+    /// Download of four pieces in a fresh directory
+    fn download(name: &str) -> (PathBuf, Storage, TorrentMetadataInfo) {
+        let data = vec![7u8; PIECE_LENGTH * 4];
+        let metadata = metadata("http://127.0.0.1:1/announce", PIECE_LENGTH, &data);
+        let dir = std::env::temp_dir().join(format!("resume-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("test.bin");
+        std::fs::write(&output, &data).unwrap();
+        let storage = Storage::new(
+            output.clone(),
+            &metadata.info,
+            4,
+            Arc::new(AtomicUsize::new(0)),
+        )
+        .unwrap();
+        (output, storage, metadata)
+    }
+
+    #[test]
+    fn repaired_piece_is_added_to_resume_state() {
+        let (output, mut storage, metadata) = download("repaired");
+        let mut have = BitVec::repeat(false, 4);
+        have.set(0, true);
+        ResumeState::new(metadata.info_hash, &have)
+            .save(&ResumeState::path(&output))
+            .unwrap();
+
+        piece_repaired(
+            &mut storage,
+            &output,
+            metadata.info_hash,
+            4,
+            2,
+            PIECE_LENGTH,
+        );
+
+        let state = ResumeState::load(&ResumeState::path(&output)).unwrap();
+        let pieces = state.pieces_of(metadata.info_hash, 4).unwrap();
+        assert_eq!(pieces.iter_ones().collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(state.downloaded, PIECE_LENGTH as u64);
+        std::fs::remove_dir_all(output.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn repair_without_resume_state_records_nothing() {
+        let (output, mut storage, metadata) = download("no-state");
+
+        piece_repaired(
+            &mut storage,
+            &output,
+            metadata.info_hash,
+            4,
+            2,
+            PIECE_LENGTH,
+        );
+
+        assert!(!ResumeState::path(&output).exists());
+        std::fs::remove_dir_all(output.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use crate::prelude::*;
+
+use super::{Storage, Torrent, TorrentInfo};
+
+impl Torrent {
+    /// Puts pieces of `existing` copy matching their hash into `output`, so download
+    /// picks them up as present. Fully matching copy is hard linked, or copied
+    /// across filesystems, unless output files are already there. Returns pieces reused
+    #[instrument(skip(self))]
+    pub fn link_existing(&self, existing: &Path, output: &Path) -> Result<usize> {
+        let mut source = self.storage(existing.to_path_buf())?;
+        source
+            .validate_existing()
+            .context("existing copy doesn't match torrent files")?;
+        let have = source.verified_pieces(&self.metadata.info)?;
+        let reused = have.count_ones();
+        debug!("{reused} of {} pieces match", have.len());
+        if reused == 0 {
+            warn!("no piece of {} matches torrent", existing.display());
+            return Ok(0);
+        }
+
+        let target = self.storage(output.to_path_buf())?;
+        let target_exists = target.file_paths().any(|path| path.exists());
+        if have.all() && !target_exists {
+            for (from, to) in source.file_paths().zip(target.file_paths()) {
+                link_or_copy(from, to)?;
+            }
+            return Ok(reused);
+        }
+
+        copy_pieces(&mut source, target, have.iter_ones(), &self.metadata.info)?;
+        Ok(reused)
+    }
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating directory {:?}", parent))?;
+    }
+    if let Err(e) = std::fs::hard_link(from, to) {
+        warn!("hard linking {from:?} failed, copying: {e}");
+        std::fs::copy(from, to).with_context(|| format!("copying {from:?} to {to:?}"))?;
+    }
+    Ok(())
+}
+
+/// Copies verified pieces one by one, rest of output is left for download
+fn copy_pieces(
+    source: &mut Storage,
+    mut target: Storage,
+    pieces: impl Iterator<Item = usize>,
+    info: &TorrentInfo,
+) -> Result<()> {
+    target.allocate()?;
+    let mut piece = Vec::with_capacity(info.piece_length);
+    for index in pieces {
+        piece.resize(info.piece_size(index), 0);
+        let offset = (index * info.piece_length) as u64;
+        source
+            .read(offset, &mut piece)
+            .with_context(|| format!("reading piece {index}"))?;
+        target
+            .write(offset, &piece)
+            .with_context(|| format!("writing piece {index}"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use bitvec::{order::Msb0, vec::BitVec};
+use futures::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+use crate::prelude::*;
+
+use super::{Peer, PeerSourceKind, Storage, Torrent, TorrentState, TransferStats};
+
+impl Torrent {
+    /// Serves completed download at `path` to peers connecting to our port,
+    /// runs until stopped. Only pieces matching their hash are announced
+    #[instrument(skip(self))]
+    pub async fn seed(&self, path: PathBuf) -> Result<()> {
+        let mut storage = self.storage(path)?;
+        storage
+            .validate_existing()
+            .context("checking download to seed")?;
+        let have = storage.verified_pieces(&self.metadata.info)?;
+        anyhow::ensure!(have.any(), "no valid pieces to seed");
+        debug!(
+            "seeding {} of {} pieces",
+            have.count_ones(),
+            self.metadata.info.pieces.len()
+        );
+
+        let mut stats = TransferStats::default();
+        stats.completed_pieces = have.iter_ones().collect();
+        *self.stats() = stats;
+        self.board().state = TorrentState::Seeding;
+        let storage = Mutex::new(storage);
+        let slots = Arc::new(Semaphore::new(self.config.upload_slots));
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.port))
+            .await
+            .with_context(|| format!("binding port {}", self.port))?;
+        debug!("listening on {}", self.port);
+
+        let mut leechers = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, socket_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("accepting connection failed: {e:#}");
+                            continue;
+                        }
+                    };
+                    if leechers.len() >= self.max_peers as usize {
+                        debug!("{} leechers connected, refusing {socket_addr}", leechers.len());
+                        continue;
+                    }
+                    if !self.is_peer_allowed(socket_addr, PeerSourceKind::Incoming) {
+                        continue;
+                    }
+                    leechers.push(self.serve_leecher(stream, socket_addr, &storage, &have, slots.clone()));
+                }
+                Some((socket_addr, result)) = leechers.next() => match result {
+                    Ok(()) => trace!("leecher {socket_addr} left"),
+                    Err(e) => debug!("leecher {socket_addr} dropped: {e:#}"),
+                }
+            }
+        }
+    }
+
+    async fn serve_leecher(
+        &self,
+        stream: TcpStream,
+        socket_addr: SocketAddr,
+        storage: &Mutex<Storage>,
+        have: &BitVec<u8, Msb0>,
+        slots: Arc<Semaphore>,
+    ) -> (SocketAddr, Result<()>) {
+        let result = async {
+            let mut peer = Peer::accept(
+                stream,
+                socket_addr,
+                self.peer_id,
+                self.metadata.info_hash,
+                &self.metadata.info,
+                have,
+            )
+            .await?;
+            peer.attach_session_log(self.session_log.as_deref());
+            peer.serve(storage, have, slots, |bytes| {
+                self.stats().block_uploaded(bytes)
+            })
+            .await
+        }
+        .await;
+        (socket_addr, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::torrent::{
+        testing::{metadata, TempDir},
+        PeerId,
+    };
+
+    const PIECE_LENGTH: usize = 32;
+
+    fn data() -> Vec<u8> {
+        (0..2 * PIECE_LENGTH as u8).collect()
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Seeder of [`data`] on a free port, with its download already in `dir`
+    fn seeder(dir: &TempDir) -> (Torrent, u16) {
+        std::fs::write(dir.join("test.bin"), data()).unwrap();
+        let port = free_port();
+        let torrent = Torrent::builder(metadata(
+            "http://127.0.0.1:1/announce",
+            PIECE_LENGTH,
+            &data(),
+        ))
+        .port(port)
+        .build()
+        .unwrap();
+        (torrent, port)
+    }
+
+    #[tokio::test]
+    async fn piece_is_downloaded_from_own_seeder() {
+        let dir = TempDir::new("seed-loopback");
+        let (torrent, port) = seeder(&dir);
+        let leecher = async {
+            // NOTE: seeding listener needs a moment to bind
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let mut peer = Peer::connect(
+                ([127, 0, 0, 1], port).into(),
+                PeerId::from([2; 20]),
+                torrent.metadata.info_hash,
+                &torrent.metadata.info,
+                Duration::from_secs(1),
+            )
+            .await
+            .expect("own seeder accepts");
+            let blocks = torrent.metadata.info.blocks_for_piece(1, 16).unwrap();
+            peer.receive_file_piece(1, blocks).await
+        };
+
+        let piece = tokio::select! {
+            result = torrent.seed(dir.join("test.bin")) => panic!("seeding stopped: {result:?}"),
+            piece = leecher => piece.unwrap(),
+        };
+
+        assert_eq!(piece, data()[PIECE_LENGTH..]);
+        assert_eq!(torrent.stats().bytes_uploaded, PIECE_LENGTH as u64);
+    }
+
+    #[tokio::test]
+    async fn handshake_for_other_torrent_is_refused() {
+        let dir = TempDir::new("seed-other-torrent");
+        let (torrent, port) = seeder(&dir);
+        let leecher = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let mut handshake = vec![19];
+            handshake.extend_from_slice(b"BitTorrent protocol");
+            handshake.extend_from_slice(&[0; 8]);
+            handshake.extend_from_slice(&[0xab; 20]);
+            handshake.extend_from_slice(&[3; 20]);
+            stream.write_all(&handshake).await.unwrap();
+            let mut reply = Vec::new();
+            let _ = stream.read_to_end(&mut reply).await;
+            reply
+        };
+
+        let reply = tokio::select! {
+            result = torrent.seed(dir.join("test.bin")) => panic!("seeding stopped: {result:?}"),
+            reply = leecher => reply,
+        };
+
+        assert!(
+            !reply.windows(20).any(|f| f == torrent.metadata.info_hash),
+            "answered with our info hash: {reply:?}"
+        );
+        assert!(reply.len() < 68, "handshake completed: {reply:?}");
+    }
+}
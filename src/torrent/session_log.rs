@@ -0,0 +1,455 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+use super::{MessageSummary, TrackerEvent};
+
+/// Disconnect reason of connections we closed ourselves
+pub const CLOSED_REASON: &str = "closed";
+
+/// Part of client a record comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    Tracker,
+    Peer,
+    Scheduler,
+    Disk,
+}
+
+/// What happened, written by [`SessionLog`] and read back by [`SessionReport`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// Tracker url has its password redacted
+    Announce {
+        tracker: String,
+        announce_event: Option<TrackerEvent>,
+    },
+    AnnounceResponse {
+        tracker: String,
+        peers: usize,
+        interval_seconds: u64,
+        min_interval_seconds: Option<u64>,
+    },
+    AnnounceFailed {
+        tracker: String,
+        error: String,
+    },
+    PeerConnected {
+        peer: SocketAddr,
+        peer_id: String,
+        extensions: Vec<String>,
+        pieces: usize,
+        choked: bool,
+    },
+    PeerDisconnected {
+        peer: SocketAddr,
+        reason: String,
+        /// Messages exchanged last before disconnect, oldest first
+        #[serde(default)]
+        last_messages: Vec<MessageSummary>,
+    },
+    Choked {
+        peer: SocketAddr,
+    },
+    Unchoked {
+        peer: SocketAddr,
+    },
+    Interested {
+        peer: SocketAddr,
+    },
+    /// Leecher got the last piece it was missing
+    PeerBecameSeed {
+        peer: SocketAddr,
+    },
+    BlockRequested {
+        peer: SocketAddr,
+        piece: usize,
+        offset: u32,
+        length: u32,
+    },
+    PieceAssigned {
+        piece: usize,
+        peers: Vec<SocketAddr>,
+    },
+    PieceVerified {
+        piece: usize,
+    },
+    PieceFailed {
+        piece: usize,
+        reason: String,
+    },
+    PieceWritten {
+        piece: usize,
+        offset: u64,
+        length: usize,
+    },
+}
+
+impl SessionEvent {
+    pub fn subsystem(&self) -> Subsystem {
+        match self {
+            SessionEvent::Announce { .. }
+            | SessionEvent::AnnounceResponse { .. }
+            | SessionEvent::AnnounceFailed { .. } => Subsystem::Tracker,
+            SessionEvent::PeerConnected { .. }
+            | SessionEvent::PeerDisconnected { .. }
+            | SessionEvent::Choked { .. }
+            | SessionEvent::Unchoked { .. }
+            | SessionEvent::Interested { .. }
+            | SessionEvent::PeerBecameSeed { .. }
+            | SessionEvent::BlockRequested { .. } => Subsystem::Peer,
+            SessionEvent::PieceAssigned { .. }
+            | SessionEvent::PieceVerified { .. }
+            | SessionEvent::PieceFailed { .. } => Subsystem::Scheduler,
+            SessionEvent::PieceWritten { .. } => Subsystem::Disk,
+        }
+    }
+}
+
+/// One line of session log, `at_us` counts microseconds from log creation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub at_us: u64,
+    pub subsystem: Subsystem,
+    #[serde(flatten)]
+    pub event: SessionEvent,
+}
+
+/// Chronological NDJSON stream of tracker, peer, scheduler and disk activity.
+/// Lines are flushed as written, so the log survives a killed download
+#[derive(Debug)]
+pub struct SessionLog {
+    started: Instant,
+    writer: Mutex<LineWriter<File>>,
+    /// Set once writing failed, the rest of the session isn't recorded
+    failed: AtomicBool,
+}
+
+impl SessionLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("creating session log {}", path.display()))?;
+        Ok(Self {
+            started: Instant::now(),
+            writer: Mutex::new(LineWriter::new(file)),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn record(&self, event: SessionEvent) {
+        if self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut writer = self.writer.lock().expect("session log lock");
+        // NOTE: taken under lock, so timestamps never go back in the file
+        let record = SessionRecord {
+            at_us: self.started.elapsed().as_micros() as u64,
+            subsystem: event.subsystem(),
+            event,
+        };
+        let result = serde_json::to_writer(&mut *writer, &record)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| writer.write_all(b"\n").map_err(anyhow::Error::from));
+        if let Err(e) = result {
+            warn!("session log stopped: {e:#}");
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Records `event` when there's a log to record to
+pub fn record_to(log: Option<&SessionLog>, event: impl FnOnce() -> SessionEvent) {
+    if let Some(log) = log {
+        log.record(event());
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PieceTimeline {
+    pub assigned_us: Vec<u64>,
+    pub failures: Vec<String>,
+    pub verified_us: Option<u64>,
+    pub written_us: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PeerTimeline {
+    pub connected_us: Option<u64>,
+    pub disconnected_us: Option<u64>,
+    pub disconnect_reason: Option<String>,
+    pub became_seed_us: Option<u64>,
+    pub last_messages: Vec<MessageSummary>,
+    pub chokes: u32,
+    pub unchokes: u32,
+    pub blocks_requested: u32,
+    /// Pieces peer was assigned to
+    pub pieces: Vec<usize>,
+}
+
+impl PeerTimeline {
+    /// Disconnected for another reason than our own polite close
+    pub fn is_abnormal_disconnect(&self) -> bool {
+        self.disconnect_reason
+            .as_deref()
+            .is_some_and(|reason| reason != CLOSED_REASON)
+    }
+}
+
+/// Ordering that a correct session never shows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "anomaly", rename_all = "snake_case")]
+pub enum Anomaly {
+    RequestBeforeUnchoke {
+        at_us: u64,
+        peer: SocketAddr,
+        piece: usize,
+    },
+    WriteBeforeVerify {
+        at_us: u64,
+        piece: usize,
+    },
+    AnnounceDuringMinInterval {
+        at_us: u64,
+        tracker: String,
+        since_response_us: u64,
+        min_interval_seconds: u64,
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::RequestBeforeUnchoke { at_us, peer, piece } => write!(
+                f,
+                "{at_us}us: piece {piece} requested from {peer} before it unchoked us"
+            ),
+            Anomaly::WriteBeforeVerify { at_us, piece } => {
+                write!(f, "{at_us}us: piece {piece} written before it was verified")
+            }
+            Anomaly::AnnounceDuringMinInterval {
+                at_us,
+                tracker,
+                since_response_us,
+                min_interval_seconds,
+            } => write!(
+                f,
+                "{at_us}us: announced to {tracker} {since_response_us}us after response, min interval is {min_interval_seconds}s"
+            ),
+        }
+    }
+}
+
+/// Per piece and per peer timelines rebuilt from a session log
+#[derive(Debug, Default, Serialize)]
+pub struct SessionReport {
+    pub records: usize,
+    pub pieces: BTreeMap<usize, PieceTimeline>,
+    pub peers: BTreeMap<SocketAddr, PeerTimeline>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl SessionReport {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("opening session log {}", path.display()))?;
+        let mut records = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("reading session log")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .with_context(|| format!("parsing session log line {}", number + 1))?;
+            records.push(record);
+        }
+        Ok(Self::from_records(&records))
+    }
+
+    /// Replays records in order, anomalies are judged by what came before each one
+    pub fn from_records(records: &[SessionRecord]) -> Self {
+        let mut report = SessionReport {
+            records: records.len(),
+            ..Default::default()
+        };
+        let mut choked = BTreeMap::new();
+        // NOTE: last response time and its min interval, by tracker
+        let mut responses = BTreeMap::new();
+        for record in records {
+            let at_us = record.at_us;
+            match &record.event {
+                SessionEvent::Announce {
+                    tracker,
+                    announce_event,
+                } => {
+                    // NOTE: started, completed and stopped go out regardless of interval
+                    if let (None, Some((response_us, Some(min_interval)))) =
+                        (announce_event, responses.get(tracker))
+                    {
+                        let since_response_us = at_us.saturating_sub(*response_us);
+                        if since_response_us < min_interval * 1_000_000 {
+                            report.anomalies.push(Anomaly::AnnounceDuringMinInterval {
+                                at_us,
+                                tracker: tracker.clone(),
+                                since_response_us,
+                                min_interval_seconds: *min_interval,
+                            });
+                        }
+                    }
+                }
+                SessionEvent::AnnounceResponse {
+                    tracker,
+                    min_interval_seconds,
+                    ..
+                } => {
+                    responses.insert(tracker.clone(), (at_us, *min_interval_seconds));
+                }
+                SessionEvent::AnnounceFailed { .. } => {}
+                SessionEvent::PeerConnected {
+                    peer,
+                    choked: peer_choked,
+                    ..
+                } => {
+                    choked.insert(*peer, *peer_choked);
+                    report.peers.entry(*peer).or_default().connected_us = Some(at_us);
+                }
+                SessionEvent::PeerDisconnected {
+                    peer,
+                    reason,
+                    last_messages,
+                } => {
+                    let timeline = report.peers.entry(*peer).or_default();
+                    timeline.disconnected_us = Some(at_us);
+                    timeline.disconnect_reason = Some(reason.clone());
+                    timeline.last_messages = last_messages.clone();
+                }
+                SessionEvent::Choked { peer } => {
+                    choked.insert(*peer, true);
+                    report.peers.entry(*peer).or_default().chokes += 1;
+                }
+                SessionEvent::Unchoked { peer } => {
+                    choked.insert(*peer, false);
+                    report.peers.entry(*peer).or_default().unchokes += 1;
+                }
+                SessionEvent::Interested { .. } => {}
+                SessionEvent::PeerBecameSeed { peer } => {
+                    report.peers.entry(*peer).or_default().became_seed_us = Some(at_us);
+                }
+                SessionEvent::BlockRequested { peer, piece, .. } => {
+                    report.peers.entry(*peer).or_default().blocks_requested += 1;
+                    // NOTE: peers connected before the log started are given the benefit of doubt
+                    if choked.get(peer).copied().unwrap_or(false) {
+                        report.anomalies.push(Anomaly::RequestBeforeUnchoke {
+                            at_us,
+                            peer: *peer,
+                            piece: *piece,
+                        });
+                    }
+                }
+                SessionEvent::PieceAssigned { piece, peers } => {
+                    report
+                        .pieces
+                        .entry(*piece)
+                        .or_default()
+                        .assigned_us
+                        .push(at_us);
+                    for peer in peers {
+                        report.peers.entry(*peer).or_default().pieces.push(*piece);
+                    }
+                }
+                SessionEvent::PieceVerified { piece } => {
+                    report.pieces.entry(*piece).or_default().verified_us = Some(at_us);
+                }
+                SessionEvent::PieceFailed { piece, reason } => {
+                    report
+                        .pieces
+                        .entry(*piece)
+                        .or_default()
+                        .failures
+                        .push(reason.clone());
+                }
+                SessionEvent::PieceWritten { piece, .. } => {
+                    let timeline = report.pieces.entry(*piece).or_default();
+                    if timeline.verified_us.is_none() {
+                        report.anomalies.push(Anomaly::WriteBeforeVerify {
+                            at_us,
+                            piece: *piece,
+                        });
+                    }
+                    timeline.written_us = Some(at_us);
+                }
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} records", self.records)?;
+        for (piece, timeline) in &self.pieces {
+            write!(
+                f,
+                "piece {piece}: {} assignments",
+                timeline.assigned_us.len()
+            )?;
+            if !timeline.failures.is_empty() {
+                write!(f, ", failed: {}", timeline.failures.join("; "))?;
+            }
+            match timeline.verified_us {
+                Some(at_us) => write!(f, ", verified at {at_us}us")?,
+                None => write!(f, ", not verified")?,
+            }
+            if let Some(at_us) = timeline.written_us {
+                write!(f, ", written at {at_us}us")?;
+            }
+            writeln!(f)?;
+        }
+        for (peer, timeline) in &self.peers {
+            write!(
+                f,
+                "peer {peer}: {} pieces, {} blocks requested, {} chokes, {} unchokes",
+                timeline.pieces.len(),
+                timeline.blocks_requested,
+                timeline.chokes,
+                timeline.unchokes
+            )?;
+            if let Some(at_us) = timeline.connected_us {
+                write!(f, ", connected at {at_us}us")?;
+            }
+            if let Some(at_us) = timeline.became_seed_us {
+                write!(f, ", became seed at {at_us}us")?;
+            }
+            if let (Some(at_us), Some(reason)) =
+                (timeline.disconnected_us, &timeline.disconnect_reason)
+            {
+                write!(f, ", disconnected at {at_us}us: {reason}")?;
+            }
+            writeln!(f)?;
+            if timeline.is_abnormal_disconnect() {
+                for message in &timeline.last_messages {
+                    writeln!(f, "  {message}")?;
+                }
+            }
+        }
+        for anomaly in &self.anomalies {
+            writeln!(f, "ANOMALY {anomaly}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    bencode::{from_bytes, to_bytes},
+    prelude::*,
+};
+
+/// Files we keep next to downloads (resume state and alike) start with magic,
+/// format version, payload length and truncated sha1 of the bencoded payload
+const SIDECAR_MAGIC: &[u8; 4] = b"BTSC";
+pub const SIDECAR_VERSION: u32 = 1;
+const CHECKSUM_LENGTH: usize = 8;
+const HEADER_LENGTH: usize = SIDECAR_MAGIC.len() + 4 + 8 + CHECKSUM_LENGTH;
+
+/// Makes temp files of concurrent saves within the process distinct
+static SAVE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `value` atomically, readers see either old or new file, never a mix.
+/// Concurrent saves each write their own temp file, last rename wins
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let payload = to_bytes(value).context("serialize sidecar")?;
+    let mut data = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    data.extend_from_slice(SIDECAR_MAGIC);
+    data.extend_from_slice(&SIDECAR_VERSION.to_be_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    data.extend_from_slice(&checksum(&payload));
+    data.extend_from_slice(&payload);
+
+    let temp_path = temp_path(path);
+    let written = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e).context(format!("writing sidecar {}", path.display()));
+    }
+
+    // NOTE: rename is durable only once directory is synced
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+            trace!("syncing {} failed: {e}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Reads sidecar, missing one gives default. Corrupt or unknown version file
+/// is moved aside as `.corrupt-<timestamp>` and default is used instead
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).context(format!("reading sidecar {}", path.display())),
+    };
+
+    match decode(&data) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            let aside = corrupt_path(path);
+            warn!(
+                "sidecar {} is unusable, moving it to {}: {e:#}",
+                path.display(),
+                aside.display()
+            );
+            std::fs::rename(path, &aside)
+                .context(format!("moving aside sidecar {}", path.display()))?;
+            Ok(T::default())
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    anyhow::ensure!(
+        data.len() >= HEADER_LENGTH,
+        "truncated header of {} bytes",
+        data.len()
+    );
+    let (magic, rest) = data.split_at(SIDECAR_MAGIC.len());
+    anyhow::ensure!(magic == SIDECAR_MAGIC, "not a sidecar file");
+    let (version, rest) = rest.split_at(4);
+    let version = u32::from_be_bytes(version.try_into().expect("4 bytes"));
+    anyhow::ensure!(
+        version == SIDECAR_VERSION,
+        "format version {version}, expected {SIDECAR_VERSION}"
+    );
+    let (length, rest) = rest.split_at(8);
+    let length = u64::from_be_bytes(length.try_into().expect("8 bytes"));
+    let (expected_checksum, payload) = rest.split_at(CHECKSUM_LENGTH);
+    anyhow::ensure!(
+        payload.len() as u64 == length,
+        "payload of {} bytes, expected {length}",
+        payload.len()
+    );
+    anyhow::ensure!(
+        checksum(payload) == expected_checksum,
+        "payload checksum mismatch"
+    );
+    from_bytes(payload).context("deserialize sidecar")
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let mut checksum = [0; CHECKSUM_LENGTH];
+    checksum.copy_from_slice(&sha1_hash(payload)[..CHECKSUM_LENGTH]);
+    checksum
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let counter = SAVE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    with_suffix(path, &format!(".tmp-{}-{counter}", std::process::id()))
+}
+
+fn corrupt_path(path: &Path) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    with_suffix(path, &format!(".corrupt-{timestamp}"))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::testing::TempDir;
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Default, PartialEq, Clone)]
+    struct State {
+        name: String,
+        pieces: Vec<u32>,
+    }
+
+    fn state(name: &str) -> State {
+        State {
+            name: name.to_string(),
+            pieces: vec![0, 3, 70000],
+        }
+    }
+
+    /// Files of `dir` other than sidecar itself
+    fn leftovers(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir.join(""))
+            .unwrap()
+            .map(|f| f.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|f| f != "state")
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Saves good sidecar, damages it with `damage` and loads it back
+    fn load_damaged(name: &str, damage: impl FnOnce(&mut Vec<u8>)) -> (State, Vec<String>) {
+        let dir = TempDir::new(name);
+        let path = dir.join("state");
+        save(&path, &state("good")).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        damage(&mut data);
+        std::fs::write(&path, data).unwrap();
+
+        let loaded = load(&path).unwrap();
+
+        assert!(!path.exists());
+        (loaded, leftovers(&dir))
+    }
+
+    #[test]
+    fn saved_state_loads_back_and_missing_one_is_default() {
+        let dir = TempDir::new("sidecar-round-trip");
+        let path = dir.join("state");
+        assert_eq!(load::<State>(&path).unwrap(), State::default());
+
+        save(&path, &state("good")).unwrap();
+
+        assert_eq!(load::<State>(&path).unwrap(), state("good"));
+        assert!(leftovers(&dir).is_empty());
+    }
+
+    #[test]
+    fn truncated_sidecar_is_moved_aside() {
+        for keep in [HEADER_LENGTH - 1, HEADER_LENGTH + 3] {
+            let (loaded, leftovers) = load_damaged("sidecar-truncated", |data| data.truncate(keep));
+
+            assert_eq!(loaded, State::default());
+            assert_eq!(leftovers.len(), 1, "{leftovers:?}");
+            assert!(leftovers[0].starts_with("state.corrupt-"), "{leftovers:?}");
+        }
+    }
+
+    #[test]
+    fn flipped_bit_is_caught_by_checksum() {
+        let (loaded, leftovers) = load_damaged("sidecar-flipped", |data| {
+            *data.last_mut().unwrap() ^= 0x01;
+        });
+
+        assert_eq!(loaded, State::default());
+        assert_eq!(leftovers.len(), 1, "{leftovers:?}");
+    }
+
+    #[test]
+    fn future_version_is_not_guessed_at() {
+        let future = (SIDECAR_VERSION + 1).to_be_bytes();
+        let (loaded, leftovers) = load_damaged("sidecar-future", |data| {
+            data[SIDECAR_MAGIC.len()..SIDECAR_MAGIC.len() + 4].copy_from_slice(&future);
+        });
+
+        assert_eq!(loaded, State::default());
+        assert_eq!(leftovers.len(), 1, "{leftovers:?}");
+    }
+
+    #[test]
+    fn concurrent_saves_never_interleave() {
+        let dir = TempDir::new("sidecar-concurrent");
+        let path = dir.join("state");
+        let states: Vec<_> = (0..8)
+            .map(|f| State {
+                name: format!("writer {f}"),
+                pieces: (0..2000).map(|piece| piece * f).collect(),
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for state in &states {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        save(path, state).unwrap();
+                    }
+                });
+            }
+        });
+
+        let loaded: State = load(&path).unwrap();
+        assert!(states.contains(&loaded), "{}", loaded.name);
+        assert!(leftovers(&dir).is_empty(), "{:?}", leftovers(&dir));
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use super::{MessageSummary, Torrent};
+
+/// What torrent is busy with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    #[default]
+    Idle,
+    Downloading,
+    Seeding,
+    Completed,
+    Failed,
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            TorrentState::Idle => "idle",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Completed => "completed",
+            TorrentState::Failed => "failed",
+        };
+        write!(f, "{state}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerSummary {
+    pub socket_addr: SocketAddr,
+    pub seed: bool,
+    pub choked: bool,
+    pub blocks_received: usize,
+    /// Works on the active piece, otherwise stands by
+    pub active: bool,
+    /// As of last publish, not printed in text status
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub last_messages: Vec<MessageSummary>,
+}
+
+impl fmt::Display for PeerSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} blocks {}",
+            self.socket_addr,
+            if self.seed { "seed" } else { "peer" },
+            if self.choked { "choked" } else { "unchoked" },
+            self.blocks_received,
+            if self.active { "active" } else { "standby" }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceAssignment {
+    pub piece_index: usize,
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeersStatus {
+    pub connected: usize,
+    pub standby: usize,
+    pub dialing: usize,
+    pub peers: Vec<PeerSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackerStatus {
+    pub next_announce_seconds: Option<u64>,
+    pub last_result: Option<String>,
+}
+
+/// Snapshot of a torrent taken at one instant, see [`Torrent::status`]
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStatus {
+    pub state: TorrentState,
+    pub verified_pieces: usize,
+    pub total_pieces: usize,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_wasted: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub peers: PeersStatus,
+    pub tracker: TrackerStatus,
+    /// Verified pieces waiting to be written
+    pub disk_queue: usize,
+    /// File handles held by storage
+    pub open_files: usize,
+    pub active_pieces: Vec<PieceAssignment>,
+}
+
+impl fmt::Display for TorrentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}/{} pieces, {} bytes at {:.0} B/s, {} peers ({} standby, {} dialing), disk queue {}",
+            self.state,
+            self.verified_pieces,
+            self.total_pieces,
+            self.bytes_downloaded,
+            self.download_rate,
+            self.peers.connected,
+            self.peers.standby,
+            self.peers.dialing,
+            self.disk_queue
+        )?;
+        if self.bytes_uploaded > 0 {
+            write!(
+                f,
+                ", uploaded {} bytes at {:.0} B/s",
+                self.bytes_uploaded, self.upload_rate
+            )?;
+        }
+        if let Some(next_announce) = self.tracker.next_announce_seconds {
+            write!(f, ", announce in {next_announce}s")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reported each time a verified piece lands on disk,
+/// see [`Torrent::download_with_progress`]
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadProgress {
+    /// Resumed pieces included
+    pub pieces_completed: usize,
+    pub total_pieces: usize,
+    /// Written by this download, resumed pieces not included
+    pub bytes_written: u64,
+    pub connected_peers: usize,
+}
+
+/// Download state that has no other home, published as download goes
+#[derive(Debug, Default)]
+pub struct StatusBoard {
+    pub state: TorrentState,
+    pub peers: Vec<PeerSummary>,
+    pub dialing: usize,
+    pub active_pieces: Vec<PieceAssignment>,
+    pub next_announce: Option<Instant>,
+    pub last_announce: Option<String>,
+}
+
+/// Counts a dial in progress while alive, dropped dial futures included
+pub struct Dialing<'a>(&'a Mutex<StatusBoard>);
+
+impl<'a> Dialing<'a> {
+    pub fn start(board: &'a Mutex<StatusBoard>) -> Self {
+        board.lock().expect("status board lock").dialing += 1;
+        Self(board)
+    }
+}
+
+impl Drop for Dialing<'_> {
+    fn drop(&mut self) {
+        self.0.lock().expect("status board lock").dialing -= 1;
+    }
+}
+
+impl Torrent {
+    /// Coherent snapshot of download, cheap enough to poll a few times a second.
+    /// Locks are always taken in the same order: stats, then status board
+    pub fn status(&self) -> TorrentStatus {
+        let stats = self.stats();
+        let board = self.board();
+        let standby = board.peers.iter().filter(|peer| !peer.active).count();
+        TorrentStatus {
+            state: board.state,
+            verified_pieces: stats.completed_pieces.len(),
+            total_pieces: self.metadata.info.pieces.len(),
+            bytes_downloaded: stats.bytes_downloaded,
+            bytes_uploaded: stats.bytes_uploaded,
+            bytes_wasted: stats.bytes_wasted,
+            download_rate: stats.download_rate.rate(),
+            upload_rate: stats.upload_rate.rate(),
+            peers: PeersStatus {
+                connected: board.peers.len(),
+                standby,
+                dialing: board.dialing,
+                peers: board.peers.clone(),
+            },
+            tracker: TrackerStatus {
+                next_announce_seconds: board
+                    .next_announce
+                    .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+                last_result: board.last_announce.clone(),
+            },
+            disk_queue: self.disk_queue.load(Ordering::Relaxed),
+            open_files: self.open_files.load(Ordering::Relaxed),
+            active_pieces: board.active_pieces.clone(),
+        }
+    }
+
+    pub(super) fn board(&self) -> MutexGuard<'_, StatusBoard> {
+        self.status_board.lock().expect("status board lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        bencode::ParseConfig,
+        torrent::{
+            testing::{
+                announce_response, metadata, seeder, torrent_bytes, MockHttp, MockPeer, Reply,
+                TempDir,
+            },
+            DownloadConfig, TorrentMetadataInfo,
+        },
+    };
+
+    const PIECE_LENGTH: usize = 32;
+    const PIECES: usize = 12;
+
+    #[tokio::test]
+    async fn snapshots_mid_download_are_consistent() {
+        let data: Vec<u8> = (0..(PIECE_LENGTH * PIECES) as u32)
+            .map(|f| f as u8)
+            .collect();
+        let info_hash = metadata("http://127.0.0.1:1/announce", PIECE_LENGTH, &data).info_hash;
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let mut serve = seeder(data.clone(), PIECE_LENGTH);
+            peers.push(
+                MockPeer::start(info_hash, vec![Reply::bitfield(PIECES)], move |message| {
+                    let mut replies = serve(message);
+                    if message.id == 6 {
+                        replies.insert(0, Reply::Delay(Duration::from_millis(10)));
+                    }
+                    replies
+                })
+                .await,
+            );
+        }
+        let response = announce_response(&peers.iter().map(MockPeer::addr).collect::<Vec<_>>());
+        let tracker = MockHttp::start(move |_| (200, response.clone())).await;
+        let torrent = Torrent::builder(
+            TorrentMetadataInfo::from_bytes(
+                &torrent_bytes(tracker.url("/announce").as_str(), PIECE_LENGTH, &data),
+                ParseConfig::default(),
+            )
+            .unwrap(),
+        )
+        .config(DownloadConfig {
+            block_size: 16,
+            ..Default::default()
+        })
+        .build()
+        .unwrap();
+        let dir = TempDir::new("status-snapshots");
+
+        let mut snapshots = Vec::new();
+        let report = tokio::select! {
+            report = torrent.download(dir.join("test.bin")) => report.unwrap(),
+            () = async {
+                loop {
+                    snapshots.push(torrent.status());
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            } => unreachable!(),
+        };
+
+        assert!(report.is_completed(), "{:?}", report.outcome);
+        assert!(
+            snapshots
+                .iter()
+                .any(|f| f.state == TorrentState::Downloading
+                    && f.verified_pieces > 0
+                    && f.verified_pieces < PIECES),
+            "no snapshot mid-download"
+        );
+        for status in &snapshots {
+            assert_eq!(status.total_pieces, PIECES);
+            assert!(status.verified_pieces <= status.total_pieces, "{status}");
+            assert!(
+                (status.verified_pieces * PIECE_LENGTH) as u64
+                    <= status.bytes_downloaded + status.bytes_wasted,
+                "{status:?}"
+            );
+            assert_eq!(status.peers.connected, status.peers.peers.len());
+            assert!(status.peers.standby <= status.peers.connected, "{status}");
+            for assignment in &status.active_pieces {
+                assert!(assignment.piece_index < PIECES, "{assignment:?}");
+            }
+        }
+        let last = torrent.status();
+        assert_eq!(last.state, TorrentState::Completed);
+        assert_eq!(last.verified_pieces, PIECES);
+        assert_eq!(last.bytes_downloaded, data.len() as u64);
+        assert!(last.active_pieces.is_empty(), "{last:?}");
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use bitvec::{order::Msb0, vec::BitVec};
+
+use crate::prelude::*;
+
+use super::TorrentInfo;
+
+/// Descriptors left for sockets, tracker and stdio when clamping open files to rlimit
+const FD_HEADROOM: usize = 128;
+
+/// Maps torrent byte offsets onto output files.
+/// Single file torrent is written to `output` itself,
+/// multi file torrent to files under `output` directory.
+/// Files are opened on demand, least recently used handle is closed
+/// once `max_open_files` are open
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<StorageFile>,
+    /// Positions of files with open handle, least recently used first
+    open: VecDeque<usize>,
+    max_open_files: usize,
+    /// Shared count of open handles, for status
+    open_files: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+struct StorageFile {
+    offset: u64,
+    length: u64,
+    path: PathBuf,
+    padding: bool,
+    handle: Option<File>,
+    /// Written since last sync
+    dirty: bool,
+}
+
+impl Storage {
+    pub fn new(
+        output: PathBuf,
+        info: &TorrentInfo,
+        max_open_files: usize,
+        open_files: Arc<AtomicUsize>,
+    ) -> Result<Self> {
+        anyhow::ensure!(max_open_files > 0, "max open files is zero");
+        let max_open_files = match open_files_limit() {
+            Some(limit) if limit.saturating_sub(FD_HEADROOM) < max_open_files => {
+                let clamped = limit.saturating_sub(FD_HEADROOM).max(1);
+                debug!("open files limit is {limit}, keeping {clamped} files open");
+                clamped
+            }
+            _ => max_open_files,
+        };
+        open_files.store(0, Ordering::Relaxed);
+        let storage = |files| Self {
+            files,
+            open: VecDeque::with_capacity(max_open_files),
+            max_open_files,
+            open_files,
+        };
+
+        let Some(torrent_files) = &info.files else {
+            let file = StorageFile {
+                offset: 0,
+                length: info.total_length() as u64,
+                path: output,
+                padding: false,
+                handle: None,
+                dirty: false,
+            };
+            return Ok(storage(vec![file]));
+        };
+
+        let mut offset = 0;
+        let mut files = Vec::with_capacity(torrent_files.len());
+        for torrent_file in torrent_files {
+            let path = torrent_file.relative_path()?;
+            files.push(StorageFile {
+                offset,
+                length: torrent_file.length as u64,
+                path: output.join(path),
+                padding: torrent_file.is_padding(),
+                handle: None,
+                dirty: false,
+            });
+            offset += torrent_file.length as u64;
+        }
+
+        Ok(storage(files))
+    }
+
+    /// Creates output files with their final size, padding files are never created
+    pub fn allocate(&mut self) -> Result<()> {
+        for position in 0..self.files.len() {
+            if self.files[position].padding {
+                continue;
+            }
+            let length = self.files[position].length;
+            self.handle(position)?
+                .set_len(length)
+                .context("setting file size")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// Checks files of a previous download are all in place with their final size
+    pub fn validate_existing(&self) -> Result<()> {
+        for file in self.files.iter().filter(|f| !f.padding) {
+            let metadata = std::fs::metadata(&file.path)
+                .with_context(|| format!("reading metadata of {:?}", file.path))?;
+            anyhow::ensure!(metadata.is_file(), "{:?} is not a file", file.path);
+            anyhow::ensure!(
+                metadata.len() == file.length,
+                "{:?} has size {}, expected {}",
+                file.path,
+                metadata.len(),
+                file.length
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        for (position, range) in self.spans(offset, data.len()) {
+            if self.files[position].padding {
+                continue;
+            }
+            let handle = self.handle(position)?;
+            handle
+                .seek(SeekFrom::Start(range.file_offset))
+                .context("seeking file")?;
+            handle
+                .write_all(&data[range.data_start..range.data_end])
+                .context("writing file")?;
+            self.files[position].dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes written data to disk, evicted handles were synced on close
+    pub fn sync(&mut self) -> Result<()> {
+        for file in self.files.iter_mut().filter(|file| file.dirty) {
+            file.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Reads torrent bytes, padding is read as zeros
+    pub fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        for (position, range) in self.spans(offset, data.len()) {
+            let data = &mut data[range.data_start..range.data_end];
+            if self.files[position].padding {
+                data.fill(0);
+                continue;
+            }
+            let handle = self.handle(position)?;
+            handle
+                .seek(SeekFrom::Start(range.file_offset))
+                .context("seeking file")?;
+            handle.read_exact(data).context("reading file")?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle of file at `position`, opening it and closing least recently used if needed
+    fn handle(&mut self, position: usize) -> Result<&mut File> {
+        if let Some(used) = self.open.iter().position(|open| *open == position) {
+            self.open.remove(used);
+        } else {
+            if self.open.len() >= self.max_open_files {
+                let evicted = self.open.pop_front().expect("max open files is not zero");
+                trace!("closing {:?}", self.files[evicted].path);
+                self.files[evicted].sync()?;
+                self.files[evicted].handle = None;
+            }
+            self.files[position].open()?;
+        }
+        self.open.push_back(position);
+        self.open_files.store(self.open.len(), Ordering::Relaxed);
+
+        Ok(self.files[position].handle.as_mut().expect("opened above"))
+    }
+
+    /// Paths of files holding data, padding files have none
+    pub fn file_paths(&self) -> impl Iterator<Item = &Path> {
+        self.files
+            .iter()
+            .filter(|file| !file.padding)
+            .map(|file| file.path.as_path())
+    }
+
+    /// Pieces on disk matching their hash, the last one may be shorter
+    pub fn verified_pieces(&mut self, info: &TorrentInfo) -> Result<BitVec<u8, Msb0>> {
+        self.verified_hashes(&info.pieces, info.piece_length)
+    }
+
+    /// Same as [`Storage::verified_pieces`], without borrowing torrent info,
+    /// so it can run on a blocking thread. One piece is held in memory at a time
+    pub fn verified_hashes(
+        &mut self,
+        hashes: &[Bytes20],
+        piece_length: usize,
+    ) -> Result<BitVec<u8, Msb0>> {
+        let total_length = self.total_length();
+        let mut have = BitVec::repeat(false, hashes.len());
+        let mut piece = Vec::with_capacity(piece_length);
+        for (index, hash) in hashes.iter().enumerate() {
+            let offset = (index * piece_length) as u64;
+            piece.resize((piece_length as u64).min(total_length - offset) as usize, 0);
+            self.read(offset, &mut piece)
+                .with_context(|| format!("reading piece {index}"))?;
+            if sha1_hash(&piece) == *hash {
+                have.set(index, true);
+            } else {
+                trace!("piece {index} doesn't match its hash");
+            }
+        }
+        Ok(have)
+    }
+
+    /// Files overlapping byte range, by position
+    fn spans(&self, offset: u64, length: usize) -> Vec<(usize, SpanRange)> {
+        let end = offset + length as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && offset < file.offset + file.length)
+            .map(|(position, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                let range = SpanRange {
+                    file_offset: start - file.offset,
+                    data_start: (start - offset) as usize,
+                    data_end: (stop - offset) as usize,
+                };
+                (position, range)
+            })
+            .collect()
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        self.open_files.store(0, Ordering::Relaxed);
+    }
+}
+
+struct SpanRange {
+    file_offset: u64,
+    data_start: usize,
+    data_end: usize,
+}
+
+impl StorageFile {
+    // NOTE: never truncates, file closed by cache is reopened with its data and size
+    fn open(&mut self) -> Result<()> {
+        if self.handle.is_none() {
+            if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("creating directory {:?}", parent))?;
+            }
+            let handle = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)
+                .with_context(|| format!("opening file {:?}", self.path))?;
+            self.handle = Some(handle);
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let (true, Some(handle)) = (self.dirty, &self.handle) {
+            handle
+                .sync_data()
+                .with_context(|| format!("syncing file {:?}", self.path))?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Soft limit of open descriptors, read from procfs where available
+fn open_files_limit() -> Option<usize> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line.trim_start_matches("Max open files")
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{testing::TempDir, FilesListing, TorrentFile};
+
+    /// Files `a` and `b` with padding between them aligning `b` to a piece,
+    /// together with bytes of whole torrent
+    fn padded_torrent() -> (TorrentInfo, Vec<u8>) {
+        let file = |name: &str, length, attr: Option<&str>| TorrentFile {
+            length,
+            path: vec![name.to_string()],
+            attr: attr.map(str::to_string),
+        };
+        let data: Vec<u8> = [vec![1; 5], vec![0; 3], vec![2; 6]].concat();
+        let info = TorrentInfo {
+            length: None,
+            files: Some(vec![
+                file("a", 5, None),
+                file(".pad", 3, Some("p")),
+                file("b", 6, None),
+            ]),
+            name: "padded".to_string(),
+            piece_length: 4,
+            pieces: data.chunks(4).map(sha1_hash).collect(),
+            info_extra: Default::default(),
+        };
+        (info, data)
+    }
+
+    #[test]
+    fn padding_is_verified_but_never_written() {
+        let (info, data) = padded_torrent();
+        let dir = TempDir::new("storage-padding");
+        let output = dir.join("padded");
+        let mut storage = Storage::new(output.clone(), &info, 4, Default::default()).unwrap();
+
+        storage.allocate().unwrap();
+        storage.write(0, &data).unwrap();
+        storage.sync().unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), [1; 5]);
+        assert_eq!(std::fs::read(output.join("b")).unwrap(), [2; 6]);
+        assert!(storage.verified_pieces(&info).unwrap().all());
+        assert_eq!(storage.file_paths().count(), 2);
+    }
+
+    #[test]
+    fn five_hundred_files_through_eight_handles() {
+        const FILES: usize = 500;
+        const MAX_OPEN: usize = 8;
+        let lengths: Vec<usize> = (0..FILES).map(|f| 1 + f % 13).collect();
+        let data: Vec<u8> = (0..lengths.iter().sum::<usize>())
+            .map(|f| (f % 251) as u8)
+            .collect();
+        let info = TorrentInfo {
+            length: None,
+            files: Some(
+                lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &length)| TorrentFile {
+                        length,
+                        path: vec![format!("dir{}", i % 10), format!("file{i}")],
+                        attr: None,
+                    })
+                    .collect(),
+            ),
+            name: "many".to_string(),
+            piece_length: 64,
+            pieces: data.chunks(64).map(sha1_hash).collect(),
+            info_extra: Default::default(),
+        };
+        let dir = TempDir::new("storage-many-files");
+        let output = dir.join("many");
+        let open_files = Arc::new(AtomicUsize::new(0));
+        let mut storage =
+            Storage::new(output.clone(), &info, MAX_OPEN, Arc::clone(&open_files)).unwrap();
+
+        storage.allocate().unwrap();
+        assert_eq!(open_files.load(Ordering::Relaxed), MAX_OPEN);
+        // NOTE: pieces out of order, so evicted files get reopened and written again
+        let pieces = info.pieces.len();
+        for piece in (0..pieces).step_by(2).chain((1..pieces).step_by(2)).rev() {
+            let start = piece * 64;
+            let end = (start + 64).min(data.len());
+            storage.write(start as u64, &data[start..end]).unwrap();
+            assert!(open_files.load(Ordering::Relaxed) <= MAX_OPEN);
+        }
+        storage.sync().unwrap();
+
+        assert!(storage.verified_pieces(&info).unwrap().all());
+        assert!(open_files.load(Ordering::Relaxed) <= MAX_OPEN);
+        let mut offset = 0;
+        for (i, length) in lengths.into_iter().enumerate() {
+            let path = output
+                .join(format!("dir{}", i % 10))
+                .join(format!("file{i}"));
+            assert_eq!(
+                std::fs::read(path).unwrap(),
+                data[offset..offset + length],
+                "file{i}"
+            );
+            offset += length;
+        }
+        drop(storage);
+        assert_eq!(open_files.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn padding_is_hidden_from_listing_unless_asked() {
+        let (info, _) = padded_torrent();
+        let listing = |show_padding| {
+            FilesListing {
+                info: &info,
+                show_padding,
+            }
+            .to_string()
+        };
+
+        assert_eq!(listing(false), "Files:\na 5\nb 6\n");
+        assert_eq!(listing(true), "Files:\na 5\n.pad 3 (padding)\nb 6\n");
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use reqwest::Url;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    bencode::{to_bytes, ParseConfig, Value},
+    prelude::*,
+};
+
+use super::{
+    MetadataMessage, MetadataMessageType, TorrentMetadataInfo, METADATA_PIECE_SIZE,
+    UT_METADATA_LOCAL_ID,
+};
+
+/// Single file torrent of `data`
+pub fn torrent_bytes(announce: &str, piece_length: usize, data: &[u8]) -> Vec<u8> {
+    let pieces: Vec<u8> = data.chunks(piece_length).flat_map(sha1_hash).collect();
+    let info = BTreeMap::from([
+        (b"length".to_vec(), Value::Integer(data.len() as i64)),
+        (b"name".to_vec(), Value::String(b"test.bin".to_vec())),
+        (
+            b"piece length".to_vec(),
+            Value::Integer(piece_length as i64),
+        ),
+        (b"pieces".to_vec(), Value::String(pieces)),
+    ]);
+    let torrent = BTreeMap::from([
+        (b"announce".to_vec(), Value::String(announce.into())),
+        (b"info".to_vec(), Value::Dict(info)),
+    ]);
+    to_bytes(Value::Dict(torrent)).expect("test torrent encodes")
+}
+
+pub fn metadata(announce: &str, piece_length: usize, data: &[u8]) -> TorrentMetadataInfo {
+    TorrentMetadataInfo::from_bytes(
+        &torrent_bytes(announce, piece_length, data),
+        ParseConfig::default(),
+    )
+    .expect("test torrent parses")
+}
+
+/// Compact announce response listing `peers`, all of them IPv4
+pub fn announce_response(peers: &[SocketAddr]) -> Vec<u8> {
+    announce_response_every(1800, peers)
+}
+
+/// Tracker response asking to re-announce every `interval` seconds
+pub fn announce_response_every(interval: i64, peers: &[SocketAddr]) -> Vec<u8> {
+    let mut compact = Vec::new();
+    for peer in peers {
+        let SocketAddr::V4(peer) = peer else {
+            panic!("compact peers are IPv4");
+        };
+        compact.extend_from_slice(&peer.ip().octets());
+        compact.extend_from_slice(&peer.port().to_be_bytes());
+    }
+    let response = BTreeMap::from([
+        (b"interval".to_vec(), Value::Integer(interval)),
+        (b"peers".to_vec(), Value::String(compact)),
+    ]);
+    to_bytes(Value::Dict(response)).expect("announce response encodes")
+}
+
+/// Fresh directory in temp dir, removed with everything in it on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("bt-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("create temp dir");
+        Self(path)
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+type Respond = dyn Fn(&str) -> (u16, Vec<u8>) + Send + Sync;
+
+/// Http server answering every GET with `respond(path and query)`, connection per request
+pub struct MockHttp {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockHttp {
+    pub async fn start(respond: impl Fn(&str) -> (u16, Vec<u8>) + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let respond: Arc<Respond> = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|f| f == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let target = request.split_whitespace().nth(1).unwrap_or_default();
+                seen.lock().unwrap().push(target.to_string());
+                let (status, body) = respond(target);
+                let head = format!(
+                    "HTTP/1.1 {status} MOCK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{path}", self.addr)).expect("mock url")
+    }
+
+    /// Path and query of every request so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Reserved bytes of handshake with extension protocol (BEP 10) bit set
+pub const LTEP_RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+/// Id mock peers want their ut_metadata messages sent with
+pub const MOCK_UT_METADATA_ID: u8 = 3;
+
+/// Peer wire message as mock peer sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireMessage {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl WireMessage {
+    /// Index, begin and length of request, cancel and reject
+    pub fn block(&self) -> (u32, u32, u32) {
+        let field =
+            |at: usize| u32::from_be_bytes(self.payload[at..at + 4].try_into().expect("4 bytes"));
+        (field(0), field(4), field(8))
+    }
+
+    /// Piece asked for by ut_metadata request, if this is one
+    pub fn metadata_request(&self) -> Option<usize> {
+        let payload = self.payload.strip_prefix(&[MOCK_UT_METADATA_ID])?;
+        let message = MetadataMessage::from_payload(payload).ok()?;
+        (self.id == 20 && matches!(message.msg_type, MetadataMessageType::Request))
+            .then_some(message.piece)
+    }
+}
+
+/// What mock peer does next
+#[derive(Debug)]
+pub enum Reply {
+    Send(u8, Vec<u8>),
+    Delay(Duration),
+    /// Drops connection without a word
+    Hangup,
+    /// Zero length message
+    KeepAlive,
+}
+
+impl Reply {
+    pub fn bitfield(pieces: usize) -> Self {
+        let mut bitfield = vec![0u8; pieces.div_ceil(8)];
+        for piece in 0..pieces {
+            bitfield[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        Reply::Send(5, bitfield)
+    }
+
+    /// Bitfield of every piece but the last
+    pub fn bitfield_but_last(pieces: usize) -> Self {
+        let Reply::Send(id, mut bitfield) = Reply::bitfield(pieces) else {
+            unreachable!("bitfield is sent");
+        };
+        let last = pieces - 1;
+        bitfield[last / 8] &= !(0x80 >> (last % 8));
+        Reply::Send(id, bitfield)
+    }
+
+    pub fn have(piece: u32) -> Self {
+        Reply::Send(4, piece.to_be_bytes().to_vec())
+    }
+
+    pub fn piece(index: u32, begin: u32, data: &[u8]) -> Self {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(data);
+        Reply::Send(7, payload)
+    }
+
+    /// Extended handshake advertising ut_metadata of `metadata_size` bytes
+    pub fn extended_handshake(metadata_size: usize) -> Self {
+        let handshake = format!(
+            "d1:md11:ut_metadatai{MOCK_UT_METADATA_ID}ee13:metadata_sizei{metadata_size}ee"
+        );
+        Reply::Send(20, [&[0], handshake.as_bytes()].concat())
+    }
+
+    /// ut_metadata data message of `piece`, with `data` as its content
+    pub fn metadata_piece(piece: usize, total_size: usize, data: &[u8]) -> Self {
+        let header = format!("d8:msg_typei1e5:piecei{piece}e10:total_sizei{total_size}ee");
+        Reply::Send(
+            20,
+            [&[UT_METADATA_LOCAL_ID], header.as_bytes(), data].concat(),
+        )
+    }
+}
+
+/// Unchokes on interested and answers requests with blocks of `data`
+pub fn seeder(data: Vec<u8>, piece_length: usize) -> impl FnMut(&WireMessage) -> Vec<Reply> {
+    move |message| match message.id {
+        2 => vec![Reply::Send(1, Vec::new())],
+        6 => {
+            let (index, begin, length) = message.block();
+            let start = index as usize * piece_length + begin as usize;
+            vec![Reply::piece(
+                index,
+                begin,
+                &data[start..start + length as usize],
+            )]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Serves `info` over ut_metadata (BEP 9) in 16 KiB pieces,
+/// greet with [`Reply::extended_handshake`] to advertise it
+pub fn metadata_seeder(info: Vec<u8>) -> impl FnMut(&WireMessage) -> Vec<Reply> {
+    move |message| match message.metadata_request() {
+        Some(piece) => {
+            let start = (piece * METADATA_PIECE_SIZE).min(info.len());
+            let end = (start + METADATA_PIECE_SIZE).min(info.len());
+            vec![Reply::metadata_piece(piece, info.len(), &info[start..end])]
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Remote peer accepting a single connection, greets with `greeting` after
+/// handshake and answers every message with `respond`. Keep-alives are only counted
+pub struct MockPeer {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<WireMessage>>>,
+    keep_alives: Arc<AtomicUsize>,
+    closed: watch::Receiver<bool>,
+}
+
+impl MockPeer {
+    pub async fn start(
+        info_hash: Bytes20,
+        greeting: Vec<Reply>,
+        respond: impl FnMut(&WireMessage) -> Vec<Reply> + Send + 'static,
+    ) -> Self {
+        Self::start_with_reserved(info_hash, [0; 8], greeting, respond).await
+    }
+
+    /// Same as [`MockPeer::start`], handshake advertises `reserved` extension bits
+    pub async fn start_with_reserved(
+        info_hash: Bytes20,
+        reserved: [u8; 8],
+        greeting: Vec<Reply>,
+        mut respond: impl FnMut(&WireMessage) -> Vec<Reply> + Send + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&received);
+        let keep_alives = Arc::new(AtomicUsize::new(0));
+        let heard = Arc::clone(&keep_alives);
+        let (close, closed) = watch::channel(false);
+        tokio::spawn(async move {
+            if let Ok((mut stream, _)) = listener.accept().await {
+                let _ = serve(
+                    &mut stream,
+                    info_hash,
+                    reserved,
+                    greeting,
+                    &seen,
+                    &heard,
+                    &mut respond,
+                )
+                .await;
+            }
+            close.send_replace(true);
+        });
+        Self {
+            addr,
+            received,
+            keep_alives,
+            closed,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn received(&self) -> Vec<WireMessage> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Ids of received messages in order
+    pub fn received_ids(&self) -> Vec<u8> {
+        self.received().iter().map(|f| f.id).collect()
+    }
+
+    /// Zero-length messages received so far
+    pub fn keep_alives(&self) -> usize {
+        self.keep_alives.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once connection is over and every message is recorded
+    pub async fn closed(&self) {
+        let _ = self.closed.clone().wait_for(|closed| *closed).await;
+    }
+}
+
+async fn serve(
+    stream: &mut TcpStream,
+    info_hash: Bytes20,
+    reserved: [u8; 8],
+    greeting: Vec<Reply>,
+    received: &Mutex<Vec<WireMessage>>,
+    keep_alives: &AtomicUsize,
+    respond: &mut (impl FnMut(&WireMessage) -> Vec<Reply> + Send),
+) -> Result<()> {
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).await?;
+    let mut reply = vec![19u8];
+    reply.extend_from_slice(b"BitTorrent protocol");
+    reply.extend_from_slice(&reserved);
+    reply.extend_from_slice(&info_hash);
+    reply.extend_from_slice(b"-MK0001-000000000000");
+    stream.write_all(&reply).await?;
+    act(stream, greeting).await?;
+
+    loop {
+        let length = stream.read_u32().await? as usize;
+        if length == 0 {
+            keep_alives.fetch_add(1, Ordering::SeqCst);
+            continue;
+        }
+        let mut message = vec![0u8; length];
+        stream.read_exact(&mut message).await?;
+        let message = WireMessage {
+            id: message[0],
+            payload: message[1..].to_vec(),
+        };
+        received.lock().unwrap().push(message.clone());
+        act(stream, respond(&message)).await?;
+    }
+}
+
+async fn act(stream: &mut TcpStream, replies: Vec<Reply>) -> Result<()> {
+    for reply in replies {
+        match reply {
+            Reply::Send(id, payload) => {
+                stream.write_u32(payload.len() as u32 + 1).await?;
+                stream.write_u8(id).await?;
+                stream.write_all(&payload).await?;
+            }
+            Reply::Delay(delay) => tokio::time::sleep(delay).await,
+            Reply::Hangup => bail!("hanging up"),
+            Reply::KeepAlive => stream.write_u32(0).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Which way recorded chunk went, as our side of connection sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Bytes of single read on recorded connection, `at` since it was accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub direction: Direction,
+    pub at: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Peer connection as [`Recording`] saw it. Saved one chunk per line
+/// as `in|out <micros> <hex>` so fixtures diff readably
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session(pub Vec<Chunk>);
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading session {}", path.display()))?;
+        let chunks = text
+            .lines()
+            .enumerate()
+            .map(|(line, text)| {
+                let parse = || {
+                    let mut fields = text.split(' ');
+                    let (Some(direction), Some(at), Some(data), None) =
+                        (fields.next(), fields.next(), fields.next(), fields.next())
+                    else {
+                        bail!("expected 3 fields");
+                    };
+                    let direction = match direction {
+                        "in" => Direction::Inbound,
+                        "out" => Direction::Outbound,
+                        other => bail!("unknown direction {other}"),
+                    };
+                    Ok(Chunk {
+                        direction,
+                        at: Duration::from_micros(at.parse()?),
+                        data: hex::decode(data)?,
+                    })
+                };
+                parse().with_context(|| format!("{}:{}", path.display(), line + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(chunks))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text: String = self
+            .0
+            .iter()
+            .map(|chunk| {
+                let direction = match chunk.direction {
+                    Direction::Inbound => "in",
+                    Direction::Outbound => "out",
+                };
+                let at = chunk.at.as_micros();
+                format!("{direction} {at} {}\n", hex::encode(&chunk.data))
+            })
+            .collect();
+        std::fs::write(path, text).with_context(|| format!("writing session {}", path.display()))
+    }
+
+    /// Handshake and length prefixed messages of both directions in order they
+    /// were seen, each timed by chunk that completed it
+    fn frames(&self) -> Result<Vec<Chunk>> {
+        // NOTE: direction, bytes not framed yet and whether handshake is through
+        let mut pending = [
+            (Direction::Inbound, Vec::new(), false),
+            (Direction::Outbound, Vec::new(), false),
+        ];
+        let mut frames = Vec::new();
+        for chunk in &self.0 {
+            let (direction, buffer, handshaken) = pending
+                .iter_mut()
+                .find(|(direction, ..)| *direction == chunk.direction)
+                .expect("both directions pending");
+            buffer.extend_from_slice(&chunk.data);
+            while let Some(length) = frame_length(buffer, *handshaken) {
+                if buffer.len() < length {
+                    break;
+                }
+                frames.push(Chunk {
+                    direction: *direction,
+                    at: chunk.at,
+                    data: buffer.drain(..length).collect(),
+                });
+                *handshaken = true;
+            }
+        }
+        for (direction, buffer, _) in pending {
+            anyhow::ensure!(buffer.is_empty(), "{direction:?} side ends mid message");
+        }
+        Ok(frames)
+    }
+}
+
+/// Length of frame `buffer` starts with, handshake comes first in either direction
+fn frame_length(buffer: &[u8], handshaken: bool) -> Option<usize> {
+    if !handshaken {
+        return Some(HANDSHAKE_LENGTH);
+    }
+    let prefix = buffer.get(..4)?;
+    Some(4 + u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize)
+}
+
+const HANDSHAKE_LENGTH: usize = 68;
+const KEEP_ALIVE: [u8; 4] = [0; 4];
+
+/// Proxy recording single connection to `upstream`, connect to [`Recording::addr`]
+/// instead of it. Works against mock peers and real clients alike
+pub struct Recording {
+    addr: SocketAddr,
+    session: JoinHandle<Session>,
+}
+
+impl Recording {
+    pub async fn start(upstream: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let session = tokio::spawn(async move {
+            let Ok((ours, _)) = listener.accept().await else {
+                return Session::default();
+            };
+            let started = Instant::now();
+            let Ok(theirs) = TcpStream::connect(upstream).await else {
+                return Session::default();
+            };
+            let chunks = Mutex::new(Vec::new());
+            let (our_read, our_write) = ours.into_split();
+            let (their_read, their_write) = theirs.into_split();
+            tokio::join!(
+                pipe(our_read, their_write, Direction::Outbound, started, &chunks),
+                pipe(their_read, our_write, Direction::Inbound, started, &chunks),
+            );
+            Session(chunks.into_inner().unwrap())
+        });
+        Self { addr, session }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Resolves once both sides closed
+    pub async fn finish(self) -> Session {
+        self.session.await.expect("recording proxy")
+    }
+}
+
+/// Copies `from` to `to` logging every read, chunk is logged before it's
+/// forwarded so anything it causes on the other side is logged after it
+async fn pipe(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    direction: Direction,
+    started: Instant,
+    chunks: &Mutex<Vec<Chunk>>,
+) {
+    let mut buffer = vec![0; 64 * 1024];
+    while let Ok(read @ 1..) = from.read(&mut buffer).await {
+        chunks.lock().unwrap().push(Chunk {
+            direction,
+            at: started.elapsed(),
+            data: buffer[..read].to_vec(),
+        });
+        if to.write_all(&buffer[..read]).await.is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+/// How fast [`ReplayPeer`] plays back inbound messages
+#[derive(Debug, Clone, Copy)]
+pub enum Pace {
+    /// No sooner than they came in when recorded
+    Recorded,
+    AsFastAsPossible,
+}
+
+/// Remote peer accepting a single connection and playing back inbound side of
+/// recorded session. Each message we send has to match recorded one byte for
+/// byte, keep-alives only are skipped as they depend on timing
+pub struct ReplayPeer {
+    addr: SocketAddr,
+    outcome: JoinHandle<Result<()>>,
+}
+
+impl ReplayPeer {
+    pub async fn start(session: &Session, pace: Pace) -> Self {
+        let frames = session.frames().expect("recorded session is framed");
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let outcome = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            play(&mut stream, frames, pace).await?;
+            // NOTE: stays until our side is done so it doesn't see playback end as hangup
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+            Ok(())
+        });
+        Self { addr, outcome }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Resolves once whole session is played and our side closed,
+    /// error names first message that differs from recording
+    pub async fn finished(self) -> Result<()> {
+        self.outcome.await.expect("replay peer")
+    }
+}
+
+/// How long playback waits for each message we are expected to send
+const REPLAY_WAIT: Duration = Duration::from_secs(5);
+
+async fn play(stream: &mut TcpStream, frames: Vec<Chunk>, pace: Pace) -> Result<()> {
+    let started = Instant::now();
+    let mut handshaken = false;
+    for (index, frame) in frames.iter().enumerate() {
+        match frame.direction {
+            Direction::Inbound => {
+                if let Pace::Recorded = pace {
+                    tokio::time::sleep_until(started + frame.at).await;
+                }
+                stream.write_all(&frame.data).await?;
+            }
+            Direction::Outbound if frame.data == KEEP_ALIVE => {}
+            Direction::Outbound => {
+                let sent = loop {
+                    let sent = tokio::time::timeout(REPLAY_WAIT, read_frame(stream, handshaken))
+                        .await
+                        .with_context(|| format!("waiting for recorded message {index}"))?
+                        .with_context(|| format!("reading recorded message {index}"))?;
+                    if sent != KEEP_ALIVE {
+                        break sent;
+                    }
+                };
+                handshaken = true;
+                anyhow::ensure!(
+                    sent == frame.data,
+                    "message {index} differs from recording\nrecorded {}\nsent     {}",
+                    hex::encode(&frame.data),
+                    hex::encode(&sent)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn read_frame(stream: &mut TcpStream, handshaken: bool) -> Result<Vec<u8>> {
+    let mut frame = vec![0; if handshaken { 4 } else { HANDSHAKE_LENGTH }];
+    stream.read_exact(&mut frame).await?;
+    let read = frame.len();
+    frame.resize(frame_length(&frame, handshaken).expect("prefix read"), 0);
+    stream.read_exact(&mut frame[read..]).await?;
+    Ok(frame)
+}
+
+/// Listener answering every handshake with `answer` as a peer of another protocol
+/// would, returns its address and count of accepted connections
+pub async fn foreign_listener(answer: &'static [u8]) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut handshake = [0u8; 68];
+                stream.read_exact(&mut handshake).await?;
+                stream.write_all(answer).await?;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                anyhow::Ok(())
+            });
+        }
+    });
+    (addr, accepted)
+}
+
+/// Path of `name` in `tests/fixtures`
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// Inputs of `tests/corpus` and every truncation of them, by name
+pub fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(dir).expect("corpus dir") {
+        let path = entry.expect("corpus entry").path();
+        if path.extension().is_none_or(|extension| extension != "bin") {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let data = std::fs::read(&path).expect("corpus file");
+        for end in 0..data.len() {
+            inputs.push((format!("{name}[..{end}]"), data[..end].to_vec()));
+        }
+        inputs.push((name, data));
+    }
+    assert!(!inputs.is_empty(), "corpus is empty");
+    inputs
+}
+
+/// Feeds every corpus input to `parse`, failing with names of inputs it panicked on
+pub fn assert_no_panics(parse: impl Fn(&[u8])) {
+    let panicked: Vec<String> = corpus()
+        .into_iter()
+        .filter(|(_, input)| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| parse(input))).is_err()
+        })
+        .map(|(name, _)| name)
+        .collect();
+    assert!(panicked.is_empty(), "panicked on {panicked:?}");
+}
@@ -0,0 +1,346 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bytes::{Buf, BufMut, BytesMut};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use crate::prelude::*;
+
+use super::{PeersResponse, RedactedUrl, TrackerConfig, TrackerEvent, TrackerResponseTooLarge};
+
+/// BEP 15 magic constant of connect request
+const PROTOCOL_ID: i64 = 0x41727101980;
+const ACTION_CONNECT: i32 = 0;
+const ACTION_ANNOUNCE: i32 = 1;
+const ACTION_ERROR: i32 = 3;
+/// Largest payload of udp datagram
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+pub struct UdpAnnounce {
+    pub info_hash: Bytes20,
+    pub peer_id: Bytes20,
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub port: u16,
+    pub event: Option<TrackerEvent>,
+}
+
+/// Connect then announce over udp, see BEP 15
+#[instrument(skip(announce, config), fields(url = %RedactedUrl(url)))]
+pub async fn udp_announce(
+    url: &Url,
+    announce: &UdpAnnounce,
+    config: &TrackerConfig,
+) -> Result<PeersResponse> {
+    tokio::time::timeout(config.request_timeout, announce_once(url, announce, config))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "udp tracker didn't answer within {:?}",
+                config.request_timeout
+            )
+        })?
+}
+
+async fn announce_once(
+    url: &Url,
+    announce: &UdpAnnounce,
+    config: &TrackerConfig,
+) -> Result<PeersResponse> {
+    let socket = connect_socket(url).await?;
+
+    let transaction_id = rand::random::<i32>();
+    let mut request = BytesMut::with_capacity(16);
+    request.put_i64(PROTOCOL_ID);
+    request.put_i32(ACTION_CONNECT);
+    request.put_i32(transaction_id);
+    let mut response = transact(
+        &socket,
+        &request,
+        ACTION_CONNECT,
+        transaction_id,
+        16,
+        config,
+    )
+    .await?;
+    let connection_id = response.get_i64();
+    trace!("udp tracker connection id {connection_id}");
+
+    let transaction_id = rand::random::<i32>();
+    let mut request = BytesMut::with_capacity(98);
+    request.put_i64(connection_id);
+    request.put_i32(ACTION_ANNOUNCE);
+    request.put_i32(transaction_id);
+    request.put_slice(&announce.info_hash);
+    request.put_slice(&announce.peer_id);
+    request.put_u64(announce.downloaded);
+    request.put_u64(announce.left);
+    request.put_u64(announce.uploaded);
+    request.put_i32(match announce.event {
+        None => 0,
+        Some(TrackerEvent::Completed) => 1,
+        Some(TrackerEvent::Started) => 2,
+        Some(TrackerEvent::Stopped) => 3,
+    });
+    // default ip, key, default number of peers
+    request.put_u32(0);
+    request.put_u32(rand::random());
+    request.put_i32(-1);
+    request.put_u16(announce.port);
+    let mut response = transact(
+        &socket,
+        &request,
+        ACTION_ANNOUNCE,
+        transaction_id,
+        20,
+        config,
+    )
+    .await?;
+
+    let interval = response.get_i32();
+    let leechers = response.get_i32();
+    let seeders = response.get_i32();
+    let peers = response
+        .chunks_exact(6)
+        .map(|f| {
+            let ip = Ipv4Addr::new(f[0], f[1], f[2], f[3]);
+            let port = u16::from_be_bytes([f[4], f[5]]);
+            SocketAddr::new(ip.into(), port)
+        })
+        .collect();
+
+    Ok(PeersResponse {
+        interval: interval.max(0) as u64,
+        min_interval: None,
+        peers,
+        listed_peers: Vec::new(),
+        peers6: Vec::new(),
+        complete: Some(seeders.max(0) as u64),
+        incomplete: Some(leechers.max(0) as u64),
+        external_ip: None,
+    })
+}
+
+async fn connect_socket(url: &Url) -> Result<UdpSocket> {
+    let host = url.host_str().context("udp tracker url has no host")?;
+    let port = url.port().context("udp tracker url has no port")?;
+    // NOTE: peers are ipv4 only, so is the tracker
+    let tracker_addr = tokio::net::lookup_host((host, port))
+        .await
+        .context("resolving udp tracker")?
+        .find(SocketAddr::is_ipv4)
+        .context("udp tracker has no ipv4 address")?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("binding udp socket")?;
+    socket
+        .connect(tracker_addr)
+        .await
+        .context("connecting udp socket")?;
+    Ok(socket)
+}
+
+/// Sends request retransmitting on timeout, returns response body
+/// after action and transaction id once they match.
+/// Wait doubles with every retransmit as in BEP 15, starting from connect timeout
+async fn transact(
+    socket: &UdpSocket,
+    request: &[u8],
+    action: i32,
+    transaction_id: i32,
+    min_length: usize,
+    config: &TrackerConfig,
+) -> Result<BytesMut> {
+    let cap = config.max_response_size;
+    // NOTE: one byte over cap tells oversized datagram from one exactly at cap
+    let mut buffer = vec![0u8; cap.min(MAX_DATAGRAM_SIZE) + 1];
+    for attempt in 0..=config.retries {
+        socket.send(request).await.context("sending udp request")?;
+        let timeout = config
+            .connect_timeout
+            .saturating_mul(2u32.saturating_pow(attempt));
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            let received = match tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+                Ok(received) => received.context("receiving udp response")?,
+                Err(_) => {
+                    trace!("udp tracker timed out after {timeout:?}, attempt {attempt}");
+                    break;
+                }
+            };
+
+            if received > cap {
+                return Err(TrackerResponseTooLarge { cap }.into());
+            }
+            let mut response = BytesMut::from(&buffer[..received]);
+            if response.len() < 8 {
+                trace!("skipping short udp response of {received} bytes");
+                continue;
+            }
+            let response_action = response.get_i32();
+            let response_transaction_id = response.get_i32();
+            if response_transaction_id != transaction_id {
+                trace!("skipping udp response of other transaction");
+                continue;
+            }
+            if response_action == ACTION_ERROR {
+                bail!("udp tracker error: {}", String::from_utf8_lossy(&response));
+            }
+            anyhow::ensure!(
+                response_action == action,
+                "udp tracker responded with action {response_action}, expected {action}"
+            );
+            anyhow::ensure!(
+                response.len() >= min_length - 8,
+                "udp tracker response of {received} bytes is too short"
+            );
+            return Ok(response);
+        }
+    }
+
+    bail!(
+        "udp tracker didn't respond after {} attempts",
+        config.retries + 1
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    const CONNECTION_ID: i64 = 0x1234_5678;
+
+    fn announce() -> UdpAnnounce {
+        UdpAnnounce {
+            info_hash: [7; 20],
+            peer_id: [8; 20],
+            downloaded: 1,
+            left: 2,
+            uploaded: 3,
+            port: 6881,
+            event: Some(TrackerEvent::Started),
+        }
+    }
+
+    fn config() -> TrackerConfig {
+        TrackerConfig {
+            connect_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_secs(3),
+            ..Default::default()
+        }
+    }
+
+    /// Receives next request, returning it with transaction id and sender
+    async fn receive(socket: &UdpSocket) -> (BytesMut, i32, SocketAddr) {
+        let mut buffer = [0u8; 1024];
+        let (received, from) = socket.recv_from(&mut buffer).await.unwrap();
+        let mut request = BytesMut::from(&buffer[..received]);
+        let transaction_id = i32::from_be_bytes(request[12..16].try_into().unwrap());
+        if request.len() == 16 {
+            assert_eq!(request.get_i64(), PROTOCOL_ID);
+        } else {
+            assert_eq!(request.get_i64(), CONNECTION_ID);
+        }
+        (request, transaction_id, from)
+    }
+
+    fn response(action: i32, transaction_id: i32, body: &[u8]) -> Vec<u8> {
+        let mut response = BytesMut::new();
+        response.put_i32(action);
+        response.put_i32(transaction_id);
+        response.put_slice(body);
+        response.to_vec()
+    }
+
+    /// Tracker dropping first `ignored` connect requests, every answer
+    /// is preceded by a stale one of another transaction
+    async fn udp_tracker(ignored: usize) -> (Url, tokio::task::JoinHandle<BytesMut>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let tracker = tokio::spawn(async move {
+            let (mut connect, mut transaction_id, mut client) = receive(&socket).await;
+            for _ in 0..ignored {
+                (connect, transaction_id, client) = receive(&socket).await;
+            }
+            assert_eq!(connect.get_i32(), ACTION_CONNECT);
+            let stale = response(ACTION_CONNECT, transaction_id.wrapping_add(1), &[0; 8]);
+            socket.send_to(&stale, client).await.unwrap();
+            let connected = response(ACTION_CONNECT, transaction_id, &CONNECTION_ID.to_be_bytes());
+            socket.send_to(&connected, client).await.unwrap();
+
+            let (mut announce, transaction_id, client) = receive(&socket).await;
+            assert_eq!(announce.get_i32(), ACTION_ANNOUNCE);
+            announce.advance(4);
+            let mut body = BytesMut::new();
+            body.put_i32(900);
+            body.put_i32(3);
+            body.put_i32(5);
+            body.put_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+            let announced = response(ACTION_ANNOUNCE, transaction_id, &body);
+            socket.send_to(&announced, client).await.unwrap();
+            announce
+        });
+        (url, tracker)
+    }
+
+    #[tokio::test]
+    async fn announce_returns_compact_peers_and_swarm() {
+        let (url, tracker) = udp_tracker(0).await;
+
+        let response = udp_announce(&url, &announce(), &config()).await.unwrap();
+
+        assert_eq!(response.interval, 900);
+        assert_eq!((response.complete, response.incomplete), (Some(5), Some(3)));
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+        let mut sent = tracker.await.unwrap();
+        assert_eq!(sent.split_to(20).as_ref(), [7; 20]);
+        assert_eq!(sent.split_to(20).as_ref(), [8; 20]);
+        assert_eq!((sent.get_u64(), sent.get_u64(), sent.get_u64()), (1, 2, 3));
+        assert_eq!(sent.get_i32(), 2);
+        sent.advance(8);
+        assert_eq!((sent.get_i32(), sent.get_u16()), (-1, 6881));
+    }
+
+    #[tokio::test]
+    async fn lost_connect_is_retransmitted() {
+        let (url, tracker) = udp_tracker(1).await;
+
+        let started = Instant::now();
+        let response = udp_announce(&url, &announce(), &config()).await.unwrap();
+
+        assert_eq!(response.peers.len(), 2);
+        assert!(started.elapsed() >= config().connect_timeout);
+        tracker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_tracker_fails_within_request_timeout() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", silent.local_addr().unwrap())).unwrap();
+        let config = TrackerConfig {
+            request_timeout: Duration::from_millis(500),
+            ..config()
+        };
+
+        let started = Instant::now();
+        let err = udp_announce(&url, &announce(), &config).await.unwrap_err();
+
+        assert!(err.to_string().contains("didn't answer"), "{err:#}");
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+}